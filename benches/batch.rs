@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use benchmark::{measure, scale};
+use kerosene::{
+    Config, Exit, IntoAsyncActor, Pid,
+    global::{send, sleep, spawn, sync::stop},
+    receive,
+};
+
+const MESSAGES: i32 = 100000;
+
+async fn receive_actor() -> Exit {
+    let mut count = 0;
+    let now = Instant::now();
+    loop {
+        receive! {
+            match i32 {
+                _ => { count += 1; }
+            }
+        };
+        if count == MESSAGES {
+            measure(now.elapsed());
+            stop();
+        }
+    }
+}
+
+fn sender_actor(receiver: Pid) -> impl IntoAsyncActor {
+    async move || {
+        for i in 0..MESSAGES {
+            send(receiver, i).await;
+        }
+
+        Exit::Normal
+    }
+}
+
+async fn main_actor() -> Exit {
+    let receive = spawn(receive_actor).await;
+    spawn(sender_actor(receive)).await;
+
+    // Just idle.
+    sleep(Duration::from_secs(100)).await;
+
+    Exit::Normal
+}
+
+fn run_batched(poll_batch: usize) {
+    scale(MESSAGES as usize);
+
+    let config = Config {
+        poll_batch,
+        ..Config::default()
+    };
+
+    kerosene::run_with(config, main_actor);
+}
+
+fn main() -> Result<(), benchmark::Regressions> {
+    benchmark::benchmark("receive 100000 messages, poll batch 1", || run_batched(1));
+    benchmark::benchmark("receive 100000 messages, poll batch 8", || run_batched(8));
+
+    benchmark::finish()
+}
@@ -0,0 +1,53 @@
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use benchmark::{measure, scale};
+use kerosene::{
+    Exit, IntoAsyncActor, global::sync::stop, library::io::buffer_pool::reserve_buffer,
+};
+
+const SIZE: usize = 1 << 20;
+const BUFFERS: usize = 100;
+
+fn resize_actor(uninit: bool) -> impl IntoAsyncActor {
+    async move || {
+        let mut elapsed = Duration::ZERO;
+
+        for _ in 0..BUFFERS {
+            let mut buffer = reserve_buffer(SIZE).await;
+
+            let now = Instant::now();
+            if uninit {
+                unsafe { buffer.resize_uninit(SIZE) };
+            } else {
+                buffer.resize(SIZE);
+            }
+
+            // Overwrite the whole buffer through the pointer, like `ReadFile` would.
+            unsafe { std::ptr::write_bytes(buffer.as_mut_ptr(), 1, SIZE) };
+            elapsed += now.elapsed();
+
+            black_box(&buffer);
+        }
+
+        measure(elapsed);
+        stop();
+
+        Exit::Normal
+    }
+}
+
+fn main() -> Result<(), benchmark::Regressions> {
+    benchmark::benchmark("resize and fill 100 1 MiB buffers, zeroed", || {
+        scale(BUFFERS);
+        kerosene::run(resize_actor(false));
+    });
+    benchmark::benchmark("resize and fill 100 1 MiB buffers, uninit", || {
+        scale(BUFFERS);
+        kerosene::run(resize_actor(true));
+    });
+
+    benchmark::finish()
+}
@@ -6,7 +6,7 @@ use kerosene::{
     run,
 };
 
-fn main() {
+fn main() -> Result<(), benchmark::Regressions> {
     benchmark::benchmark("Print Hello, World!", || {
         run(async move || {
             let now = Instant::now();
@@ -21,4 +21,6 @@ fn main() {
             Exit::Normal
         });
     });
+
+    benchmark::finish()
 }
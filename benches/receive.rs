@@ -44,10 +44,12 @@ async fn main_actor() -> Exit {
     Exit::Normal
 }
 
-fn main() {
+fn main() -> Result<(), benchmark::Regressions> {
     benchmark::benchmark("receive 100000 messages", || {
         scale(100000);
 
         kerosene::run(main_actor);
     });
+
+    benchmark::finish()
 }
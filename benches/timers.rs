@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+
+use benchmark::{measure, scale};
+use kerosene::{
+    Exit,
+    global::sync::{cancel_timer, pid, schedule, stop},
+};
+
+const TIMERS: usize = 100000;
+
+async fn main_actor() -> Exit {
+    let now = Instant::now();
+
+    // Far enough out that none of them fire while the benchmark runs.
+    let ids: Vec<_> = (0..TIMERS)
+        .map(|i| schedule(pid(), i, Duration::from_secs(60 + (i % 1000) as u64)))
+        .collect();
+
+    for id in ids {
+        cancel_timer(id);
+    }
+
+    measure(now.elapsed());
+    stop();
+
+    Exit::Normal
+}
+
+fn main() -> Result<(), benchmark::Regressions> {
+    benchmark::benchmark("schedule and cancel 100000 timers", || {
+        scale(TIMERS);
+        kerosene::run(main_actor);
+    });
+
+    benchmark::finish()
+}
@@ -3,17 +3,31 @@ mod samples;
 mod stats;
 
 use std::{
+    error::Error,
+    fmt::Display,
     hint::black_box,
+    sync::Mutex,
     time::{Duration, Instant},
 };
 
 use stats::*;
 
+pub use stats::{Stats, Thresholds};
+
 use crate::samples::SampleSet;
 
 static mut TIME: Duration = Duration::ZERO;
 static mut SCALE: usize = 1;
 
+/// Trivial closures can measure as zero, they are counted as taking at least this long.
+const MIN_ITERATION_TIME: Duration = Duration::from_nanos(1);
+
+/// Every sample is kept in memory, so the number of iterations is capped.
+const MAX_ITERATIONS: usize = 1_000_000;
+
+/// The regressions of this process, reported by `finish`.
+static REGRESSIONS: Mutex<Vec<Regression>> = Mutex::new(Vec::new());
+
 pub fn scale(scale: usize) {
     unsafe {
         SCALE = scale;
@@ -26,10 +40,84 @@ pub fn measure(elapsed: Duration) {
     }
 }
 
-pub fn benchmark<F>(name: &str, mut f: F)
+/// A benchmark regressed beyond the configured threshold.
+#[derive(Debug)]
+pub struct Regression {
+    pub name: String,
+    pub percent: f64,
+    pub max_regression: f64,
+}
+
+impl Display for Regression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Benchmark '{}' regressed by {:.2}% (max {:.2}%)",
+            self.name, self.percent, self.max_regression
+        )
+    }
+}
+
+impl Error for Regression {}
+
+/// Every benchmark that regressed in this process, see `finish`.
+#[derive(Debug)]
+pub struct Regressions(pub Vec<Regression>);
+
+impl Display for Regressions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} benchmark(s) regressed", self.0.len())?;
+        for regression in &self.0 {
+            write!(f, "\n  {}", regression)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for Regressions {}
+
+/// Report the regressions of every benchmark that ran so far, call this at the end of `main`.
+///
+/// Returns an error if any benchmark regressed beyond `KEROSENE_BENCH_MAX_REGRESSION`, see `Thresholds::from_env`.
+pub fn finish() -> Result<(), Regressions> {
+    let regressions = std::mem::take(&mut *REGRESSIONS.lock().unwrap());
+
+    if regressions.is_empty() {
+        Ok(())
+    } else {
+        Err(Regressions(regressions))
+    }
+}
+
+/// Run a benchmark and compare it against the previous run.
+///
+/// Set `KEROSENE_BENCH_BASELINE` to compare against a named baseline instead,
+/// and `KEROSENE_BENCH_SAVE_BASELINE` to also save the results as a named baseline.
+/// Set `KEROSENE_BENCH_TRIM` to leave out the fastest and slowest percentage of samples when computing the mean.
+/// Results are stored in `target/benchmarks`, set `KEROSENE_BENCH_DIR` to store them elsewhere.
+///
+/// A regression beyond `KEROSENE_BENCH_MAX_REGRESSION` doesn't stop the other benchmarks, it is reported by `finish`.
+pub fn benchmark<F>(name: &str, f: F)
 where
     F: FnMut(),
 {
+    let baseline = std::env::var("KEROSENE_BENCH_BASELINE").ok();
+    benchmark_against(name, baseline.as_deref(), f)
+}
+
+/// Run a benchmark and compare it against a named baseline, or the previous run if `None`.
+pub fn benchmark_against<F>(name: &str, baseline: Option<&str>, mut f: F)
+where
+    F: FnMut(),
+{
+    let thresholds = Thresholds::from_env();
+    let directory = directory();
+    let trim = std::env::var("KEROSENE_BENCH_TRIM")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0.0);
+
     scale(1);
 
     let n = black_box(find_iterations(Duration::from_secs(5), &mut f));
@@ -46,21 +134,67 @@ where
         samples.push(unsafe { TIME } / scale as u32);
     }
 
-    let stats = samples.to_stats();
+    let stats = Stats {
+        scale,
+        ..samples.to_stats(trim)
+    };
 
     println!("");
     println!("Benchmark '{}':", name);
     println!("  Results are scaled by {}", scale);
     println!("{:<22} {:>12}", "warmup iterations:", m);
     println!("{:<22} {:>12}", "iterations:", n);
+    if trim > 0.0 {
+        println!("  Mean is trimmed by {}% on both ends", trim);
+    }
     println!("");
     println!("{}", &stats);
     println!("");
-    compare_stats(name, &stats);
+    let comparison = compare_stats(&directory, name, baseline, &stats, &thresholds);
     println!("");
     println!("{}", samples.histogram());
 
-    stats.save(name);
+    stats.save(&directory, name);
+    if let Ok(save_as) = std::env::var("KEROSENE_BENCH_SAVE_BASELINE") {
+        stats.save_as(&directory, name, &save_as);
+    }
+    export_json(name, &stats);
+
+    if let (Some(comparison), Some(max_regression)) = (comparison, thresholds.max_regression)
+        && comparison.is_regression(max_regression)
+    {
+        REGRESSIONS.lock().unwrap().push(Regression {
+            name: name.to_string(),
+            percent: comparison.percent(),
+            max_regression,
+        });
+    }
+}
+
+/// The directory results and baselines are stored in, `KEROSENE_BENCH_DIR` or `target/benchmarks`.
+fn directory() -> String {
+    std::env::var("KEROSENE_BENCH_DIR").unwrap_or_else(|_| "target/benchmarks".to_string())
+}
+
+/// Tests keep their results out of the real ones.
+#[cfg(test)]
+fn test_directory() -> String {
+    std::env::temp_dir()
+        .join("kerosene-benchmarks")
+        .display()
+        .to_string()
+}
+
+/// Adds the result to the JSON array at the path in `KEROSENE_BENCH_JSON`, if set.
+///
+/// Every bench binary is its own process, so results are merged into the existing file.
+/// Results of benchmarks that were removed stay in the file until it is deleted.
+fn export_json(name: &str, stats: &Stats) {
+    let Ok(path) = std::env::var("KEROSENE_BENCH_JSON") else {
+        return;
+    };
+
+    stats.export_json(name, &path);
 }
 
 fn find_iterations<F>(duration: Duration, f: &mut F) -> usize
@@ -69,22 +203,116 @@ where
 {
     let now = Instant::now();
     f();
-    let time = now.elapsed();
+    let time = now.elapsed().max(MIN_ITERATION_TIME);
 
     let iterations = duration.div_duration_f64(time);
-    iterations as _
+    (iterations as usize).clamp(1, MAX_ITERATIONS)
 }
 
-fn compare_stats(name: &str, current: &Stats) {
-    let Some(prev) = Stats::load(name) else {
-        println!("No previous benchmark found.");
-        return;
+fn compare_stats(
+    directory: &str,
+    name: &str,
+    baseline: Option<&str>,
+    current: &Stats,
+    thresholds: &Thresholds,
+) -> Option<Comparison> {
+    let prev = match baseline {
+        Some(baseline) => Stats::load_from(directory, name, baseline),
+        None => Stats::load(directory, name),
     };
 
-    let comparison = Comparison::compare(&prev, current);
+    let Some(prev) = prev else {
+        match baseline {
+            Some(baseline) => println!("Baseline '{}' not found.", baseline),
+            None => println!("No previous benchmark found."),
+        }
+        return None;
+    };
 
-    println!("Comparison with previous run:");
+    // Trimming changes the mean, only means that were trimmed the same way can be compared.
+    if prev.trim != current.trim {
+        println!(
+            "Previous mean is trimmed by {}%, current mean by {}%, not comparing.",
+            prev.trim, current.trim
+        );
+        return None;
+    }
+
+    let comparison = Comparison::compare(&prev, current, thresholds);
+
+    match baseline {
+        Some(baseline) => println!("Comparison with baseline '{}':", baseline),
+        None => println!("Comparison with previous run:"),
+    }
     println!("{:<22} {:>12.3?}", "Previous mean:", prev.mean);
     println!("{:<22} {:>12.3?}", "Current mean:", current.mean);
     println!("{}", comparison);
+
+    Some(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        MAX_ITERATIONS, REGRESSIONS, Regression, Thresholds, compare_stats, find_iterations,
+        finish, samples::SampleSet, test_directory as directory,
+    };
+
+    #[test]
+    fn test_finish_reports_every_regression() {
+        for name in ["first", "second"] {
+            REGRESSIONS.lock().unwrap().push(Regression {
+                name: name.to_string(),
+                percent: 20.0,
+                max_regression: 10.0,
+            });
+        }
+
+        let regressions = finish().unwrap_err();
+        let names: Vec<_> = regressions.0.iter().map(|r| r.name.as_str()).collect();
+
+        assert_eq!(names, ["first", "second"]);
+        assert!(finish().is_ok());
+    }
+
+    #[test]
+    fn test_differently_trimmed_means_are_not_compared() {
+        let name = "differently trimmed";
+        let samples = SampleSet::from((1..=100).map(Duration::from_micros).collect::<Vec<_>>());
+        let thresholds = Thresholds {
+            noise_margin: 5.0,
+            max_regression: Some(10.0),
+        };
+
+        let directory = directory();
+
+        samples.to_stats(0.0).save_as(&directory, name, "untrimmed");
+
+        let trimmed = samples.to_stats(10.0);
+        let comparison = compare_stats(&directory, name, Some("untrimmed"), &trimmed, &thresholds);
+        assert!(comparison.is_none());
+
+        let untrimmed = samples.to_stats(0.0);
+        let comparison =
+            compare_stats(&directory, name, Some("untrimmed"), &untrimmed, &thresholds);
+        assert!(comparison.is_some());
+    }
+
+    #[test]
+    fn test_find_iterations_is_bounded() {
+        let iterations = find_iterations(Duration::from_secs(5), &mut || {});
+
+        assert!((1..=MAX_ITERATIONS).contains(&iterations));
+    }
+
+    #[test]
+    fn test_find_iterations_runs_slow_closures_once() {
+        let iterations = find_iterations(Duration::from_millis(1), &mut || {
+            std::thread::sleep(Duration::from_millis(5))
+        });
+
+        assert_eq!(iterations, 1);
+    }
 }
@@ -45,9 +45,35 @@ impl SampleSet {
         self.total() / self.samples.len() as u32
     }
 
+    /// The mean without the lowest and highest `percent` of the samples, e.g. a slow first iteration.
+    ///
+    /// At least one sample is kept.
+    pub fn trimmed_mean(&self, percent: f64) -> Duration {
+        let durations = self.sorted();
+        if durations.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let trim = (durations.len() as f64 * percent / 100.0) as usize;
+        let trim = trim.min((durations.len() - 1) / 2);
+        let kept = &durations[trim..durations.len() - trim];
+
+        kept.iter().sum::<Duration>() / kept.len() as u32
+    }
+
+    /// The sample below which `percent` of the samples fall, using the nearest rank.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        let durations = self.sorted();
+        if durations.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let rank = (durations.len() as f64 * percent / 100.0).ceil() as usize;
+        durations[rank.clamp(1, durations.len()) - 1]
+    }
+
     pub fn median(&self) -> Duration {
-        let mut durations = self.samples.clone();
-        durations.sort();
+        let durations = self.sorted();
 
         let mid = durations.len() / 2;
         if durations.len() % 2 == 0 {
@@ -77,14 +103,64 @@ impl SampleSet {
         Histogram::new(&self.samples)
     }
 
-    pub fn to_stats(&self) -> Stats {
+    /// The stats of the samples, the mean is trimmed by `trim` percent on both ends, see `trimmed_mean`.
+    pub fn to_stats(&self, trim: f64) -> Stats {
         Stats {
+            samples: self.samples.len(),
+            scale: 1,
             total: self.total(),
-            mean: self.mean(),
+            mean: self.trimmed_mean(trim),
             median: self.median(),
             stddev: self.stddev(),
             min: self.min(),
             max: self.max(),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            trim,
         }
     }
+
+    fn sorted(&self) -> Vec<Duration> {
+        let mut durations = self.samples.clone();
+        durations.sort();
+
+        durations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SampleSet;
+
+    fn nanos(range: impl Iterator<Item = u64>) -> SampleSet {
+        SampleSet::from(range.map(Duration::from_nanos).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let samples = nanos(1..=100);
+
+        assert_eq!(samples.percentile(90.0), Duration::from_nanos(90));
+        assert_eq!(samples.percentile(99.0), Duration::from_nanos(99));
+        assert_eq!(samples.percentile(100.0), Duration::from_nanos(100));
+
+        let stats = samples.to_stats(0.0);
+        assert_eq!(stats.p90, Duration::from_nanos(90));
+        assert_eq!(stats.p99, Duration::from_nanos(99));
+    }
+
+    #[test]
+    fn test_trimmed_mean_drops_outliers() {
+        // A slow first iteration that skews the mean.
+        let samples = nanos([10_000].into_iter().chain(1..=99));
+
+        assert_eq!(samples.mean(), Duration::from_nanos(149));
+        assert_eq!(samples.trimmed_mean(1.0), Duration::from_nanos(50));
+        assert_eq!(samples.to_stats(1.0).mean, Duration::from_nanos(50));
+
+        // Trimming everything keeps the middle sample.
+        assert_eq!(nanos(1..=3).trimmed_mean(50.0), Duration::from_nanos(2));
+    }
 }
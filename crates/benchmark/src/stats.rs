@@ -2,12 +2,18 @@ use std::{fmt::Display, time::Duration};
 
 #[derive(Debug)]
 pub struct Stats {
+    pub samples: usize,
+    pub scale: usize,
     pub total: Duration,
     pub mean: Duration,
     pub median: Duration,
     pub stddev: Duration,
     pub min: Duration,
     pub max: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    /// The percentage of samples left out on both ends when computing the mean.
+    pub trim: f64,
 }
 
 impl Display for Stats {
@@ -17,6 +23,8 @@ impl Display for Stats {
         writeln!(f, "{:<22} {:>12.3?}", "Max time:", self.max)?;
         writeln!(f, "{:<22} {:>12.3?}", "Mean time:", self.mean)?;
         writeln!(f, "{:<22} {:>12.3?}", "Median time:", self.median)?;
+        writeln!(f, "{:<22} {:>12.3?}", "P90 time:", self.p90)?;
+        writeln!(f, "{:<22} {:>12.3?}", "P99 time:", self.p99)?;
         writeln!(f, "{:<22} {:>12.3?}", "Stddev time:", self.stddev)?;
 
         Ok(())
@@ -31,56 +39,245 @@ impl Stats {
         stddev.as_secs_f64() / mean.as_secs_f64() * 100.0
     }
 
-    pub fn save(&self, name: &str) {
-        let path = format!("target/benchmarks/{}.bench", sanitize(name));
-        std::fs::create_dir_all("target/benchmarks").unwrap();
+    /// Save as the last run.
+    pub fn save(&self, directory: &str, name: &str) {
+        self.save_to(&path(directory, name, None, "bench"));
+    }
+
+    /// Save as a named baseline.
+    pub fn save_as(&self, directory: &str, name: &str, baseline: &str) {
+        self.save_to(&path(directory, name, Some(baseline), "bench"));
+    }
+
+    /// Load the last run.
+    pub fn load(directory: &str, name: &str) -> Option<Stats> {
+        Self::load_path(&path(directory, name, None, "bench"))
+    }
+
+    /// Load a named baseline.
+    pub fn load_from(directory: &str, name: &str, baseline: &str) -> Option<Stats> {
+        Self::load_path(&path(directory, name, Some(baseline), "bench"))
+    }
+
+    fn save_to(&self, path: &str) {
+        create_parent(path);
         let contents = format!(
-            "mean_ns={}\nmedian_ns={}\nstddev_ns={}\nmin_ns={}\nmax_ns={}\ntotal_ns={}",
+            "samples={}\nscale={}\nmean_ns={}\nmedian_ns={}\nstddev_ns={}\nmin_ns={}\nmax_ns={}\ntotal_ns={}\np90_ns={}\np99_ns={}\ntrim={}",
+            self.samples,
+            self.scale,
             self.mean.as_nanos(),
             self.median.as_nanos(),
             self.stddev.as_nanos(),
             self.min.as_nanos(),
             self.max.as_nanos(),
-            self.total.as_nanos()
+            self.total.as_nanos(),
+            self.p90.as_nanos(),
+            self.p99.as_nanos(),
+            self.trim
         );
         std::fs::write(path, contents).unwrap();
     }
 
-    pub fn load(name: &str) -> Option<Stats> {
-        let path = format!("target/benchmarks/{}.bench", sanitize(name));
+    fn load_path(path: &str) -> Option<Stats> {
         let contents = std::fs::read_to_string(path).ok()?;
 
+        let mut samples: Option<usize> = None;
+        let mut scale: Option<usize> = None;
         let mut mean_ns: Option<u64> = None;
         let mut median_ns: Option<u64> = None;
         let mut stddev_ns: Option<u64> = None;
         let mut min_ns: Option<u64> = None;
         let mut max_ns: Option<u64> = None;
         let mut total_ns: Option<u64> = None;
+        let mut p90_ns: Option<u64> = None;
+        let mut p99_ns: Option<u64> = None;
+        let mut trim: Option<f64> = None;
 
         for line in contents.lines() {
             let mut parts = line.split('=');
             let key = parts.next()?;
             let value = parts.next()?;
             match key.trim() {
+                "samples" => samples = value.trim().parse().ok(),
+                "scale" => scale = value.trim().parse().ok(),
                 "mean_ns" => mean_ns = value.trim().parse().ok(),
                 "median_ns" => median_ns = value.trim().parse().ok(),
                 "stddev_ns" => stddev_ns = value.trim().parse().ok(),
                 "min_ns" => min_ns = value.trim().parse().ok(),
                 "max_ns" => max_ns = value.trim().parse().ok(),
                 "total_ns" => total_ns = value.trim().parse().ok(),
+                "p90_ns" => p90_ns = value.trim().parse().ok(),
+                "p99_ns" => p99_ns = value.trim().parse().ok(),
+                "trim" => trim = value.trim().parse().ok(),
                 _ => {}
             }
         }
 
+        // Older files don't contain the sample count, scale, percentiles and trim.
         Some(Stats {
+            samples: samples.unwrap_or(0),
+            scale: scale.unwrap_or(1),
             total: Duration::from_nanos(total_ns?),
             mean: Duration::from_nanos(mean_ns?),
             median: Duration::from_nanos(median_ns?),
             stddev: Duration::from_nanos(stddev_ns?),
             min: Duration::from_nanos(min_ns?),
             max: Duration::from_nanos(max_ns?),
+            p90: Duration::from_nanos(p90_ns.unwrap_or(0)),
+            p99: Duration::from_nanos(p99_ns.unwrap_or(0)),
+            trim: trim.unwrap_or(0.0),
         })
     }
+
+    /// Serialize the stats as a JSON object.
+    pub fn to_json(&self, name: &str) -> String {
+        format!(
+            "{{\"name\":\"{}\",\"samples\":{},\"scale\":{},\"mean_ns\":{},\"median_ns\":{},\"stddev_ns\":{},\"min_ns\":{},\"max_ns\":{},\"total_ns\":{},\"p90_ns\":{},\"p99_ns\":{},\"trim\":{}}}",
+            escape(name),
+            self.samples,
+            self.scale,
+            self.mean.as_nanos(),
+            self.median.as_nanos(),
+            self.stddev.as_nanos(),
+            self.min.as_nanos(),
+            self.max.as_nanos(),
+            self.total.as_nanos(),
+            self.p90.as_nanos(),
+            self.p99.as_nanos(),
+            self.trim
+        )
+    }
+
+    /// Parse stats from a JSON object written by `to_json`.
+    pub fn from_json(contents: &str) -> Option<Stats> {
+        Some(Stats {
+            samples: json_number(contents, "samples")? as usize,
+            scale: json_number(contents, "scale")? as usize,
+            total: Duration::from_nanos(json_number(contents, "total_ns")?),
+            mean: Duration::from_nanos(json_number(contents, "mean_ns")?),
+            median: Duration::from_nanos(json_number(contents, "median_ns")?),
+            stddev: Duration::from_nanos(json_number(contents, "stddev_ns")?),
+            min: Duration::from_nanos(json_number(contents, "min_ns")?),
+            max: Duration::from_nanos(json_number(contents, "max_ns")?),
+            p90: Duration::from_nanos(json_number(contents, "p90_ns").unwrap_or(0)),
+            p99: Duration::from_nanos(json_number(contents, "p99_ns").unwrap_or(0)),
+            trim: json_float(contents, "trim").unwrap_or(0.0),
+        })
+    }
+
+    pub fn save_json(&self, directory: &str, name: &str) {
+        let path = path(directory, name, None, "json");
+        create_parent(&path);
+        std::fs::write(path, self.to_json(name)).unwrap();
+    }
+
+    /// Add to the JSON array at `path`, replacing an earlier result with the same name.
+    ///
+    /// Results of other benchmarks are kept, including ones that no longer exist. Remove the file to start over.
+    pub(crate) fn export_json(&self, name: &str, path: &str) {
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        let prefix = format!("{{\"name\":\"{}\",", escape(name));
+
+        let mut results: Vec<&str> = json_objects(&contents)
+            .into_iter()
+            .filter(|result| !result.starts_with(&prefix))
+            .collect();
+        let json = self.to_json(name);
+        results.push(&json);
+
+        create_parent(path);
+        std::fs::write(path, format!("[{}]", results.join(","))).unwrap();
+    }
+
+    pub fn load_json(directory: &str, name: &str) -> Option<Stats> {
+        let path = path(directory, name, None, "json");
+        let contents = std::fs::read_to_string(path).ok()?;
+
+        Self::from_json(&contents)
+    }
+}
+
+/// Find a numeric field in a flat JSON object.
+fn json_number(contents: &str, key: &str) -> Option<u64> {
+    json_value(contents, key, |c| c.is_ascii_digit())?
+        .parse()
+        .ok()
+}
+
+/// Find a numeric field in a flat JSON object that may have a fraction.
+fn json_float(contents: &str, key: &str) -> Option<f64> {
+    json_value(contents, key, |c| c.is_ascii_digit() || c == '.')?
+        .parse()
+        .ok()
+}
+
+fn json_value<'a>(contents: &'a str, key: &str, is_part: impl Fn(char) -> bool) -> Option<&'a str> {
+    let pattern = format!("\"{}\":", key);
+    let start = contents.find(&pattern)? + pattern.len();
+    let value = contents[start..].trim_start();
+    let end = value.find(|c: char| !is_part(c)).unwrap_or(value.len());
+
+    Some(&value[..end])
+}
+
+/// Split a JSON array of flat objects into its objects.
+fn json_objects(contents: &str) -> Vec<&str> {
+    let mut objects = Vec::new();
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in contents.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '{' if !in_string => start = Some(i),
+            '}' if !in_string => {
+                if let Some(start) = start.take() {
+                    objects.push(&contents[start..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    objects
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// The path of a benchmark, named baselines are stored in their own directory.
+fn path(directory: &str, name: &str, baseline: Option<&str>, extension: &str) -> String {
+    match baseline {
+        Some(baseline) => format!(
+            "{}/{}/{}.{}",
+            directory,
+            sanitize(baseline),
+            sanitize(name),
+            extension
+        ),
+        None => format!("{}/{}.{}", directory, sanitize(name), extension),
+    }
+}
+
+fn create_parent(path: &str) {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
 }
 
 fn sanitize(name: &str) -> String {
@@ -89,25 +286,66 @@ fn sanitize(name: &str) -> String {
         .collect()
 }
 
+/// Thresholds used when comparing against a previous run.
+#[derive(Copy, Clone, Debug)]
+pub struct Thresholds {
+    /// Changes within this percentage are considered noise,
+    /// as are all changes when the coefficient of variation exceeds it.
+    pub noise_margin: f64,
+
+    /// A regression beyond this percentage fails the benchmark.
+    pub max_regression: Option<f64>,
+}
+
+impl Thresholds {
+    /// Read the thresholds from the environment.
+    ///
+    /// - `KEROSENE_BENCH_NOISE_MARGIN`: the noise margin in percent, defaults to 5.
+    /// - `KEROSENE_BENCH_MAX_REGRESSION`: the maximum allowed regression in percent, disabled by default.
+    pub fn from_env() -> Self {
+        let env = |key| std::env::var(key).ok().and_then(|value| value.parse().ok());
+
+        Self {
+            noise_margin: env("KEROSENE_BENCH_NOISE_MARGIN").unwrap_or(5.0),
+            max_regression: env("KEROSENE_BENCH_MAX_REGRESSION"),
+        }
+    }
+}
+
 pub struct Comparison {
     delta: f64,
     percent: f64,
     cv: f64,
+    margin: f64,
 }
 
 impl Comparison {
-    pub fn compare(prev: &Stats, current: &Stats) -> Self {
+    pub fn compare(prev: &Stats, current: &Stats, thresholds: &Thresholds) -> Self {
         let prev_mean = prev.mean.as_nanos() as f64;
         let curr_mean = current.mean.as_nanos() as f64;
         let delta = curr_mean - prev_mean;
         let percent = delta / prev_mean * 100.0;
         let cv = current.cv();
 
-        Self { delta, percent, cv }
+        Self {
+            delta,
+            percent,
+            cv,
+            margin: thresholds.noise_margin,
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.percent
     }
 
-    fn is_noise(&self, margin: f64) -> bool {
-        self.cv >= margin
+    fn is_noise(&self) -> bool {
+        self.cv >= self.margin
+    }
+
+    /// Returns true if this is a regression beyond `max_regression` percent that isn't noise.
+    pub fn is_regression(&self, max_regression: f64) -> bool {
+        !self.is_noise() && self.percent > max_regression
     }
 }
 
@@ -122,9 +360,9 @@ impl Display for Comparison {
             self.percent
         )?;
 
-        let margin = 5.0; // ±5%
+        let margin = self.margin;
 
-        if self.is_noise(margin) {
+        if self.is_noise() {
             writeln!(
                 f,
                 "⚠️  High variation detected (CV >= {:.1}%), results likely noise",
@@ -151,3 +389,139 @@ impl Display for Comparison {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Comparison, Stats, Thresholds};
+    use crate::test_directory as directory;
+
+    fn stats() -> Stats {
+        Stats {
+            samples: 100,
+            scale: 1000,
+            total: Duration::from_nanos(123_456),
+            mean: Duration::from_nanos(1_234),
+            median: Duration::from_nanos(1_200),
+            stddev: Duration::from_nanos(56),
+            min: Duration::from_nanos(1_000),
+            max: Duration::from_nanos(2_000),
+            p90: Duration::from_nanos(1_500),
+            p99: Duration::from_nanos(1_900),
+            trim: 2.5,
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let name = "json \"round\" trip";
+        let stats = stats();
+        stats.save_json(&directory(), name);
+
+        let loaded = Stats::load_json(&directory(), name).unwrap();
+
+        assert_eq!(loaded.samples, stats.samples);
+        assert_eq!(loaded.scale, stats.scale);
+        assert_eq!(loaded.total, stats.total);
+        assert_eq!(loaded.mean, stats.mean);
+        assert_eq!(loaded.median, stats.median);
+        assert_eq!(loaded.stddev, stats.stddev);
+        assert_eq!(loaded.min, stats.min);
+        assert_eq!(loaded.max, stats.max);
+        assert_eq!(loaded.p90, stats.p90);
+        assert_eq!(loaded.p99, stats.p99);
+        assert_eq!(loaded.trim, stats.trim);
+    }
+
+    #[test]
+    fn test_json_escapes_name() {
+        let json = stats().to_json("a \"quoted\" name");
+
+        assert!(json.starts_with(r#"{"name":"a \"quoted\" name","samples":100,"#));
+    }
+
+    #[test]
+    fn test_export_json_merges_results() {
+        let path = directory() + "/export.json";
+        let _ = std::fs::remove_file(&path);
+
+        // Two bench binaries in one run, the second also reruns a benchmark.
+        with_mean(1_000, 10).export_json("first", &path);
+        with_mean(2_000, 10).export_json("a {\"braced\"} name", &path);
+        with_mean(3_000, 10).export_json("first", &path);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let results = super::json_objects(&contents);
+
+        assert!(contents.starts_with('[') && contents.ends_with(']'));
+        assert_eq!(results.len(), 2);
+        assert!(results[0].starts_with(r#"{"name":"a {\"braced\"} name","#));
+        assert_eq!(
+            Stats::from_json(results[0]).unwrap().mean,
+            Duration::from_nanos(2_000)
+        );
+        assert!(results[1].starts_with(r#"{"name":"first","#));
+        assert_eq!(
+            Stats::from_json(results[1]).unwrap().mean,
+            Duration::from_nanos(3_000)
+        );
+    }
+
+    fn with_mean(mean: u64, stddev: u64) -> Stats {
+        Stats {
+            mean: Duration::from_nanos(mean),
+            stddev: Duration::from_nanos(stddev),
+            ..stats()
+        }
+    }
+
+    #[test]
+    fn test_regression() {
+        let thresholds = Thresholds {
+            noise_margin: 5.0,
+            max_regression: Some(10.0),
+        };
+
+        let prev = with_mean(1_000, 10);
+
+        // 20% slower with a low variation.
+        let comparison = Comparison::compare(&prev, &with_mean(1_200, 10), &thresholds);
+        assert!(comparison.is_regression(10.0));
+
+        // Within the allowed regression.
+        let comparison = Comparison::compare(&prev, &with_mean(1_050, 10), &thresholds);
+        assert!(!comparison.is_regression(10.0));
+
+        // 20% slower, but the variation says it's noise.
+        let comparison = Comparison::compare(&prev, &with_mean(1_200, 600), &thresholds);
+        assert!(!comparison.is_regression(10.0));
+    }
+
+    #[test]
+    fn test_named_baselines() {
+        let name = "named baselines";
+
+        let directory = directory();
+
+        with_mean(1_000, 10).save_as(&directory, name, "main");
+        with_mean(2_000, 10).save_as(&directory, name, "pr-123");
+
+        let main = Stats::load_from(&directory, name, "main").unwrap();
+        let pr = Stats::load_from(&directory, name, "pr-123").unwrap();
+
+        assert_eq!(main.mean, Duration::from_nanos(1_000));
+        assert_eq!(pr.mean, Duration::from_nanos(2_000));
+        assert_eq!(main.trim, 2.5);
+        assert!(Stats::load_from(&directory, name, "unknown").is_none());
+
+        let thresholds = Thresholds {
+            noise_margin: 5.0,
+            max_regression: None,
+        };
+        let current = with_mean(1_500, 10);
+
+        assert!(Comparison::compare(&main, &current, &thresholds).is_regression(10.0));
+        assert!(!Comparison::compare(&pr, &current, &thresholds).is_regression(10.0));
+    }
+}
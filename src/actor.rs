@@ -7,13 +7,18 @@ mod waker;
 use std::{
     any::Any,
     io,
+    panic::{AssertUnwindSafe, catch_unwind},
     pin::Pin,
     sync::{Arc, Mutex, MutexGuard, atomic::Ordering},
 };
 
 use crate::{
-    actor::waker::ActorWaker, async_actor::IntoAsyncActor, metadata::MetaKeyValue,
-    utils::UnsortedSet,
+    actor::waker::ActorWaker,
+    async_actor::IntoAsyncActor,
+    global::MailboxHint,
+    metadata::{MetaKeyValue, Propagated},
+    timer::TimerId,
+    utils::{self, UnsortedSet},
 };
 
 pub use control_block::{ActorControlBlock, MAX_LINKS, MAX_META_KV};
@@ -30,6 +35,15 @@ pub trait HydratedActorBase: Send + Sync + 'static {
 
     fn has_messages(&self) -> bool;
 
+    /// The number of messages and signals that haven't been received yet.
+    fn pending_messages(&self) -> usize;
+
+    /// The number of signals that didn't fit in the inbox and wait in its overflow queue.
+    fn overflowed_signals(&self) -> usize;
+
+    /// Drop the future of an actor that exited, it won't be polled again.
+    fn terminate(&self);
+
     fn queue(&self) -> MutexGuard<MessageQueue>;
     fn links(&self) -> MutexGuard<UnsortedSet<Pid, MAX_LINKS>>;
     fn metadata(&self) -> MutexGuard<UnsortedSet<MetaKeyValue, MAX_META_KV>>;
@@ -40,26 +54,33 @@ pub struct TrapExitMessage {
     pub reason: Exit,
 }
 
+/// Refers to a monitor, see [`DownMessage`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MonitorRef(pub(crate) u64);
+
+/// Sent to the monitoring actor when the monitored actor exits.
+///
+/// Unlike the exit of a linked actor this is always a message, it never takes the monitoring actor down.
+pub struct DownMessage {
+    pub monitor_ref: MonitorRef,
+    pub pid: Pid,
+    pub reason: Exit,
+}
+
 impl<B> HydratedActorBase for HydratedActor<B>
 where
     B: IntoAsyncActor,
 {
     fn queue(&self) -> MutexGuard<MessageQueue> {
-        self.messages.lock().expect("Failed to acquire lock")
+        utils::lock(&self.messages)
     }
 
     fn links(&self) -> MutexGuard<UnsortedSet<Pid, MAX_LINKS>> {
-        self.control_block
-            .links
-            .lock()
-            .expect("Failed to acquire lock")
+        utils::lock(&self.control_block.links)
     }
 
     fn metadata(&self) -> MutexGuard<UnsortedSet<MetaKeyValue, MAX_META_KV>> {
-        self.control_block
-            .metadata
-            .lock()
-            .expect("Failed to acquire lock")
+        utils::lock(&self.control_block.metadata)
     }
 
     fn send_signal(&self, message: Signal) {
@@ -70,6 +91,14 @@ where
         &self.control_block
     }
 
+    fn pending_messages(&self) -> usize {
+        self.inbox.len() + utils::lock(&self.messages).len()
+    }
+
+    fn overflowed_signals(&self) -> usize {
+        self.inbox.overflow_len()
+    }
+
     fn poll(self: Pin<&Self>) -> Option<Exit> {
         if let Some(signal) = self.inbox.pop() {
             match signal {
@@ -78,10 +107,13 @@ where
                     self.links().remove(&pid);
 
                     if self.control_block.trap_exit.load(Ordering::Relaxed) {
-                        self.messages
-                            .lock()
-                            .unwrap()
-                            .push(Box::new(TrapExitMessage { pid, reason }));
+                        utils::lock(&self.messages).push(
+                            pid,
+                            Box::new(TrapExitMessage { pid, reason }),
+                            None,
+                            // Exits stay behind the messages sent before them, e.g. the output of a port.
+                            Priority::Normal,
+                        );
                     } else if pid == self.control_block.pid || reason != Exit::Normal {
                         // TODO: Investigate the if condition
                         return Some(reason);
@@ -94,16 +126,17 @@ where
                 Signal::Unlink(pid) => {
                     let _ = self.control_block.remove_link(pid);
                 }
-                Signal::TimerFired => {
-                    // We don't need to do anything but run the future.
+                Signal::TimerFired(id) => {
+                    // Disarm the timer so whoever is waiting on it can complete.
+                    let _ = self.control_block.fire_timer(id);
                 }
-                Signal::Message(msg) => {
-                    self.messages.lock().unwrap().push(msg);
+                Signal::Message(from, msg, metadata, priority) => {
+                    utils::lock(&self.messages).push(from, msg, metadata, priority);
                 }
             }
         }
 
-        let mut actor = self.actor.lock().expect("Failed to acquire lock");
+        let mut actor = utils::lock(&self.actor);
         actor.to_running();
 
         if let ActorState::Running(future) = &mut *actor {
@@ -113,11 +146,16 @@ where
             // SAFETY: This is OK because we are not moving the future out of the actor and the actor is pinned.
             let future = unsafe { Pin::new_unchecked(future) };
 
-            let status = Future::poll(future, &mut cx);
+            let status = catch_unwind(AssertUnwindSafe(|| Future::poll(future, &mut cx)));
 
             match status {
-                std::task::Poll::Ready(exit) => Some(exit),
-                std::task::Poll::Pending => None,
+                Ok(std::task::Poll::Ready(exit)) => Some(exit),
+                Ok(std::task::Poll::Pending) => self.control_block.take_stop_reason(),
+                Err(err) => {
+                    // The future is in an unknown state after a panic, it must never be polled again.
+                    *actor = ActorState::Uninitialized;
+                    Some(Exit::Panic(panic_to_string(err)))
+                }
             }
         } else {
             None
@@ -127,9 +165,18 @@ where
     fn has_messages(&self) -> bool {
         !self.inbox.is_empty()
     }
+
+    fn terminate(&self) {
+        // Not polling, so the future is suspended at an await point and safe to drop.
+        *utils::lock(&self.actor) = ActorState::Uninitialized;
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// The reason an actor exited.
+///
+/// New reasons may be added, so matches outside this crate need a wildcard arm.
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum Exit {
     /// Graceful shutdown (actor chose to exit normally)
     Normal,
@@ -143,7 +190,62 @@ pub enum Exit {
     /// Actor was killed intentionally (e.g. supervisor or monitor)
     Killed,
 
+    /// An IO operation failed, usually created from an `io::Error`.
     Io(String, io::ErrorKind),
+
+    /// An application specific reason, see [`Exit::custom`].
+    Custom(Arc<dyn Any + Send + Sync>),
+}
+
+impl Exit {
+    /// Exit with an application specific reason.
+    ///
+    /// Whoever traps the exit can get the value back with [`Exit::downcast_ref`].
+    pub fn custom<T>(value: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        Exit::Custom(Arc::new(value))
+    }
+
+    /// The value of a custom reason, if this is one of type `T`.
+    pub fn downcast_ref<T>(&self) -> Option<&T>
+    where
+        T: 'static,
+    {
+        match self {
+            Exit::Custom(value) => value.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exit::Normal => write!(f, "Normal"),
+            Exit::Panic(message) => f.debug_tuple("Panic").field(message).finish(),
+            Exit::Shutdown => write!(f, "Shutdown"),
+            Exit::Killed => write!(f, "Killed"),
+            Exit::Io(message, kind) => f.debug_tuple("Io").field(message).field(kind).finish(),
+            Exit::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+// Custom reasons are only equal to themselves, the payload can't be compared.
+impl PartialEq for Exit {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Exit::Normal, Exit::Normal) => true,
+            (Exit::Panic(a), Exit::Panic(b)) => a == b,
+            (Exit::Shutdown, Exit::Shutdown) => true,
+            (Exit::Killed, Exit::Killed) => true,
+            (Exit::Io(a, a_kind), Exit::Io(b, b_kind)) => a == b && a_kind == b_kind,
+            (Exit::Custom(a), Exit::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 impl From<io::Error> for Exit {
@@ -152,13 +254,27 @@ impl From<io::Error> for Exit {
     }
 }
 
+pub(crate) fn panic_to_string(err: Box<dyn Any + Send>) -> String {
+    if let Some(str) = err.downcast_ref::<String>() {
+        str.to_string()
+    } else if let Some(err) = err.downcast_ref::<&'static str>() {
+        err.to_string()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
 pub enum Signal {
     Exit(Pid, Exit),
     Kill,
     Link(Pid),
     Unlink(Pid),
-    TimerFired,
-    Message(Box<dyn Any + Send>),
+    TimerFired(TimerId),
+    /// A message and the actor that sent it, `Pid::invalid()` if it was sent from outside an actor.
+    ///
+    /// The metadata is what the sender propagates, see `Config::propagate_metadata`.
+    /// The priority picks the lane of the mailbox the message goes into.
+    Message(Pid, Box<dyn Any + Send>, Propagated, Priority),
 }
 
 enum ActorState<A>
@@ -207,15 +323,183 @@ where
     A: IntoAsyncActor,
 {
     pub(crate) fn new(control_block: ActorControlBlock, actor: A) -> Self {
+        Self::with_mailbox_hint(control_block, actor, MailboxHint::Default)
+    }
+
+    pub(crate) fn with_mailbox_hint(
+        control_block: ActorControlBlock,
+        actor: A,
+        hint: MailboxHint,
+    ) -> Self {
         let pid = control_block.pid;
         let system = unsafe { crate::thread::borrow() };
 
         Self {
             control_block,
-            inbox: Inbox::new(),
+            inbox: Inbox::with_hint(hint),
             waker: Arc::new(ActorWaker::new(&system, pid)),
             actor: Mutex::new(ActorState::Waiting(actor)),
             messages: Mutex::new(MessageQueue::new()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{AssertUnwindSafe, catch_unwind},
+        sync::{Arc, Mutex, atomic::Ordering},
+    };
+
+    use crate::{Config, Exit, TrapExitMessage, global, receive, system::System};
+
+    use super::{ActorControlBlock, HydratedActor, HydratedActorBase, Signal};
+
+    #[test]
+    fn panic_becomes_exit_panic() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                global::trap_exit(true);
+
+                let child = global::spawn_linked(async || -> Exit {
+                    panic!("boom");
+                });
+
+                let (pid, reason) = receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { pid, reason } => (pid, reason),
+                    }
+                };
+
+                completion.complete((pid == child, reason));
+
+                Exit::Normal
+            }
+        });
+
+        let (from_child, reason) = result.unwrap();
+
+        assert!(from_child);
+        assert_eq!(reason, Exit::Panic(String::from("boom")));
+    }
+
+    #[test]
+    fn custom_exit_can_be_trapped() {
+        #[derive(Debug, PartialEq)]
+        struct ConfigError(&'static str);
+
+        let result = crate::run_single(|completion| {
+            async move || {
+                global::trap_exit(true);
+
+                global::spawn_linked(async || Exit::custom(ConfigError("missing key")));
+
+                let reason = receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { reason, .. } => reason,
+                    }
+                };
+
+                completion.complete(reason);
+
+                Exit::Normal
+            }
+        });
+
+        let reason = result.unwrap();
+
+        assert_eq!(
+            reason.downcast_ref::<ConfigError>(),
+            Some(&ConfigError("missing key"))
+        );
+        assert_eq!(reason.downcast_ref::<String>(), None);
+        assert_eq!(reason, reason.clone());
+    }
+
+    #[test]
+    fn future_is_dropped_in_context_at_exit() {
+        struct Guard(Arc<Mutex<Vec<String>>>);
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                let pid = global::sync::pid();
+                self.0.lock().unwrap().push(format!("dropped in {}", pid.0));
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let config = Config {
+            workers: 1,
+            ..Config::default()
+        };
+
+        {
+            let events = events.clone();
+
+            crate::run_with(config, async move || {
+                global::trap_exit(true);
+
+                let guard = Guard(events.clone());
+                let child = global::spawn_linked(async move || {
+                    let _guard = guard;
+                    global::sleep(std::time::Duration::from_millis(1)).await;
+
+                    Exit::Normal
+                });
+
+                receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { .. } => {}
+                    }
+                }
+
+                events.lock().unwrap().push(format!("exit of {}", child.0));
+                global::sync::stop();
+
+                Exit::Normal
+            });
+        }
+
+        let events = events.lock().unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].replace("dropped in", "exit of"), events[1]);
+    }
+
+    #[test]
+    fn io_error_converts_to_exit() {
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+
+        assert_eq!(
+            Exit::from(err),
+            Exit::Io(String::from("missing"), std::io::ErrorKind::NotFound)
+        );
+    }
+
+    #[test]
+    fn poisoned_queue_is_recovered() {
+        let system = System::new(&Config::default());
+        crate::thread::give(system.clone());
+
+        let pid = system.registry.allocate_pid();
+        let control_block = ActorControlBlock::new(pid, 0);
+        let actor = Arc::pin(HydratedActor::new(control_block, async || Exit::Normal));
+
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _queue = actor.queue();
+            panic!("boom");
+        }));
+        assert!(actor.messages.is_poisoned());
+
+        // Trapped exits are pushed onto the queue while polling, the actor can still run to completion.
+        actor.control_block.trap_exit.store(true, Ordering::Relaxed);
+        actor.send_signal(Signal::Exit(system.registry.allocate_pid(), Exit::Killed));
+
+        assert_eq!(actor.as_ref().poll(), Some(Exit::Normal));
+        assert!(!actor.queue().is_empty());
+
+        drop(actor);
+        drop(unsafe { crate::thread::get() });
+    }
+}
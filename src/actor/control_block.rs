@@ -1,18 +1,28 @@
-use std::sync::{
-    Mutex,
-    atomic::{AtomicBool, AtomicU64, Ordering},
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::Waker,
 };
 
 use crate::{
-    actor::Pid,
+    actor::{Exit, MonitorRef, Pid},
     metadata::MetaKeyValue,
-    utils::{CachePadded, UnsortedSet},
+    timer::TimerId,
+    utils::{self, CachePadded, UnsortedSet},
     worker::WorkerId,
 };
 
 pub const MAX_LINKS: usize = 32;
 pub const MAX_META_KV: usize = 4;
+pub const MAX_TIMERS: usize = 4;
 
+/// The shared state of an actor.
+///
+/// Locks are taken in this order: a registry shard, then the locks of a control block, then the inbox.
+/// The locks of a control block are never held while taking the locks of another actor,
+/// copy what's needed and release the lock first (e.g. the links in `System::exited`).
 pub struct ActorControlBlock {
     pub pid: Pid,
     pub trap_exit: AtomicBool,
@@ -21,6 +31,13 @@ pub struct ActorControlBlock {
     pub worker_id: AtomicU64,
     pub(crate) links: Mutex<UnsortedSet<Pid, MAX_LINKS>>,
     pub(crate) metadata: Mutex<UnsortedSet<MetaKeyValue, MAX_META_KV>>,
+    pub(crate) timers: Mutex<UnsortedSet<TimerId, MAX_TIMERS>>,
+    stopping: AtomicBool,
+    stop_reason: Mutex<Option<Exit>>,
+    blocked_senders: Mutex<Vec<Pid>>,
+    monitors: Mutex<Vec<(Pid, MonitorRef)>>,
+    receive_waker: Mutex<Option<Waker>>,
+    overflowed: AtomicBool,
 }
 
 impl ActorControlBlock {
@@ -33,6 +50,13 @@ impl ActorControlBlock {
             worker_id: AtomicU64::new(worker_id as _),
             links: Mutex::new(UnsortedSet::new()),
             metadata: Mutex::new(UnsortedSet::new()),
+            timers: Mutex::new(UnsortedSet::new()),
+            stopping: AtomicBool::new(false),
+            stop_reason: Mutex::new(None),
+            blocked_senders: Mutex::new(Vec::new()),
+            monitors: Mutex::new(Vec::new()),
+            receive_waker: Mutex::new(None),
+            overflowed: AtomicBool::new(false),
         }
     }
 
@@ -40,14 +64,118 @@ impl ActorControlBlock {
         self.is_scheduled.swap(true, Ordering::AcqRel) == false
     }
 
+    /// Undo a `try_schedule` when the actor couldn't be queued after all.
+    pub fn unschedule(&self) {
+        self.is_scheduled.store(false, Ordering::Release);
+    }
+
     pub fn add_link(&self, pid: Pid) -> Result<(), ()> {
-        let mut links = self.links.lock().expect("Failed to acquire lock");
+        let mut links = utils::lock(&self.links);
         if links.insert(pid) { Ok(()) } else { Err(()) }
     }
 
     pub fn remove_link(&self, pid: Pid) -> Result<(), ()> {
-        let mut links = self.links.lock().expect("Failed to acquire lock");
+        let mut links = utils::lock(&self.links);
 
         if links.remove(&pid) { Ok(()) } else { Err(()) }
     }
+
+    /// Start waiting on a timer, it stays armed until its `TimerFired` signal arrives.
+    pub fn arm_timer(&self, id: TimerId) {
+        let mut timers = utils::lock(&self.timers);
+        timers.insert(id);
+    }
+
+    /// Disarm a timer that has fired.
+    ///
+    /// Returns `false` if nobody was waiting on the timer.
+    pub fn fire_timer(&self, id: TimerId) -> bool {
+        let mut timers = utils::lock(&self.timers);
+        timers.remove(&id)
+    }
+
+    pub fn is_timer_armed(&self, id: TimerId) -> bool {
+        let timers = utils::lock(&self.timers);
+        timers.contains(&id)
+    }
+
+    /// Exit with the given reason the next time the actor suspends.
+    pub fn request_stop(&self, reason: Exit) {
+        *utils::lock(&self.stop_reason) = Some(reason);
+        self.stopping.store(true, Ordering::Release);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    pub fn take_stop_reason(&self) -> Option<Exit> {
+        if !self.is_stopping() {
+            return None;
+        }
+
+        utils::lock(&self.stop_reason).take()
+    }
+
+    /// Wake the given actor once this actor has room in its mailbox, see `send_blocking`.
+    pub fn block_sender(&self, pid: Pid) {
+        let mut blocked = utils::lock(&self.blocked_senders);
+        if !blocked.contains(&pid) {
+            blocked.push(pid);
+        }
+    }
+
+    pub fn has_blocked_senders(&self) -> bool {
+        !utils::lock(&self.blocked_senders).is_empty()
+    }
+
+    pub fn take_blocked_senders(&self) -> Vec<Pid> {
+        std::mem::take(&mut *utils::lock(&self.blocked_senders))
+    }
+
+    /// Send a `DownMessage` to the given actor when this actor exits.
+    pub fn add_monitor(&self, pid: Pid, monitor_ref: MonitorRef) {
+        utils::lock(&self.monitors).push((pid, monitor_ref));
+    }
+
+    pub fn take_monitors(&self) -> Vec<(Pid, MonitorRef)> {
+        std::mem::take(&mut *utils::lock(&self.monitors))
+    }
+
+    /// Mark the mailbox as overflowed, returns `false` if it already was.
+    pub fn mark_overflowed(&self) -> bool {
+        !self.overflowed.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn clear_overflowed(&self) {
+        if self.overflowed.load(Ordering::Relaxed) {
+            self.overflowed.store(false, Ordering::Release);
+        }
+    }
+
+    /// Remember the waker of a receive that is waiting for a message.
+    pub fn set_receive_waker(&self, waker: &Waker) {
+        let mut receive_waker = utils::lock(&self.receive_waker);
+        match &mut *receive_waker {
+            Some(current) => current.clone_from(waker),
+            None => *receive_waker = Some(waker.clone()),
+        }
+    }
+
+    pub fn clear_receive_waker(&self) {
+        utils::lock(&self.receive_waker).take();
+    }
+
+    /// Wake a waiting receive, returns `false` if nothing was waiting.
+    pub fn wake_receiver(&self) -> bool {
+        let waker = utils::lock(&self.receive_waker).take();
+
+        match waker {
+            Some(waker) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
 }
@@ -6,20 +6,72 @@ use std::{
     },
 };
 
-use crate::utils::Queue;
+use crate::{
+    global::MailboxHint,
+    utils::{self, Queue},
+};
 
+const SMALL_QUEUE_SIZE: usize = 64;
 const QUEUE_SIZE: usize = 1024;
+const LARGE_QUEUE_SIZE: usize = 4096;
+
+/// The lock-free part of the inbox, its size is picked when the actor is spawned.
+///
+/// The queues are boxed so an actor is the same size whatever its mailbox hint.
+enum Ring<T> {
+    Small(Box<Queue<SMALL_QUEUE_SIZE, T>>),
+    Default(Box<Queue<QUEUE_SIZE, T>>),
+    Large(Box<Queue<LARGE_QUEUE_SIZE, T>>),
+}
+
+macro_rules! with_ring {
+    ($ring:expr, $queue:ident => $body:expr) => {
+        match $ring {
+            Ring::Small($queue) => $body,
+            Ring::Default($queue) => $body,
+            Ring::Large($queue) => $body,
+        }
+    };
+}
+
+impl<T> Ring<T> {
+    fn push(&self, value: T) -> Result<(), T> {
+        with_ring!(self, queue => queue.push(value))
+    }
+
+    fn pop(&self) -> Option<T> {
+        with_ring!(self, queue => queue.pop())
+    }
+
+    fn len(&self) -> usize {
+        with_ring!(self, queue => queue.len())
+    }
+
+    fn is_empty(&self) -> bool {
+        with_ring!(self, queue => queue.is_empty())
+    }
+}
 
 pub struct Inbox<T> {
-    queue: Queue<QUEUE_SIZE, T>,
+    queue: Ring<T>,
     overflow_count: AtomicUsize,
     overflow: Mutex<VecDeque<T>>,
 }
 
 impl<T> Inbox<T> {
     pub fn new() -> Self {
+        Self::with_hint(MailboxHint::Default)
+    }
+
+    pub fn with_hint(hint: MailboxHint) -> Self {
+        let queue = match hint {
+            MailboxHint::Small => Ring::Small(Box::new(Queue::new())),
+            MailboxHint::Default => Ring::Default(Box::new(Queue::new())),
+            MailboxHint::Large => Ring::Large(Box::new(Queue::new())),
+        };
+
         Self {
-            queue: Queue::new(),
+            queue,
             overflow_count: AtomicUsize::new(0),
             overflow: Mutex::new(VecDeque::new()),
         }
@@ -27,7 +79,7 @@ impl<T> Inbox<T> {
 
     pub fn push(&self, message: T) {
         if let Err(message) = self.queue.push(message) {
-            let mut overflow = self.overflow.lock().expect("Failed to acquire lock");
+            let mut overflow = utils::lock(&self.overflow);
             overflow.push_back(message);
             self.overflow_count.fetch_add(1, Ordering::Release);
         }
@@ -43,7 +95,7 @@ impl<T> Inbox<T> {
         }
 
         {
-            let mut overflow = self.overflow.lock().expect("Failed to acquire lock");
+            let mut overflow = utils::lock(&self.overflow);
             while let Some(item) = overflow.pop_front() {
                 self.overflow_count.fetch_sub(1, Ordering::Release);
 
@@ -58,6 +110,16 @@ impl<T> Inbox<T> {
         self.queue.pop()
     }
 
+    /// The number of signals that didn't fit in the lock-free queue.
+    pub fn overflow_len(&self) -> usize {
+        self.overflow_count.load(Ordering::Acquire)
+    }
+
+    /// The number of signals waiting to be popped.
+    pub fn len(&self) -> usize {
+        self.queue.len() + self.overflow_count.load(Ordering::Acquire)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.queue.is_empty() && self.overflow_count.load(Ordering::Acquire) == 0
     }
@@ -1,30 +1,128 @@
 use std::{any::Any, collections::VecDeque};
 
+use crate::{
+    actor::{MAX_META_KV, Pid},
+    metadata::{MetaKeyValue, Propagated},
+    utils::UnsortedSet,
+};
+
+/// The lane of the mailbox a message goes into.
+///
+/// Receiving looks at every high priority message before any normal one, and at those before any low one.
+/// Within a lane messages stay in the order they arrived.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Priority {
+    /// Control messages that should overtake the regular work.
+    High,
+    #[default]
+    Normal,
+    /// Bulk work that can wait until nothing else is pending.
+    Low,
+}
+
+impl Priority {
+    const LANES: usize = 3;
+
+    fn lane(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+struct Envelope {
+    from: Pid,
+    message: Box<dyn Any + Send>,
+    metadata: Propagated,
+}
+
 // TODO: Rework this to use a intrusive linked list
-// TODO: Introduce an 'Envelope' and 'Message' type
+// TODO: Introduce a 'Message' type
 pub struct MessageQueue {
-    queue: VecDeque<Box<dyn Any + Send>>,
+    lanes: [VecDeque<Envelope>; Priority::LANES],
+    sender: Option<Pid>,
+    metadata: Propagated,
 }
 
 impl MessageQueue {
     pub fn new() -> Self {
         Self {
-            queue: VecDeque::new(),
+            lanes: std::array::from_fn(|_| VecDeque::new()),
+            sender: None,
+            metadata: None,
         }
     }
 
-    pub fn push(&mut self, envelope: Box<dyn Any + Send>) {
-        self.queue.push_back(envelope);
+    pub fn push(
+        &mut self,
+        from: Pid,
+        message: Box<dyn Any + Send>,
+        metadata: Propagated,
+        priority: Priority,
+    ) {
+        self.lanes[priority.lane()].push_back(Envelope {
+            from,
+            message,
+            metadata,
+        });
     }
 
+    /// Remove the first matching message, its sender and metadata become the current ones.
+    ///
+    /// The matcher gets the sender and the message. The lanes are searched from high to low priority.
     pub fn remove_matching(
         &mut self,
-        matcher: &dyn Fn(&Box<dyn Any + Send>) -> bool,
+        matcher: &mut dyn FnMut(Pid, &Box<dyn Any + Send>) -> bool,
     ) -> Option<Box<dyn Any + Send>> {
-        if let Some(index) = self.queue.iter().position(|msg| matcher(msg)) {
-            self.queue.remove(index)
-        } else {
-            None
+        let envelope = self.lanes.iter_mut().find_map(|lane| {
+            let index = lane
+                .iter()
+                .position(|envelope| matcher(envelope.from, &envelope.message))?;
+            lane.remove(index)
+        })?;
+
+        self.sender = Some(envelope.from);
+        self.metadata = envelope.metadata;
+        Some(envelope.message)
+    }
+
+    /// The sender of the last message that was removed with `remove_matching`.
+    pub fn sender(&self) -> Option<Pid> {
+        self.sender
+    }
+
+    /// The metadata that came with the last message that was removed with `remove_matching`.
+    pub fn metadata(&self) -> Option<&UnsortedSet<MetaKeyValue, MAX_META_KV>> {
+        self.metadata.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(VecDeque::is_empty)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lanes.iter().map(VecDeque::capacity).sum()
+    }
+
+    /// Release the backing storage that isn't in use.
+    pub fn shrink(&mut self) {
+        for lane in &mut self.lanes {
+            lane.shrink_to_fit();
+        }
+    }
+
+    /// Remove and drop every message that matches, returns how many were removed.
+    pub fn remove_all_matching(&mut self, matcher: &dyn Fn(&Box<dyn Any + Send>) -> bool) -> usize {
+        let len = self.len();
+        for lane in &mut self.lanes {
+            lane.retain(|envelope| !matcher(&envelope.message));
         }
+        len - self.len()
     }
 }
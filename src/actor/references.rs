@@ -1,5 +1,10 @@
 use crate::registry::Registry;
 
+/// Anything that can be resolved to a `Pid`.
+///
+/// Names are resolved through the registry every time they are used.
+/// A name that isn't registered resolves to `Pid::invalid()`, which behaves like a dead actor:
+/// messages and signals sent to it are dropped, nothing panics.
 pub trait ToPid {
     fn to_reference(&self, registry: &Registry) -> Pid;
 }
@@ -10,9 +15,21 @@ impl ToPid for Pid {
     }
 }
 
-impl ToPid for &'static str {
+impl ToPid for &str {
     fn to_reference(&self, registry: &Registry) -> Pid {
-        registry.lookup_name(*self).unwrap_or(Pid::invalid()).into()
+        registry.lookup_name(self).unwrap_or(Pid::invalid())
+    }
+}
+
+impl ToPid for String {
+    fn to_reference(&self, registry: &Registry) -> Pid {
+        self.as_str().to_reference(registry)
+    }
+}
+
+impl ToPid for NamedRef {
+    fn to_reference(&self, registry: &Registry) -> Pid {
+        registry.lookup_name(self.0).unwrap_or(Pid::invalid())
     }
 }
 
@@ -24,3 +41,60 @@ impl Pid {
         Pid(u64::MAX)
     }
 }
+
+/// A reference to an actor by its registered name.
+///
+/// See `ToPid` for how names are resolved.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NamedRef(pub &'static str);
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Config, Exit,
+        global::{send, sync},
+        receive,
+    };
+
+    use super::{NamedRef, Pid, ToPid};
+
+    #[test]
+    fn send_resolves_names() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let system = unsafe { crate::thread::borrow() };
+
+                sync::register("named_ref_test", sync::pid());
+
+                let hit = NamedRef("named_ref_test").to_reference(&system.registry);
+                let miss = NamedRef("does_not_exist").to_reference(&system.registry);
+
+                // Unknown names are dropped, not delivered and not a panic.
+                send("does_not_exist", 1u32).await;
+                send(NamedRef("named_ref_test"), 2u32).await;
+                send("named_ref_test", 3u32).await;
+
+                let first = receive! {
+                    match u32 {
+                        n => n,
+                    }
+                };
+                let second = receive! {
+                    match u32 {
+                        n => n,
+                    }
+                };
+
+                completion.complete((hit == sync::pid(), miss, first, second));
+
+                Exit::Normal
+            }
+        });
+
+        let (hit, miss, first, second) = result.unwrap();
+
+        assert!(hit);
+        assert_eq!(miss, Pid::invalid());
+        assert_eq!((first, second), (2, 3));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     actor::{Exit, Pid, TrapExitMessage},
-    receive,
+    global, receive,
 };
 
 pub trait IntoAsyncActor: Send + 'static {
@@ -34,6 +34,15 @@ pub trait SimpleActor: Send + 'static + Sized {
         let _ = from;
         async { Some(reason) }
     }
+
+    /// Called with the exit reason right before the actor exits, like `terminate/2` of a gen_server.
+    ///
+    /// This only runs when the actor itself returns an exit, including exits it receives while trapping them.
+    /// An actor that is killed without trapping exits is dropped without calling it.
+    fn terminate(&mut self, reason: &Exit) -> impl Future<Output = ()> + Send {
+        let _ = reason;
+        async {}
+    }
 }
 
 pub fn into_actor<A>(mut actor: A) -> impl IntoAsyncActor
@@ -41,28 +50,205 @@ where
     A: SimpleActor,
 {
     async move || {
-        if let Some(exit) = actor.started().await {
-            return exit;
-        }
+        let exit = run(&mut actor).await;
+        actor.terminate(&exit).await;
+        exit
+    }
+}
 
-        loop {
-            receive! {
-                match TrapExitMessage {
-                    TrapExitMessage { pid, reason } => {
-                        if let Some(exit) = actor.on_exit(pid, reason).await {
-                            return exit;
-                        }
+async fn run<A>(actor: &mut A) -> Exit
+where
+    A: SimpleActor,
+{
+    if let Some(exit) = actor.started().await {
+        return exit;
+    }
+
+    loop {
+        receive! {
+            match TrapExitMessage {
+                TrapExitMessage { pid, reason } => {
+                    if let Some(exit) = actor.on_exit(pid, reason).await {
+                        return exit;
                     }
                 }
+            }
+
+            match A::Message {
+                message => {
+                    if let Some(exit) = actor.handle(message).await {
+                        return exit;
+                    }
+                }
+            }
+        }
+
+        // Handlers can do a lot of work without awaiting, give other actors a chance to run.
+        global::maybe_yield().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{Config, Exit, Pid, global};
+
+    use super::{SimpleActor, into_actor};
 
-                match A::Message {
-                    message => {
-                        if let Some(exit) = actor.handle(message).await {
-                            return exit;
+    struct Busy {
+        events: Arc<Mutex<Vec<&'static str>>>,
+        remaining: usize,
+    }
+
+    impl SimpleActor for Busy {
+        type Message = ();
+
+        // Never awaits, only the actor loop can let others run between messages.
+        async fn handle(&mut self, _message: ()) -> Option<Exit> {
+            std::thread::sleep(Duration::from_micros(500));
+
+            self.remaining -= 1;
+            if self.remaining > 0 {
+                return None;
+            }
+
+            self.events.lock().unwrap().push("busy");
+            global::sync::stop();
+
+            Some(Exit::Normal)
+        }
+    }
+
+    struct Reporter {
+        parent: Pid,
+        reasons: Arc<Mutex<Vec<Exit>>>,
+    }
+
+    impl SimpleActor for Reporter {
+        type Message = ();
+
+        async fn started(&mut self) -> Option<Exit> {
+            global::trap_exit(true);
+            global::send(self.parent, ()).await;
+            None
+        }
+
+        async fn handle(&mut self, _message: ()) -> Option<Exit> {
+            Some(Exit::Normal)
+        }
+
+        async fn terminate(&mut self, reason: &Exit) {
+            self.reasons.lock().unwrap().push(reason.clone());
+        }
+    }
+
+    #[test]
+    fn terminate_sees_the_exit_reason() {
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+
+        let config = Config {
+            workers: 1,
+            ..Config::default()
+        };
+
+        {
+            let reasons = reasons.clone();
+
+            crate::run_with(config, async move || {
+                global::trap_exit(true);
+                let parent = global::sync::pid();
+
+                let stopped = global::spawn_linked(into_actor(Reporter {
+                    parent,
+                    reasons: reasons.clone(),
+                }));
+                wait_until_started().await;
+                global::send(stopped, ()).await;
+                wait_for_exit(stopped).await;
+
+                let killed = global::spawn_linked(into_actor(Reporter { parent, reasons }));
+                // Once it started it traps exits, otherwise the exit kills it outright.
+                wait_until_started().await;
+                global::exit(killed, Exit::Killed).await;
+                wait_for_exit(killed).await;
+
+                global::sync::stop();
+
+                Exit::Normal
+            });
+        }
+
+        let reasons = reasons.lock().unwrap();
+
+        assert_eq!(*reasons, [Exit::Normal, Exit::Killed]);
+    }
+
+    async fn wait_until_started() {
+        crate::receive! {
+            match () {
+                () => (),
+            }
+        }
+    }
+
+    async fn wait_for_exit(pid: Pid) {
+        loop {
+            crate::receive! {
+                match crate::TrapExitMessage {
+                    crate::TrapExitMessage { pid: from, .. } => {
+                        if from == pid {
+                            return;
                         }
                     }
                 }
             }
         }
     }
+
+    #[test]
+    fn busy_handler_lets_others_run() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let config = Config {
+            workers: 1,
+            ..Config::default()
+        };
+
+        {
+            let events = events.clone();
+
+            crate::run_with(config, async move || {
+                {
+                    let events = events.clone();
+
+                    global::spawn(async move || {
+                        global::sleep(Duration::from_millis(5)).await;
+                        events.lock().unwrap().push("sleeper");
+
+                        Exit::Normal
+                    })
+                    .await;
+                }
+
+                let busy = global::spawn(into_actor(Busy {
+                    events,
+                    remaining: 200,
+                }))
+                .await;
+                for _ in 0..200 {
+                    global::send(busy, ()).await;
+                }
+
+                Exit::Normal
+            });
+        }
+
+        let events = events.lock().unwrap();
+
+        assert_eq!(*events, ["sleeper", "busy"]);
+    }
 }
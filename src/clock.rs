@@ -0,0 +1,70 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The source of time used by timers.
+///
+/// Set [`crate::Config::clock`] to a [`MockClock`] to control time in tests.
+pub trait Clock: Send + Sync + Debug {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// How long the timer thread may block before checking the clock again,
+    /// given the time remaining until the next timer expires.
+    fn wait_timeout(&self, remaining: Duration) -> Duration {
+        remaining
+    }
+}
+
+/// The real monotonic clock, this is the default.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves forward when it is advanced.
+///
+/// Clones share the same time, keep a clone around to advance it.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// How often the timer thread checks a mock clock for changes.
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock forward, timers that expire are fired by the timer thread.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().expect("Failed to acquire lock");
+        *now += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("Failed to acquire lock")
+    }
+
+    fn wait_timeout(&self, remaining: Duration) -> Duration {
+        remaining.min(Self::POLL_INTERVAL)
+    }
+}
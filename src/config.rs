@@ -0,0 +1,140 @@
+use std::{num::NonZero, sync::Arc, time::Duration};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Runtime configuration.
+///
+/// Start from `Config::default()` and override the fields you need.
+/// Pass it to [`crate::run_with`] to start the system.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The amount of worker threads to start.
+    ///
+    /// Defaults to the available parallelism of the machine.
+    pub workers: usize,
+
+    /// The amount of budget an actor can spend before it is forced to yield.
+    ///
+    /// Lower values make actors yield more often which improves latency,
+    /// higher values improve throughput.
+    pub max_budget: usize,
+
+    /// The maximum number of times an actor with pending signals is polled in a row
+    /// before it goes back to the run queue.
+    ///
+    /// Higher values cut scheduling overhead for busy actors, lower values improve fairness.
+    pub poll_batch: usize,
+
+    /// The clock used by timers, `sleep` and receive timeouts.
+    ///
+    /// Defaults to [`SystemClock`], use a [`crate::MockClock`] to control time in tests.
+    pub clock: Arc<dyn Clock>,
+
+    /// How an idle worker picks the worker to steal from.
+    ///
+    /// Defaults to [`StealStrategy::Ring`].
+    pub steal: StealStrategy,
+
+    /// Which worker an actor spawned from outside an actor starts on.
+    ///
+    /// Actors spawned by another actor start on that actor's worker.
+    /// Defaults to [`SpawnPlacement::RoundRobin`].
+    pub spawn_placement: SpawnPlacement,
+
+    /// The number of pending messages at which an actor's mailbox counts as full.
+    ///
+    /// Mailboxes are never bounded for regular sends, only `send_blocking` waits for room.
+    pub mailbox_capacity: usize,
+
+    /// How long the timer thread may wait past a deadline to fire more timers at once.
+    ///
+    /// Timers never fire early, but can fire up to this much late.
+    /// With many timers a small slack (e.g. 1ms) saves a lot of wakeups. Defaults to zero, every timer fires on time.
+    pub timer_slack: Duration,
+
+    /// Metadata keys that are sent along with every message, e.g. `trace_id`.
+    ///
+    /// While the receiver handles such a message the keys are part of its metadata, so they show up in its logs
+    /// and are passed on to the messages it sends. They are replaced by the next message it receives.
+    /// Defaults to none.
+    pub propagate_metadata: Vec<&'static str>,
+
+    /// The number of pending messages at which a mailbox overflows.
+    ///
+    /// Unlike `mailbox_capacity` this is a hard ceiling for actors that fell far behind.
+    /// Defaults to one million.
+    pub mailbox_ceiling: usize,
+
+    /// What happens when a mailbox reaches `mailbox_ceiling`.
+    ///
+    /// Defaults to [`MailboxOverflowPolicy::Alert`].
+    pub mailbox_overflow_policy: MailboxOverflowPolicy,
+
+    /// How often the observer logs runtime telemetry, see [`crate::library::observer`].
+    ///
+    /// Defaults to `None`, the observer isn't started.
+    pub observer_interval: Option<Duration>,
+}
+
+/// How a worker is picked for an actor spawned from outside an actor, e.g. from an I/O thread.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum SpawnPlacement {
+    /// Take turns over the workers.
+    #[default]
+    RoundRobin,
+
+    /// Pick the worker with the shortest run queue.
+    ///
+    /// This looks at every worker, so spawning is a bit more expensive.
+    LeastLoaded,
+}
+
+/// What happens when a mailbox reaches the ceiling.
+///
+/// Either way an alert is logged once, with the pid and the length of the mailbox.
+/// Another alert is logged if the mailbox reaches the ceiling again after dropping below it.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum MailboxOverflowPolicy {
+    /// Only log the alert, the mailbox keeps growing.
+    #[default]
+    Alert,
+
+    /// Log the alert and kill the actor, it exits with `Exit::Killed` the next time it suspends.
+    Kill,
+}
+
+/// How an idle worker picks the worker to steal from.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum StealStrategy {
+    /// Scan the other workers in order, starting at the next worker.
+    ///
+    /// This is cheap but favors the next worker, which can create hotspots with many workers.
+    #[default]
+    Ring,
+
+    /// Start the scan at a random worker, spreading steals over all workers.
+    Random,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let workers = std::thread::available_parallelism()
+            .map(NonZero::get)
+            .unwrap_or(1);
+
+        Self {
+            workers,
+            max_budget: 16,
+            poll_batch: 8,
+            clock: Arc::new(SystemClock),
+            steal: StealStrategy::default(),
+            spawn_placement: SpawnPlacement::default(),
+            mailbox_capacity: 1024,
+            timer_slack: Duration::ZERO,
+            propagate_metadata: Vec::new(),
+            mailbox_ceiling: 1_000_000,
+            mailbox_overflow_policy: MailboxOverflowPolicy::default(),
+            observer_interval: None,
+        }
+    }
+}
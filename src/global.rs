@@ -1,62 +1,183 @@
 //! Actor context
 //!
 //! This module provides functions that can be used within an actor.
+mod promise;
 mod receive;
+mod select;
 pub mod sync;
 
+pub use crate::system::QUEUE_HISTOGRAM_BINS;
+pub use crate::timer::TimerId;
+pub use crate::worker::WorkerSnapshot;
+pub use promise::{BrokenPromise, Promise, ReplyHandle, promise};
+pub use select::{Either, join, race};
+
 use std::{
     any::Any,
     cell::Cell,
     marker::PhantomData,
     pin::Pin,
-    sync::{Arc, Mutex, atomic::Ordering},
-    time::{Duration, Instant},
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
 };
 
 use crate::{
-    actor::{ActorControlBlock, Exit, HydratedActor, HydratedActorBase, Pid, Signal, ToPid},
+    actor::{Exit, HydratedActorBase, MonitorRef, Pid, Priority, Signal, ToPid},
     async_actor::IntoAsyncActor,
     metadata::{MetaKeyValue, MetaValue},
+    utils,
+    worker::Worker,
 };
 
 thread_local! {
-    static CONTEXT: Cell<*mut GlobalContext> = const { Cell::new(std::ptr::null_mut()) };
+    static CONTEXT: Cell<*const GlobalContext<'static>> = const { Cell::new(std::ptr::null()) };
 }
 
-#[allow(dead_code)]
+/// The state of the actor that is being polled on this thread.
+///
+/// It's only reachable through `with_context`, which hands out a shared reference that can't outlive the call.
+/// Everything that changes while polling lives in a `Cell`, so nested reads and writes never alias a `&mut`.
 pub(crate) struct GlobalContext<'a> {
-    pub(crate) budget: usize,
+    pub(crate) budget: Cell<usize>,
     pub(crate) actor: &'a Pin<Arc<dyn HydratedActorBase>>,
 
-    pub(crate) _marker: PhantomData<*const ()>,
+    _marker: PhantomData<*const ()>,
+}
+
+/// Keeps a `GlobalContext` installed on this thread, the previous one is restored on drop.
+pub(crate) struct ContextGuard<'a> {
+    previous: *const GlobalContext<'static>,
+    _marker: PhantomData<&'a GlobalContext<'a>>,
+}
+
+impl Drop for ContextGuard<'_> {
+    fn drop(&mut self) {
+        CONTEXT.set(self.previous);
+    }
 }
 
 impl<'a> GlobalContext<'a> {
+    pub(crate) fn new(actor: &'a Pin<Arc<dyn HydratedActorBase>>) -> Self {
+        Self {
+            budget: Cell::new(0),
+            actor,
+            _marker: PhantomData,
+        }
+    }
+
     #[inline]
     pub fn pid(&self) -> Pid {
         self.actor.control_block().pid
     }
 }
 
-#[doc(hidden)]
+/// The reason a receive completed without a message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum RecvError {
+    /// No message arrived before the timeout.
     Timeout,
 }
 
-fn context<'a>() -> &'static GlobalContext<'a> {
-    CONTEXT.with(|ctx| unsafe { &*(ctx.get() as *const GlobalContext) })
+/// The reason a message could not be delivered.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SendError {
+    /// The target doesn't resolve to a live actor.
+    NoProcess,
+}
+
+/// How many messages an actor is expected to have waiting, see [`SpawnOptions`].
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum MailboxHint {
+    /// A handful at most, the mailbox takes less memory.
+    Small,
+    /// Suits most actors.
+    #[default]
+    Default,
+    /// Bursts of thousands, they are absorbed without taking the overflow lock.
+    Large,
+}
+
+/// Options for [`spawn_opts`].
+///
+/// The other spawn functions are shorthands for common options, e.g. `spawn_linked` sets `link`.
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct SpawnOptions {
+    /// The size class of the mailbox.
+    ///
+    /// Mailboxes are never bounded, messages that don't fit go to a slower overflow queue.
+    pub mailbox_hint: MailboxHint,
+    /// Link the actor to the current actor, see [`spawn_linked`].
+    ///
+    /// This is ignored on an unmanaged thread, the actor is spawned without a link.
+    pub link: bool,
+    /// Monitor the actor from the current actor, a [`DownMessage`](crate::DownMessage) is sent to it when the actor exits.
+    ///
+    /// This is ignored on an unmanaged thread, the actor is spawned without a monitor.
+    pub monitor: bool,
+    /// The worker to start the actor on, see [`spawn_on`].
+    ///
+    /// If there is no such worker, or it isn't set, the actor starts on the worker of the spawning actor.
+    pub worker: Option<usize>,
+    /// Metadata for the actor, on top of the metadata it inherits from the spawning actor.
+    pub metadata: Vec<MetaKeyValue>,
+}
+
+/// The actor started by [`spawn_opts`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SpawnResult {
+    /// The Pid of the spawned actor.
+    pub pid: Pid,
+    /// The monitor of the spawned actor, if `monitor` was set.
+    pub monitor_ref: Option<MonitorRef>,
+}
+
+/// How many copies of a message were delivered and dropped, see [`send_many`].
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Delivery {
+    /// The number of actors the message was queued for.
+    pub delivered: usize,
+    /// The number of actors that were not found.
+    pub dropped: usize,
 }
 
-fn context_mut<'a>() -> &'static mut GlobalContext<'a> {
-    CONTEXT.with(|ctx| unsafe { &mut *(ctx.get() as *mut GlobalContext) })
+/// A snapshot of the runtime, see [`stats`].
+#[derive(Clone, Debug)]
+pub struct RuntimeStats {
+    /// The number of running workers.
+    pub workers: usize,
+    /// The run queue length of each worker.
+    pub run_queue_lengths: Vec<usize>,
+    /// The counters of each worker.
+    pub worker_snapshots: Vec<WorkerSnapshot>,
+    /// The number of timers that have not fired yet.
+    pub pending_timers: usize,
+    /// The number of live actors.
+    pub processes: usize,
+    /// The number of times the workers were rebalanced.
+    pub balances: u64,
 }
 
-pub(crate) fn set_context(context: *mut GlobalContext) {
-    CONTEXT.with(|ctx| ctx.set(context as *mut _));
+/// Run `f` with the context of the current actor.
+///
+/// Panics when called outside of an actor, see `has_context`.
+pub(crate) fn with_context<R>(f: impl FnOnce(&GlobalContext<'_>) -> R) -> R {
+    let context = CONTEXT.get();
+    assert!(!context.is_null(), "not called from within an actor");
+
+    // SAFETY: The pointer was installed by `enter_context` and the guard that removes it outlives the poll we're in.
+    // The reference is only handed to `f`, so it can't outlive that guard either.
+    f(unsafe { &*context })
 }
 
-pub(crate) fn reset_context() {
-    CONTEXT.with(|ctx| ctx.set(std::ptr::null_mut()));
+/// Install `context` on this thread until the returned guard is dropped.
+pub(crate) fn enter_context<'a>(context: &'a GlobalContext<'a>) -> ContextGuard<'a> {
+    // The lifetime is erased here, `ContextGuard<'a>` is what keeps `context` alive while it's installed.
+    let context: *const GlobalContext<'static> = std::ptr::from_ref(context).cast();
+
+    ContextGuard {
+        previous: CONTEXT.replace(context),
+        _marker: PhantomData,
+    }
 }
 
 pub(crate) fn has_context() -> bool {
@@ -80,6 +201,69 @@ pub async fn exit(to: impl ToPid, reason: Exit) {
     }
 }
 
+/// Register a name for an actor, unless another live actor already has it.
+///
+/// Returns the actor that owns the name on conflict, see [`sync::try_register`].
+pub async fn try_register(name: impl AsRef<str>, actor: Pid) -> Result<(), Pid> {
+    yield_now(1).await;
+    sync::try_register(name, actor)
+}
+
+/// Kills the chosen actor, it exits with `Exit::Killed` even if it traps exits.
+///
+/// If the actor is the current actor, it will yield immediately.
+/// Otherwise, it will add one to the budget.
+pub async fn kill(to: impl ToPid) {
+    let system = unsafe { crate::thread::borrow() };
+    let to = to.to_reference(&system.registry);
+
+    sync::kill(to);
+
+    if to == sync::pid() {
+        yield_immediate().await
+    } else {
+        yield_now(1).await;
+    }
+}
+
+/// Links the current actor to another actor, when either exits the other gets an exit signal.
+///
+/// Returns `SendError::NoProcess` if the actor doesn't exist, no link is made in that case.
+pub async fn link(to: impl ToPid) -> Result<(), SendError> {
+    yield_now(1).await;
+
+    let system = unsafe { crate::thread::borrow() };
+    let to = to.to_reference(&system.registry);
+
+    let pid = with_context(|context| {
+        let _ = context.actor.control_block().add_link(to);
+        context.pid()
+    });
+
+    sync::try_send_signal(to, Signal::Link(pid)).inspect_err(|_| {
+        with_context(|context| {
+            let _ = context.actor.control_block().remove_link(to);
+        })
+    })
+}
+
+/// Removes the link between the current actor and another actor.
+///
+/// Unlinking actors that aren't linked does nothing.
+pub async fn unlink(to: impl ToPid) {
+    yield_now(1).await;
+
+    let system = unsafe { crate::thread::borrow() };
+    let to = to.to_reference(&system.registry);
+
+    let pid = with_context(|context| {
+        let _ = context.actor.control_block().remove_link(to);
+        context.pid()
+    });
+
+    sync::send_signal(to, Signal::Unlink(pid));
+}
+
 /// Traps the exit signal
 ///
 /// Normally when an actor receives a exit signal from a linked actor, it will exit itself if the reason is not `Exit::Normal`.
@@ -87,20 +271,162 @@ pub async fn exit(to: impl ToPid, reason: Exit) {
 ///
 /// The following messages can be received when trap_exit is set:
 /// - `TrapExitMessage`: when a linked actor dies.
-/// - `TrapPortExitMessage`: when a linked port dies.
+///
+/// There is no separate message for ports, ports are implemented as helper actors (e.g. the file actor).
+/// Their exits are delivered as a `TrapExitMessage` as well.
+///
+/// Exit signals are handled with the value of the flag at the time they are taken from the inbox,
+/// which happens right before the actor is polled. Changing the flag doesn't affect exits that were
+/// already turned into a `TrapExitMessage`, while exits still waiting in the inbox follow the new value.
 pub fn trap_exit(should_trap: bool) {
-    context()
-        .actor
-        .control_block()
-        .trap_exit
-        .store(should_trap, Ordering::Relaxed);
+    with_context(|context| {
+        context
+            .actor
+            .control_block()
+            .trap_exit
+            .store(should_trap, Ordering::Relaxed)
+    });
+}
+
+/// Whether the current actor traps exit signals, see [`trap_exit`].
+pub fn is_trapping_exits() -> bool {
+    with_context(|context| {
+        context
+            .actor
+            .control_block()
+            .trap_exit
+            .load(Ordering::Relaxed)
+    })
+}
+
+/// Gather runtime statistics.
+///
+/// This only reads counters and takes short locks, it is safe to call from any managed or unmanaged thread.
+/// The values are gathered one after the other, so they are not a consistent snapshot.
+pub fn stats() -> RuntimeStats {
+    let system = unsafe { crate::thread::borrow() };
+
+    let workers = system.scheduler.count();
+    let worker_snapshots: Vec<_> = (0..system.scheduler.slots())
+        .filter_map(|id| system.scheduler.get_worker(id))
+        .map(|worker| worker.snapshot())
+        .collect();
+
+    RuntimeStats {
+        workers,
+        run_queue_lengths: worker_snapshots
+            .iter()
+            .map(|snapshot| snapshot.run_queue_length)
+            .collect(),
+        worker_snapshots,
+        pending_timers: system.timer.pending(),
+        processes: system.registry.count(),
+        balances: system.scheduler.balances(),
+    }
+}
+
+/// Start an extra worker thread.
+///
+/// Returns the id of the new worker, or `None` if the maximum of 128 workers is reached or the system is stopping.
+pub fn add_worker() -> Option<usize> {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.start_worker()
+}
+
+/// Stop a worker thread, actors queued on it are moved to the other workers.
+///
+/// Returns `false` if there is no such worker or if it is the last one.
+/// If called from an actor running on that worker, the actor finishes its current poll first.
+pub fn remove_worker(id: usize) -> bool {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.stop_worker(id)
+}
+
+/// How many workers currently have each run queue length.
+///
+/// Bin `i` counts the workers with `i` actors in their run queue, longer queues are counted in the last bin.
+/// Sample this periodically to see how load is spread over the workers.
+pub fn queue_histogram() -> [usize; QUEUE_HISTOGRAM_BINS] {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.queue_histogram().into_bins()
+}
+
+/// Drops all messages of type `T` that are currently in the mailbox.
+///
+/// Signals are moved into the mailbox one at a time while the actor is polled,
+/// so messages that were sent but not yet moved are not affected.
+/// Returns the number of messages that were dropped.
+pub fn flush<T>() -> usize
+where
+    T: Send + 'static,
+{
+    let removed = with_context(|context| {
+        context
+            .actor
+            .queue()
+            .remove_all_matching(&|msg| msg.is::<T>())
+    });
+    wake_blocked_senders();
+
+    removed
+}
+
+/// Drops all messages that are currently in the mailbox.
+///
+/// Returns the number of messages that were dropped.
+pub fn flush_all() -> usize {
+    let removed = with_context(|context| context.actor.queue().remove_all_matching(&|_| true));
+    wake_blocked_senders();
+
+    removed
+}
+
+/// Shrink the footprint of the current actor and wait for the next message.
+///
+/// Use this when the actor is expected to stay idle for a while.
+/// The mailbox releases its unused storage, the message itself is left in the mailbox for `receive!`.
+/// Only a message that arrives after hibernating wakes the actor, messages already in the mailbox don't.
+/// The state held by the actor's future can't be released, keep large buffers out of it before hibernating.
+pub async fn hibernate() {
+    let queued = with_context(|context| {
+        let mut queue = context.actor.queue();
+        queue.shrink();
+        queue.len()
+    });
+
+    // Only this actor takes messages out of its mailbox, so it grows once a message arrives.
+    std::future::poll_fn(|_cx| {
+        if with_context(|context| context.actor.queue().len()) <= queued {
+            std::task::Poll::Pending
+        } else {
+            std::task::Poll::Ready(())
+        }
+    })
+    .await
+}
+
+/// Stop the current actor with the given reason.
+///
+/// The actor exits the next time it suspends, `receive!` and yields suspend right away once a stop is requested.
+/// Code up to that point still runs, so awaits that are already in flight complete first.
+pub fn stop(reason: Exit) {
+    with_context(|context| context.actor.control_block().request_stop(reason));
+}
+
+/// Stop the current actor normally, see [`stop`].
+pub fn stop_normal() {
+    stop(Exit::Normal);
 }
 
 /// Sleeps for a given duration
 ///
+/// The timer is armed once, the sleep completes when that timer fires.
 /// This will spend 1 budget unit.
 pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
-    struct Sleep(Instant, Duration);
+    struct Sleep(TimerId);
 
     impl Future for Sleep {
         type Output = ();
@@ -109,7 +435,7 @@ pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
             self: Pin<&mut Self>,
             _cx: &mut std::task::Context<'_>,
         ) -> std::task::Poll<Self::Output> {
-            if self.0.elapsed() < self.1 {
+            if with_context(|context| context.actor.control_block().is_timer_armed(self.0)) {
                 std::task::Poll::Pending
             } else {
                 std::task::Poll::Ready(())
@@ -117,38 +443,70 @@ pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
         }
     }
 
+    impl Drop for Sleep {
+        fn drop(&mut self) {
+            // A sleep that lost a race is dropped while still armed, its timer is no longer needed.
+            let armed = has_context()
+                && with_context(|context| context.actor.control_block().fire_timer(self.0));
+
+            if armed {
+                let system = unsafe { crate::thread::borrow() };
+                system.timer.cancel(self.0);
+            }
+        }
+    }
+
     // We don't use yield_now here because we're already going to sleep.
-    context_mut().budget += 1;
+    with_context(|context| context.budget.set(context.budget.get() + 1));
     let system = unsafe { crate::thread::borrow() };
-    system.timer.wake_up(sync::pid(), duration);
-    let now = Instant::now();
+    let id = system.timer.wake_up(sync::pid(), duration);
+
+    // The timer can't be disarmed before this, signals are only handled while polling the actor.
+    with_context(|context| context.actor.control_block().arm_timer(id));
 
-    Sleep(now, duration)
+    Sleep(id)
 }
 
-/// Sends a signal to an actor.
+/// Schedule a message to be delivered to an actor after a given delay.
 ///
 /// If the actor is not found, the signal is dropped.
-pub async fn send_signal(to: impl ToPid, message: Signal) {
+pub async fn schedule<T>(to: impl ToPid, message: T, delay: Duration) -> TimerId
+where
+    T: Send + 'static,
+{
     yield_now(1).await;
-    sync::send_signal(to, message);
+    sync::schedule(to, message, delay)
 }
 
-/// Schedule a message to be delivered to an actor after a given delay.
-///
-/// If the actor is not found, the signal is dropped.
-pub async fn schedule<T>(to: Pid, message: T, delay: Duration)
+/// Schedule a message to the current actor, see [`schedule`].
+pub async fn schedule_self<T>(message: T, delay: Duration) -> TimerId
 where
     T: Send + 'static,
+{
+    schedule(sync::pid(), message, delay).await
+}
+
+/// Send a message to the current actor every period, see [`sync::schedule_interval`].
+///
+/// The messages stop once the timer is canceled with [`cancel_timer`], or when the actor exits.
+pub async fn schedule_self_interval<T>(message: T, period: Duration) -> TimerId
+where
+    T: Clone + Send + 'static,
 {
     yield_now(1).await;
-    sync::schedule(to, message, delay);
+    sync::schedule_interval(sync::pid(), message, period)
+}
+
+/// Cancel a scheduled message, see [`sync::cancel_timer`].
+pub async fn cancel_timer(id: TimerId) -> bool {
+    yield_now(1).await;
+    sync::cancel_timer(id)
 }
 
 /// Send a message to an actor.
 ///
 /// If the actor is not found, the message is dropped.
-/// an actor can either be a `Pid` or a `NamedRef`.
+/// An actor can either be a `Pid`, a `NamedRef` or a name (`&str` or `String`).
 pub async fn send<M>(to: impl ToPid, message: M)
 where
     M: Send + 'static,
@@ -157,6 +515,127 @@ where
     sync::send(to, message);
 }
 
+/// Send a message into a lane of the actor's mailbox, see [`Priority`].
+///
+/// High priority messages are received before normal ones, and those before low ones.
+/// If the actor is not found, the message is dropped.
+pub async fn send_with_priority<M>(to: impl ToPid, message: M, priority: Priority)
+where
+    M: Send + 'static,
+{
+    yield_now(1).await;
+    sync::send_with_priority(to, message, priority);
+}
+
+/// Send a copy of a message to each of the actors, see [`sync::send_many`].
+///
+/// This spends 1 budget unit, like a single `send`.
+pub async fn send_many<M>(to: &[Pid], message: M) -> Delivery
+where
+    M: Clone + Send + 'static,
+{
+    yield_now(1).await;
+    sync::send_many(to, message)
+}
+
+/// Send a message to an actor, reporting if it doesn't exist.
+///
+/// Delivery only means the message was queued, the actor can still exit before handling it.
+pub async fn try_send<M>(to: impl ToPid, message: M) -> Result<(), SendError>
+where
+    M: Send + 'static,
+{
+    yield_now(1).await;
+    sync::try_send(to, message)
+}
+
+/// Send a message to an actor, waiting while its mailbox is full.
+///
+/// A mailbox is full once it holds `Config::mailbox_capacity` pending messages,
+/// the sender suspends until the receiver makes room by receiving or flushing messages.
+/// Use this to keep a fast producer from flooding a slow consumer.
+/// Sending to the current actor never waits, nobody else would make room.
+///
+/// Messages the receiver doesn't match count towards the capacity as well.
+/// A receiver that never matches them stays full, and senders wait until it flushes them or exits.
+///
+/// Returns `SendError::NoProcess` if the actor is not found, also when it exits while we wait.
+/// The message is dropped in that case.
+pub async fn send_blocking<M>(to: impl ToPid, message: M) -> Result<(), SendError>
+where
+    M: Send + 'static,
+{
+    yield_now(1).await;
+
+    let to = {
+        let system = unsafe { crate::thread::borrow() };
+        to.to_reference(&system.registry)
+    };
+
+    while to != sync::pid() {
+        {
+            let system = unsafe { crate::thread::borrow() };
+            let Some(actor) = system.registry.lookup_pid(to) else {
+                return Err(SendError::NoProcess);
+            };
+
+            if actor.pending_messages() < system.mailbox_capacity {
+                break;
+            }
+
+            actor.control_block().block_sender(sync::pid());
+
+            // The receiver could have exited before we were added, then nobody would wake us.
+            if system.registry.lookup_pid(to).is_none() {
+                return Err(SendError::NoProcess);
+            }
+
+            // The receiver could have made room before we were added.
+            if actor.pending_messages() < system.mailbox_capacity {
+                break;
+            }
+        }
+
+        wait_for_wake().await;
+    }
+
+    sync::try_send(to, message)
+}
+
+// Suspend until the actor is scheduled again.
+fn wait_for_wake() -> impl Future<Output = ()> {
+    let mut woken = false;
+
+    std::future::poll_fn(move |_cx| {
+        if woken {
+            std::task::Poll::Ready(())
+        } else {
+            woken = true;
+            std::task::Poll::Pending
+        }
+    })
+}
+
+// Let actors waiting in `send_blocking` retry once the mailbox has room again.
+fn wake_blocked_senders() {
+    let system = unsafe { crate::thread::borrow() };
+
+    let blocked_senders = with_context(|context| {
+        let control_block = context.actor.control_block();
+        if !control_block.has_blocked_senders()
+            || context.actor.pending_messages() >= system.mailbox_capacity
+        {
+            return Vec::new();
+        }
+
+        control_block.take_blocked_senders()
+    });
+
+    for pid in blocked_senders {
+        system.schedule(pid);
+    }
+}
+
 /// Spawns a new actor.
 ///
 /// The spawned actor will not be linked to the current actor.
@@ -169,6 +648,29 @@ where
     sync::spawn(behavior)
 }
 
+/// Spawns a new actor with the given options, see [`sync::spawn_opts`].
+///
+/// The Pid of the spawned actor is returned along with its monitor.
+pub async fn spawn_opts<B>(behavior: B, options: SpawnOptions) -> SpawnResult
+where
+    B: IntoAsyncActor,
+{
+    yield_now(1).await;
+    sync::spawn_opts(behavior, options)
+}
+
+/// Spawns a new actor on the given worker, see [`sync::spawn_on`].
+///
+/// The spawned actor will not be linked to the current actor.
+/// The Pid of the spawned actor is returned.
+pub async fn spawn_on<B>(worker: usize, behavior: B) -> Pid
+where
+    B: IntoAsyncActor,
+{
+    yield_now(1).await;
+    sync::spawn_on(worker, behavior)
+}
+
 // TODO: Make async
 /// Spawns a new actor and links it to the current actor.
 ///
@@ -177,31 +679,14 @@ pub fn spawn_linked<B>(behavior: B) -> Pid
 where
     B: IntoAsyncActor,
 {
-    let system = unsafe { crate::thread::borrow() };
-    let context = context();
-    let pid = context.pid();
-    let new_pid = system.registry.allocate_pid();
-
-    let spawn_at = context
-        .actor
-        .control_block()
-        .worker_id
-        .load(Ordering::Acquire) as _;
-
-    let mut control_block = ActorControlBlock::new(new_pid, spawn_at);
-    control_block.metadata = Mutex::new(context.actor.metadata().clone());
-
-    let _ = control_block.add_link(pid);
-
-    let actor = HydratedActor::new(control_block, behavior);
-
-    let _ = context.actor.control_block().add_link(new_pid);
-
-    system.registry.add(actor);
-
-    system.schedule(new_pid);
-
-    new_pid
+    sync::spawn_opts(
+        behavior,
+        SpawnOptions {
+            link: true,
+            ..SpawnOptions::default()
+        },
+    )
+    .pid
 }
 
 /// Yield the current actor if the budget is spent.
@@ -213,8 +698,6 @@ where
 /// This allows other actors to run.
 /// If you use the `receive!` macro, that will automatically yield.
 pub fn yield_now(budget: usize) -> impl Future<Output = ()> {
-    const MAX_BUDGET: usize = 16;
-
     struct YieldNow;
 
     impl Future for YieldNow {
@@ -224,9 +707,16 @@ pub fn yield_now(budget: usize) -> impl Future<Output = ()> {
             self: Pin<&mut Self>,
             _cx: &mut std::task::Context<'_>,
         ) -> std::task::Poll<Self::Output> {
+            // Suspend so the actor can exit, see `stop`.
+            if with_context(|context| context.actor.control_block().is_stopping()) {
+                return std::task::Poll::Pending;
+            }
+
             let system = unsafe { crate::thread::borrow() };
-            if context().budget >= MAX_BUDGET {
-                context_mut().budget = 0;
+            if with_context(|context| context.budget.get())
+                >= system.max_budget.load(Ordering::Relaxed)
+            {
+                with_context(|context| context.budget.set(0));
                 system.schedule(sync::pid());
 
                 std::task::Poll::Pending
@@ -236,57 +726,1448 @@ pub fn yield_now(budget: usize) -> impl Future<Output = ()> {
         }
     }
 
-    context_mut().budget += budget;
+    with_context(|context| context.budget.set(context.budget.get() + budget));
 
     YieldNow
 }
 
-// TODO: Implement this better.
-pub async fn yield_immediate() {
-    yield_now(16).await;
+/// A cheap checkpoint for long running synchronous work.
+///
+/// This spends 1 budget unit and yields if the budget is spent,
+/// so a loop that calls it lets other actors run now and then.
+pub fn maybe_yield() -> impl Future<Output = ()> {
+    yield_now(1)
 }
 
-/// Insert or update metadata for the current actor.
-pub fn insert_metadata(key: &'static str, value: impl Into<MetaValue>) {
-    context().actor.metadata().insert(MetaKeyValue {
-        key,
-        value: value.into(),
-    });
-}
+/// Yield the current actor immediately.
+///
+/// Unlike `yield_now` this always reschedules the actor, regardless of the configured maximum budget.
+pub fn yield_immediate() -> impl Future<Output = ()> {
+    struct YieldImmediate(bool);
 
-// TODO: We should consider tracking where we are in the message queue and resume from there, since obviously none of the previous messages matched.
-#[doc(hidden)]
-#[must_use]
-pub async fn recv_matching<F>(
-    timeout: Option<Duration>,
-    matcher: F,
-) -> Result<Box<dyn Any + Send>, RecvError>
-where
-    F: Fn(&Box<dyn Any + Send>) -> bool,
-{
-    let now = Instant::now();
+    impl Future for YieldImmediate {
+        type Output = ();
 
-    if let Some(timeout) = timeout {
-        let system = unsafe { crate::thread::borrow() };
-        system.timer.wake_up(sync::pid(), timeout);
-    }
+        fn poll(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Self::Output> {
+            if self.0 {
+                return std::task::Poll::Ready(());
+            }
 
-    yield_now(0).await;
+            self.0 = true;
 
-    std::future::poll_fn(move |_cx| {
-        if let Some(timeout) = timeout {
-            // Handle timeouts
-            if now.elapsed() >= timeout {
-                return std::task::Poll::Ready(Err(RecvError::Timeout));
-            }
-        }
+            let system = unsafe { crate::thread::borrow() };
+            with_context(|context| context.budget.set(0));
+            system.schedule(sync::pid());
 
-        if let Some(message) = context().actor.queue().remove_matching(&matcher) {
-            context_mut().budget += 1;
-            std::task::Poll::Ready(Ok(message))
-        } else {
             std::task::Poll::Pending
         }
+    }
+
+    YieldImmediate(false)
+}
+
+/// The reductions left on the current worker before it tries to balance the workers again.
+///
+/// The budget is shared by every actor on the worker and only counts down in between actors,
+/// so the value is approximate. A long running actor can check it to save its state and yield
+/// before it keeps the worker to itself for too long.
+pub fn reductions_remaining() -> u64 {
+    current_worker().map_or(0, |worker| worker.reductions.load(Ordering::Relaxed))
+}
+
+/// Spend reductions of the current worker on work the runtime can't see, like a long computation.
+///
+/// The budget stops at zero, the worker balances the next time it picks an actor.
+pub fn consume_reductions(n: u64) {
+    if let Some(worker) = current_worker() {
+        let _ =
+            worker
+                .reductions
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reductions| {
+                    Some(reductions.saturating_sub(n))
+                });
+    }
+}
+
+/// The worker the current actor is running on, `None` if it was stopped in the meantime.
+fn current_worker() -> Option<Arc<Worker>> {
+    let system = unsafe { crate::thread::borrow() };
+    let worker_id = with_context(|context| {
+        context
+            .actor
+            .control_block()
+            .worker_id
+            .load(Ordering::Acquire) as _
+    });
+
+    system.scheduler.get_worker(worker_id)
+}
+
+/// Insert or update metadata for the current actor.
+pub fn insert_metadata(key: &'static str, value: impl Into<MetaValue>) {
+    let value = value.into();
+    with_context(|context| context.actor.metadata().upsert(MetaKeyValue { key, value }));
+}
+
+/// Find the actors that have the given metadata.
+///
+/// This scans every actor, so it's meant for debugging and admin tooling rather than hot paths.
+/// Actors are checked one after the other, the result is not a consistent snapshot.
+pub fn find_by_metadata(key: &'static str, value: impl Into<MetaValue>) -> Vec<Pid> {
+    let system = unsafe { crate::thread::borrow() };
+    let value = value.into();
+
+    system.registry.find(|control_block| {
+        utils::lock(&control_block.metadata)
+            .iter()
+            .any(|meta| meta.key == key && meta.value == value)
+    })
+}
+
+/// The sender of the message that was received last.
+///
+/// Returns `None` before the first message, or if it was sent from outside an actor.
+/// Delayed messages are sent by the actor that scheduled them, trapped exits by the actor that exited
+/// and the replies of a port by the port.
+pub fn sender() -> Option<Pid> {
+    with_context(|context| context.actor.queue().sender()).filter(|&pid| pid != Pid::invalid())
+}
+
+/// Receive the next message of any type.
+///
+/// Waits at most `timeout` if given, forever otherwise.
+/// The message is type-erased, it is up to the caller to downcast it.
+/// The message is removed from the mailbox even if the caller doesn't recognize it,
+/// use `receive!` to leave unmatched messages in the mailbox instead.
+pub async fn recv_any(timeout: Option<Duration>) -> Result<Box<dyn Any + Send>, RecvError> {
+    recv_matching(timeout, |_| true).await
+}
+
+/// Receive up to `max` messages of type `T` at once, in the order they arrived.
+///
+/// Takes the matching messages that are already in the mailbox and returns without waiting for more.
+/// Only if there are none and a `timeout` is given, this waits at most `timeout` for the first one.
+/// Without a timeout an empty mailbox gives an empty batch, which spends 1 budget unit so polling lets other actors run.
+pub async fn recv_batch<T>(max: usize, timeout: Option<Duration>) -> Vec<T>
+where
+    T: Send + 'static,
+{
+    yield_now(0).await;
+
+    let mut batch = Vec::new();
+    if max == 0 {
+        return batch;
+    }
+
+    take_batch(&mut batch, max);
+
+    if batch.is_empty()
+        && let Some(timeout) = timeout
+        && let Ok(message) = recv_matching(Some(timeout), |msg| msg.is::<T>()).await
+    {
+        batch.push(*message.downcast::<T>().unwrap());
+        take_batch(&mut batch, max);
+    }
+
+    if batch.is_empty() {
+        yield_now(1).await;
+    } else {
+        with_context(|context| context.budget.set(context.budget.get() + batch.len()));
+        wake_blocked_senders();
+    }
+
+    batch
+}
+
+// Move queued messages of type `T` into the batch until it holds `max` messages.
+fn take_batch<T>(batch: &mut Vec<T>, max: usize)
+where
+    T: Send + 'static,
+{
+    with_context(|context| {
+        let mut queue = context.actor.queue();
+
+        while batch.len() < max {
+            let Some(message) = queue.remove_matching(&mut |_, msg| msg.is::<T>()) else {
+                break;
+            };
+
+            batch.push(*message.downcast::<T>().unwrap());
+        }
+    });
+}
+
+// TODO: We should consider tracking where we are in the message queue and resume from there, since obviously none of the previous messages matched.
+#[doc(hidden)]
+#[must_use]
+pub async fn recv_matching<F>(
+    timeout: Option<Duration>,
+    mut matcher: F,
+) -> Result<Box<dyn Any + Send>, RecvError>
+where
+    F: FnMut(&Box<dyn Any + Send>) -> bool,
+{
+    recv_matching_from(timeout, |_, msg| matcher(msg)).await
+}
+
+/// Like `recv_matching`, the matcher also gets the sender of each message.
+pub(crate) async fn recv_matching_from<F>(
+    timeout: Option<Duration>,
+    mut matcher: F,
+) -> Result<Box<dyn Any + Send>, RecvError>
+where
+    F: FnMut(Pid, &Box<dyn Any + Send>) -> bool,
+{
+    let system = unsafe { crate::thread::borrow() };
+    let now = system.timer.now();
+
+    let timer = timeout.map(|timeout| system.timer.wake_up(sync::pid(), timeout));
+
+    yield_now(0).await;
+
+    // A message delivered while this is pending wakes it through `cx`, see `System::deliver`.
+    std::future::poll_fn(move |cx| {
+        let message = with_context(|context| {
+            let control_block = context.actor.control_block();
+
+            // Suspend so the actor can exit, see `stop`.
+            if control_block.is_stopping() {
+                return std::task::Poll::Pending;
+            }
+
+            if let Some(timeout) = timeout {
+                // Handle timeouts
+                if system.timer.now() - now >= timeout {
+                    control_block.clear_receive_waker();
+                    return std::task::Poll::Ready(Err(RecvError::Timeout));
+                }
+            }
+
+            let message = context.actor.queue().remove_matching(&mut matcher);
+            if let Some(message) = message {
+                control_block.clear_receive_waker();
+                context.budget.set(context.budget.get() + 1);
+                std::task::Poll::Ready(Ok(message))
+            } else {
+                // Register before going pending, the next message wakes this receive through `cx`.
+                control_block.set_receive_waker(cx.waker());
+                std::task::Poll::Pending
+            }
+        });
+
+        if let std::task::Poll::Ready(Ok(_)) = message {
+            // The timeout lost, its timer would only wake the actor for nothing.
+            if let Some(timer) = timer {
+                system.timer.cancel(timer);
+            }
+
+            wake_blocked_senders();
+        }
+
+        message
     })
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::{Pin, pin},
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
+        task::{Context, Wake, Waker},
+        time::{Duration, Instant},
+    };
+
+    use crate::{Config, Exit, receive};
+
+    /// Runs two actors on a single worker and counts how often execution switched between them.
+    fn count_switches(max_budget: usize) -> usize {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+
+        let config = Config {
+            workers: 1,
+            max_budget,
+            ..Config::default()
+        };
+
+        {
+            let trace = trace.clone();
+
+            crate::run_with(config, async move || {
+                let done = Arc::new(AtomicUsize::new(0));
+
+                for id in 0..2 {
+                    let trace = trace.clone();
+                    let done = done.clone();
+
+                    super::spawn(async move || {
+                        for _ in 0..64 {
+                            trace.lock().unwrap().push(id);
+                            super::yield_now(1).await;
+                        }
+
+                        if done.fetch_add(1, Ordering::AcqRel) == 1 {
+                            super::sync::stop();
+                        }
+
+                        Exit::Normal
+                    })
+                    .await;
+                }
+
+                Exit::Normal
+            });
+        }
+
+        let trace = trace.lock().unwrap();
+        trace.windows(2).filter(|w| w[0] != w[1]).count()
+    }
+
+    #[test]
+    fn low_budget_reschedules_more_often() {
+        let low = count_switches(1);
+        let high = count_switches(1024);
+
+        assert!(
+            low > high,
+            "low budget: {low} switches, high budget: {high}"
+        );
+    }
+
+    #[test]
+    fn recv_any_receives_every_type() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                super::send(super::sync::pid(), 42i32).await;
+                super::send(super::sync::pid(), String::from("hello")).await;
+
+                let mut received = Vec::new();
+                for _ in 0..2 {
+                    let Ok(message) = super::recv_any(None).await else {
+                        unreachable!()
+                    };
+
+                    match message.downcast::<i32>() {
+                        Ok(number) => received.push(number.to_string()),
+                        Err(message) => received.push(*message.downcast::<String>().unwrap()),
+                    }
+                }
+
+                let timeout = super::recv_any(Some(Duration::from_millis(10))).await;
+
+                completion.complete((received, timeout.err()));
+
+                Exit::Normal
+            }
+        });
+
+        let (received, timeout) = result.unwrap();
+
+        assert_eq!(received, ["42", "hello"]);
+        assert_eq!(timeout, Some(super::RecvError::Timeout));
+    }
+
+    #[test]
+    fn flush_drops_only_the_given_type() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                super::send(super::sync::pid(), 1i32).await;
+                super::send(super::sync::pid(), String::from("a")).await;
+                super::send(super::sync::pid(), 2i32).await;
+                super::send(super::sync::pid(), 3u8).await;
+
+                // Signals are moved into the mailbox in order, so once the marker arrives the rest has too.
+                super::send(super::sync::pid(), ()).await;
+                receive! {
+                    match () {
+                        () => {}
+                    }
+                }
+
+                let flushed = super::flush::<i32>();
+
+                let Ok(first) = super::recv_any(None).await else {
+                    unreachable!()
+                };
+                let Ok(second) = super::recv_any(None).await else {
+                    unreachable!()
+                };
+                let remaining = (
+                    *first.downcast::<String>().unwrap(),
+                    *second.downcast::<u8>().unwrap(),
+                );
+
+                super::send(super::sync::pid(), 4i32).await;
+                super::send(super::sync::pid(), 5u8).await;
+                super::send(super::sync::pid(), ()).await;
+                receive! {
+                    match () {
+                        () => {}
+                    }
+                }
+
+                let flushed_all = super::flush_all();
+                let empty = super::recv_any(Some(Duration::from_millis(10)))
+                    .await
+                    .is_err();
+
+                completion.complete((flushed, remaining, flushed_all, empty));
+
+                Exit::Normal
+            }
+        });
+
+        let (flushed, remaining, flushed_all, empty) = result.unwrap();
+
+        assert_eq!(flushed, 2);
+        assert_eq!(remaining, (String::from("a"), 3));
+        assert_eq!(flushed_all, 2);
+        assert!(empty);
+    }
+
+    #[test]
+    fn stats_counts_workers_and_processes() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let before = super::stats();
+
+                let pid = super::spawn(async || {
+                    receive! {
+                        match () {
+                            () => {}
+                        }
+                    }
+
+                    Exit::Normal
+                })
+                .await;
+
+                let after = super::stats();
+                super::send(pid, ()).await;
+
+                completion.complete((before, after));
+
+                Exit::Normal
+            }
+        });
+
+        let (before, after) = result.unwrap();
+
+        assert_eq!(before.workers, 2);
+        assert_eq!(before.run_queue_lengths.len(), 2);
+        assert_eq!(after.processes, before.processes + 1);
+    }
+
+    #[test]
+    fn worker_snapshots_advance() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let before = super::stats();
+
+                for _ in 0..10 {
+                    let pid = super::spawn(async || {
+                        receive! {
+                            match () {
+                                () => {}
+                            }
+                        }
+
+                        Exit::Normal
+                    })
+                    .await;
+
+                    super::send(pid, ()).await;
+                    super::sleep(Duration::from_millis(1)).await;
+                }
+
+                let after = super::stats();
+
+                completion.complete((before, after));
+
+                Exit::Normal
+            }
+        });
+
+        let (before, after) = result.unwrap();
+        let total = |stats: &super::RuntimeStats| {
+            stats
+                .worker_snapshots
+                .iter()
+                .fold((0, 0, Duration::ZERO), |(polls, runs, time), s| {
+                    (
+                        polls + s.polls,
+                        runs + s.actors_run,
+                        time + s.parked + s.running,
+                    )
+                })
+        };
+
+        let (polls_before, runs_before, time_before) = total(&before);
+        let (polls_after, runs_after, time_after) = total(&after);
+
+        assert_eq!(after.worker_snapshots.len(), 2);
+        assert!(
+            runs_after >= runs_before + 20,
+            "{runs_before} -> {runs_after}"
+        );
+        assert!(polls_after >= polls_before + 20);
+        assert!(time_after > time_before);
+    }
+
+    #[test]
+    fn idle_workers_park_instead_of_stealing() {
+        let config = Config {
+            workers: 4,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let before = super::stats();
+                super::sleep(Duration::from_millis(50)).await;
+                let after = super::stats();
+
+                completion.complete((before, after));
+
+                Exit::Normal
+            }
+        });
+
+        let (before, after) = result.unwrap();
+        let total = |stats: &super::RuntimeStats| {
+            stats
+                .worker_snapshots
+                .iter()
+                .fold((0, 0, 0), |(parks, attempts, steals), s| {
+                    (
+                        parks + s.parks,
+                        attempts + s.steal_attempts,
+                        steals + s.steals,
+                    )
+                })
+        };
+
+        let (parks_before, attempts_before, steals_before) = total(&before);
+        let (parks_after, attempts_after, steals_after) = total(&after);
+
+        assert!(
+            parks_after > parks_before,
+            "{parks_before} -> {parks_after}"
+        );
+        assert!(attempts_after > attempts_before);
+        assert!(
+            steals_after - steals_before <= 2,
+            "{steals_before} -> {steals_after}"
+        );
+    }
+
+    #[test]
+    fn added_workers_share_the_load() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                for _ in 0..32 {
+                    super::spawn(async || {
+                        loop {
+                            super::yield_immediate().await;
+                        }
+                    })
+                    .await;
+                }
+
+                let added: Vec<_> = (0..3).map(|_| super::add_worker().unwrap()).collect();
+                let workers = super::stats().workers;
+
+                super::sleep(Duration::from_millis(50)).await;
+
+                let system = unsafe { crate::thread::borrow() };
+                let runs: Vec<_> = added
+                    .iter()
+                    .map(|&id| {
+                        system
+                            .scheduler
+                            .get_worker(id)
+                            .unwrap()
+                            .snapshot()
+                            .actors_run
+                    })
+                    .collect();
+
+                let removed = super::remove_worker(added[0]);
+                let after_remove = super::stats().workers;
+
+                completion.complete((workers, runs, removed, after_remove));
+
+                Exit::Normal
+            }
+        });
+
+        let (workers, runs, removed, after_remove) = result.unwrap();
+
+        assert_eq!(workers, 4);
+        assert!(runs.iter().all(|&runs| runs > 0), "{runs:?}");
+        assert!(removed);
+        assert_eq!(after_remove, 3);
+    }
+
+    #[test]
+    fn find_by_metadata_returns_tagged_actors() {
+        struct Tagged;
+
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let test = super::sync::pid();
+                let mut tagged = Vec::new();
+
+                for i in 0..4u32 {
+                    let pid = super::spawn(async move || {
+                        if i % 2 == 0 {
+                            super::insert_metadata("request_id", "X");
+                        } else {
+                            super::insert_metadata("request_id", "Y");
+                        }
+                        super::sync::send(test, Tagged);
+
+                        receive! {
+                            match () {
+                                () => Exit::Normal,
+                            }
+                        }
+                    })
+                    .await;
+
+                    if i % 2 == 0 {
+                        tagged.push(pid);
+                    }
+                }
+
+                for _ in 0..4 {
+                    receive! {
+                        match Tagged {
+                            Tagged => {}
+                        }
+                    }
+                }
+
+                let mut found = super::find_by_metadata("request_id", "X");
+                found.sort();
+
+                completion.complete((tagged, found));
+
+                Exit::Normal
+            }
+        });
+
+        let (tagged, found) = result.unwrap();
+
+        assert_eq!(found, tagged);
+    }
+
+    #[test]
+    fn nested_context_access() {
+        use crate::{
+            Pid,
+            actor::{ActorControlBlock, HydratedActor, HydratedActorBase},
+            system::System,
+        };
+
+        let system = System::new(&Config::default());
+        crate::thread::give(system);
+
+        let outer: Pin<Arc<dyn HydratedActorBase>> = Arc::pin(HydratedActor::new(
+            ActorControlBlock::new(Pid(1), 0),
+            async || Exit::Normal,
+        ));
+        let inner: Pin<Arc<dyn HydratedActorBase>> = Arc::pin(HydratedActor::new(
+            ActorControlBlock::new(Pid(2), 0),
+            async || Exit::Normal,
+        ));
+
+        assert!(!super::has_context());
+
+        {
+            let outer_context = super::GlobalContext::new(&outer);
+            let _outer_guard = super::enter_context(&outer_context);
+
+            // Reads and writes nested inside another read of the same context.
+            super::with_context(|context| {
+                assert_eq!(context.pid(), Pid(1));
+                assert_eq!(super::sync::pid(), Pid(1));
+
+                drop(super::yield_now(3));
+                super::insert_metadata("nested", 1);
+
+                assert_eq!(context.budget.get(), 3);
+                assert_eq!(super::with_context(|context| context.budget.get()), 3);
+            });
+
+            {
+                let inner_context = super::GlobalContext::new(&inner);
+                let _inner_guard = super::enter_context(&inner_context);
+
+                assert_eq!(super::sync::pid(), Pid(2));
+                assert_eq!(super::with_context(|context| context.budget.get()), 0);
+            }
+
+            // Leaving the inner context restores the outer one.
+            assert_eq!(super::sync::pid(), Pid(1));
+            assert_eq!(super::sync::metadata().len(), 1);
+        }
+
+        assert!(!super::has_context());
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn actor_schedules_itself() {
+        #[derive(Clone)]
+        struct Tick;
+        struct Wake;
+
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                for delay in 1..=3 {
+                    super::schedule_self(Wake, Duration::from_millis(delay)).await;
+                }
+
+                let mut wakeups = 0;
+                while wakeups < 3 {
+                    receive! {
+                        match Wake {
+                            Wake => wakeups += 1,
+                        }
+                    }
+                }
+
+                let interval = super::schedule_self_interval(Tick, Duration::from_millis(2)).await;
+
+                let mut ticks = 0;
+                while ticks < 3 {
+                    receive! {
+                        match Tick {
+                            Tick => ticks += 1,
+                        }
+                    }
+                }
+
+                let canceled = super::cancel_timer(interval).await;
+                let canceled_again = super::cancel_timer(interval).await;
+
+                completion.complete((wakeups, ticks, canceled, canceled_again));
+
+                Exit::Normal
+            }
+        });
+
+        let (wakeups, ticks, canceled, canceled_again) = result.unwrap();
+
+        assert_eq!(wakeups, 3);
+        assert_eq!(ticks, 3);
+        // The interval keeps going until it's canceled.
+        assert!(canceled);
+        assert!(!canceled_again);
+    }
+
+    #[test]
+    fn kill_cannot_be_trapped() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                super::trap_exit(true);
+
+                let child = super::spawn_linked(async || {
+                    super::trap_exit(true);
+
+                    loop {
+                        receive! {
+                            match crate::TrapExitMessage {
+                                _ => (),
+                            }
+                        }
+                    }
+                });
+
+                super::kill(child).await;
+
+                let reason = receive! {
+                    match crate::TrapExitMessage {
+                        crate::TrapExitMessage { reason, .. } => reason,
+                    }
+                };
+
+                completion.complete(reason);
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(Exit::Killed));
+    }
+
+    #[test]
+    fn untrapping_lets_exits_propagate_again() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = super::sync::pid();
+                super::trap_exit(true);
+
+                let middle = super::spawn_linked(async move || {
+                    let initially = super::is_trapping_exits();
+                    super::trap_exit(true);
+                    let trapping = super::is_trapping_exits();
+
+                    super::spawn_linked(async || Exit::Shutdown);
+                    let trapped = receive! {
+                        match crate::TrapExitMessage {
+                            crate::TrapExitMessage { reason, .. } => reason,
+                        }
+                    };
+
+                    super::trap_exit(false);
+                    super::send(
+                        root,
+                        (initially, trapping, super::is_trapping_exits(), trapped),
+                    )
+                    .await;
+
+                    // This exit isn't trapped anymore, so it takes the actor down with it.
+                    super::spawn_linked(async || Exit::Killed);
+                    receive! {
+                        match () {
+                            () => Exit::Normal,
+                        }
+                        after Duration::from_secs(1) => Exit::Normal,
+                    }
+                });
+
+                let flags = receive! {
+                    match (bool, bool, bool, Exit) {
+                        flags => flags,
+                    }
+                };
+
+                let propagated = receive! {
+                    match crate::TrapExitMessage {
+                        crate::TrapExitMessage { pid, reason } => (pid == middle, reason),
+                    }
+                };
+
+                completion.complete((flags, propagated));
+
+                Exit::Normal
+            }
+        });
+
+        let ((initially, trapping, untrapped, trapped), propagated) = result.unwrap();
+
+        assert!(!initially);
+        assert!(trapping);
+        assert!(!untrapped);
+        assert_eq!(trapped, Exit::Shutdown);
+        assert_eq!(propagated, (true, Exit::Killed));
+    }
+
+    #[test]
+    fn link_and_unlink() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                super::trap_exit(true);
+
+                let exit_on_message = async || {
+                    receive! {
+                        match () {
+                            () => Exit::Shutdown,
+                        }
+                    }
+                };
+
+                let linked = super::sync::spawn(exit_on_message);
+                let linked_result = super::link(linked).await;
+                super::send(linked, ()).await;
+
+                let linked_exit = receive! {
+                    match crate::TrapExitMessage {
+                        crate::TrapExitMessage { pid, reason } => (pid == linked, reason),
+                    }
+                    after Duration::from_secs(1) => (false, Exit::Normal),
+                };
+
+                let unlinked = super::sync::spawn(exit_on_message);
+                let _ = super::link(unlinked).await;
+                super::unlink(unlinked).await;
+                super::send(unlinked, ()).await;
+
+                let unlinked_exit = receive! {
+                    match crate::TrapExitMessage {
+                        _ => true,
+                    }
+                    after Duration::from_millis(50) => false,
+                };
+
+                let dead_result = super::link(linked).await;
+
+                completion.complete((linked_result, linked_exit, unlinked_exit, dead_result));
+
+                Exit::Normal
+            }
+        });
+
+        let (linked_result, linked_exit, unlinked_exit, dead_result) = result.unwrap();
+
+        assert_eq!(linked_result, Ok(()));
+        assert_eq!(linked_exit, (true, Exit::Shutdown));
+        assert!(!unlinked_exit);
+        assert_eq!(dead_result, Err(super::SendError::NoProcess));
+    }
+
+    #[test]
+    fn recv_batch_drains_up_to_max() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let consumer = super::sync::spawn(async move || {
+                    // Every number is in the mailbox once the marker is received.
+                    receive! {
+                        match () {
+                            () => (),
+                        }
+                    }
+
+                    let mut batches = Vec::new();
+                    loop {
+                        let batch = super::recv_batch::<u32>(4, None).await;
+                        if batch.is_empty() {
+                            break;
+                        }
+                        batches.push(batch);
+                    }
+
+                    let waited = super::recv_batch::<u32>(4, Some(Duration::from_millis(10))).await;
+
+                    completion.complete((batches, waited));
+
+                    Exit::Normal
+                });
+
+                for n in 0..10u32 {
+                    super::send(consumer, n).await;
+                }
+                super::send(consumer, ()).await;
+
+                Exit::Normal
+            }
+        });
+
+        let (batches, waited) = result.unwrap();
+
+        assert_eq!(batches, [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+        assert!(waited.is_empty());
+    }
+
+    #[test]
+    fn polling_an_empty_batch_lets_others_run() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let poller = super::spawn(async move || {
+                    let mut polls = 0;
+                    let batch = loop {
+                        let batch = super::recv_batch::<u32>(4, None).await;
+                        if !batch.is_empty() {
+                            break batch;
+                        }
+                        polls += 1;
+                    };
+
+                    completion.complete((batch, polls));
+
+                    Exit::Normal
+                })
+                .await;
+
+                // Only gets to run if the poller yields, there is a single worker.
+                super::spawn(async move || {
+                    super::send(poller, 7u32).await;
+                    Exit::Normal
+                })
+                .await;
+
+                Exit::Normal
+            }
+        });
+
+        let (batch, polls) = result.unwrap();
+
+        assert_eq!(batch, [7]);
+        assert!(polls > 0);
+    }
+
+    #[test]
+    fn low_reductions_let_other_actors_run() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                const LOW: u64 = 1_000_000;
+
+                let ran = Arc::new(AtomicBool::new(false));
+
+                // Nothing else runs on this worker, so only the consumed reductions are gone.
+                let before = super::reductions_remaining();
+                super::consume_reductions(10);
+                let after = super::reductions_remaining();
+
+                {
+                    let ran = ran.clone();
+
+                    super::spawn(async move || {
+                        // Heavy work that only gives up the worker once its reductions run low.
+                        let work = async |amount| {
+                            for _ in 0..1000 {
+                                if ran.load(Ordering::Acquire) {
+                                    break;
+                                }
+
+                                super::consume_reductions(amount);
+                                if super::reductions_remaining() < LOW {
+                                    super::yield_immediate().await;
+                                }
+                            }
+
+                            ran.load(Ordering::Acquire)
+                        };
+
+                        // With plenty of reductions left it keeps the worker, the other actor can't run.
+                        let ran_early = work(10).await;
+                        let ran_late = work(500_000).await;
+
+                        completion.complete((before, after, ran_early, ran_late));
+
+                        Exit::Normal
+                    })
+                    .await;
+                }
+
+                super::spawn(async move || {
+                    ran.store(true, Ordering::Release);
+
+                    Exit::Normal
+                })
+                .await;
+
+                Exit::Normal
+            }
+        });
+
+        let (before, after, ran_early, ran_late) = result.unwrap();
+
+        assert!(before >= 10);
+        assert_eq!(after, before - 10);
+        assert!(!ran_early, "yielded with reductions left");
+        assert!(ran_late, "didn't yield once reductions ran low");
+    }
+
+    #[test]
+    fn concurrent_link_unlink_and_exit_dont_deadlock() {
+        const PAIRS: usize = 16;
+
+        let config = Config {
+            workers: 4,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let done = Arc::new(AtomicUsize::new(0));
+
+                for _ in 0..PAIRS {
+                    let done = done.clone();
+
+                    super::spawn(async move || {
+                        let me = super::sync::pid();
+
+                        let partner = super::spawn(async move || {
+                            for _ in 0..100 {
+                                let _ = super::link(me).await;
+                                super::unlink(me).await;
+                            }
+
+                            Exit::Normal
+                        })
+                        .await;
+
+                        for _ in 0..100 {
+                            let _ = super::link(partner).await;
+                            super::unlink(partner).await;
+                        }
+
+                        done.fetch_add(1, Ordering::AcqRel);
+
+                        Exit::Normal
+                    })
+                    .await;
+                }
+
+                // Look at the links while holding the registry shards, like the lock order allows.
+                let system = unsafe { crate::thread::borrow() };
+                while done.load(Ordering::Acquire) < PAIRS {
+                    system
+                        .registry
+                        .find(|control_block| !crate::utils::lock(&control_block.links).is_empty());
+                    super::yield_immediate().await;
+                }
+
+                completion.complete(done.load(Ordering::Acquire));
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(PAIRS));
+    }
+
+    #[test]
+    fn pending_receive_is_woken_through_its_context() {
+        /// Counts how often it is woken, and passes the wake on to the actor.
+        struct CountingWaker {
+            wakes: Arc<AtomicUsize>,
+            actor: Waker,
+        }
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.wake_by_ref();
+            }
+
+            fn wake_by_ref(self: &Arc<Self>) {
+                self.wakes.fetch_add(1, Ordering::SeqCst);
+                self.actor.wake_by_ref();
+            }
+        }
+
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let receiver = super::spawn(async move || {
+                    let wakes = Arc::new(AtomicUsize::new(0));
+                    let mut receive = pin!(super::recv_matching(None, |msg| msg.is::<u32>()));
+
+                    let message = std::future::poll_fn(|cx| {
+                        let waker = Waker::from(Arc::new(CountingWaker {
+                            wakes: wakes.clone(),
+                            actor: cx.waker().clone(),
+                        }));
+                        receive.as_mut().poll(&mut Context::from_waker(&waker))
+                    })
+                    .await
+                    .unwrap();
+
+                    completion.complete((
+                        *message.downcast::<u32>().unwrap(),
+                        wakes.load(Ordering::SeqCst),
+                    ));
+
+                    Exit::Normal
+                })
+                .await;
+
+                // Make sure the receiver has gone pending before the message arrives.
+                super::sleep(Duration::from_millis(20)).await;
+                super::send(receiver, 7u32).await;
+
+                Exit::Normal
+            }
+        });
+
+        let (value, wakes) = result.unwrap();
+
+        assert_eq!(value, 7);
+        assert!(wakes >= 1, "the message didn't wake the receive");
+    }
+
+    #[test]
+    fn hibernate_shrinks_the_mailbox() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                for i in 0..256 {
+                    super::send(super::sync::pid(), i).await;
+                }
+                super::send(super::sync::pid(), ()).await;
+                receive! {
+                    match () {
+                        () => {}
+                    }
+                }
+
+                super::flush_all();
+                let before = super::with_context(|context| context.actor.queue().capacity());
+
+                super::schedule_self((), Duration::from_millis(10)).await;
+                super::hibernate().await;
+                let after = super::with_context(|context| context.actor.queue().capacity());
+
+                receive! {
+                    match () {
+                        () => {}
+                    }
+                }
+
+                completion.complete((before, after));
+
+                Exit::Normal
+            }
+        });
+
+        let (before, after) = result.unwrap();
+
+        assert!(before >= 256, "capacity before hibernating: {before}");
+        assert!(after < before, "capacity after hibernating: {after}");
+    }
+
+    #[test]
+    fn hibernate_waits_for_a_new_message() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let pid = super::sync::pid();
+
+                // Unmatched mail that is in the mailbox before hibernating.
+                super::send(pid, "junk").await;
+                super::send(pid, ()).await;
+                receive! {
+                    match () {
+                        () => {}
+                    }
+                }
+
+                let now = Instant::now();
+                super::schedule_self(7u32, Duration::from_millis(20)).await;
+                super::hibernate().await;
+                let elapsed = now.elapsed();
+
+                let received = receive! {
+                    match u32 {
+                        n => n,
+                    }
+                };
+
+                completion.complete((elapsed, received));
+
+                Exit::Normal
+            }
+        });
+
+        let (elapsed, received) = result.unwrap();
+
+        assert!(
+            elapsed >= Duration::from_millis(20),
+            "woke after {elapsed:?}"
+        );
+        assert_eq!(received, 7);
+    }
+
+    #[test]
+    fn stop_normal_exits_at_next_receive() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                super::trap_exit(true);
+
+                let handled = Arc::new(Mutex::new(Vec::new()));
+
+                let child = {
+                    let handled = handled.clone();
+
+                    super::spawn_linked(async move || {
+                        loop {
+                            receive! {
+                                match i32 {
+                                    n => {
+                                        handled.lock().unwrap().push(n);
+                                        if n == 2 {
+                                            super::stop_normal();
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    })
+                };
+
+                for n in 1..=3 {
+                    super::send(child, n).await;
+                }
+
+                let reason = receive! {
+                    match crate::TrapExitMessage {
+                        crate::TrapExitMessage { reason, .. } => reason,
+                    }
+                };
+
+                completion.complete((reason, handled.lock().unwrap().clone()));
+
+                Exit::Normal
+            }
+        });
+
+        let (reason, handled) = result.unwrap();
+
+        assert_eq!(reason, Exit::Normal);
+        assert_eq!(handled, [1, 2]);
+    }
+
+    #[test]
+    fn reply_to_sender() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let echo = super::spawn(async || {
+                    loop {
+                        receive! {
+                            match &'static str {
+                                _ => {
+                                    if let Some(sender) = super::sender() {
+                                        super::send(sender, "pong").await;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                })
+                .await;
+
+                super::send(echo, "ping").await;
+
+                let reply = receive! {
+                    match &'static str {
+                        reply => reply,
+                    }
+                };
+
+                completion.complete((reply, super::sender() == Some(echo)));
+
+                Exit::Normal
+            }
+        });
+
+        let (reply, from_echo) = result.unwrap();
+
+        assert_eq!(reply, "pong");
+        assert!(from_echo);
+    }
+
+    #[test]
+    fn send_blocking_waits_for_room() {
+        let config = Config {
+            workers: 2,
+            mailbox_capacity: 4,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let consumer = super::spawn(async move || {
+                    let mut received = Vec::new();
+                    let mut max_pending = 0;
+
+                    while received.len() < 40 {
+                        receive! {
+                            match u32 {
+                                n => {
+                                    // Count the message that was just received as well.
+                                    let pending = super::with_context(|context| context.actor.pending_messages()) + 1;
+                                    max_pending = max_pending.max(pending);
+                                    received.push(n);
+                                }
+                            }
+                        }
+
+                        super::sleep(Duration::from_millis(1)).await;
+                    }
+
+                    completion.complete((received, max_pending));
+
+                    Exit::Normal
+                })
+                .await;
+
+                for n in 0..40u32 {
+                    super::send_blocking(consumer, n).await.unwrap();
+                }
+
+                Exit::Normal
+            }
+        });
+
+        let (received, max_pending) = result.unwrap();
+
+        assert_eq!(received, (0..40).collect::<Vec<_>>());
+        assert!(max_pending <= 4, "{max_pending}");
+    }
+
+    #[test]
+    fn send_blocking_gives_up_when_the_receiver_exits() {
+        let config = Config {
+            workers: 2,
+            mailbox_capacity: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let root = super::sync::pid();
+
+                // Never matches the numbers, so its mailbox stays full.
+                let consumer = super::spawn(async || {
+                    loop {
+                        receive! {
+                            match () {
+                                () => (),
+                            }
+                        }
+                    }
+                })
+                .await;
+
+                super::spawn(async move || {
+                    let mut results = Vec::new();
+                    for n in 0..3u32 {
+                        results.push(super::send_blocking(consumer, n).await);
+                    }
+
+                    super::send(root, results).await;
+
+                    Exit::Normal
+                })
+                .await;
+
+                super::sleep(Duration::from_millis(20)).await;
+                super::kill(consumer).await;
+
+                let results = receive! {
+                    match Vec<Result<(), super::SendError>> {
+                        results => results,
+                    }
+                };
+
+                completion.complete(results);
+
+                Exit::Normal
+            }
+        });
+
+        let results = result.unwrap();
+
+        assert_eq!(
+            results,
+            vec![Ok(()), Ok(()), Err(super::SendError::NoProcess)]
+        );
+    }
+}
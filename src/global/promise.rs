@@ -0,0 +1,163 @@
+//! One shot replies that don't go through the mailbox.
+//!
+//! The `ReplyHandle` is moved into a request, whoever handles the request resolves it.
+//! The actor awaiting the `Promise` is woken through its waker like any other future,
+//! so the reply can come from another actor or from any thread.
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use crate::utils;
+
+/// The `ReplyHandle` was dropped without resolving the promise, e.g. because its actor exited.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BrokenPromise;
+
+enum Slot<T> {
+    Waiting(Option<Waker>),
+    Resolved(T),
+    Broken,
+    /// The value was taken or the promise was dropped.
+    Done,
+}
+
+/// The receiving side of a reply, await it for the value.
+pub struct Promise<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+/// The sending side of a reply, it can be moved into a message or to another thread.
+pub struct ReplyHandle<T> {
+    slot: Option<Arc<Mutex<Slot<T>>>>,
+}
+
+/// Create a promise and the handle that resolves it.
+pub fn promise<T>() -> (Promise<T>, ReplyHandle<T>) {
+    let slot = Arc::new(Mutex::new(Slot::Waiting(None)));
+
+    (
+        Promise { slot: slot.clone() },
+        ReplyHandle { slot: Some(slot) },
+    )
+}
+
+impl<T> ReplyHandle<T> {
+    /// Resolve the promise, waking whoever awaits it.
+    ///
+    /// The value is returned if the promise was dropped already.
+    pub fn resolve(mut self, value: T) -> Result<(), T> {
+        let slot = self.slot.take().expect("Handle is resolved once");
+        let mut slot = utils::lock(&slot);
+
+        match std::mem::replace(&mut *slot, Slot::Done) {
+            Slot::Waiting(waker) => {
+                *slot = Slot::Resolved(value);
+                drop(slot);
+
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+
+                Ok(())
+            }
+            _ => Err(value),
+        }
+    }
+}
+
+impl<T> Drop for ReplyHandle<T> {
+    fn drop(&mut self) {
+        let Some(slot) = self.slot.take() else {
+            return;
+        };
+
+        let mut slot = utils::lock(&slot);
+        if let Slot::Waiting(waker) = std::mem::replace(&mut *slot, Slot::Broken) {
+            drop(slot);
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Future for Promise<T> {
+    type Output = Result<T, BrokenPromise>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = utils::lock(&self.slot);
+
+        match std::mem::replace(&mut *slot, Slot::Done) {
+            Slot::Waiting(mut waker) => {
+                match &mut waker {
+                    Some(current) => current.clone_from(cx.waker()),
+                    None => waker = Some(cx.waker().clone()),
+                }
+                *slot = Slot::Waiting(waker);
+
+                Poll::Pending
+            }
+            Slot::Resolved(value) => Poll::Ready(Ok(value)),
+            Slot::Broken => Poll::Ready(Err(BrokenPromise)),
+            Slot::Done => panic!("Promise polled after it completed"),
+        }
+    }
+}
+
+impl<T> Drop for Promise<T> {
+    fn drop(&mut self) {
+        // Let a later `resolve` hand the value back.
+        *utils::lock(&self.slot) = Slot::Done;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Exit, global};
+
+    use super::{BrokenPromise, ReplyHandle, promise};
+
+    #[test]
+    fn resolved_by_another_actor_and_a_thread() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let (from_actor, handle) = promise::<u32>();
+                global::spawn(async move || {
+                    let _ = handle.resolve(1);
+
+                    Exit::Normal
+                })
+                .await;
+
+                let (from_thread, handle) = promise::<u32>();
+                std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    let _ = handle.resolve(2);
+                });
+
+                let (dropped, handle) = promise::<u32>();
+                drop::<ReplyHandle<u32>>(handle);
+
+                let values = (from_actor.await, from_thread.await, dropped.await);
+
+                completion.complete(values);
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some((Ok(1), Ok(2), Err(BrokenPromise))));
+    }
+
+    #[test]
+    fn resolve_returns_the_value_without_a_promise() {
+        let (promise, handle) = promise::<u32>();
+        drop(promise);
+
+        assert_eq!(handle.resolve(7), Err(7));
+    }
+}
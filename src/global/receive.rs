@@ -1,7 +1,19 @@
+/// Receive the first message in the mailbox that matches one of the arms.
+///
+/// Without an `else` arm, messages that match no arm stay in the mailbox. With an `else` arm the first message is
+/// always taken, anything that matches no arm is handed to the `else` block, optionally bound as the raw
+/// `Box<dyn Any + Send>`, so junk mail can be logged or dropped instead of piling up.
+///
+/// Each `match` block can have any number of arms, with `|` patterns and guards, so a type only needs to be listed
+/// once. A message of a listed type that matches none of its arms is treated like any other unmatched message.
+///
+/// Guards can borrow local state, mutably too, but can't move out of it. A guard runs exactly once for every message
+/// that is looked at, against a reference to it, so bindings are `&T` in guards and `T` in the arm itself.
+///
 /// ```no_run
 /// use std::time::Duration;
 /// async fn test() {
-///     kerosene::receive_new! {
+///     kerosene::receive! {
 ///         match String {
 ///             s if s.is_empty() => println!("{}", s),
 ///         }
@@ -16,7 +28,7 @@
 /// ```no_run
 /// use std::time::Duration;
 /// async fn test() {
-///     kerosene::receive_new! {
+///     kerosene::receive! {
 ///         match String {
 ///             s if s.is_empty() => println!("{}", s),
 ///         }
@@ -32,7 +44,7 @@
 /// ```no_run
 /// use std::time::Duration;
 /// async fn test() {
-///     kerosene::receive_new! {
+///     kerosene::receive! {
 ///         match String {
 ///             s if s.is_empty() => println!("{}", s),
 ///         }
@@ -44,7 +56,7 @@
 /// ```no_run
 /// use std::time::Duration;
 /// async fn test() {
-///     kerosene::receive_new! {
+///     kerosene::receive! {
 ///         match String {
 ///             s => println!("{}", s),
 ///         }
@@ -55,6 +67,28 @@
 ///     }
 /// }
 /// ```
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// enum Command {
+///     Push(u32),
+///     Pop,
+///     Clear,
+///     Stop,
+/// }
+///
+/// async fn test(stack: &mut Vec<u32>) {
+///     kerosene::receive! {
+///         match Command {
+///             Command::Push(value) if stack.len() < 16 => stack.push(value),
+///             Command::Pop => drop(stack.pop()),
+///             Command::Clear | Command::Stop => stack.clear(),
+///         }
+///         after Duration::from_secs(1) => println!("Timeout"),
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! receive {
     {
@@ -69,22 +103,13 @@ macro_rules! receive {
 
         match msg {
             Ok(msg) => {
-                if false { unreachable!() }
-                $(
-                    else if msg.is::<$ty>() {
-                        let msg = msg.downcast::<$ty>().unwrap();
-                        match *msg {
-                            $(
-                                $pat $(if $guard)? => $expr,
-                            )+
-                            #[allow(unreachable_patterns)]
-                            _ => unreachable!(),
-                        }
-                    }
-                )*
-                else {
+                let arm = $crate::__receive_select!(msg; $($ty { $($pat $(if $guard)?),+ })*);
+
+                if arm == 0 {
                     let $else = msg;
                     $else_block
+                } else {
+                    $crate::__receive_dispatch!(msg, arm; $($ty { $($pat => $expr),+ })*)
                 }
             },
             Err(_) => {
@@ -105,21 +130,12 @@ macro_rules! receive {
 
         match msg {
             Ok(msg) => {
-                if false { unreachable!() }
-                $(
-                    else if msg.is::<$ty>() {
-                        let msg = msg.downcast::<$ty>().unwrap();
-                        match *msg {
-                            $(
-                                $pat $(if $guard)? => $expr,
-                            )+
-                            #[allow(unreachable_patterns)]
-                            _ => unreachable!(),
-                        }
-                    }
-                )*
-                else {
+                let arm = $crate::__receive_select!(msg; $($ty { $($pat $(if $guard)?),+ })*);
+
+                if arm == 0 {
                     $else_block
+                } else {
+                    $crate::__receive_dispatch!(msg, arm; $($ty { $($pat => $expr),+ })*)
                 }
             },
             Err(_) => {
@@ -135,41 +151,14 @@ macro_rules! receive {
             }
         )*
     } => {{
+        let mut arm = 0;
         let msg = $crate::global::recv_matching(None, |msg| {
-            $(
-                if let Some(msg) = msg.downcast_ref::<$ty>() {
-                    match msg {
-                        $(
-                            #[allow(unused_variables)]
-                            $pat $(if $guard)? => return true,
-                        )+
-                        #[allow(unreachable_patterns)]
-                        _ => (),
-                    }
-                }
-
-            )*
-
-            false
+            arm = $crate::__receive_select!(msg; $($ty { $($pat $(if $guard)?),+ })*);
+            arm != 0
         }).await;
 
         match msg {
-            Ok(msg) => {
-                if false { unreachable!() }
-                $(
-                    else if msg.is::<$ty>() {
-                        let msg = msg.downcast::<$ty>().unwrap();
-                        match *msg {
-                            $(
-                                $pat $(if $guard)? => $expr,
-                            )+
-                            #[allow(unreachable_patterns)]
-                            _ => unreachable!(),
-                        }
-                    }
-                )*
-                else { unreachable!() }
-            },
+            Ok(msg) => $crate::__receive_dispatch!(msg, arm; $($ty { $($pat => $expr),+ })*),
             Err(_) => {
                 unreachable!()
             },
@@ -189,22 +178,13 @@ macro_rules! receive {
 
         match msg {
             Ok(msg) => {
-                if false { unreachable!() }
-                $(
-                    else if msg.is::<$ty>() {
-                        let msg = msg.downcast::<$ty>().unwrap();
-                        match *msg {
-                            $(
-                                $pat $(if $guard)? => $expr,
-                            )+
-                            #[allow(unreachable_patterns)]
-                            _ => unreachable!(),
-                        }
-                    }
-                )*
-                else {
+                let arm = $crate::__receive_select!(msg; $($ty { $($pat $(if $guard)?),+ })*);
+
+                if arm == 0 {
                     let $else = msg;
                     $else_block
+                } else {
+                    $crate::__receive_dispatch!(msg, arm; $($ty { $($pat => $expr),+ })*)
                 }
             },
             Err(_) => {
@@ -226,21 +206,12 @@ macro_rules! receive {
 
         match msg {
             Ok(msg) => {
-                if false { unreachable!() }
-                $(
-                    else if msg.is::<$ty>() {
-                        let msg = msg.downcast::<$ty>().unwrap();
-                        match *msg {
-                            $(
-                                $pat $(if $guard)? => $expr,
-                            )+
-                            #[allow(unreachable_patterns)]
-                            _ => unreachable!(),
-                        }
-                    }
-                )*
-                else {
+                let arm = $crate::__receive_select!(msg; $($ty { $($pat $(if $guard)?),+ })*);
+
+                if arm == 0 {
                     $else_block
+                } else {
+                    $crate::__receive_dispatch!(msg, arm; $($ty { $($pat => $expr),+ })*)
                 }
             },
             Err(_) => {
@@ -257,44 +228,271 @@ macro_rules! receive {
         )*
         after $timeout:expr => $timeout_block:expr $(,)?
     } => {{
+        let mut arm = 0;
         let msg = $crate::global::recv_matching(Some($timeout), |msg| {
-            $(
-                if let Some(msg) = msg.downcast_ref::<$ty>() {
-                    match msg {
-                        $(
-                            #[allow(unused_variables)]
-                            $pat $(if $guard)? => return true,
-                        )+
-                        #[allow(unreachable_patterns)]
-                        _ => (),
-                    }
-                }
-
-            )*
-
-            false
+            arm = $crate::__receive_select!(msg; $($ty { $($pat $(if $guard)?),+ })*);
+            arm != 0
         }).await;
 
         match msg {
-            Ok(msg) => {
-                if false { unreachable!() }
-                $(
-                    else if msg.is::<$ty>() {
-                        let msg = msg.downcast::<$ty>().unwrap();
-                        match *msg {
-                            $(
-                                $pat $(if $guard)? => $expr,
-                            )+
-                            #[allow(unreachable_patterns)]
-                            _ => unreachable!(),
-                        }
-                    }
-                )*
-                else { unreachable!() }
-            },
+            Ok(msg) => $crate::__receive_dispatch!(msg, arm; $($ty { $($pat => $expr),+ })*),
             Err(_) => {
                 $timeout_block
             },
         }
     }};
 }
+
+/// The arm of `receive!` that matches the message, counting from 1 across all types, or 0 if none does.
+///
+/// Arms are tried in order and each guard runs at most once, the bindings in guards are references.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __receive_select {
+    ($msg:ident; $($ty:ty { $($pat:pat $(if $guard:expr)?),+ })*) => {{
+        let mut index = 0usize;
+
+        if false {
+            0
+        }
+        $($(
+            else if {
+                index += 1;
+                match $msg.downcast_ref::<$ty>() {
+                    #[allow(unused_variables)]
+                    Some($pat) $(if $guard)? => true,
+                    _ => false,
+                }
+            } {
+                index
+            }
+        )+)*
+        else {
+            0
+        }
+    }};
+}
+
+/// Take the message and evaluate the arm picked by `__receive_select`, without running its guard again.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __receive_dispatch {
+    ($msg:ident, $arm:ident; $($ty:ty { $($pat:pat => $expr:expr),+ })*) => {{
+        let mut index = 0usize;
+
+        if false {
+            unreachable!()
+        }
+        $($(
+            else if {
+                index += 1;
+                index == $arm
+            } {
+                match *$msg.downcast::<$ty>().unwrap() {
+                    // Bindings that only the guard used.
+                    #[allow(unused_variables)]
+                    $pat => $expr,
+                    #[allow(unreachable_patterns)]
+                    _ => unreachable!(),
+                }
+            }
+        )+)*
+        else {
+            unreachable!()
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{Config, Exit, Priority, global};
+
+    #[test]
+    fn else_arm_catches_unexpected_messages() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let pid = global::sync::pid();
+
+                global::send(pid, String::from("junk")).await;
+                global::send(pid, 2i32).await;
+                global::send(pid, 1i32).await;
+
+                let mut handled = Vec::new();
+                for _ in 0..3 {
+                    let outcome = crate::receive! {
+                        match i32 {
+                            1 => "one",
+                        }
+                        else msg {
+                            if msg.is::<String>() { "string" } else { "other" }
+                        }
+                    };
+                    handled.push(outcome);
+                }
+
+                // Every message was taken out of the mailbox.
+                let leaked = global::flush_all();
+
+                completion.complete((handled, leaked));
+
+                Exit::Normal
+            }
+        });
+
+        let (handled, leaked) = result.unwrap();
+
+        assert_eq!(handled, vec!["string", "other", "one"]);
+        assert_eq!(leaked, 0);
+    }
+
+    #[test]
+    fn guard_compares_against_a_mutable_counter() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let pid = global::sync::pid();
+
+                for n in [3, 1, 2] {
+                    global::send(pid, n).await;
+                }
+
+                // Once the marker is received every number is in the mailbox.
+                global::send(pid, ()).await;
+                crate::receive! {
+                    match () {
+                        _ => (),
+                    }
+                }
+
+                let mut expected = 1;
+                let mut evaluations = 0;
+                let mut order = Vec::new();
+                while expected <= 3 {
+                    crate::receive! {
+                        match i32 {
+                            n if {
+                                evaluations += 1;
+                                *n == expected
+                            } => order.push(n),
+                        }
+                    }
+                    expected += 1;
+                }
+
+                completion.complete((order, evaluations));
+
+                Exit::Normal
+            }
+        });
+
+        let (order, evaluations) = result.unwrap();
+
+        assert_eq!(order, [1, 2, 3]);
+        // Once per message looked at: [3, 1], [3, 2] and [3].
+        assert_eq!(evaluations, 2 + 2 + 1);
+    }
+
+    #[test]
+    fn late_message_wakes_pending_receive() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let receiver = global::spawn(async move || {
+                    let (value, still_waiting, sent_at) = receive! {
+                        match (u32, bool, Instant) {
+                            message => message,
+                        }
+                    };
+
+                    completion.complete((value, still_waiting, sent_at, Instant::now()));
+
+                    Exit::Normal
+                })
+                .await;
+
+                // Make sure the receiver has gone pending, unrelated messages don't complete it.
+                global::sleep(Duration::from_millis(20)).await;
+                global::send(receiver, "not a number").await;
+                global::sleep(Duration::from_millis(5)).await;
+                let still_waiting = global::sync::pid_status(receiver) == crate::PidStatus::Alive;
+
+                global::send(receiver, (7u32, still_waiting, Instant::now())).await;
+
+                Exit::Normal
+            }
+        });
+
+        let (value, still_waiting, sent_at, received_at) = result.unwrap();
+
+        assert!(still_waiting);
+        assert_eq!(value, 7);
+        assert!(
+            received_at - sent_at < Duration::from_millis(100),
+            "{:?}",
+            received_at - sent_at
+        );
+    }
+
+    #[test]
+    fn received_message_cancels_the_timeout() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::send(global::sync::pid(), ()).await;
+
+                receive! {
+                    match () {
+                        () => (),
+                    }
+                    after Duration::from_secs(60) => (),
+                }
+
+                let system = unsafe { crate::thread::borrow() };
+                completion.complete(system.timer.pending());
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(0));
+    }
+
+    #[test]
+    fn priority_lanes_drain_in_order() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let me = global::sync::pid();
+                let lanes = [Priority::Low, Priority::Normal, Priority::High];
+
+                for n in 0..3u32 {
+                    for priority in lanes {
+                        global::send_with_priority(me, (priority, n), priority).await;
+                    }
+                }
+
+                // Let every signal reach the mailbox before receiving.
+                global::sleep(Duration::from_millis(10)).await;
+
+                let mut received = Vec::new();
+                for _ in 0..9 {
+                    received.push(receive! {
+                        match (Priority, u32) {
+                            message => message,
+                        }
+                    });
+                }
+
+                completion.complete(received);
+
+                Exit::Normal
+            }
+        });
+
+        let received = result.unwrap();
+
+        let expected: Vec<_> = [Priority::High, Priority::Normal, Priority::Low]
+            .into_iter()
+            .flat_map(|priority| (0..3).map(move |n| (priority, n)))
+            .collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,168 @@
+//! Combinators for awaiting several futures within one actor.
+//!
+//! These don't spawn anything, the futures are polled by the actor that awaits them.
+//! Receiving from the mailbox is a future as well, so it can be raced against other futures.
+
+use std::{pin::pin, task::Poll};
+
+/// The output of [`race`], telling which of the two futures completed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Wait for the first of two futures to complete, the other future is dropped.
+///
+/// The left future is polled first, so it wins if both are ready.
+pub async fn race<A, B>(a: A, b: B) -> Either<A::Output, B::Output>
+where
+    A: Future,
+    B: Future,
+{
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+
+        if let Poll::Ready(output) = b.as_mut().poll(cx) {
+            return Poll::Ready(Either::Right(output));
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Wait for both futures to complete.
+pub async fn join<A, B>(a: A, b: B) -> (A::Output, B::Output)
+where
+    A: Future,
+    B: Future,
+{
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut a_output = None;
+    let mut b_output = None;
+
+    std::future::poll_fn(|cx| {
+        if a_output.is_none()
+            && let Poll::Ready(output) = a.as_mut().poll(cx)
+        {
+            a_output = Some(output);
+        }
+
+        if b_output.is_none()
+            && let Poll::Ready(output) = b.as_mut().poll(cx)
+        {
+            b_output = Some(output);
+        }
+
+        if a_output.is_some() && b_output.is_some() {
+            Poll::Ready((a_output.take().unwrap(), b_output.take().unwrap()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Wait for the first of several futures to complete and run the matching arm.
+///
+/// Arms are polled in order, so earlier arms win if several futures are ready.
+/// The other futures are dropped. Patterns must be irrefutable.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use kerosene::global::{recv_any, sleep};
+/// async fn test() {
+///     kerosene::select! {
+///         message = recv_any(None) => println!("Received {:?}", message.is_ok()),
+///         _ = sleep(Duration::from_secs(1)) => println!("Timeout"),
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! select {
+    ($($pat:pat = $future:expr => $body:expr),+ $(,)?) => {{
+        let output = $crate::__select_race!($($future),+).await;
+        $crate::__select_match!(output; $($pat => $body),+)
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __select_race {
+    ($future:expr) => {
+        async { $future.await }
+    };
+    ($future:expr, $($rest:expr),+) => {
+        $crate::global::race($future, $crate::__select_race!($($rest),+))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __select_match {
+    ($output:ident; $pat:pat => $body:expr) => {
+        match $output {
+            $pat => $body,
+        }
+    };
+    ($output:ident; $pat:pat => $body:expr, $($rest_pat:pat => $rest_body:expr),+) => {
+        match $output {
+            $crate::global::Either::Left($pat) => $body,
+            $crate::global::Either::Right(output) => {
+                $crate::__select_match!(output; $($rest_pat => $rest_body),+)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{Exit, global};
+
+    use super::{Either, join, race};
+
+    #[test]
+    fn sleep_races_message_arrival() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let pid = global::sync::pid();
+
+                // Nothing arrives, the sleep wins.
+                let timeout = race(
+                    global::sleep(Duration::from_millis(5)),
+                    global::recv_any(None),
+                )
+                .await;
+
+                global::schedule(pid, 42i32, Duration::from_millis(5)).await;
+
+                // The message arrives well before the sleep completes.
+                let message = crate::select! {
+                    _ = global::sleep(Duration::from_secs(10)) => None,
+                    message = global::recv_any(None) => message.ok().and_then(|m| m.downcast::<i32>().ok()),
+                };
+
+                let both = join(global::sleep(Duration::from_millis(1)), async { 7 }).await;
+
+                completion.complete((matches!(timeout, Either::Left(())), message, both));
+
+                Exit::Normal
+            }
+        });
+
+        let (timed_out, message, both) = result.unwrap();
+
+        assert!(timed_out);
+        assert_eq!(message.as_deref(), Some(&42));
+        assert_eq!(both, ((), 7));
+    }
+}
@@ -7,68 +7,219 @@
 use std::time::Duration;
 
 use crate::{
-    Exit, IntoAsyncActor, Pid,
+    Exit, IntoAsyncActor, Pid, PidStatus, Priority,
     actor::{MAX_META_KV, Signal, ToPid},
-    metadata::MetaKeyValue,
+    global::{Delivery, SendError, SpawnOptions, SpawnResult, TimerId},
+    metadata::{MetaKeyValue, Propagated},
     utils::UnsortedSet,
 };
 
 /// Sends a signal to an actor.
 ///
 /// If the actor is not found, the signal is dropped.
-pub fn send_signal(to: impl ToPid, message: Signal) {
+pub(crate) fn send_signal(to: impl ToPid, message: Signal) {
+    let _ = try_send_signal(to, message);
+}
+
+/// Sends a signal to an actor, reporting if it doesn't exist.
+pub(crate) fn try_send_signal(to: impl ToPid, message: Signal) -> Result<(), SendError> {
     let system = unsafe { crate::thread::borrow() };
 
     let pid = to.to_reference(&system.registry);
 
-    if let Some(actor) = system.registry.lookup_pid(pid) {
-        actor.send_signal(message);
-        system.schedule(pid);
+    if system.deliver(pid, message) {
+        Ok(())
+    } else {
+        Err(SendError::NoProcess)
     }
 }
 
 /// Schedule a message to be delivered to an actor after a given delay.
 ///
 /// If the actor is not found, the signal is dropped.
-pub fn schedule<T>(to: impl ToPid, message: T, delay: Duration)
+/// The returned id can be used to cancel the timer, see [`cancel_timer`].
+pub fn schedule<T>(to: impl ToPid, message: T, delay: Duration) -> TimerId
 where
     T: Send + 'static,
 {
     let system = unsafe { crate::thread::borrow() };
 
     let to = to.to_reference(&system.registry);
-    system.timer.add(to, delay, message);
+    system.timer.add(to, pid(), delay, message)
+}
+
+/// Send a message to an actor every period, until the timer is canceled or the actor is gone.
+///
+/// The first message is sent after one period.
+/// The returned id can be used to cancel the timer, see [`cancel_timer`].
+///
+/// # Panics
+///
+/// Panics if the period is zero.
+pub fn schedule_interval<T>(to: impl ToPid, message: T, period: Duration) -> TimerId
+where
+    T: Clone + Send + 'static,
+{
+    let system = unsafe { crate::thread::borrow() };
+
+    let to = to.to_reference(&system.registry);
+    system.timer.add_interval(to, pid(), period, message)
+}
+
+/// Cancel a scheduled message.
+///
+/// Returns `false` if the message was already delivered or the timer was canceled before.
+pub fn cancel_timer(id: TimerId) -> bool {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.timer.cancel(id)
 }
 
 /// Send a message to an actor.
 ///
 /// If the actor is not found, the message is dropped.
-/// an actor can either be a `Pid` or a `NamedRef`.
+/// An actor can either be a `Pid`, a `NamedRef` or a name (`&str` or `String`).
 pub fn send<M>(to: impl ToPid, message: M)
 where
     M: Send + 'static,
 {
-    let message = Signal::Message(Box::new(message));
+    send_with_priority(to, message, Priority::Normal);
+}
+
+/// Send a message into a lane of the actor's mailbox, see [`Priority`].
+///
+/// If the actor is not found, the message is dropped.
+pub fn send_with_priority<M>(to: impl ToPid, message: M, priority: Priority)
+where
+    M: Send + 'static,
+{
+    let message = Signal::Message(pid(), Box::new(message), propagated_metadata(), priority);
     send_signal(to, message);
 }
 
+/// Send a message on behalf of another actor, e.g. from the helper thread of a port.
+///
+/// If the actor is not found, the message is dropped.
+pub(crate) fn send_from<M>(from: Pid, to: impl ToPid, message: M)
+where
+    M: Send + 'static,
+{
+    send_signal(
+        to,
+        Signal::Message(from, Box::new(message), None, Priority::Normal),
+    );
+}
+
+/// Send a copy of a message to each of the actors.
+///
+/// The sender and its propagated metadata are looked up once for all of them,
+/// so this is cheaper than calling `send` in a loop.
+/// Messages to actors that are not found are dropped.
+pub fn send_many<M>(to: &[Pid], message: M) -> Delivery
+where
+    M: Clone + Send + 'static,
+{
+    let system = unsafe { crate::thread::borrow() };
+
+    let from = pid();
+    let metadata = propagated_metadata();
+
+    let mut delivery = Delivery::default();
+    for &pid in to {
+        let signal = Signal::Message(
+            from,
+            Box::new(message.clone()),
+            metadata.clone(),
+            Priority::Normal,
+        );
+
+        if system.deliver(pid, signal) {
+            delivery.delivered += 1;
+        } else {
+            delivery.dropped += 1;
+        }
+    }
+
+    delivery
+}
+
+/// Send a message to an actor, reporting if it doesn't exist.
+pub fn try_send<M>(to: impl ToPid, message: M) -> Result<(), SendError>
+where
+    M: Send + 'static,
+{
+    try_send_signal(
+        to,
+        Signal::Message(
+            pid(),
+            Box::new(message),
+            propagated_metadata(),
+            Priority::Normal,
+        ),
+    )
+}
+
+/// The metadata of the current actor that goes along with the messages it sends.
+fn propagated_metadata() -> Propagated {
+    let system = unsafe { crate::thread::borrow() };
+    if system.propagate_metadata.is_empty() || !super::has_context() {
+        return None;
+    }
+
+    let mut propagated = UnsortedSet::new();
+    for meta in metadata().into_iter() {
+        if system.propagate_metadata.contains(&meta.key) {
+            propagated.insert(meta);
+        }
+    }
+
+    (!propagated.is_empty()).then(|| Box::new(propagated))
+}
+
+/// Asks the root actor to stop the system, see [`stop`].
+pub(crate) struct StopSystem;
+
 /// Stops the system
+///
+/// The root supervision tree is shut down first, children in reverse start order.
+/// The logger was started first, so logs emitted while shutting down are still printed.
+///
+/// This only asks the root actor to stop, it returns before anything has stopped.
+/// Shutting down can take a while, each child that doesn't exit gets up to five seconds before it's killed.
 pub fn stop() {
     let system = unsafe { crate::thread::borrow() };
 
+    if let Some(&root) = system.root.get()
+        && try_send(root, StopSystem).is_ok()
+    {
+        return;
+    }
+
     system.stop_all();
 }
 
 /// Gets all the metadata for the current actor.
 ///
+/// This includes the metadata that came with the message received last, see `Config::propagate_metadata`.
 /// If ran from an unmanaged thread without a valid context,
 /// an empty `UnsortedSet` will be returned.
 pub fn metadata() -> UnsortedSet<MetaKeyValue, MAX_META_KV> {
-    if super::has_context() {
-        super::context().actor.metadata().clone()
-    } else {
-        UnsortedSet::new()
+    if !super::has_context() {
+        return UnsortedSet::new();
     }
+
+    super::with_context(|context| {
+        let mut metadata = context.actor.metadata().clone();
+
+        // Metadata from the message takes precedence while it is being handled.
+        if let Some(propagated) = context.actor.queue().metadata() {
+            for meta in propagated.iter() {
+                metadata.upsert(meta.clone());
+            }
+        }
+
+        metadata
+    })
 }
 
 /// Returns the current actors' PID
@@ -77,12 +228,43 @@ pub fn metadata() -> UnsortedSet<MetaKeyValue, MAX_META_KV> {
 /// `Pid::invalid()` will be returned.
 pub fn pid() -> Pid {
     if super::has_context() {
-        super::context().pid()
+        super::with_context(|context| context.pid())
     } else {
         Pid::invalid()
     }
 }
 
+/// Returns the worker the current actor is assigned to.
+///
+/// Actors can be moved to other workers, the answer is only valid until the actor suspends.
+/// If ran from an unmanaged thread without a valid context, `None` will be returned.
+pub fn worker_id() -> Option<usize> {
+    use std::sync::atomic::Ordering;
+
+    if !super::has_context() {
+        return None;
+    }
+
+    super::with_context(|context| {
+        Some(
+            context
+                .actor
+                .control_block()
+                .worker_id
+                .load(Ordering::Acquire) as _,
+        )
+    })
+}
+
+/// Whether a pid belongs to a live actor, a dead one, or was never handed out.
+///
+/// This is meant for debugging, sending to a pid doesn't need it.
+pub fn pid_status(pid: Pid) -> PidStatus {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.registry.pid_status(pid)
+}
+
 /// Sends an exit signal to the chosen actor.
 pub fn exit(to: impl ToPid, reason: Exit) {
     let system = unsafe { crate::thread::borrow() };
@@ -91,48 +273,514 @@ pub fn exit(to: impl ToPid, reason: Exit) {
     send_signal(to, Signal::Exit(to, reason));
 }
 
+/// Kills the chosen actor.
+///
+/// Unlike an exit signal this can't be trapped, the actor exits with `Exit::Killed`.
+pub fn kill(to: impl ToPid) {
+    send_signal(to, Signal::Kill);
+}
+
 /// Spawns a new actor.
 ///
 /// The spawned actor will not be linked to the current actor.
 /// The Pid of the spawned actor is returned.
 pub fn spawn<B>(behavior: B) -> Pid
+where
+    B: IntoAsyncActor,
+{
+    spawn_opts(behavior, SpawnOptions::default()).pid
+}
+
+/// Spawns a new actor with the given options, see [`SpawnOptions`].
+///
+/// The Pid of the spawned actor is returned along with its monitor.
+pub fn spawn_opts<B>(behavior: B, options: SpawnOptions) -> SpawnResult
 where
     B: IntoAsyncActor,
 {
     use crate::actor::{ActorControlBlock, HydratedActor};
-    use std::sync::{Mutex, atomic::Ordering};
+    use std::sync::Mutex;
 
-    let metadata = metadata();
     let system = unsafe { crate::thread::borrow() };
 
-    let pid = system.registry.allocate_pid();
+    let spawn_at = options
+        .worker
+        .filter(|&worker| system.scheduler.get_worker(worker).is_some())
+        .or_else(worker_id)
+        .unwrap_or_else(|| system.spawn_target());
 
-    let spawn_at = if super::has_context() {
-        let context = super::context();
-        context
-            .actor
-            .control_block()
-            .worker_id
-            .load(Ordering::Acquire) as _
+    // Propagated metadata is scoped to handling the message, children only inherit the actor's own.
+    let mut metadata = if super::has_context() {
+        super::with_context(|context| context.actor.metadata().clone())
     } else {
-        // TODO: Better algorithm than just blindly pick worker 0.
-        0
+        UnsortedSet::new()
     };
+    for meta in options.metadata {
+        metadata.upsert(meta);
+    }
+
+    let pid = system.registry.allocate_pid();
 
     let mut control_block = ActorControlBlock::new(pid, spawn_at);
     control_block.metadata = Mutex::new(metadata);
 
-    let actor = HydratedActor::new(control_block, behavior);
+    // Outside an actor there is nothing to link to.
+    if options.link && super::has_context() {
+        let parent = super::with_context(|context| {
+            let _ = context.actor.control_block().add_link(pid);
+            context.pid()
+        });
+        let _ = control_block.add_link(parent);
+    }
+
+    // Like links, there is nothing to monitor from outside an actor.
+    let monitor_ref = (options.monitor && super::has_context()).then(|| {
+        let monitor_ref = system.monitor_ref();
+        control_block.add_monitor(super::with_context(|context| context.pid()), monitor_ref);
+        monitor_ref
+    });
+
+    let actor = HydratedActor::with_mailbox_hint(control_block, behavior, options.mailbox_hint);
 
     system.registry.add(actor);
     system.schedule(pid);
 
-    pid
+    SpawnResult { pid, monitor_ref }
+}
+
+/// Spawns a new actor on the given worker.
+///
+/// If there is no such worker, the worker is picked like `spawn` does.
+/// The spawned actor will not be linked to the current actor.
+/// The Pid of the spawned actor is returned.
+pub fn spawn_on<B>(worker: usize, behavior: B) -> Pid
+where
+    B: IntoAsyncActor,
+{
+    spawn_opts(
+        behavior,
+        SpawnOptions {
+            worker: Some(worker),
+            ..SpawnOptions::default()
+        },
+    )
+    .pid
 }
 
 /// Register a name for an actor
-pub fn register(name: &'static str, actor: Pid) {
+///
+/// The name can be built at runtime, registering a name again replaces the actor it refers to.
+pub fn register(name: impl AsRef<str>, actor: Pid) {
     let system = unsafe { crate::thread::borrow() };
 
-    system.registry.register(name, actor);
+    system.registry.register(name.as_ref(), actor);
+}
+
+/// Register a name for an actor, unless another live actor already has it.
+///
+/// Returns the actor that owns the name on conflict, use this for actors that must only run once.
+pub fn try_register(name: impl AsRef<str>, actor: Pid) -> Result<(), Pid> {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.registry.try_register(name.as_ref(), actor)
+}
+
+/// Remove a registered name, the actor itself keeps running.
+pub fn unregister(name: impl AsRef<str>) {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.registry.unregister(name.as_ref());
+}
+
+/// The actor registered under a name.
+pub fn whereis(name: impl AsRef<str>) -> Option<Pid> {
+    let system = unsafe { crate::thread::borrow() };
+
+    system.registry.lookup_name(name.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use crate::{
+        Config, Exit,
+        global::{self, SpawnOptions},
+        metadata::{MetaKeyValue, MetaValue},
+        receive,
+    };
+
+    #[test]
+    fn spawn_on_places_the_actor_on_the_chosen_worker() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let system = unsafe { crate::thread::borrow() };
+                let waiting = async || {
+                    receive! {
+                        match () {
+                            () => Exit::Normal,
+                        }
+                    }
+                };
+
+                // Stealing moves an actor to the stealing worker, an idle worker can steal from this one.
+                // So each placement is read right after its spawn, before the other worker gets to run.
+                let placed_at = |pid| {
+                    system.registry.lookup_pid(pid).map(|actor| {
+                        actor.control_block().worker_id.load(Ordering::Acquire) as usize
+                    })
+                };
+
+                let own = global::sync::worker_id().unwrap();
+
+                // Spawned first, scheduling it only wakes this worker, the chosen actor wakes the other one.
+                let invalid = global::sync::spawn_on(7, waiting);
+                let invalid_at = placed_at(invalid);
+
+                let chosen = global::sync::spawn_on(1 - own, waiting);
+                let chosen_at = placed_at(chosen);
+
+                let placed = (own, chosen_at, invalid_at);
+
+                global::sync::send(chosen, ());
+                global::sync::send(invalid, ());
+
+                completion.complete(placed);
+
+                Exit::Normal
+            }
+        });
+
+        let (own, chosen, invalid) = result.unwrap();
+
+        assert_eq!(chosen, Some(1 - own));
+        // An invalid worker falls back to the worker of the spawning actor.
+        assert_eq!(invalid, Some(own));
+    }
+
+    #[test]
+    fn children_dont_inherit_propagated_metadata() {
+        struct Request;
+
+        let config = Config {
+            propagate_metadata: vec!["trace_id"],
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let root = global::sync::pid();
+
+                let handler = global::spawn(async move || {
+                    global::insert_metadata("own", "handler");
+
+                    receive! {
+                        match Request {
+                            Request => {}
+                        }
+                    }
+
+                    // Both children are spawned while the request with the trace id is handled.
+                    let report = async move || {
+                        let metadata = global::sync::metadata();
+                        let has = |key| metadata.iter().any(|meta: &MetaKeyValue| meta.key == key);
+                        global::send(root, (has("trace_id"), has("own"))).await;
+
+                        Exit::Normal
+                    };
+                    global::spawn(report).await;
+                    global::spawn_linked(report);
+
+                    Exit::Normal
+                })
+                .await;
+
+                global::spawn(async move || {
+                    global::insert_metadata("trace_id", "abc");
+                    global::send(handler, Request).await;
+
+                    Exit::Normal
+                })
+                .await;
+
+                let mut reports = Vec::new();
+                for _ in 0..2 {
+                    receive! {
+                        match (bool, bool) {
+                            report => reports.push(report),
+                        }
+                    }
+                }
+
+                completion.complete(reports);
+
+                Exit::Normal
+            }
+        });
+
+        let reports = result.unwrap();
+
+        // Neither child has the trace id, both have the metadata of the handler itself.
+        assert_eq!(reports, [(false, true), (false, true)]);
+    }
+
+    #[test]
+    fn spawn_opts_from_an_unmanaged_thread_ignores_link_and_monitor() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = global::sync::pid();
+
+                let spawned = crate::thread::spawn(move || {
+                    let options = SpawnOptions {
+                        link: true,
+                        monitor: true,
+                        ..SpawnOptions::default()
+                    };
+
+                    global::sync::spawn_opts(
+                        async move || {
+                            global::send(root, global::sync::pid()).await;
+                            Exit::Normal
+                        },
+                        options,
+                    )
+                })
+                .join();
+
+                let ran = receive! {
+                    match crate::Pid {
+                        pid => pid,
+                    }
+                };
+
+                let spawned = spawned.unwrap();
+                completion.complete((spawned.pid == ran, spawned.monitor_ref));
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some((true, None)));
+    }
+
+    #[test]
+    fn spawn_opts_combines_options() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let root = global::sync::pid();
+                global::trap_exit(true);
+                global::insert_metadata("inherited", "yes");
+
+                let own = global::sync::worker_id().unwrap();
+                let options = SpawnOptions {
+                    link: true,
+                    monitor: true,
+                    worker: Some(1 - own),
+                    metadata: vec![MetaKeyValue {
+                        key: "tenant",
+                        value: "a".into(),
+                    }],
+                    ..SpawnOptions::default()
+                };
+
+                let spawned = global::sync::spawn_opts(
+                    async move || {
+                        receive! {
+                            match () {
+                                () => (),
+                            }
+                        }
+
+                        let metadata = global::sync::metadata();
+                        let find = |key| {
+                            metadata
+                                .iter()
+                                .find(|meta| meta.key == key)
+                                .map(|meta| meta.value.clone())
+                        };
+
+                        global::send(root, (find("tenant"), find("inherited"))).await;
+
+                        Exit::Shutdown
+                    },
+                    options,
+                );
+                let child = spawned.pid;
+
+                // Checked before the child runs, once it does the idle worker can steal it.
+                let system = unsafe { crate::thread::borrow() };
+                let worker = system
+                    .registry
+                    .lookup_pid(child)
+                    .map(|actor| actor.control_block().worker_id.load(Ordering::Acquire) as usize);
+                global::send(child, ()).await;
+
+                let (tenant, inherited) = receive! {
+                    match (Option<MetaValue>, Option<MetaValue>) {
+                        reply => reply,
+                    }
+                };
+
+                // The link takes the exit of the child back to us.
+                let exit = receive! {
+                    match crate::TrapExitMessage {
+                        crate::TrapExitMessage { pid, reason } => (pid == child, reason),
+                    }
+                    after Duration::from_secs(1) => (false, Exit::Normal),
+                };
+
+                // The monitor reports it as well.
+                let down = receive! {
+                    match crate::DownMessage {
+                        crate::DownMessage { monitor_ref, pid, reason } => {
+                            (Some(monitor_ref) == spawned.monitor_ref, pid == child, reason)
+                        }
+                    }
+                    after Duration::from_secs(1) => (false, false, Exit::Normal),
+                };
+
+                completion.complete((own, worker, tenant, inherited, exit, down));
+
+                Exit::Normal
+            }
+        });
+
+        let (own, worker, tenant, inherited, exit, down) = result.unwrap();
+
+        assert_eq!(worker, Some(1 - own));
+        assert_eq!(tenant, Some(MetaValue::StaticStr("a")));
+        assert_eq!(inherited, Some(MetaValue::StaticStr("yes")));
+        assert_eq!(exit, (true, Exit::Shutdown));
+        assert_eq!(down, (true, true, Exit::Shutdown));
+    }
+
+    #[test]
+    fn monitor_reports_the_exit_without_taking_the_actor_down() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let options = SpawnOptions {
+                    monitor: true,
+                    ..SpawnOptions::default()
+                };
+                let spawned = global::spawn_opts(async || Exit::Killed, options).await;
+
+                // The exit isn't trapped, but a monitor only sends a message.
+                let down = receive! {
+                    match crate::DownMessage {
+                        crate::DownMessage { monitor_ref, pid, reason } => {
+                            (Some(monitor_ref) == spawned.monitor_ref, pid == spawned.pid, reason)
+                        }
+                    }
+                };
+
+                completion.complete(down);
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some((true, true, Exit::Killed)));
+    }
+
+    #[test]
+    fn send_many_fans_out() {
+        #[derive(Clone)]
+        struct Ping(u32);
+
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let parent = global::sync::pid();
+
+                let mut pids = Vec::new();
+                for _ in 0..1000 {
+                    let pid = global::spawn(async move || {
+                        receive! {
+                            match Ping {
+                                Ping(n) => global::sync::send(parent, n),
+                            }
+                        }
+
+                        Exit::Normal
+                    })
+                    .await;
+                    pids.push(pid);
+                }
+
+                // Pids that were never handed out are dropped.
+                pids.extend((0..10).map(|i| crate::Pid(u64::MAX - i)));
+
+                let delivery = global::send_many(&pids, Ping(7)).await;
+
+                let mut sum = 0;
+                for _ in 0..delivery.delivered {
+                    receive! {
+                        match u32 {
+                            n => sum += n,
+                        }
+                    }
+                }
+
+                completion.complete((delivery, sum));
+
+                Exit::Normal
+            }
+        });
+
+        let (delivery, sum) = result.unwrap();
+
+        assert_eq!(
+            delivery,
+            global::Delivery {
+                delivered: 1000,
+                dropped: 10
+            }
+        );
+        assert_eq!(sum, 7000);
+    }
+
+    #[test]
+    fn try_send_reports_missing_actors() {
+        let result = crate::run_single(|completion| {
+            async move || {
+                let never = global::try_send(crate::Pid::invalid(), ()).await;
+                let alive = global::try_send(global::sync::pid(), ()).await;
+
+                let pid = global::spawn(async || {
+                    global::sleep(Duration::from_secs(10)).await;
+                    Exit::Normal
+                })
+                .await;
+                global::exit(pid, Exit::Killed).await;
+
+                while global::sync::pid_status(pid) != crate::PidStatus::Dead {
+                    global::sleep(Duration::from_millis(1)).await;
+                }
+
+                let killed = global::try_send(pid, ()).await;
+
+                completion.complete((never, alive, killed));
+
+                Exit::Normal
+            }
+        });
+
+        let (never, alive, killed) = result.unwrap();
+
+        assert_eq!(never, Err(global::SendError::NoProcess));
+        assert_eq!(alive, Ok(()));
+        assert_eq!(killed, Err(global::SendError::NoProcess));
+    }
 }
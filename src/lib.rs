@@ -1,4 +1,4 @@
-use std::{num::NonZero, sync::Arc, thread::JoinHandle, time::Duration};
+use std::{sync::mpsc, time::Duration};
 
 use crate::{
     actor::{ActorControlBlock, HydratedActor},
@@ -7,11 +7,12 @@ use crate::{
         supervisor::{RestartPolicy, Strategy, Supervisor},
     },
     system::System,
-    worker::{ActiveWorker, Worker},
 };
 
 mod actor;
 mod async_actor;
+mod clock;
+mod config;
 pub mod global;
 pub mod library;
 mod metadata;
@@ -24,19 +25,20 @@ mod timer;
 mod utils;
 mod worker;
 
-pub use actor::{Exit, Pid, TrapExitMessage};
+pub use actor::{DownMessage, Exit, MonitorRef, NamedRef, Pid, Priority, TrapExitMessage};
 pub use async_actor::IntoAsyncActor;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use config::{Config, MailboxOverflowPolicy, SpawnPlacement, StealStrategy};
+pub use registry::PidStatus;
 
-fn main_actor<A>(actor: A) -> impl IntoAsyncActor
+fn main_actor<A>(actor: A, observer_interval: Option<Duration>) -> impl IntoAsyncActor
 where
     A: IntoAsyncActor,
 {
     async move || {
         let mut actor = Some(actor);
         let supervisor = Supervisor::spawn_linked(Strategy::OneForOne);
-
-        supervisor.supervise(RestartPolicy::Permanent, || logger_actor);
-        supervisor.supervise(RestartPolicy::Permanent, || library::blocking::router);
+        supervise_services(&supervisor, observer_interval);
 
         global::schedule(global::sync::pid(), (), Duration::from_millis(10)).await;
 
@@ -51,60 +53,69 @@ where
                         }
                     }
                 }
+                match global::sync::StopSystem {
+                    _ => {
+                        supervisor.shutdown().await;
+
+                        let system = unsafe { crate::thread::borrow() };
+                        system.stop_all();
+                    }
+                }
             }
         }
     }
 }
 
-fn start_worker(system: Arc<System>) -> JoinHandle<()> {
-    let id = system.scheduler.allocate_slot();
-
-    let worker = Arc::new(Worker::new(id));
+/// Start the named services of the system under the supervisor.
+///
+/// A service exits normally when its name is taken already, they are transient so that isn't restarted.
+fn supervise_services(supervisor: &Supervisor, observer_interval: Option<Duration>) {
+    supervisor.supervise(RestartPolicy::Transient, || logger_actor);
+    supervisor.supervise(RestartPolicy::Transient, || library::blocking::router);
 
-    let handle = {
-        let worker = worker.clone();
-
-        crate::thread::spawn(move || {
-            worker.run();
-        })
-    };
-
-    let _ = system.scheduler.replace_slot(
-        id,
-        ActiveWorker {
-            thread: handle.thread().clone(),
-            worker,
-        },
-    );
-
-    handle
+    if let Some(interval) = observer_interval {
+        supervisor.supervise(RestartPolicy::Transient, move || {
+            library::observer::observer(interval)
+        });
+    }
 }
 
+/// Run the system with the default configuration.
+///
+/// See [`run_with`].
 pub fn run<A>(entry_point: A)
 where
     A: IntoAsyncActor,
 {
-    let system = System::new();
-    crate::thread::give(system.clone());
+    run_with(Config::default(), entry_point);
+}
 
-    let handles = {
-        let cores = std::thread::available_parallelism()
-            .map(NonZero::get)
-            .unwrap_or(1);
+/// Run the system with the given configuration.
+///
+/// This blocks until the system is stopped.
+pub fn run_with<A>(config: Config, entry_point: A)
+where
+    A: IntoAsyncActor,
+{
+    let system = System::new(&config);
+    crate::thread::give(system.clone());
 
-        (0..cores)
-            .map(|_| start_worker(system.clone()))
-            .collect::<Vec<_>>()
-    };
+    for _ in 0..config.workers.clamp(1, 128) {
+        system.start_worker();
+    }
 
     {
         let pid = system.registry.allocate_pid();
 
         let control_block = ActorControlBlock::new(pid, 0);
 
-        let actor = HydratedActor::new(control_block, main_actor(entry_point));
+        let actor = HydratedActor::new(
+            control_block,
+            main_actor(entry_point, config.observer_interval),
+        );
 
         system.registry.add(actor);
+        let _ = system.root.set(pid);
 
         system.schedule(pid);
     }
@@ -116,14 +127,73 @@ where
         })
     };
 
-    for handle in handles {
-        handle.join().unwrap();
-    }
+    system.join_workers();
     timer_handle.join().unwrap();
 
     drop(unsafe { crate::thread::get() });
 }
 
+/// Hands the result of [`run_until`] back to the caller.
+pub struct Completion<T>(mpsc::Sender<T>);
+
+impl<T> Completion<T> {
+    /// Set the result and stop the system, [`run_until`] returns it once the system stopped.
+    pub fn complete(self, value: T) {
+        let _ = self.0.send(value);
+        global::sync::stop();
+    }
+}
+
+/// Run the system with the given configuration until the entry point completes.
+///
+/// The entry point is given a [`Completion`], completing it stops the system and the value is returned.
+/// Returns `None` if the system was stopped without completing.
+///
+/// ```no_run
+/// use kerosene::{Config, Exit};
+///
+/// let answer = kerosene::run_until(Config::default(), |completion| {
+///     async move || {
+///         completion.complete(42);
+///         Exit::Normal
+///     }
+/// });
+///
+/// assert_eq!(answer, Some(42));
+/// ```
+pub fn run_until<T, F, A>(config: Config, entry_point: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(Completion<T>) -> A + Send + 'static,
+    A: IntoAsyncActor,
+{
+    let (sender, receiver) = mpsc::channel();
+    let completion = Completion(sender);
+
+    run_with(config, async move || {
+        entry_point(completion).into_async_actor().await
+    });
+
+    receiver.try_recv().ok()
+}
+
+/// Like [`run_until`] on a single worker, so the actors of a test take turns in a predictable order.
+#[cfg(test)]
+pub(crate) fn run_single<T, F, A>(entry_point: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(Completion<T>) -> A + Send + 'static,
+    A: IntoAsyncActor,
+{
+    run_until(
+        Config {
+            workers: 1,
+            ..Config::default()
+        },
+        entry_point,
+    )
+}
+
 #[macro_export]
 macro_rules! main {
     ($actor:expr) => {
@@ -132,3 +202,153 @@ macro_rules! main {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        Config, Exit, TrapExitMessage, global,
+        library::supervisor::{Strategy, Supervisor},
+    };
+
+    #[test]
+    fn run_until_returns_the_result() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let sum = crate::run_until(config, |completion| {
+            async move || {
+                let parent = global::sync::pid();
+
+                for n in 1..=10u64 {
+                    global::spawn(async move || {
+                        global::send(parent, n * n).await;
+                        Exit::Normal
+                    })
+                    .await;
+                }
+
+                let mut sum = 0;
+                for _ in 0..10 {
+                    crate::receive! {
+                        match u64 {
+                            n => sum += n,
+                        }
+                    }
+                }
+
+                completion.complete(sum);
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(sum, Some(385));
+
+        // Stopping without completing has no result.
+        let nothing = crate::run_until(Config::default(), |_completion: crate::Completion<u64>| {
+            async move || {
+                global::sync::stop();
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(nothing, None);
+    }
+
+    #[test]
+    fn stop_shuts_the_logger_down_last() {
+        let config = Config {
+            observer_interval: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let stopped = stopped.clone();
+
+            crate::run_with(config, async move || {
+                let parent = global::sync::pid();
+                let names = ["logger", "blocking_pool", "observer"];
+                let pids = names.map(|name| global::sync::whereis(name).unwrap());
+
+                global::spawn(async move || {
+                    global::trap_exit(true);
+                    for pid in pids {
+                        global::link(pid).await.unwrap();
+                    }
+                    global::send(parent, ()).await;
+
+                    loop {
+                        crate::receive! {
+                            match TrapExitMessage {
+                                TrapExitMessage { pid, .. } => {
+                                    let name = names[pids.iter().position(|p| *p == pid).unwrap()];
+                                    stopped.lock().unwrap().push(name);
+                                }
+                            }
+                        }
+                    }
+                })
+                .await;
+
+                crate::receive! {
+                    match () {
+                        _ => global::sync::stop(),
+                    }
+                }
+
+                Exit::Normal
+            });
+        }
+
+        let stopped = stopped.lock().unwrap();
+
+        assert_eq!(*stopped, ["observer", "blocking_pool", "logger"]);
+    }
+
+    #[test]
+    fn second_services_leave_the_running_ones_alone() {
+        let config = Config {
+            observer_interval: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                // A supervisor that keeps restarting its children gives up and takes this actor down.
+                global::trap_exit(true);
+
+                let names = ["logger", "blocking_pool", "observer"];
+                let running = names.map(|name| global::sync::whereis(name).unwrap());
+
+                let supervisor = Supervisor::spawn_linked(Strategy::OneForOne);
+                super::supervise_services(&supervisor, Some(Duration::from_secs(60)));
+
+                // Wait for every second service to find its name taken, restarted ones keep running.
+                let mut stopped = 0;
+                for _ in 0..1000 {
+                    let children = supervisor.which_children().await;
+                    stopped = children.iter().filter(|(_, pid)| pid.is_none()).count();
+                    if stopped == names.len() {
+                        break;
+                    }
+
+                    global::sleep(Duration::from_millis(1)).await;
+                }
+
+                let after = names.map(|name| global::sync::whereis(name).unwrap());
+                completion.complete((stopped, running == after));
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some((3, true)));
+    }
+}
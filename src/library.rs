@@ -6,4 +6,6 @@
 pub mod blocking;
 pub mod io;
 pub mod logger;
+pub mod observer;
+pub mod statem;
 pub mod supervisor;
@@ -1,30 +1,90 @@
 use std::{
-    any::Any,
     collections::VecDeque,
     panic::{AssertUnwindSafe, catch_unwind},
-    sync::mpsc::channel,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::channel,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    Exit, IntoAsyncActor, Pid,
+    Exit, IntoAsyncActor, Pid, TrapExitMessage,
+    actor::panic_to_string,
     global::{
-        exit, send, spawn_linked,
-        sync::{self, pid, register},
+        ReplyHandle, exit, promise, send, spawn_linked,
+        sync::{self, pid, try_register},
+        trap_exit,
     },
+    library::io::spawn_helper,
     receive,
 };
 
 const NAME: &str = "blocking_pool";
 
+// TODO: Make this configurable
+const HANDLERS: usize = 4;
+
+/// The maximum number of temporary handlers that are started under sustained load.
+const MAX_TEMPORARY_HANDLERS: usize = 4;
+
+/// How long a job waits for a free handler before a temporary one is started.
+const GROW_AFTER: Duration = Duration::from_millis(100);
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Why a job didn't run, see [`block_on_timeout`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockError {
+    /// No handler picked up the job in time, it will not run.
+    Timeout,
+}
+
 /// Run a blocking closure.
 ///
 /// This will run on a dedicated thread pool.
-pub async fn block_on<F, R>(f: F) -> R
+/// The job is submitted right away, await the returned handle for the result.
+/// If the closure panics the awaiting actor exits with `Exit::Panic`.
+pub fn block_on<F, R>(f: F) -> JobHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let handle = submit(f, None);
+
+    JobHandle {
+        future: Box::pin(async move {
+            match handle.await {
+                Ok(res) => res,
+                // Without a timeout a job is only dropped when the pool shuts down.
+                Err(BlockError::Timeout) => panic!("Job was dropped by the blocking pool"),
+            }
+        }),
+    }
+}
+
+/// Run a blocking closure, unless no handler is free to pick it up within the timeout.
+///
+/// Once a handler picks up the job it runs to completion, the timeout only covers the wait for a handler.
+/// See [`block_on`].
+pub fn block_on_timeout<F, R>(f: F, timeout: Duration) -> JobHandle<Result<R, BlockError>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    submit(f, Some(timeout))
+}
+
+fn submit<F, R>(f: F, timeout: Option<Duration>) -> JobHandle<Result<R, BlockError>>
 where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
     let pid = pid();
+    let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+    let (reply, handle): (_, ReplyHandle<JobResult<R>>) = promise();
 
     let closure = move || {
         // TODO: Capture backtrace
@@ -33,71 +93,197 @@ where
             Err(err) => JobResult::Panic(panic_to_string(err)),
         };
 
-        sync::send(pid, result);
+        let _ = handle.resolve(result);
     };
 
-    send(
+    sync::send(
         NAME,
         Job {
+            id,
+            timeout,
             closure: Box::new(closure),
         },
-    )
-    .await;
-
-    receive! {
-        match JobResult<R> {
-            JobResult::Success(res) => res,
-            JobResult::Panic(err) => {
-                exit(pid, Exit::Panic(err)).await;
-                unreachable!()
+    );
+
+    JobHandle {
+        future: Box::pin(async move {
+            // The closure is dropped without running when the job expires.
+            let Ok(result) = reply.await else {
+                return Err(BlockError::Timeout);
+            };
+
+            match result {
+                JobResult::Success(res) => Ok(res),
+                JobResult::Panic(err) => {
+                    exit(pid, Exit::Panic(err)).await;
+                    unreachable!()
+                }
             }
-        }
+        }),
+    }
+}
+
+/// The result of a blocking closure, see [`block_on`].
+///
+/// Dropping the handle doesn't cancel the job, its result is dropped once it ran.
+pub struct JobHandle<R> {
+    future: Pin<Box<dyn Future<Output = R> + Send>>,
+}
+
+impl<R> Future for JobHandle<R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        self.future.as_mut().poll(cx)
     }
 }
 
-#[allow(dead_code)]
 enum JobResult<R> {
     Success(R),
     Panic(String),
 }
 
 struct Job {
+    id: u64,
+    timeout: Option<Duration>,
     closure: Box<dyn FnOnce() + Send + 'static>,
 }
 
 struct Idle(Pid);
 
+/// Expire a job if it is still waiting for a handler.
+struct Expire(u64);
+
+/// Start a temporary handler if the oldest job waited too long.
+struct Grow;
+
+/// Ask a handler to finish its jobs and exit.
+struct Retire;
+
+/// Sent by a handler thread once it ran its last job.
+struct Drained;
+
 pub(crate) async fn router() -> Exit {
-    register(NAME, pid());
+    if try_register(NAME, pid()).is_err() {
+        // Another router is running already, jobs go to that one.
+        return Exit::Normal;
+    }
 
-    // TODO: Make this configurable
-    const HANDLERS: usize = 4;
+    // Exits are trapped so the handlers can be drained when the router is asked to exit.
+    trap_exit(true);
+
+    let system = unsafe { crate::thread::borrow() };
 
     let mut idle = (0..HANDLERS)
         .map(|_| spawn_linked(handler(pid())))
         .collect::<VecDeque<_>>();
+    let mut handlers = idle.iter().copied().collect::<Vec<_>>();
+    let mut temporary = Vec::new();
+
+    // Jobs waiting for a handler, with the time they were queued.
+    let mut pending = VecDeque::<(Instant, Job)>::new();
+    let mut growing = false;
 
     loop {
-        if idle.is_empty() {
-            receive! {
-                match Idle {
-                    Idle(pid) => {
-                        idle.push_back(pid);
+        receive! {
+            match Job {
+                job => {
+                    if let Some(handler) = idle.pop_front() {
+                        send(handler, job).await;
+                    } else {
+                        if let Some(timeout) = job.timeout {
+                            sync::schedule(pid(), Expire(job.id), timeout);
+                        }
+
+                        if !growing {
+                            sync::schedule(pid(), Grow, GROW_AFTER);
+                            growing = true;
+                        }
+
+                        pending.push_back((system.timer.now(), job));
                     }
                 }
             }
-        } else {
-            receive! {
-                match Job {
-                    job => {
-                        let pid = idle.pop_front().expect("Idle queue should not be empty");
-                        send(pid, job).await;
+            match Idle {
+                Idle(handler) => {
+                    if let Some((_, job)) = pending.pop_front() {
+                        send(handler, job).await;
+                    } else if let Some(index) = temporary.iter().position(|&pid| pid == handler) {
+                        temporary.swap_remove(index);
+                        send(handler, Retire).await;
+                    } else {
+                        idle.push_back(handler);
+                    }
+                }
+            }
+            match Expire {
+                Expire(id) => {
+                    let index = pending.iter().position(|(_, job)| job.id == id);
+                    if let Some(index) = index {
+                        // Dropping the job breaks its promise, which tells the owner it expired.
+                        pending.remove(index).expect("Index should be valid");
                     }
                 }
-                match Idle {
-                    Idle(pid) => {
-                        idle.push_back(pid);
+            }
+            match Grow {
+                Grow => {
+                    growing = false;
+
+                    let waited_too_long = pending
+                        .front()
+                        .is_some_and(|(queued_at, _)| system.timer.now() - *queued_at >= GROW_AFTER);
+
+                    if waited_too_long && temporary.len() < MAX_TEMPORARY_HANDLERS {
+                        let handler = spawn_linked(handler(pid()));
+                        handlers.push(handler);
+                        temporary.push(handler);
+
+                        let (_, job) = pending.pop_front().expect("Pending jobs should not be empty");
+                        send(handler, job).await;
                     }
+
+                    if !pending.is_empty() {
+                        sync::schedule(pid(), Grow, GROW_AFTER);
+                        growing = true;
+                    }
+                }
+            }
+            match TrapExitMessage {
+                TrapExitMessage { pid: from, reason } => {
+                    let is_handler = handlers.contains(&from);
+                    handlers.retain(|&pid| pid != from);
+                    idle.retain(|&pid| pid != from);
+
+                    // Retired handlers exit normally, anything else takes the pool down.
+                    if !is_handler || reason != Exit::Normal {
+                        drain(handlers, pending.into_iter().map(|(_, job)| job)).await;
+                        return reason;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Let every handler finish the jobs it was given and wait until their threads are done.
+// Jobs that are still waiting for a handler are run as well. Only if no handler is left they are dropped,
+// which breaks their promise.
+async fn drain(mut handlers: Vec<Pid>, pending: impl Iterator<Item = Job>) {
+    if !handlers.is_empty() {
+        for (job, &handler) in pending.zip(handlers.iter().cycle()) {
+            send(handler, job).await;
+        }
+    }
+
+    for &handler in &handlers {
+        send(handler, Retire).await;
+    }
+
+    while !handlers.is_empty() {
+        receive! {
+            match TrapExitMessage {
+                TrapExitMessage { pid, .. } => {
+                    handlers.retain(|&handler| handler != pid);
                 }
             }
         }
@@ -109,14 +295,16 @@ fn handler(router: Pid) -> impl IntoAsyncActor {
         let pid = pid();
         let (tx, rx) = channel::<Job>();
 
-        crate::thread::spawn(move || {
+        // Jobs catch their own panics, anything else that panics takes the handler down.
+        let thread = spawn_helper(pid, move || {
             for job in rx {
-                // TODO: Handle panics
                 (job.closure)();
 
                 // Mark ourselves as idle
                 sync::send(router, Idle(pid));
             }
+
+            sync::send(pid, Drained);
         });
 
         loop {
@@ -126,17 +314,219 @@ fn handler(router: Pid) -> impl IntoAsyncActor {
                         let _ = tx.send(job);
                     }
                 }
+                match Retire {
+                    Retire => break,
+                }
             }
         }
+
+        // Closing the channel lets the thread run the jobs it has left and stop.
+        drop(tx);
+
+        receive! {
+            match Drained {
+                Drained => {}
+            }
+        }
+
+        // The thread is done, joining doesn't block the worker.
+        let _ = thread.join();
+
+        Exit::Normal
     }
 }
 
-fn panic_to_string(err: Box<dyn Any + Send>) -> String {
-    if let Some(str) = err.downcast_ref::<String>() {
-        str.to_string()
-    } else if let Some(err) = err.downcast_ref::<&'static str>() {
-        err.to_string()
-    } else {
-        "Unknown panic".to_string()
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        Config, Exit, TrapExitMessage,
+        global::{self, join},
+        receive,
+    };
+
+    use super::{BlockError, HANDLERS, NAME, block_on, block_on_timeout, router};
+
+    /// Occupies every regular handler until the returned sender is dropped.
+    fn saturate() -> mpsc::Sender<()> {
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for _ in 0..HANDLERS {
+            let rx = rx.clone();
+            // Jobs are submitted right away, the reply is never awaited.
+            drop(block_on(move || {
+                let _ = rx.lock().unwrap().recv();
+            }));
+        }
+
+        tx
+    }
+
+    #[test]
+    fn block_on_leaves_other_messages() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                global::send(global::sync::pid(), String::from("unrelated")).await;
+
+                let answer = block_on(|| 6 * 7).await;
+                let both = join(block_on(|| 1), block_on(|| 2)).await;
+
+                let unrelated = global::recv_any(None)
+                    .await
+                    .ok()
+                    .and_then(|msg| msg.downcast::<String>().ok());
+
+                completion.complete((answer, both, unrelated));
+
+                Exit::Normal
+            }
+        });
+
+        let (answer, both, unrelated) = result.unwrap();
+
+        assert_eq!(answer, 42);
+        assert_eq!(both, (1, 2));
+        assert_eq!(unrelated.as_deref().map(String::as_str), Some("unrelated"));
+    }
+
+    #[test]
+    fn timed_job_fails_on_saturated_pool() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let release = saturate();
+
+                let timed = block_on_timeout(|| 1, Duration::from_millis(10)).await;
+
+                drop(release);
+                completion.complete(timed);
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(Err(BlockError::Timeout)));
+    }
+
+    #[test]
+    fn sustained_load_starts_temporary_handler() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let release = saturate();
+
+                // Only a temporary handler can run this while the pool is saturated.
+                let answer = block_on(|| 42).await;
+
+                drop(release);
+                completion.complete(answer);
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(42));
+    }
+
+    #[test]
+    fn shutdown_drains_handlers() {
+        thread_local! {
+            static STOPPED: Cell<Option<StopGuard>> = const { Cell::new(None) };
+        }
+
+        // Counts the handler threads that ran a job and terminated.
+        struct StopGuard(Arc<AtomicUsize>);
+
+        impl Drop for StopGuard {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let result = {
+            let stopped = stopped.clone();
+
+            crate::run_until(Config::default(), |completion| {
+                async move || {
+                    struct Started;
+
+                    let root = global::sync::pid();
+                    global::trap_exit(true);
+
+                    // Takes over the name from the system's pool, which keeps running unnamed.
+                    global::sync::unregister(NAME);
+                    let pool = global::spawn_linked(router);
+                    while global::sync::whereis(NAME) != Some(pool) {
+                        global::yield_immediate().await;
+                    }
+
+                    // Occupy every handler, so the next job has to wait for one.
+                    let (release, rx) = mpsc::channel::<()>();
+                    let rx = Arc::new(Mutex::new(rx));
+                    for _ in 0..HANDLERS {
+                        let rx = rx.clone();
+                        let stopped = stopped.clone();
+                        drop(block_on(move || {
+                            STOPPED.set(Some(StopGuard(stopped)));
+                            global::sync::send(root, Started);
+                            let _ = rx.lock().unwrap().recv();
+                        }));
+                    }
+
+                    for _ in 0..HANDLERS {
+                        receive! {
+                            match Started {
+                                Started => {}
+                            }
+                        }
+                    }
+
+                    // The exit arrives behind the job, which is waiting for a handler by then.
+                    let pending = block_on(|| 42);
+                    global::exit(pool, Exit::Shutdown).await;
+
+                    // Once the pool took the exit it is draining, it hands the waiting job to a handler before it retires them.
+                    let system = unsafe { crate::thread::borrow() };
+                    while system
+                        .registry
+                        .lookup_pid(pool)
+                        .is_some_and(|pool| pool.pending_messages() > 0)
+                    {
+                        global::yield_immediate().await;
+                    }
+                    drop(release);
+
+                    let answer = pending.await;
+
+                    // The pool only exits once every handler joined its thread.
+                    let (from, exit) = receive! {
+                        match TrapExitMessage {
+                            TrapExitMessage { pid, reason } => (pid, reason),
+                        }
+                    };
+
+                    completion.complete((answer, from == pool, exit));
+
+                    Exit::Normal
+                }
+            })
+        };
+
+        assert_eq!(result, Some((42, true, Exit::Shutdown)));
+        assert_eq!(stopped.load(Ordering::SeqCst), HANDLERS);
     }
 }
@@ -1,3 +1,83 @@
 pub mod buffer_pool;
+pub mod capture;
+pub mod command;
 pub mod file;
 pub mod io_pump;
+
+use std::{
+    panic::{AssertUnwindSafe, catch_unwind},
+    thread::JoinHandle,
+};
+
+use crate::{
+    Exit, Pid, PidStatus,
+    actor::panic_to_string,
+    global::{sync, trap_exit},
+};
+
+/// Make the current port trap exits, so it closes when its owner exits, even when the owner exits normally.
+///
+/// Returns `false` if the owner exited before that, the port should exit right away.
+pub(crate) fn watch_owner(owner: Pid) -> bool {
+    trap_exit(true);
+
+    // The owner is removed from the registry before its exit signals are sent, so this can't be missed.
+    sync::pid_status(owner) == PidStatus::Alive
+}
+
+/// Spawn the helper thread of a port.
+///
+/// A panic in the helper makes the port exit with `Exit::Panic`, so the owner finds out through the link
+/// instead of waiting on a thread that is gone.
+pub(crate) fn spawn_helper<F>(port: Pid, f: F) -> JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    crate::thread::spawn(move || {
+        if let Err(err) = catch_unwind(AssertUnwindSafe(f)) {
+            sync::exit(port, Exit::Panic(panic_to_string(err)));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Exit, TrapExitMessage, global, receive};
+
+    use super::spawn_helper;
+
+    #[test]
+    fn helper_panic_exits_the_port() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                let port = global::spawn_linked(async || {
+                    global::trap_exit(true);
+                    spawn_helper(global::sync::pid(), || panic!("helper failed"));
+
+                    receive! {
+                        match TrapExitMessage {
+                            TrapExitMessage { reason, .. } => reason,
+                        }
+                    }
+                });
+
+                let exit = receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { pid, reason } => (pid == port, reason),
+                    }
+                };
+
+                completion.complete(exit);
+
+                Exit::Normal
+            }
+        });
+
+        let (from_port, reason) = result.unwrap();
+
+        assert!(from_port);
+        assert_eq!(reason, Exit::Panic(String::from("helper failed")));
+    }
+}
@@ -6,6 +6,13 @@ use std::{
 
 const CHUNK_SIZE: usize = 0x1000;
 
+/// The requested length doesn't fit in the buffer, see [`Buffer::try_set_len`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CapacityError {
+    pub len: usize,
+    pub capacity: usize,
+}
+
 pub struct Buffer {
     len: usize,
     capacity: usize,
@@ -45,11 +52,61 @@ impl Buffer {
     }
 
     pub unsafe fn set_len(&mut self, new_len: usize) {
+        debug_assert!(new_len <= self.capacity, "Buffer length exceeds capacity");
+        self.len = new_len;
+    }
+
+    /// Like `set_len`, but refuses lengths past the capacity instead of trusting the caller.
+    ///
+    /// # Safety
+    ///
+    /// The bytes up to `new_len` must have been initialized.
+    pub unsafe fn try_set_len(&mut self, new_len: usize) -> Result<(), CapacityError> {
+        if new_len > self.capacity {
+            return Err(CapacityError {
+                len: new_len,
+                capacity: self.capacity,
+            });
+        }
+
         self.len = new_len;
+        Ok(())
     }
 
+    /// Make sure there is room for at least `additional` more bytes, growing the buffer if needed.
+    ///
+    /// The contents are preserved, pointers into the buffer are invalidated when it grows.
+    pub fn reserve(&mut self, additional: usize) {
+        let required = self
+            .len
+            .checked_add(additional)
+            .expect("Buffer capacity overflow");
+
+        if required <= self.capacity {
+            return;
+        }
+
+        let new_capacity = required.max(self.capacity * 2);
+        let layout = Layout::array::<u8>(self.capacity).expect("Failed to create buffer");
+        // Also checks that the new size fits in a layout.
+        let new_layout = Layout::array::<u8>(new_capacity).expect("Failed to create buffer");
+
+        let ptr = unsafe { std::alloc::realloc(self.ptr, layout, new_layout.size()) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(new_layout);
+        }
+
+        self.ptr = ptr;
+        self.capacity = new_capacity;
+    }
+
+    /// Resize the buffer, new bytes are zeroed.
+    ///
+    /// The buffer grows if `new_len` is larger than its capacity.
     pub fn resize(&mut self, new_len: usize) {
-        let new_len = new_len.min(self.capacity);
+        if new_len > self.capacity {
+            self.reserve(new_len - self.len);
+        }
 
         if new_len > self.len {
             let ptr = self.ptr.wrapping_add(self.len);
@@ -60,6 +117,23 @@ impl Buffer {
         self.len = new_len;
     }
 
+    /// Resize the buffer without zeroing new bytes, for buffers that are about to be overwritten by the OS.
+    ///
+    /// The buffer grows if `new_len` is larger than its capacity.
+    ///
+    /// # Safety
+    ///
+    /// New bytes are uninitialized, they must be written through `as_mut_ptr` before the buffer is used as a slice.
+    /// Only hand the pointer to raw APIs that never read it, e.g. `ReadFile`, then truncate it to what was written.
+    /// Never pass the buffer to a `std::io::Read` implementation, use `resize` for those.
+    pub unsafe fn resize_uninit(&mut self, new_len: usize) {
+        if new_len > self.capacity {
+            self.reserve(new_len - self.len);
+        }
+
+        self.len = new_len;
+    }
+
     /// Copy a slice into the buffer.
     /// This will only copy up to the available space in the buffer from `src`.
     ///
@@ -113,3 +187,119 @@ pub async fn reserve_buffer(size_hint: usize) -> Buffer {
 }
 
 // TODO: Consider free_buffer
+
+#[cfg(test)]
+mod tests {
+    use super::{Buffer, CHUNK_SIZE, CapacityError};
+
+    #[test]
+    fn resize_grows_past_capacity() {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(b"hello");
+
+        buffer.resize(3 * CHUNK_SIZE);
+
+        assert_eq!(buffer.len(), 3 * CHUNK_SIZE);
+        assert!(buffer.capacity() >= 3 * CHUNK_SIZE);
+        assert_eq!(&buffer[..5], b"hello");
+        assert!(buffer[5..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reserve_preserves_contents() {
+        let mut buffer = Buffer::new();
+        let contents = (0..CHUNK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        buffer.copy_from_slice(&contents);
+
+        buffer.reserve(1);
+        assert!(buffer.remaining_capacity() >= 1);
+        assert_eq!(&buffer[..], &contents[..]);
+
+        buffer.copy_from_slice(&contents);
+        assert_eq!(&buffer[..CHUNK_SIZE], &contents[..]);
+        assert_eq!(&buffer[CHUNK_SIZE..], &contents[..]);
+    }
+
+    #[test]
+    fn reserve_does_nothing_with_room_left() {
+        let mut buffer = Buffer::new();
+        buffer.reserve(CHUNK_SIZE);
+
+        assert_eq!(buffer.capacity(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn resize_zero_fills_and_truncates() {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(b"abc");
+
+        buffer.resize(1);
+        assert_eq!(&buffer[..], b"a");
+
+        // Bytes past the length are zeroed again, even if they were written before.
+        buffer.resize(3);
+        assert_eq!(&buffer[..], b"a\0\0");
+    }
+
+    #[test]
+    fn resize_uninit_grows_and_keeps_contents() {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(b"abc");
+
+        unsafe {
+            buffer.resize_uninit(2 * CHUNK_SIZE);
+            std::ptr::write_bytes(buffer.as_mut_ptr().add(3), 7, 2 * CHUNK_SIZE - 3);
+        }
+
+        assert_eq!(buffer.len(), 2 * CHUNK_SIZE);
+        assert!(buffer.capacity() >= 2 * CHUNK_SIZE);
+        assert_eq!(&buffer[..3], b"abc");
+        assert!(buffer[3..].iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn copy_from_slice_appends() {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(b"abc");
+        buffer.copy_from_slice(b"def");
+
+        assert_eq!(&buffer[..], b"abcdef");
+        assert_eq!(buffer.remaining_capacity(), CHUNK_SIZE - 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Buffer is too small to copy the slice")]
+    fn copy_from_slice_panics_without_room() {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(&[0; CHUNK_SIZE + 1]);
+    }
+
+    #[test]
+    fn try_set_len_rejects_over_capacity() {
+        let mut buffer = Buffer::new();
+        buffer.copy_from_slice(b"abc");
+
+        let result = unsafe { buffer.try_set_len(CHUNK_SIZE + 1) };
+
+        assert_eq!(
+            result,
+            Err(CapacityError {
+                len: CHUNK_SIZE + 1,
+                capacity: CHUNK_SIZE,
+            })
+        );
+        assert_eq!(&buffer[..], b"abc");
+
+        assert_eq!(unsafe { buffer.try_set_len(2) }, Ok(()));
+        assert_eq!(&buffer[..], b"ab");
+    }
+
+    #[test]
+    fn deref_mut_writes_through() {
+        let mut buffer = Buffer::new();
+        buffer.resize(4);
+        buffer[1] = 7;
+
+        assert_eq!(&buffer[..], &[0, 7, 0, 0]);
+    }
+}
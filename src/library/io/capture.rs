@@ -0,0 +1,104 @@
+use std::io::{self, BufRead, BufReader, PipeReader, PipeWriter};
+
+use crate::{
+    Exit, IntoAsyncActor, Pid, TrapExitMessage,
+    global::{
+        spawn_linked,
+        sync::{self, pid},
+    },
+    library::io::{spawn_helper, watch_owner},
+    receive,
+};
+
+/// A line written to a capture port, without the line ending.
+pub struct Line(pub String);
+
+fn capture_actor(reader: PipeReader) -> impl IntoAsyncActor {
+    let owner = pid();
+
+    async move || {
+        let pid = pid();
+
+        if !watch_owner(owner) {
+            return Exit::Normal;
+        }
+
+        // The helper blocks on the pipe until every write end is closed, even after the port exited.
+        spawn_helper(pid, move || {
+            for line in BufReader::new(reader).lines() {
+                match line {
+                    Ok(line) => sync::send_from(pid, owner, Line(line)),
+                    Err(err) => {
+                        sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                        return;
+                    }
+                }
+            }
+
+            sync::exit(pid, Exit::Normal);
+        });
+
+        receive! {
+            match TrapExitMessage {
+                // Either the owner exited, the pipe was closed or we were asked to exit.
+                TrapExitMessage { reason, .. } => reason,
+            }
+        }
+    }
+}
+
+/// Open a capture port, the port is linked to the current actor.
+///
+/// Returns the port and the write end of its pipe. Every line written to the pipe is sent to the current actor
+/// as a `Line`, the port exits normally once all copies of the write end are closed.
+///
+/// Standard output belongs to the whole process, so it can't be redirected for a single actor.
+/// Hand the write end to a child process instead, e.g. with `Command::stdout`, or write to it directly.
+pub fn open() -> io::Result<(Pid, PipeWriter)> {
+    let (reader, writer) = io::pipe()?;
+
+    Ok((spawn_linked(capture_actor(reader)), writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{Config, Exit, TrapExitMessage, global, receive};
+
+    use super::{Line, open};
+
+    #[test]
+    fn captures_written_lines() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                let (port, mut writer) = open().unwrap();
+                writer.write_all(b"hello\nworld\n").unwrap();
+                drop(writer);
+
+                let mut lines = Vec::new();
+                let exit = loop {
+                    receive! {
+                        match Line {
+                            Line(line) => lines.push(line),
+                        }
+                        match TrapExitMessage {
+                            TrapExitMessage { pid, reason } => break (pid == port, reason),
+                        }
+                    }
+                };
+
+                completion.complete((lines, exit));
+
+                Exit::Normal
+            }
+        });
+
+        let (lines, exit) = result.unwrap();
+
+        assert_eq!(lines, ["hello", "world"]);
+        assert_eq!(exit, (true, Exit::Normal));
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    sync::mpsc::channel,
+};
+
+use crate::{
+    Exit, IntoAsyncActor, Pid, TrapExitMessage,
+    global::{
+        spawn_linked,
+        sync::{self, pid},
+    },
+    library::io::{buffer_pool::Buffer, spawn_helper, watch_owner},
+    receive,
+};
+
+fn command_actor(mut command: Command) -> impl IntoAsyncActor {
+    let owner = pid();
+
+    async move || {
+        let pid = pid();
+        let (tx, rx) = channel();
+
+        if !watch_owner(owner) {
+            return Exit::Normal;
+        }
+
+        spawn_helper(pid, move || {
+            let mut child = match command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                    return;
+                }
+            };
+
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            let mut stdout = child.stdout.take().expect("stdout is piped");
+
+            spawn_helper(pid, move || {
+                for msg in rx {
+                    match msg {
+                        CommandRequest::Write(data) => {
+                            // The child closed its stdin, most likely it exited, which the reading side reports.
+                            if stdin.write_all(&data).is_err() {
+                                return;
+                            }
+                        }
+                        CommandRequest::CloseStdin => return,
+                    }
+                }
+            });
+
+            loop {
+                let mut buffer = Buffer::new();
+                buffer.resize(buffer.capacity());
+
+                match stdout.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buffer.resize(n);
+                        sync::send_from(pid, owner, CommandReply::Output(buffer));
+                    }
+                    Err(err) => {
+                        // Nobody can see its output anymore, stop the child and reap it.
+                        let _ = child.kill();
+                        let _ = child.wait();
+
+                        sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                        return;
+                    }
+                }
+            }
+
+            // Stdout is closed, the child is done or about to be.
+            match child.wait() {
+                Ok(status) if status.success() => sync::exit(pid, Exit::Normal),
+                Ok(status) => sync::exit(pid, Exit::custom(status)),
+                Err(err) => sync::exit(pid, Exit::Io(err.to_string(), err.kind())),
+            }
+        });
+
+        loop {
+            receive! {
+                match CommandRequest {
+                    request => {
+                        // The writing side is gone once stdin is closed, later writes are dropped.
+                        let _ = tx.send(request);
+                    },
+                }
+                match TrapExitMessage {
+                    // Either the owner exited, the child exited or we were asked to exit.
+                    // Dropping `tx` closes the stdin of the child.
+                    TrapExitMessage { reason, .. } => return reason,
+                }
+            }
+        }
+    }
+}
+
+pub enum CommandRequest {
+    /// Write the bytes to the stdin of the child.
+    Write(Box<[u8]>),
+    /// Close the stdin of the child, so it sees the end of its input.
+    CloseStdin,
+}
+
+pub enum CommandReply {
+    /// Bytes the child wrote to its stdout.
+    Output(Buffer),
+}
+
+/// Spawn a child process behind a port, the port is linked to the current actor.
+///
+/// The stdin and stdout of the command are piped, everything the child writes is sent to the current actor
+/// as `CommandReply::Output`. The port exits when the child does, normally if it succeeded and with
+/// `Exit::custom(ExitStatus)` otherwise.
+///
+/// Like an Erlang port the child isn't killed when the port closes, its stdin is closed instead.
+/// The program is expected to exit once its input ends.
+pub fn open(command: Command) -> Pid {
+    spawn_linked(command_actor(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        process::{Command, ExitStatus},
+    };
+
+    use crate::{
+        Config, Exit, TrapExitMessage,
+        global::{self, send},
+        receive,
+    };
+
+    use super::{CommandReply, CommandRequest, open};
+
+    #[cfg(unix)]
+    #[test]
+    fn round_trips_through_cat() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                let port = open(Command::new("cat"));
+                send(port, CommandRequest::Write(Box::new(*b"hello port"))).await;
+                send(port, CommandRequest::CloseStdin).await;
+
+                let mut output = Vec::new();
+                let exit = loop {
+                    receive! {
+                        match CommandReply {
+                            CommandReply::Output(buffer) => output.extend_from_slice(&buffer),
+                        }
+                        match TrapExitMessage {
+                            TrapExitMessage { pid, reason } => break (pid == port, reason),
+                        }
+                    }
+                };
+
+                completion.complete((output, exit));
+
+                Exit::Normal
+            }
+        });
+
+        let (output, exit) = result.unwrap();
+
+        assert_eq!(output, b"hello port");
+        assert_eq!(exit, (true, Exit::Normal));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_child_exits_with_its_status() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                open(Command::new("false"));
+
+                let reason = receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { reason, .. } => reason,
+                    }
+                };
+
+                completion.complete(reason);
+
+                Exit::Normal
+            }
+        });
+
+        let reason = result.unwrap();
+        let status = reason.downcast_ref::<ExitStatus>().unwrap();
+
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn missing_program_exits_with_io_error() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                open(Command::new("kerosene-does-not-exist"));
+
+                let reason = receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { reason, .. } => reason,
+                    }
+                };
+
+                completion.complete(reason);
+
+                Exit::Normal
+            }
+        });
+
+        let reason = result.unwrap();
+
+        assert!(matches!(reason, Exit::Io(_, io::ErrorKind::NotFound)));
+    }
+}
@@ -1,21 +1,29 @@
 use std::{
-    fs::File,
+    collections::HashMap,
+    fs::OpenOptions,
     io::{Read, Seek, SeekFrom, Write},
     path::PathBuf,
     sync::mpsc::channel,
 };
 
 use crate::{
-    Exit, IntoAsyncActor,
+    Exit, IntoAsyncActor, Pid, TrapExitMessage,
     global::{
-        exit, send, spawn_linked,
+        exit, recv_matching_from, send, sender, spawn_linked,
         sync::{self, pid},
     },
-    library::io::buffer_pool::Buffer,
+    library::io::{
+        buffer_pool::{Buffer, reserve_buffer},
+        spawn_helper, watch_owner,
+    },
     receive,
 };
 
 fn file_actor(path: impl Into<PathBuf>) -> impl IntoAsyncActor {
+    file_actor_with(path, OpenOptions::new().read(true).clone())
+}
+
+fn file_actor_with(path: impl Into<PathBuf>, options: OpenOptions) -> impl IntoAsyncActor {
     let owner = pid();
     let path = path.into();
 
@@ -23,8 +31,12 @@ fn file_actor(path: impl Into<PathBuf>) -> impl IntoAsyncActor {
         let pid = pid();
         let (tx, rx) = channel();
 
-        crate::thread::spawn(move || {
-            let mut file = match File::open(path) {
+        if !watch_owner(owner) {
+            return Exit::Normal;
+        }
+
+        spawn_helper(pid, move || {
+            let mut file = match options.open(path) {
                 Ok(file) => file,
                 Err(err) => {
                     sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
@@ -49,7 +61,7 @@ fn file_actor(path: impl Into<PathBuf>) -> impl IntoAsyncActor {
                         match file.read(&mut buffer) {
                             Ok(n) => {
                                 buffer.resize(n);
-                                sync::send(owner, FileReply::Read(buffer));
+                                sync::send_from(pid, owner, FileReply::Read(buffer));
                             }
                             Err(err) => {
                                 sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
@@ -74,6 +86,45 @@ fn file_actor(path: impl Into<PathBuf>) -> impl IntoAsyncActor {
                             }
                         }
                     }
+                    FileRequest::ReadInto { offset, mut buffer } => {
+                        match file.seek(SeekFrom::Start(offset)) {
+                            Ok(_) => {}
+                            Err(err) => {
+                                sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                                return;
+                            }
+                        }
+
+                        buffer.resize(buffer.capacity());
+
+                        match file.read(&mut buffer) {
+                            Ok(n) => {
+                                buffer.resize(n);
+                                sync::send_from(pid, owner, FileReply::Read(buffer));
+                            }
+                            Err(err) => {
+                                sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                                return;
+                            }
+                        }
+                    }
+                    FileRequest::WriteFrom { offset, buffer } => {
+                        match file.seek(SeekFrom::Start(offset)) {
+                            Ok(_) => {}
+                            Err(err) => {
+                                sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                                return;
+                            }
+                        }
+
+                        match file.write_all(&buffer) {
+                            Ok(_) => sync::send_from(pid, owner, FileReply::Written(buffer)),
+                            Err(err) => {
+                                sync::exit(pid, Exit::Io(err.to_string(), err.kind()));
+                                return;
+                            }
+                        }
+                    }
                 }
             }
         });
@@ -82,9 +133,15 @@ fn file_actor(path: impl Into<PathBuf>) -> impl IntoAsyncActor {
             receive! {
                 match FileRequest {
                     request => {
-                        tx.send(request).expect("Failed to send request to helper thread");
+                        // The helper thread is gone once it failed, its exit is on the way.
+                        let _ = tx.send(request);
                     },
                 }
+                match TrapExitMessage {
+                    // Either the owner exited or we were asked to exit.
+                    // Dropping `tx` stops the helper thread, which closes the file.
+                    TrapExitMessage { reason, .. } => return reason,
+                }
             }
         }
     }
@@ -103,11 +160,179 @@ pub enum FileRequest {
         len: usize,
         data: Box<[u8]>,
     },
+    /// Read as much as fits into the buffer, it is sent back in `FileReply::Read`.
+    ReadInto {
+        offset: u64,
+        buffer: Buffer,
+    },
+    /// Write the whole buffer, it is sent back in `FileReply::Written` once written.
+    WriteFrom {
+        offset: u64,
+        buffer: Buffer,
+    },
 }
 
 pub enum FileReply {
     Write(usize),
     Read(Buffer),
+    Written(Buffer),
+}
+
+/// Open a file for reading, the port is linked to the current actor.
+pub fn open(path: impl Into<PathBuf>) -> Pid {
+    spawn_linked(file_actor(path))
+}
+
+/// Create or truncate a file for writing, the port is linked to the current actor.
+pub fn create(path: impl Into<PathBuf>) -> Pid {
+    spawn_linked(file_actor_with(
+        path,
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .clone(),
+    ))
+}
+
+/// Wait for the reply of a single port, or for its exit when the current actor traps exits.
+async fn recv_reply(port: Pid) -> Result<FileReply, Exit> {
+    let Ok(msg) = recv_matching_from(None, |from, msg| {
+        from == port && (msg.is::<FileReply>() || msg.is::<TrapExitMessage>())
+    })
+    .await
+    else {
+        unreachable!("Receiving without a timeout")
+    };
+
+    match msg.downcast::<TrapExitMessage>() {
+        Ok(exit) => Err(exit.reason),
+        Err(msg) => Ok(*msg.downcast().expect("Matched a FileReply")),
+    }
+}
+
+/// Read from a port into the given buffer, which is returned with the bytes that were read.
+///
+/// The buffer is filled from the start, up to its capacity. An empty buffer means the end of the file was reached.
+/// Returns `Err` with the exit reason if the port exited instead, which is only seen when trapping exits.
+pub async fn read_into(port: Pid, offset: u64, buffer: Buffer) -> Result<Buffer, Exit> {
+    send(port, FileRequest::ReadInto { offset, buffer }).await;
+
+    match recv_reply(port).await? {
+        FileReply::Read(buffer) => Ok(buffer),
+        _ => unreachable!("A port replies to ReadInto with Read"),
+    }
+}
+
+/// Write the contents of the buffer to a port, the buffer is returned once it was written.
+///
+/// Returns `Err` with the exit reason if the port exited instead, which is only seen when trapping exits.
+pub async fn write_from(port: Pid, offset: u64, buffer: Buffer) -> Result<Buffer, Exit> {
+    send(port, FileRequest::WriteFrom { offset, buffer }).await;
+
+    match recv_reply(port).await? {
+        FileReply::Written(buffer) => Ok(buffer),
+        _ => unreachable!("A port replies to WriteFrom with Written"),
+    }
+}
+
+/// Copy a file, reusing a single buffer for the whole transfer.
+///
+/// Returns the number of bytes copied, or the exit reason of the port that failed.
+pub async fn copy_file(src: impl Into<PathBuf>, dst: impl Into<PathBuf>) -> Result<u64, Exit> {
+    let src = open(src);
+    let dst = create(dst);
+
+    let copied = async {
+        let mut offset = 0;
+        let mut buffer = reserve_buffer(CHUNK_SIZE).await;
+
+        loop {
+            buffer = read_into(src, offset, buffer).await?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            buffer = write_from(dst, offset, buffer).await?;
+            offset += buffer.len() as u64;
+        }
+
+        Ok(offset)
+    }
+    .await;
+
+    exit(src, Exit::Normal).await;
+    exit(dst, Exit::Normal).await;
+
+    copied
+}
+
+/// Requests to several file ports, their replies are collected in the order they arrive.
+///
+/// A port that exits doesn't reply, the owner finds out through the link.
+/// When the owner traps exits, `recv_any` returns the exit of a port with outstanding requests.
+#[derive(Default)]
+pub struct PortSet {
+    outstanding: HashMap<Pid, usize>,
+}
+
+impl PortSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a request to a port, its reply is returned by `recv_any`.
+    pub async fn send(&mut self, port: Pid, request: FileRequest) {
+        if !matches!(request, FileRequest::Write { .. }) {
+            *self.outstanding.entry(port).or_default() += 1;
+        }
+
+        send(port, request).await;
+    }
+
+    /// The number of replies that weren't received yet.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.values().sum()
+    }
+
+    /// Wait for the next reply from any port in the set.
+    ///
+    /// Returns `Err` with the exit reason if a port exited instead, its outstanding requests are dropped.
+    /// Returns `None` if no replies are outstanding. Replies from ports outside the set stay in the mailbox.
+    pub async fn recv_any(&mut self) -> Option<Result<(Pid, FileReply), (Pid, Exit)>> {
+        if self.outstanding.is_empty() {
+            return None;
+        }
+
+        let outstanding = &self.outstanding;
+        let Ok(msg) = recv_matching_from(None, |from, msg| {
+            (msg.is::<FileReply>() || msg.is::<TrapExitMessage>())
+                && outstanding.contains_key(&from)
+        })
+        .await
+        else {
+            unreachable!("Receiving without a timeout")
+        };
+
+        let port = sender().expect("Replies are sent by the port");
+
+        let msg = match msg.downcast::<TrapExitMessage>() {
+            Ok(exit) => {
+                self.outstanding.remove(&port);
+                return Some(Err((port, exit.reason)));
+            }
+            Err(msg) => msg,
+        };
+
+        if let Some(count) = self.outstanding.get_mut(&port) {
+            *count -= 1;
+            if *count == 0 {
+                self.outstanding.remove(&port);
+            }
+        }
+
+        Some(Ok((port, *msg.downcast().expect("Matched a FileReply"))))
+    }
 }
 
 pub enum ReadStringError {
@@ -149,3 +374,313 @@ pub async fn read_string(path: impl Into<PathBuf>) -> Result<String, ReadStringE
 
     String::from_utf8(buffer).map_err(|_| ReadStringError::InvalidUtf8)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::atomic::Ordering, time::Duration};
+
+    use crate::{
+        Config, Exit, Pid, TrapExitMessage,
+        global::{self, send, sleep, spawn, spawn_linked, sync},
+        receive,
+    };
+
+    use super::{
+        FileReply, FileRequest, PortSet, copy_file, file_actor, open, read_into, reserve_buffer,
+    };
+
+    #[test]
+    fn port_exit_is_trapped() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                let port = spawn_linked(file_actor("does_not_exist.txt"));
+                send(port, FileRequest::Read { offset: 0, len: 16 }).await;
+
+                let exit = receive! {
+                    match TrapExitMessage {
+                        TrapExitMessage { pid, reason } => (pid, reason),
+                    }
+                };
+
+                completion.complete((port, exit));
+
+                Exit::Normal
+            }
+        });
+
+        let (port, (pid, reason)) = result.unwrap();
+
+        assert_eq!(pid, port);
+        assert!(matches!(reason, Exit::Io(_, io::ErrorKind::NotFound)));
+    }
+
+    #[test]
+    fn read_into_returns_the_exit_of_a_failed_port() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                global::trap_exit(true);
+
+                let port = open("does_not_exist.txt");
+                let buffer = reserve_buffer(16).await;
+
+                completion.complete(read_into(port, 0, buffer).await.err());
+
+                Exit::Normal
+            }
+        });
+
+        assert!(matches!(
+            result,
+            Some(Some(Exit::Io(_, io::ErrorKind::NotFound)))
+        ));
+    }
+
+    #[test]
+    fn read_into_ignores_replies_from_other_senders() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = sync::pid();
+
+                // A stray reply that arrives before the port's own.
+                spawn(async move || {
+                    send(root, FileReply::Read(reserve_buffer(16).await)).await;
+                    send(root, ()).await;
+
+                    Exit::Normal
+                })
+                .await;
+
+                receive! {
+                    match () {
+                        () => (),
+                    }
+                }
+
+                let port = open("Cargo.toml");
+                let Ok(buffer) = read_into(port, 0, reserve_buffer(9).await).await else {
+                    unreachable!("Not trapping exits");
+                };
+
+                completion.complete(buffer.to_vec());
+
+                Exit::Normal
+            }
+        });
+
+        assert!(result.unwrap().starts_with(b"[package]"));
+    }
+
+    #[test]
+    fn port_is_closed_when_owner_exits() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = sync::pid();
+
+                spawn(async move || {
+                    let port = spawn_linked(file_actor("Cargo.toml"));
+                    send(root, port).await;
+
+                    Exit::Normal
+                })
+                .await;
+
+                let port = receive! {
+                    match Pid {
+                        port => port,
+                    }
+                };
+
+                sleep(Duration::from_millis(50)).await;
+
+                let system = unsafe { crate::thread::borrow() };
+                completion.complete(system.registry.lookup_pid(port).is_none());
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(true));
+    }
+
+    #[test]
+    fn port_replies_after_owner_migrates() {
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let port = spawn_linked(file_actor("Cargo.toml"));
+
+                // Move ourselves to the other worker, like stealing or balancing would.
+                let system = unsafe { crate::thread::borrow() };
+                let owner = system.registry.lookup_pid(sync::pid()).unwrap();
+                let worker_id = &owner.control_block().worker_id;
+                let target = (worker_id.load(Ordering::Acquire) + 1) % 2;
+                worker_id.store(target, Ordering::Release);
+
+                send(port, FileRequest::Read { offset: 0, len: 9 }).await;
+
+                let contents = receive! {
+                    match FileReply {
+                        FileReply::Read(buffer) => buffer.to_vec(),
+                        FileReply::Write(_) => Vec::new(),
+                    }
+                };
+
+                completion.complete(contents);
+
+                Exit::Normal
+            }
+        });
+
+        assert!(result.unwrap().starts_with(b"[package]"));
+    }
+
+    #[test]
+    fn port_set_returns_the_exit_of_a_failed_port() {
+        let path =
+            std::env::temp_dir().join(format!("kerosene_port_set_exit_{}", std::process::id()));
+        std::fs::write(&path, "contents").unwrap();
+
+        let result = {
+            let path = path.clone();
+
+            crate::run_until(Config::default(), |completion| {
+                async move || {
+                    global::trap_exit(true);
+
+                    let mut set = PortSet::new();
+                    let good = open(path);
+                    let bad = open("does_not_exist.txt");
+
+                    for port in [good, bad] {
+                        set.send(port, FileRequest::Read { offset: 0, len: 64 })
+                            .await;
+                    }
+
+                    let mut read = None;
+                    let mut failed = None;
+                    while let Some(reply) = set.recv_any().await {
+                        match reply {
+                            Ok((port, FileReply::Read(buffer))) => {
+                                read = Some((port == good, buffer.to_vec()));
+                            }
+                            Ok(_) => panic!("Expected a read reply"),
+                            Err((port, reason)) => failed = Some((port == bad, reason)),
+                        }
+                    }
+
+                    completion.complete((read, failed, set.outstanding()));
+
+                    Exit::Normal
+                }
+            })
+        };
+
+        let _ = std::fs::remove_file(&path);
+
+        let (read, failed, outstanding) = result.unwrap();
+
+        assert_eq!(read, Some((true, b"contents".to_vec())));
+        let (from_bad, reason) = failed.unwrap();
+        assert!(from_bad);
+        assert!(matches!(reason, Exit::Io(_, io::ErrorKind::NotFound)));
+        assert_eq!(outstanding, 0);
+    }
+
+    #[test]
+    fn port_set_reads_files_concurrently() {
+        let dir = std::env::temp_dir();
+        let files = (0..3)
+            .map(|i| {
+                let path = dir.join(format!("kerosene_port_set_{}_{i}", std::process::id()));
+                std::fs::write(&path, format!("contents of file {i}")).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+
+        let result = {
+            let files = files.clone();
+
+            crate::run_until(Config::default(), |completion| {
+                async move || {
+                    let mut set = PortSet::new();
+                    let mut ports = Vec::new();
+
+                    for path in files {
+                        let port = open(path);
+                        set.send(port, FileRequest::Read { offset: 0, len: 64 })
+                            .await;
+                        ports.push(port);
+                    }
+
+                    let mut contents = vec![String::new(); ports.len()];
+                    while let Some(reply) = set.recv_any().await {
+                        let Ok((port, FileReply::Read(buffer))) = reply else {
+                            panic!("Expected a read reply");
+                        };
+
+                        let index = ports.iter().position(|&p| p == port).unwrap();
+                        contents[index] = String::from_utf8(buffer.to_vec()).unwrap();
+                    }
+
+                    completion.complete((contents, set.outstanding()));
+
+                    Exit::Normal
+                }
+            })
+        };
+
+        for path in &files {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let (contents, outstanding) = result.unwrap();
+
+        assert_eq!(
+            contents,
+            [
+                "contents of file 0",
+                "contents of file 1",
+                "contents of file 2"
+            ]
+        );
+        assert_eq!(outstanding, 0);
+    }
+
+    #[test]
+    fn copy_file_is_byte_exact() {
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!("kerosene_copy_src_{}", std::process::id()));
+        let dst = dir.join(format!("kerosene_copy_dst_{}", std::process::id()));
+
+        // A few chunks and a partial one, with a pattern that catches misplaced chunks.
+        let contents = (0..3 * super::CHUNK_SIZE + 123)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&src, &contents).unwrap();
+
+        let copied = {
+            let (src, dst) = (src.clone(), dst.clone());
+
+            crate::run_until(Config::default(), |completion| {
+                async move || {
+                    completion.complete(copy_file(src, dst).await);
+
+                    Exit::Normal
+                }
+            })
+        };
+
+        let output = std::fs::read(&dst).unwrap();
+        let _ = std::fs::remove_file(&src);
+        let _ = std::fs::remove_file(&dst);
+
+        assert!(matches!(copied, Some(Ok(n)) if n == contents.len() as u64));
+        assert!(output == contents);
+    }
+}
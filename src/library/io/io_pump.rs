@@ -7,6 +7,9 @@ pub use windows::Descriptor;
 #[cfg(windows)]
 pub use windows::pump_actor as pump;
 
+#[cfg(windows)]
+pub use windows::{DEFAULT_PUMP_THREADS, pump_with_threads};
+
 use crate::Pid;
 use crate::global::exit;
 use crate::global::send;
@@ -15,6 +18,7 @@ use crate::global::sync::pid;
 use crate::receive;
 
 use super::buffer_pool::Buffer;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 struct OpenRequest {
@@ -26,6 +30,20 @@ struct OpenResponse {
     descriptor: Descriptor,
 }
 
+struct ListenRequest {
+    pid: Pid,
+    address: SocketAddr,
+}
+
+struct AcceptRequest {
+    pid: Pid,
+    listener: Descriptor,
+}
+
+struct AcceptResponse {
+    descriptor: Descriptor,
+}
+
 struct CloseRequest {
     descriptor: Descriptor,
 }
@@ -133,8 +151,52 @@ pub async fn write(descriptor: Descriptor, offset: u64, buffer: Buffer) -> Buffe
     }
 }
 
-// TODO: This might need to be slightly redesigned because apparently `AcceptEx` uses a buffer to capture local and remote addresses?
+/// Listen for connections on the given address.
+pub async fn listen(address: SocketAddr) -> Descriptor {
+    send(
+        "io_pump",
+        ListenRequest {
+            pid: pid(),
+            address,
+        },
+    )
+    .await;
+
+    receive! {
+        match OpenResponse {
+            OpenResponse { descriptor } => descriptor,
+        }
+        match ErrorResponse {
+            ErrorResponse { error } => {
+                exit(pid(), error.into()).await;
+                unreachable!()
+            }
+        }
+    }
+}
+
+/// Wait for a connection on a listener created with `listen`.
+///
+/// The connection can be used with `read` and `write`, the offset is ignored for sockets.
 pub async fn accept(listener: Descriptor) -> Descriptor {
-    let _ = listener;
-    todo!();
+    send(
+        "io_pump",
+        AcceptRequest {
+            pid: pid(),
+            listener,
+        },
+    )
+    .await;
+
+    receive! {
+        match AcceptResponse {
+            AcceptResponse { descriptor } => descriptor,
+        }
+        match ErrorResponse {
+            ErrorResponse { error } => {
+                exit(pid(), error.into()).await;
+                unreachable!()
+            }
+        }
+    }
 }
@@ -1,7 +1,8 @@
 use std::{
     collections::HashMap,
-    io::Error,
-    os::windows::ffi::OsStrExt,
+    io::{Error, ErrorKind},
+    net::TcpListener,
+    os::windows::{ffi::OsStrExt, io::IntoRawSocket},
     path::Path,
     ptr::{null, null_mut},
     sync::Arc,
@@ -11,6 +12,11 @@ use windows_sys::Win32::{
     Foundation::{
         CloseHandle, ERROR_IO_PENDING, FALSE, GetLastError, HANDLE, INVALID_HANDLE_VALUE, TRUE,
     },
+    Networking::WinSock::{
+        AcceptEx, INVALID_SOCKET, IPPROTO_TCP, SO_UPDATE_ACCEPT_CONTEXT, SOCK_STREAM, SOCKADDR,
+        SOCKADDR_STORAGE, SOCKET, SOL_SOCKET, WSA_FLAG_OVERLAPPED, WSASocketW, closesocket,
+        getsockname, setsockopt,
+    },
     Storage::FileSystem::{
         CreateFileW, FILE_FLAG_OVERLAPPED, FILE_GENERIC_READ, FILE_GENERIC_WRITE, FILE_SHARE_READ,
         OPEN_EXISTING, ReadFile, WriteFile,
@@ -25,14 +31,15 @@ use crate::{
     Exit, Pid,
     global::{
         send,
-        sync::{pid, register},
+        sync::{pid, try_register},
     },
     library::io::{
         buffer_pool::Buffer,
         io_pump::{
-            CloseRequest, ErrorResponse, OpenRequest, OpenResponse, ReadRequest, ReadResponse,
-            WriteRequest, WriteResponse,
+            AcceptRequest, AcceptResponse, CloseRequest, ErrorResponse, ListenRequest, OpenRequest,
+            OpenResponse, ReadRequest, ReadResponse, WriteRequest, WriteResponse,
         },
+        spawn_helper,
     },
     receive,
 };
@@ -86,16 +93,34 @@ impl CompletionPort {
             return Err(get_error());
         }
 
+        let descriptor = OpenDescriptor::File(handle);
+        self.associate(handle)?;
+
+        Ok(descriptor)
+    }
+
+    /// Take over a listening socket, its connections can be accepted with `accept`.
+    pub fn listen(&self, listener: TcpListener) -> Result<OpenDescriptor, Error> {
+        // Sockets created by std are overlapped, so they can be used with the completion port.
+        let socket = listener.into_raw_socket() as SOCKET;
+        let descriptor = OpenDescriptor::Socket(socket);
+        self.associate(socket as HANDLE)?;
+
+        Ok(descriptor)
+    }
+
+    fn associate(&self, handle: HANDLE) -> Result<(), Error> {
         let iocp = unsafe { CreateIoCompletionPort(handle, self.handle, 0, 0) };
 
         if iocp == null_mut() {
             return Err(get_error());
         }
 
-        Ok(OpenDescriptor(handle))
+        Ok(())
     }
 
-    fn pump(&self) -> Box<ActiveOperation> {
+    // A failed operation, or a completion that claims more bytes than fit in the buffer, is reported to the requester instead.
+    fn pump(&self) -> Result<Box<ActiveOperation>, (Pid, Error)> {
         let mut bytes_transferred = 0;
         let mut completion_key = 0;
         let mut overlapped = null_mut();
@@ -111,7 +136,19 @@ impl CompletionPort {
 
         if success == FALSE {
             let error = unsafe { GetLastError() };
-            panic!("Pump failed {}", error);
+
+            // Nothing was dequeued, the completion port itself is broken.
+            if overlapped.is_null() {
+                panic!("Pump failed {}", error);
+            }
+
+            // The operation failed, e.g. an accept on a listener that was closed.
+            let operation = unsafe { Box::from_raw(overlapped as *mut ActiveOperation) };
+            if let Operation::Accept(socket) = operation.operation {
+                unsafe { closesocket(socket) };
+            }
+
+            return Err((operation.pid, Error::from_raw_os_error(error as _)));
         }
 
         let mut operation = unsafe { Box::from_raw(overlapped as *mut ActiveOperation) };
@@ -119,13 +156,23 @@ impl CompletionPort {
         operation.start = operation.start.wrapping_add(bytes_transferred as _);
         operation.length -= bytes_transferred as usize;
 
-        unsafe {
-            operation
-                .buffer
-                .set_len(operation.buffer.len() + bytes_transferred as usize);
+        let len = match operation.operation {
+            // The read was issued over the whole uninitialized buffer, only what was transferred is written.
+            Operation::Read => bytes_transferred as usize,
+            _ => operation.buffer.len() + bytes_transferred as usize,
+        };
+        if let Err(error) = unsafe { operation.buffer.try_set_len(len) } {
+            let error = Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Completion of {bytes_transferred} bytes overflows the buffer, {} > {}",
+                    error.len, error.capacity
+                ),
+            );
+            return Err((operation.pid, error));
         }
 
-        operation
+        Ok(operation)
     }
 }
 
@@ -140,34 +187,120 @@ impl Drop for CompletionPort {
 pub enum Operation {
     Read,
     Write,
-    Accept,
+    /// Accepting a connection into the socket, which is owned by the operation until it completes.
+    Accept(SOCKET),
 }
 
+// `AcceptEx` needs room for each address plus 16 bytes.
+const ACCEPT_ADDRESS_LENGTH: usize = size_of::<SOCKADDR_STORAGE>() + 16;
+
 fn read(mut request: ReadRequest) -> Result<(), Error> {
-    request.buffer.resize(0);
+    // `ReadFile` only writes through the pointer, the buffer is truncated to what was read once it completes.
+    let capacity = request.buffer.capacity();
+    unsafe { request.buffer.resize_uninit(capacity) };
 
     let operation = Box::new(ActiveOperation::from(request));
 
+    // Ownership of the operation moves to whichever pump thread dequeues its completion.
+    let raw = Box::into_raw(operation);
     let success = unsafe {
         ReadFile(
-            operation.descriptor.0,
-            operation.start,
-            operation.length as _,
+            (*raw).descriptor.0,
+            (*raw).start,
+            (*raw).length as _,
             null_mut(),
-            Box::into_raw(operation).cast(),
+            raw.cast(),
         )
     };
 
-    let error = unsafe { GetLastError() };
-    if success == FALSE && error != ERROR_IO_PENDING {
+    complete_issue(success, raw)
+}
+
+fn accept(request: AcceptRequest) -> Result<(), Error> {
+    let listener = request.listener.0 as SOCKET;
+
+    let mut address: SOCKADDR_STORAGE = unsafe { std::mem::zeroed() };
+    let mut address_length = size_of::<SOCKADDR_STORAGE>() as i32;
+    if unsafe {
+        getsockname(
+            listener,
+            (&raw mut address).cast::<SOCKADDR>(),
+            &mut address_length,
+        )
+    } != 0
+    {
         return Err(get_error());
     }
 
-    if success == TRUE {
-        return Err(Error::other("Don't know what to do now!"));
+    let socket = unsafe {
+        WSASocketW(
+            address.ss_family as _,
+            SOCK_STREAM,
+            IPPROTO_TCP,
+            null(),
+            0,
+            WSA_FLAG_OVERLAPPED,
+        )
+    };
+
+    if socket == INVALID_SOCKET {
+        return Err(get_error());
     }
 
-    Ok(())
+    let mut operation = Box::new(ActiveOperation::from(request));
+    operation.operation = Operation::Accept(socket);
+
+    // Ownership of the operation moves to whichever pump thread dequeues its completion.
+    // The addresses are written into the operation's buffer, which lives as long as the operation.
+    let raw = Box::into_raw(operation);
+    let mut bytes_received = 0;
+    let success = unsafe {
+        AcceptEx(
+            listener,
+            socket,
+            (*raw).start.cast(),
+            0,
+            ACCEPT_ADDRESS_LENGTH as _,
+            ACCEPT_ADDRESS_LENGTH as _,
+            &mut bytes_received,
+            raw.cast(),
+        )
+    };
+
+    complete_issue(success, raw).inspect_err(|_| unsafe {
+        closesocket(socket);
+    })
+}
+
+// Make an accepted socket usable, it inherits the properties of the listener and gets its own completions.
+fn finish_accept(
+    port: &CompletionPort,
+    operation: &ActiveOperation,
+) -> Result<OpenDescriptor, Error> {
+    let Operation::Accept(socket) = operation.operation else {
+        unreachable!("Not an accept operation");
+    };
+
+    let descriptor = OpenDescriptor::Socket(socket);
+    let listener = operation.descriptor.0 as SOCKET;
+
+    let result = unsafe {
+        setsockopt(
+            socket,
+            SOL_SOCKET,
+            SO_UPDATE_ACCEPT_CONTEXT,
+            (&raw const listener).cast(),
+            size_of::<SOCKET>() as _,
+        )
+    };
+
+    if result != 0 {
+        return Err(get_error());
+    }
+
+    port.associate(socket as HANDLE)?;
+
+    Ok(descriptor)
 }
 
 fn write(request: WriteRequest) -> Result<(), Error> {
@@ -178,26 +311,36 @@ fn write(request: WriteRequest) -> Result<(), Error> {
 }
 
 fn resume_write(operation: Box<ActiveOperation>) -> Result<(), Error> {
+    // Ownership of the operation moves to whichever pump thread dequeues its completion.
+    let raw = Box::into_raw(operation);
     let success = unsafe {
         WriteFile(
-            operation.descriptor.0,
-            operation.start,
-            operation.length as _,
+            (*raw).descriptor.0,
+            (*raw).start,
+            (*raw).length as _,
             null_mut(),
-            Box::into_raw(operation).cast(),
+            raw.cast(),
         )
     };
 
-    let error = unsafe { GetLastError() };
-    if success == FALSE && error != ERROR_IO_PENDING {
-        return Err(get_error());
-    }
+    complete_issue(success, raw)
+}
 
+// An operation that completed synchronously still queues a completion packet,
+// only a failed operation has to be taken back since no pump thread will see it.
+// The operation must not be touched after a successful issue, a pump thread might already own it.
+fn complete_issue(success: i32, raw: *mut ActiveOperation) -> Result<(), Error> {
     if success == TRUE {
-        return Err(Error::other("Don't know what to do now!"));
+        return Ok(());
+    }
+
+    let error = unsafe { GetLastError() };
+    if error == ERROR_IO_PENDING {
+        return Ok(());
     }
 
-    Ok(())
+    drop(unsafe { Box::from_raw(raw) });
+    Err(Error::from_raw_os_error(error as _))
 }
 
 #[repr(C)]
@@ -222,7 +365,7 @@ impl From<ReadRequest> for ActiveOperation {
         Self {
             overlapped,
             start: value.buffer.as_mut_ptr(),
-            length: value.buffer.capacity(),
+            length: value.buffer.len(),
             buffer: value.buffer,
             pid: value.pid,
             descriptor: value.descriptor,
@@ -249,6 +392,23 @@ impl From<WriteRequest> for ActiveOperation {
     }
 }
 
+impl From<AcceptRequest> for ActiveOperation {
+    fn from(value: AcceptRequest) -> Self {
+        let mut buffer = Buffer::new();
+
+        Self {
+            overlapped: Default::default(),
+            start: buffer.as_mut_ptr(),
+            length: 2 * ACCEPT_ADDRESS_LENGTH,
+            buffer,
+            pid: value.pid,
+            descriptor: value.listener,
+            // The socket is created when the accept is issued.
+            operation: Operation::Accept(INVALID_SOCKET),
+        }
+    }
+}
+
 // TODO: Not Currently used yet
 #[derive(Copy, Clone, PartialEq, Hash, Eq)]
 pub struct Descriptor(HANDLE);
@@ -256,56 +416,116 @@ pub struct Descriptor(HANDLE);
 unsafe impl Send for Descriptor {}
 unsafe impl Sync for Descriptor {}
 
-pub struct OpenDescriptor(HANDLE);
+pub enum OpenDescriptor {
+    File(HANDLE),
+    Socket(SOCKET),
+}
 
 unsafe impl Send for OpenDescriptor {}
 
+impl OpenDescriptor {
+    pub fn descriptor(&self) -> Descriptor {
+        match self {
+            OpenDescriptor::File(handle) => Descriptor(*handle),
+            OpenDescriptor::Socket(socket) => Descriptor(*socket as HANDLE),
+        }
+    }
+}
+
 impl Drop for OpenDescriptor {
     fn drop(&mut self) {
-        unsafe {
-            CloseHandle(self.0);
+        match self {
+            OpenDescriptor::File(handle) => unsafe {
+                CloseHandle(*handle);
+            },
+            OpenDescriptor::Socket(socket) => unsafe {
+                closesocket(*socket);
+            },
+        }
+    }
+}
+
+// A connection accepted by a pump thread, the pump actor takes ownership before replying.
+struct Accepted {
+    pid: Pid,
+    descriptor: OpenDescriptor,
+}
+
+/// The number of threads draining the completion port, see [`pump_with_threads`].
+pub const DEFAULT_PUMP_THREADS: usize = 2;
+
+// Drain completions and hand every operation back to the actor that requested it.
+fn pump_thread(port: Arc<CompletionPort>) {
+    loop {
+        let operation = match port.pump() {
+            Ok(operation) => operation,
+            Err((pid, error)) => {
+                crate::global::sync::send(pid, ErrorResponse { error });
+                continue;
+            }
+        };
+
+        match operation.operation {
+            Operation::Read => {
+                crate::global::sync::send(
+                    operation.pid,
+                    ReadResponse {
+                        buffer: operation.buffer,
+                    },
+                );
+            }
+            Operation::Write => {
+                if operation.length == 0 {
+                    crate::global::sync::send(
+                        operation.pid,
+                        WriteResponse {
+                            buffer: operation.buffer,
+                        },
+                    );
+                } else {
+                    let pid = operation.pid;
+                    if let Err(error) = resume_write(operation) {
+                        crate::global::sync::send(pid, ErrorResponse { error });
+                    }
+                }
+            }
+            Operation::Accept(_) => match finish_accept(&port, &operation) {
+                Ok(descriptor) => {
+                    crate::global::sync::send(
+                        "io_pump",
+                        Accepted {
+                            pid: operation.pid,
+                            descriptor,
+                        },
+                    );
+                }
+                Err(error) => {
+                    crate::global::sync::send(operation.pid, ErrorResponse { error });
+                }
+            },
         }
     }
 }
 
 pub async fn pump_actor() -> Exit {
-    register("io_pump", pid());
+    pump_with_threads(DEFAULT_PUMP_THREADS).await
+}
+
+/// Run the io pump with the given number of threads draining the completion port.
+///
+/// Each completion is dequeued by exactly one thread, so any of them can answer any request.
+pub async fn pump_with_threads(threads: usize) -> Exit {
+    if try_register("io_pump", pid()).is_err() {
+        // Another pump owns the name, a second completion port would never get any requests.
+        return Exit::Normal;
+    }
 
     let mut descriptors = HashMap::<Descriptor, OpenDescriptor>::new();
     let port = Arc::new(CompletionPort::new());
 
-    // TODO: Spawn more than 1 and move to their own actors
-    {
+    for _ in 0..threads.max(1) {
         let port = port.clone();
-        crate::thread::spawn(move || {
-            loop {
-                let operation = port.pump();
-
-                match operation.operation {
-                    Operation::Read => {
-                        crate::global::sync::send(
-                            operation.pid,
-                            ReadResponse {
-                                buffer: operation.buffer,
-                            },
-                        );
-                    }
-                    Operation::Write => {
-                        if operation.length == 0 {
-                            crate::global::sync::send(
-                                operation.pid,
-                                WriteResponse {
-                                    buffer: operation.buffer,
-                                },
-                            );
-                        } else {
-                            resume_write(operation).unwrap();
-                        }
-                    }
-                    Operation::Accept => todo!(),
-                }
-            }
-        });
+        spawn_helper(pid(), move || pump_thread(port));
     }
 
     loop {
@@ -314,7 +534,22 @@ pub async fn pump_actor() -> Exit {
                 req => {
                     handle_errors(req.pid, async || {
                         let open_descriptor = port.open_file(req.path)?; // TODO: HANDLE ME
-                        let descriptor = Descriptor(open_descriptor.0);
+                        let descriptor = open_descriptor.descriptor();
+                        descriptors.insert(descriptor, open_descriptor);
+
+                        send(req.pid, OpenResponse {
+                            descriptor,
+                        }).await;
+
+                        Ok(())
+                    }).await;
+                }
+            }
+            match ListenRequest {
+                req => {
+                    handle_errors(req.pid, async || {
+                        let open_descriptor = port.listen(TcpListener::bind(req.address)?)?;
+                        let descriptor = open_descriptor.descriptor();
                         descriptors.insert(descriptor, open_descriptor);
 
                         send(req.pid, OpenResponse {
@@ -325,6 +560,19 @@ pub async fn pump_actor() -> Exit {
                     }).await;
                 }
             }
+            match AcceptRequest {
+                req => {
+                    handle_errors(req.pid, async || accept(req)).await;
+                }
+            }
+            match Accepted {
+                accepted => {
+                    let descriptor = accepted.descriptor.descriptor();
+                    descriptors.insert(descriptor, accepted.descriptor);
+
+                    send(accepted.pid, AcceptResponse { descriptor }).await;
+                }
+            }
             match CloseRequest {
                 req => {
                     let _ = descriptors.remove(&req.descriptor);
@@ -363,7 +611,7 @@ mod tests {
     pub fn read_test() {
         let port = CompletionPort::new();
         let open_file = port.open_file("Cargo.toml").unwrap();
-        let file = Descriptor(open_file.0);
+        let file = open_file.descriptor();
         let request = ReadRequest {
             buffer: Buffer::new(),
             descriptor: file,
@@ -372,8 +620,126 @@ mod tests {
         };
 
         read(request).unwrap();
-        let operation = port.pump();
+        let operation = port.pump().unwrap();
         let buf = std::str::from_utf8(&operation.buffer).unwrap();
         println!("{}", buf);
     }
+
+    #[test]
+    pub fn failed_accept_is_reported() {
+        let port = CompletionPort::new();
+        let listener = port
+            .listen(TcpListener::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+
+        accept(AcceptRequest {
+            pid: Pid::invalid(),
+            listener: listener.descriptor(),
+        })
+        .unwrap();
+
+        // Closing the listener aborts the pending accept.
+        drop(listener);
+
+        assert!(matches!(port.pump(), Err((pid, _)) if pid == Pid::invalid()));
+    }
+
+    #[test]
+    pub fn concurrent_pumps_test() {
+        const READS: usize = 256;
+        const THREADS: usize = 4;
+
+        let contents = std::fs::read("Cargo.toml").unwrap();
+        let port = Arc::new(CompletionPort::new());
+        let open_file = port.open_file("Cargo.toml").unwrap();
+        let file = open_file.descriptor();
+
+        // Every pump claims a completion before waiting on one, so all threads return.
+        let remaining = Arc::new(std::sync::atomic::AtomicUsize::new(READS));
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let pumps = (0..THREADS)
+            .map(|_| {
+                let port = port.clone();
+                let remaining = remaining.clone();
+                let tx = tx.clone();
+
+                std::thread::spawn(move || {
+                    while remaining
+                        .fetch_update(
+                            std::sync::atomic::Ordering::AcqRel,
+                            std::sync::atomic::Ordering::Acquire,
+                            |n| n.checked_sub(1),
+                        )
+                        .is_ok()
+                    {
+                        let operation = port.pump().unwrap();
+                        let offset =
+                            unsafe { operation.overlapped.Anonymous.Anonymous.Offset as usize };
+
+                        tx.send((offset, operation.buffer.to_vec())).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 0..READS {
+            let request = ReadRequest {
+                buffer: Buffer::new(),
+                descriptor: file,
+                offset: (i % contents.len()) as u64,
+                pid: Pid::invalid(),
+            };
+
+            read(request).unwrap();
+        }
+
+        for pump in pumps {
+            pump.join().unwrap();
+        }
+
+        drop(tx);
+        let results = rx.iter().collect::<Vec<_>>();
+
+        assert_eq!(results.len(), READS);
+        for (offset, buffer) in results {
+            let expected = &contents[offset..contents.len().min(offset + buffer.len())];
+            assert_eq!(buffer.as_slice(), expected);
+            assert!(!buffer.is_empty());
+        }
+    }
+
+    #[test]
+    pub fn accept_test() {
+        use std::io::Write;
+
+        let port = CompletionPort::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let open_listener = port.listen(listener).unwrap();
+
+        accept(AcceptRequest {
+            pid: Pid::invalid(),
+            listener: open_listener.descriptor(),
+        })
+        .unwrap();
+
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+
+        let operation = port.pump().unwrap();
+        let connection = finish_accept(&port, &operation).unwrap();
+
+        client.write_all(b"hello").unwrap();
+
+        read(ReadRequest {
+            buffer: Buffer::new(),
+            descriptor: connection.descriptor(),
+            offset: 0,
+            pid: Pid::invalid(),
+        })
+        .unwrap();
+
+        let operation = port.pump().unwrap();
+        assert_eq!(&*operation.buffer, b"hello");
+    }
 }
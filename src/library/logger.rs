@@ -14,16 +14,27 @@
 //!
 //! There is system level metadata always availble, see `LogBuilder::emit` for details.
 
-use std::{fmt::Display, panic::Location};
+use std::{fmt::Display, io::Write, panic::Location, sync::Mutex, thread};
 
 use crate::{
-    Exit,
-    global::sync::{self, metadata, pid, register},
+    Exit, Pid, global,
+    global::sync::{self, metadata, pid, try_register},
     metadata::{MetaKeyValue, MetaValue},
     receive,
-    utils::{Timestamp, UnsortedSet},
+    utils::{self, Timestamp, UnsortedSet},
 };
 
+pub use crate::utils::TimeFormat;
+
+static TIME_FORMAT: Mutex<TimeFormat> = Mutex::new(TimeFormat::Utc);
+
+/// Set how timestamps in log messages are displayed, e.g. to log in local time.
+///
+/// This applies to every logger in the process. Timestamps are still recorded in UTC.
+pub fn set_time_format(format: TimeFormat) {
+    *utils::lock(&TIME_FORMAT) = format;
+}
+
 enum LogMessage {
     Log(Record),
 }
@@ -65,7 +76,7 @@ struct Record {
 
 /// Allows building a log message with metadata.
 ///
-/// This is safe to use from any unmanaged thread.
+/// This is safe to use from any unmanaged thread, see `LogBuilder::emit`.
 #[must_use]
 pub struct LogBuilder {
     logger: &'static str,
@@ -100,13 +111,19 @@ impl LogBuilder {
         }
     }
 
+    /// Send the log message to the logger registered under `name`, instead of the default one.
+    pub fn to(mut self, name: &'static str) -> Self {
+        self.logger = name;
+        self
+    }
+
     /// Add metadata to the log message
     pub fn with(mut self, key: &'static str, value: impl Into<MetaValue>) -> Self {
         let meta = MetaKeyValue {
             key,
             value: value.into(),
         };
-        self.values.insert(meta);
+        self.values.upsert(meta);
         self
     }
 
@@ -114,10 +131,17 @@ impl LogBuilder {
     ///
     /// This will insert the following metadata:
     /// - time: The current timestamp
-    /// - pid: The process ID
+    /// - pid: The process ID, or the name or id of the thread when not emitted by an actor
     /// - file: The file name where the log was emitted
     /// - line: The line number where the log was emitted
+    ///
+    /// Logs emitted from a thread that wasn't started with `thread::spawn` can't reach a logger
+    /// and are dropped.
     pub fn emit(self) {
+        if !crate::thread::has_system() {
+            return;
+        }
+
         let location = self.location;
         let mut values = self.values;
 
@@ -128,7 +152,7 @@ impl LogBuilder {
 
         values.insert(MetaKeyValue {
             key: "pid",
-            value: pid().into(),
+            value: origin(),
         });
 
         values.insert(MetaKeyValue {
@@ -152,6 +176,19 @@ impl LogBuilder {
     }
 }
 
+/// The pid of the current actor, or the current thread on an unmanaged thread.
+fn origin() -> MetaValue {
+    if global::has_context() {
+        return pid().into();
+    }
+
+    let thread = thread::current();
+    match thread.name() {
+        Some(name) => format!("thread {name}").into(),
+        None => format!("thread {:?}", thread.id()).into(),
+    }
+}
+
 /// Create a new log builder with the 'debug' level.
 #[track_caller]
 pub fn debug(message: &'static str) -> LogBuilder {
@@ -200,16 +237,113 @@ pub fn emergency(message: &'static str) -> LogBuilder {
     LogBuilder::with_location(Location::caller(), Level::Emergency, message)
 }
 
+/// Creates log builders that send to a specific logger, see [`with_logger`].
+#[derive(Clone, Copy, Debug)]
+pub struct Logger {
+    name: &'static str,
+}
+
+impl Logger {
+    /// Create a new log builder with the 'debug' level.
+    #[track_caller]
+    pub fn debug(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Debug, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'info' level.
+    #[track_caller]
+    pub fn info(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Info, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'notice' level.
+    #[track_caller]
+    pub fn notice(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Notice, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'warning' level.
+    #[track_caller]
+    pub fn warning(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Warning, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'error' level.
+    #[track_caller]
+    pub fn error(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Error, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'critical' level.
+    #[track_caller]
+    pub fn critical(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Critical, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'alert' level.
+    #[track_caller]
+    pub fn alert(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Alert, message).to(self.name)
+    }
+
+    /// Create a new log builder with the 'emergency' level.
+    #[track_caller]
+    pub fn emergency(&self, message: &'static str) -> LogBuilder {
+        LogBuilder::with_location(Location::caller(), Level::Emergency, message).to(self.name)
+    }
+}
+
+/// Log to the logger registered under `name` instead of the default one.
+///
+/// Keep the returned `Logger` around to give a subtree of actors its own log handler,
+/// start that handler with `spawn_named`.
+pub fn with_logger(name: &'static str) -> Logger {
+    Logger { name }
+}
+
+/// Spawn a logger registered under `name`, to log to with `with_logger`.
+///
+/// Log lines are written to `output`, e.g. `std::io::stdout()` or the write end of a capture port.
+/// Returns the pid of the logger already registered under `name` if there is one.
+pub async fn spawn_named<W>(name: &'static str, mut output: W) -> Result<Pid, Pid>
+where
+    W: Write + Send + 'static,
+{
+    let logger = global::spawn(async move || {
+        write_logs(move |line| {
+            let _ = writeln!(output, "{line}");
+        })
+        .await
+    })
+    .await;
+
+    // Registered before any log can be sent to it, the logger hasn't run yet.
+    match try_register(name, logger) {
+        Ok(()) => Ok(logger),
+        Err(existing) => {
+            sync::kill(logger);
+            Err(existing)
+        }
+    }
+}
+
 /// The Logger actor.
 pub(crate) async fn logger_actor() -> Exit {
-    register("logger", pid());
+    if try_register("logger", pid()).is_err() {
+        // Another logger is running already.
+        return Exit::Normal;
+    }
+
+    write_logs(|line| println!("{line}")).await
+}
 
+async fn write_logs(mut write_line: impl FnMut(String)) -> Exit {
     loop {
         receive! {
             match LogMessage {
                 LogMessage::Log(log) => {
                     let message = parse(log.message, &log.values);
-                    println!("[{}] {}", log.level, message);
+                    write_line(format!("[{}] {}", log.level, message));
                 }
             }
             else {
@@ -226,70 +360,316 @@ fn find_key<'a, const N: usize>(
     values.iter().find(|meta| meta.key == key)
 }
 
-// TODO: Rewrite this to be less spaghetti
+/// Whether a character can be part of a placeholder key.
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Replace the placeholders in a message with their values.
+///
+/// - `{key}` is replaced with the value of `key`, unknown keys and `{}` are kept as they are.
+/// - `{{` and `}}` are a literal `{` and `}`, a lone `}` is kept as well.
+/// - A `{` that doesn't start a valid placeholder, e.g. because it's never closed, is kept as text.
 fn parse<const N: usize>(msg: &'static str, values: &UnsortedSet<MetaKeyValue, N>) -> String {
+    #[derive(Clone, Copy)]
+    enum State {
+        Text,
+        /// Inside a placeholder that started at the given byte offset.
+        Key(usize),
+    }
+
     let mut result = String::with_capacity(msg.len());
+    let mut state = State::Text;
     let mut chars = msg.char_indices().peekable();
 
     while let Some((i, c)) = chars.next() {
-        if c == '{' {
-            if let Some(&(_, next)) = chars.peek() {
-                if next == '{' {
-                    result.push('{');
-                    chars.next();
-                    continue;
+        if let State::Key(start) = state {
+            if c == '}' {
+                let key = &msg[start + 1..i];
+                match find_key(key, values) {
+                    Some(meta) if !key.is_empty() => push_value(&mut result, &meta.value),
+                    _ => result.push_str(&msg[start..=i]),
                 }
+
+                state = State::Text;
+                continue;
+            }
+
+            if is_key_char(c) {
+                continue;
+            }
+
+            // Not a placeholder after all, keep it and handle `c` as text.
+            result.push_str(&msg[start..i]);
+            state = State::Text;
+        }
+
+        match c {
+            '{' if chars.next_if(|&(_, next)| next == '{').is_some() => result.push('{'),
+            '{' => state = State::Key(i),
+            '}' => {
+                chars.next_if(|&(_, next)| next == '}');
+                result.push('}');
             }
+            c => result.push(c),
+        }
+    }
+
+    if let State::Key(start) = state {
+        result.push_str(&msg[start..]);
+    }
+
+    result
+}
 
-            let start = i + 1;
-            let mut end = None;
-            while let Some((j, d)) = chars.next() {
-                if d == '}' {
-                    // handle escaped '}}'
-                    if let Some(&(_, next)) = chars.peek() {
-                        if next == '}' {
-                            chars.next(); // skip one '}'
+fn push_value(result: &mut String, value: &MetaValue) {
+    match value {
+        MetaValue::Timestamp(timestamp) => {
+            result.push_str(&timestamp.format(*utils::lock(&TIME_FORMAT)));
+        }
+        value => result.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        Config, DownMessage, Exit, MailboxOverflowPolicy, TrapExitMessage,
+        global::{self, SpawnOptions},
+        receive,
+        utils::UnsortedSet,
+    };
+
+    use crate::library::io::capture::{self, Line};
+
+    use super::{Level, LogMessage, MetaKeyValue, info, parse, spawn_named, with_logger};
+
+    #[test]
+    fn targeted_logs_reach_only_their_logger() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = global::sync::pid();
+
+                // Take over from the real logger to see what reaches it.
+                global::sync::register("logger", root);
+
+                let (_port, output) = capture::open().unwrap();
+                let tenant = spawn_named("tenant_a_logger", output).await.unwrap();
+                assert_eq!(
+                    spawn_named("tenant_a_logger", std::io::sink()).await,
+                    Err(tenant)
+                );
+
+                with_logger("tenant_a_logger")
+                    .warning("to {tenant}")
+                    .with("tenant", "tenant a")
+                    .emit();
+                info("to default").emit();
+
+                let mut default = None;
+                let mut targeted = None;
+                while default.is_none() || targeted.is_none() {
+                    receive! {
+                        match LogMessage {
+                            LogMessage::Log(record) => {
+                                assert!(default.replace(record.message).is_none());
+                            }
+                        }
+                        match Line {
+                            Line(line) => {
+                                assert!(targeted.replace(line).is_none());
+                            }
                         }
                     }
-
-                    end = Some(j);
-                    break;
                 }
+
+                completion.complete((default, targeted));
+
+                Exit::Normal
             }
+        });
 
-            if let Some(end) = end {
-                let key = &msg[start..end];
-                if let Some(meta) = find_key(key, values) {
-                    result.push_str(&meta.value.to_string());
-                } else {
-                    result.push('{');
-                    result.push_str(key);
-                    result.push('}');
-                }
+        let (default, targeted) = result.unwrap();
+
+        assert_eq!(default, Some("to default"));
+        assert_eq!(targeted.as_deref(), Some("[WARNING] to tenant a"));
+    }
+
+    #[test]
+    fn unmanaged_threads_log_with_their_thread() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                // Take over from the real logger to see what reaches it.
+                global::sync::register("logger", global::sync::pid());
+
+                let expected = crate::thread::spawn(|| {
+                    info("off actor").emit();
+                    format!("thread {:?}", std::thread::current().id())
+                })
+                .join()
+                .unwrap();
+
+                // Without a system there is no logger to reach, this shouldn't panic.
+                std::thread::spawn(|| info("no system").emit())
+                    .join()
+                    .unwrap();
+
+                let origin = receive! {
+                    match LogMessage {
+                        LogMessage::Log(record) => {
+                            (record.message, parse("{pid}", &record.values))
+                        }
+                    }
+                };
+
+                completion.complete((origin, expected));
+
+                Exit::Normal
             }
-        } else if c == '}' {
-            if let Some(&(_, next)) = chars.peek() {
-                if next == '}' {
-                    result.push('}');
-                    chars.next();
-                    continue;
+        });
+
+        let ((message, pid), expected) = result.unwrap();
+
+        assert_eq!(message, "off actor");
+        assert_eq!(pid, expected);
+    }
+
+    #[test]
+    fn mailbox_overflow_alerts_and_kills() {
+        let config = Config {
+            mailbox_ceiling: 64,
+            mailbox_overflow_policy: MailboxOverflowPolicy::Kill,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                // Take over from the real logger to see the alert.
+                global::sync::register("logger", global::sync::pid());
+                global::trap_exit(true);
+
+                // Never receives what it is sent.
+                let flooded = global::spawn_linked(async || {
+                    receive! {
+                        match () {
+                            () => Exit::Normal,
+                        }
+                    }
+                });
+
+                for n in 0..100u32 {
+                    global::sync::send(flooded, n);
                 }
+
+                let outcome = {
+                    let mut alerts = Vec::new();
+
+                    let reason = loop {
+                        receive! {
+                            match LogMessage {
+                                LogMessage::Log(record) => {
+                                    if record.level == Level::Alert {
+                                        alerts.push(parse(record.message, &record.values));
+                                    }
+                                }
+                            }
+                            match TrapExitMessage {
+                                TrapExitMessage { pid, reason } => {
+                                    if pid == flooded {
+                                        break reason;
+                                    }
+                                }
+                            }
+                        }
+                    };
+
+                    (alerts, reason, flooded)
+                };
+
+                completion.complete(outcome);
+
+                Exit::Normal
             }
+        });
 
-            result.push('}'); // unmatched `}`, maybe invalid, but keep
-        } else {
-            result.push(c);
-        }
+        let (alerts, reason, flooded) = result.unwrap();
+
+        assert_eq!(
+            alerts,
+            [format!(
+                "Mailbox of {} overflowed with 64 messages",
+                flooded.0
+            )]
+        );
+        assert_eq!(reason, Exit::Killed);
     }
 
-    result
-}
+    #[test]
+    fn trace_id_is_propagated_to_the_receivers_logs() {
+        struct Request;
 
-#[cfg(test)]
-mod tests {
-    use crate::utils::UnsortedSet;
+        let result = Arc::new(Mutex::new(Vec::new()));
+
+        let config = Config {
+            propagate_metadata: vec!["trace_id"],
+            ..Config::default()
+        };
+
+        {
+            let result = result.clone();
+
+            crate::run_with(config, async move || {
+                let receiver = global::spawn(async move || {
+                    for _ in 0..2 {
+                        receive! {
+                            match Request {
+                                Request => {
+                                    let log = info("handling {trace_id}");
+                                    result.lock().unwrap().push(parse(log.message, &log.values));
+                                }
+                            }
+                        }
+                    }
 
-    use super::{MetaKeyValue, parse};
+                    global::sync::stop();
+                    Exit::Normal
+                })
+                .await;
+
+                // Sent by an actor that has a trace id.
+                let traced = global::spawn_opts(
+                    async move || {
+                        global::insert_metadata("trace_id", "abc");
+                        global::send(receiver, Request).await;
+                        Exit::Normal
+                    },
+                    SpawnOptions {
+                        monitor: true,
+                        ..SpawnOptions::default()
+                    },
+                )
+                .await;
+
+                // Its request is queued once it is down. A reply from it would carry the trace id here.
+                receive! {
+                    match DownMessage {
+                        DownMessage { pid, .. } if *pid == traced.pid => (),
+                    }
+                }
+
+                // Sent without one, the previous trace id is gone.
+                global::send(receiver, Request).await;
+
+                Exit::Normal
+            });
+        }
+
+        let result = result.lock().unwrap();
+
+        assert_eq!(*result, ["handling abc", "handling {trace_id}"]);
+    }
 
     #[test]
     fn test_parse() {
@@ -302,6 +682,39 @@ mod tests {
         });
 
         let parsed = parse(msg, &values);
-        assert_eq!(parsed, "Hello John!");
+        assert_eq!(parsed, "Hello John {last_name} John!");
+    }
+
+    #[test]
+    fn test_parse_edge_cases() {
+        let mut values = UnsortedSet::<MetaKeyValue, 16>::new();
+        values.insert(MetaKeyValue {
+            key: "name",
+            value: "John".into(),
+        });
+
+        let cases = [
+            ("", ""),
+            ("{name}", "John"),
+            ("{}", "{}"),
+            ("trailing {", "trailing {"),
+            ("unterminated {name", "unterminated {name"),
+            ("{{name}}", "{name}"),
+            ("{{{name}}}", "{John}"),
+            ("{{", "{"),
+            ("}}", "}"),
+            ("lone } brace", "lone } brace"),
+            ("{na{name}", "{naJohn"),
+            ("{ {name}", "{ John"),
+            ("{na me}", "{na me}"),
+            ("{name}}", "John}"),
+            ("{unknown}", "{unknown}"),
+            ("{name}{name}", "JohnJohn"),
+            ("ünïcode {name} ✓", "ünïcode John ✓"),
+        ];
+
+        for (msg, expected) in cases {
+            assert_eq!(parse(msg, &values), expected, "parsing {msg:?}");
+        }
     }
 }
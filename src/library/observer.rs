@@ -0,0 +1,99 @@
+//! Periodic runtime telemetry.
+//!
+//! The observer logs a snapshot of [`crate::global::stats`] at a fixed interval.
+//! Enable it with `Config::observer_interval`, it is registered as `"observer"`.
+
+use std::time::Duration;
+
+use crate::{
+    Exit, IntoAsyncActor,
+    global::{
+        self,
+        sync::{pid, try_register},
+    },
+    library::logger::info,
+};
+
+const NAME: &str = "observer";
+
+pub(crate) fn observer(interval: Duration) -> impl IntoAsyncActor {
+    async move || -> Exit {
+        if try_register(NAME, pid()).is_err() {
+            // Another observer is running already.
+            return Exit::Normal;
+        }
+
+        loop {
+            global::sleep(interval).await;
+
+            // Nothing is sent to the observer, drop whatever arrives anyway.
+            global::flush_all();
+
+            let stats = global::stats();
+
+            info("Runtime: {processes} processes on {workers} workers, run queues {run_queue_lengths}, {pending_timers} pending timers, {balances} rebalances")
+                .with("processes", stats.processes as u64)
+                .with("workers", stats.workers as u64)
+                .with("run_queue_lengths", format!("{:?}", stats.run_queue_lengths))
+                .with("pending_timers", stats.pending_timers as u64)
+                .with("balances", stats.balances)
+                .emit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        Config, Exit, global,
+        library::{
+            io::capture::{self, Line},
+            logger::spawn_named,
+        },
+        receive,
+    };
+
+    #[test]
+    fn observer_logs_runtime_telemetry() {
+        let config = Config {
+            workers: 2,
+            observer_interval: Some(Duration::from_millis(5)),
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                // Take over from the real logger with one that writes to us, to see the telemetry.
+                let (_port, output) = capture::open().unwrap();
+                let logger = spawn_named("observed_logger", output).await.unwrap();
+                global::sync::register("logger", logger);
+
+                let telemetry = loop {
+                    receive! {
+                        match Line {
+                            Line(line) => {
+                                if let Some(start) = line.find("Runtime: ") {
+                                    break line[start..].to_string();
+                                }
+                            }
+                        }
+                    }
+                };
+
+                completion.complete(telemetry);
+
+                Exit::Normal
+            }
+        });
+
+        let telemetry = result.unwrap();
+
+        assert!(
+            telemetry.contains(" processes on 2 workers, run queues ["),
+            "{telemetry}"
+        );
+        assert!(!telemetry.contains('{'), "{telemetry}");
+    }
+}
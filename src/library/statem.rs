@@ -0,0 +1,200 @@
+//! State machine actors.
+//!
+//! A [`StateMachine`] handles messages in explicit states, the actor loop takes care of receiving.
+//! Use [`into_statem`] to turn a state machine into an actor.
+
+use crate::{
+    actor::{Exit, Pid},
+    async_actor::{IntoAsyncActor, SimpleActor, into_actor},
+};
+
+/// What a state machine does after handling a message.
+pub enum Transition<S> {
+    /// Stay in the current state, `leave` and `enter` are not called.
+    Keep,
+    /// Leave the current state and enter the given one.
+    Next(S),
+    /// Stop the actor with the given reason, `leave` is not called.
+    Stop(Exit),
+}
+
+pub trait StateMachine: Send + 'static + Sized {
+    type State: Send + 'static;
+    type Message: Send + 'static;
+
+    fn handle(
+        &mut self,
+        state: &mut Self::State,
+        message: Self::Message,
+    ) -> impl Future<Output = Transition<Self::State>> + Send;
+
+    /// Called when a state is entered, including the initial state when the actor starts.
+    fn enter(&mut self, state: &mut Self::State) -> impl Future<Output = ()> + Send {
+        let _ = state;
+        async {}
+    }
+
+    /// Called before the machine moves to another state.
+    fn leave(&mut self, state: &mut Self::State) -> impl Future<Output = ()> + Send {
+        let _ = state;
+        async {}
+    }
+
+    /// Called for exit signals of linked actors, only if the actor traps exits.
+    fn on_exit(
+        &mut self,
+        state: &mut Self::State,
+        from: Pid,
+        reason: Exit,
+    ) -> impl Future<Output = Transition<Self::State>> + Send {
+        let _ = (state, from);
+        async { Transition::Stop(reason) }
+    }
+}
+
+struct Statem<M>
+where
+    M: StateMachine,
+{
+    machine: M,
+    state: M::State,
+}
+
+impl<M> Statem<M>
+where
+    M: StateMachine,
+{
+    async fn apply(&mut self, transition: Transition<M::State>) -> Option<Exit> {
+        match transition {
+            Transition::Keep => None,
+            Transition::Next(next) => {
+                self.machine.leave(&mut self.state).await;
+                self.state = next;
+                self.machine.enter(&mut self.state).await;
+
+                None
+            }
+            Transition::Stop(reason) => Some(reason),
+        }
+    }
+}
+
+impl<M> SimpleActor for Statem<M>
+where
+    M: StateMachine,
+{
+    type Message = M::Message;
+
+    async fn started(&mut self) -> Option<Exit> {
+        self.machine.enter(&mut self.state).await;
+
+        None
+    }
+
+    async fn handle(&mut self, message: Self::Message) -> Option<Exit> {
+        let transition = self.machine.handle(&mut self.state, message).await;
+        self.apply(transition).await
+    }
+
+    async fn on_exit(&mut self, from: Pid, reason: Exit) -> Option<Exit> {
+        let transition = self.machine.on_exit(&mut self.state, from, reason).await;
+        self.apply(transition).await
+    }
+}
+
+/// Turn a state machine into an actor that starts in the given state.
+pub fn into_statem<M>(machine: M, initial_state: M::State) -> impl IntoAsyncActor
+where
+    M: StateMachine,
+{
+    into_actor(Statem {
+        machine,
+        state: initial_state,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{Config, Exit, global};
+
+    use super::{StateMachine, Transition, into_statem};
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum State {
+        Locked,
+        Unlocked,
+    }
+
+    enum Event {
+        Coin,
+        Push,
+        Done,
+    }
+
+    struct Turnstile {
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StateMachine for Turnstile {
+        type State = State;
+        type Message = Event;
+
+        async fn handle(&mut self, state: &mut State, message: Event) -> Transition<State> {
+            match (*state, message) {
+                (State::Locked, Event::Coin) => Transition::Next(State::Unlocked),
+                (State::Unlocked, Event::Push) => Transition::Next(State::Locked),
+                (_, Event::Coin | Event::Push) => Transition::Keep,
+                (_, Event::Done) => {
+                    global::sync::stop();
+                    Transition::Stop(Exit::Normal)
+                }
+            }
+        }
+
+        async fn enter(&mut self, state: &mut State) {
+            self.log.lock().unwrap().push(format!("enter {state:?}"));
+        }
+
+        async fn leave(&mut self, state: &mut State) {
+            self.log.lock().unwrap().push(format!("leave {state:?}"));
+        }
+    }
+
+    #[test]
+    fn turnstile() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let log = log.clone();
+
+            crate::run_with(Config::default(), async move || {
+                let turnstile = global::spawn(into_statem(Turnstile { log }, State::Locked)).await;
+
+                for event in [
+                    Event::Push,
+                    Event::Coin,
+                    Event::Coin,
+                    Event::Push,
+                    Event::Done,
+                ] {
+                    global::send(turnstile, event).await;
+                }
+
+                Exit::Normal
+            });
+        }
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "enter Locked",
+                "leave Locked",
+                "enter Unlocked",
+                "leave Unlocked",
+                "enter Locked",
+            ]
+        );
+    }
+}
@@ -1,9 +1,14 @@
+use std::time::Duration;
+
 use crate::{
-    actor::{Exit, Pid, Signal},
+    actor::{Exit, Pid},
     async_actor::{IntoAsyncActor, SimpleActor, into_actor},
-    global,
+    global::{self, ReplyHandle},
 };
 
+/// How long a child gets to exit during a shutdown before it is killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 type Factory = Box<dyn Fn() -> Pid + Send + 'static>;
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -18,30 +23,58 @@ enum ChildState {
 pub enum RestartPolicy {
     Permanent,
     Transient,
+    /// Like `Transient`, but the function decides which other reasons count as normal.
+    ///
+    /// Use this for custom exit reasons that shouldn't cause a restart.
+    TransientWith(fn(&Exit) -> bool),
     Temporary,
 }
 
+/// Identifies a child of a supervisor, unlike its pid this stays the same across restarts.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ChildId {
+    /// A child started with [`Supervisor::supervise_named`].
+    Named(&'static str),
+    /// Any other child, numbered in the order they were supervised.
+    Index(usize),
+}
+
 struct Child {
+    id: ChildId,
     pid: Pid,
     factory: Factory,
     policy: RestartPolicy,
     state: ChildState,
+    /// Whether the child counts towards `AutoShutdown`.
+    significant: bool,
 }
 
-impl Child {
+impl RestartPolicy {
     fn should_restart(&self, reason: &Exit) -> bool {
-        match self.policy {
+        match self {
             RestartPolicy::Permanent => true,
+            // Every other reason, including `Exit::Io` and `Exit::Panic`, is abnormal.
             RestartPolicy::Transient => !matches!(reason, Exit::Normal | Exit::Shutdown),
+            RestartPolicy::TransientWith(is_normal) => {
+                !matches!(reason, Exit::Normal | Exit::Shutdown) && !is_normal(reason)
+            }
             RestartPolicy::Temporary => false,
         }
     }
 }
 
+impl Child {
+    fn should_restart(&self, reason: &Exit) -> bool {
+        self.policy.should_restart(reason)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 enum SupervisorState {
     Idle,
     Stopping(usize),
+    /// Children are stopped one by one, `shutdown_reply` is resolved when all of them are gone.
+    ShuttingDown,
 }
 
 #[derive(Copy, Clone)]
@@ -49,11 +82,31 @@ pub enum Strategy {
     /// One child is restarted if it fails.
     OneForOne,
     /// All children are restarted if one fails.
+    ///
+    /// The others are killed first, once all of them exited they are restarted in start order.
     OneForAll,
     /// All the children after the failing one are restarted if one fails.
+    ///
+    /// Like `OneForAll` the failed child and those after it are restarted in start order.
     RestForOne,
 }
 
+/// When a supervisor shuts itself down after its significant children are gone.
+///
+/// Only children that exit without being restarted count, so this is meant for
+/// `Transient` and `Temporary` children. A supervisor that shuts itself down exits with `Exit::Normal`,
+/// after stopping the children that are left like [`Supervisor::shutdown`] does.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum AutoShutdown {
+    /// Keep running, this is the default.
+    #[default]
+    Never,
+    /// Shut down as soon as any significant child is gone.
+    AnySignificant,
+    /// Shut down once all significant children are gone.
+    AllSignificant,
+}
+
 impl Strategy {
     fn is_affected(self, index: usize, failed_index: usize) -> bool {
         match self {
@@ -67,25 +120,109 @@ impl Strategy {
 struct SupervisorActor {
     children: Vec<Child>,
     strategy: Strategy,
+    auto_shutdown: AutoShutdown,
     state: SupervisorState,
+    shutdown_reply: Option<ReplyHandle<()>>,
 }
 
 enum Request {
-    Supervise(Factory, RestartPolicy),
+    /// The flag marks a significant child, the name becomes its `ChildId`.
+    Supervise(Factory, RestartPolicy, bool, Option<&'static str>),
+    Shutdown(ReplyHandle<()>),
+    WhichChildren(ReplyHandle<Vec<(ChildId, Option<Pid>)>>),
+    ShutdownTimeout(Pid),
 }
 
 impl SupervisorActor {
-    pub fn new(strategy: Strategy) -> Self {
+    pub fn new(strategy: Strategy, auto_shutdown: AutoShutdown) -> Self {
         Self {
             children: Vec::new(),
             strategy,
+            auto_shutdown,
             state: SupervisorState::Idle,
+            shutdown_reply: None,
+        }
+    }
+
+    /// Whether the significant children are gone, according to `auto_shutdown`.
+    fn should_auto_shutdown(&self) -> bool {
+        let mut significant = self
+            .children
+            .iter()
+            .filter(|child| child.significant)
+            .peekable();
+
+        match self.auto_shutdown {
+            AutoShutdown::Never => false,
+            AutoShutdown::AnySignificant => {
+                significant.any(|child| child.state == ChildState::Dead)
+            }
+            AutoShutdown::AllSignificant => {
+                significant.peek().is_some()
+                    && significant.all(|child| child.state == ChildState::Dead)
+            }
         }
     }
 
     fn failed_index(&self, pid: Pid) -> Option<usize> {
         self.children.iter().position(|child| child.pid == pid)
     }
+
+    /// Restart the children that were stopped, in the order they were first started.
+    ///
+    /// Children can depend on the ones started before them, so those have to be up first.
+    fn restart_stopped(&mut self) {
+        for child in self.children.iter_mut() {
+            if child.state == ChildState::Stopped {
+                child.pid = (child.factory)();
+                child.state = ChildState::Running;
+            }
+        }
+    }
+
+    /// Stop the last child that is still running, or finish the shutdown if there is none.
+    ///
+    /// Children are stopped in reverse start order, so those started first can still be used by the others.
+    async fn shutdown_next(&mut self) -> Option<Exit> {
+        if self.state != SupervisorState::ShuttingDown {
+            return None;
+        }
+
+        // Wait for the children that are already exiting.
+        if self
+            .children
+            .iter()
+            .any(|child| matches!(child.state, ChildState::Stopping | ChildState::Restarting))
+        {
+            return None;
+        }
+
+        let Some(child) = self
+            .children
+            .iter_mut()
+            .rev()
+            .find(|child| child.state == ChildState::Running)
+        else {
+            if let Some(reply) = self.shutdown_reply.take() {
+                let _ = reply.resolve(());
+            }
+            return Some(Exit::Normal);
+        };
+
+        child.state = ChildState::Stopping;
+
+        // An exit from the child itself keeps the link, so the exit comes back to us.
+        // Children that trap exits get a chance to clean up.
+        global::exit(child.pid, Exit::Shutdown).await;
+        global::schedule(
+            global::sync::pid(),
+            Request::ShutdownTimeout(child.pid),
+            SHUTDOWN_TIMEOUT,
+        )
+        .await;
+
+        None
+    }
 }
 
 impl SimpleActor for SupervisorActor {
@@ -93,15 +230,54 @@ impl SimpleActor for SupervisorActor {
 
     async fn handle(&mut self, message: Self::Message) -> Option<Exit> {
         match message {
-            Request::Supervise(factory, policy) => {
+            Request::Supervise(factory, policy, significant, name) => {
+                // Don't start new children while shutting down.
+                if self.state == SupervisorState::ShuttingDown {
+                    return None;
+                }
+
+                let id = match name {
+                    Some(name) => ChildId::Named(name),
+                    None => ChildId::Index(self.children.len()),
+                };
+
                 let pid = factory();
                 self.children.push(Child {
+                    id,
                     pid,
                     factory,
                     policy,
                     state: ChildState::Running,
+                    significant,
                 });
             }
+            Request::Shutdown(reply) => {
+                self.state = SupervisorState::ShuttingDown;
+                self.shutdown_reply = Some(reply);
+                return self.shutdown_next().await;
+            }
+            Request::WhichChildren(reply) => {
+                let children = self
+                    .children
+                    .iter()
+                    .map(|child| {
+                        let pid = (child.state == ChildState::Running).then_some(child.pid);
+                        (child.id, pid)
+                    })
+                    .collect();
+
+                let _ = reply.resolve(children);
+            }
+            Request::ShutdownTimeout(pid) => {
+                let is_stopping = self
+                    .children
+                    .iter()
+                    .any(|child| child.pid == pid && child.state == ChildState::Stopping);
+
+                if is_stopping {
+                    global::kill(pid).await;
+                }
+            }
         }
 
         None
@@ -121,6 +297,14 @@ impl SimpleActor for SupervisorActor {
             return Some(reason);
         }
 
+        if self.state == SupervisorState::ShuttingDown {
+            if let Some(child) = self.children.iter_mut().find(|child| child.pid == from) {
+                child.state = ChildState::Dead;
+            }
+
+            return self.shutdown_next().await;
+        }
+
         match (self.children.len(), self.strategy) {
             (_, Strategy::OneForOne) | (1, Strategy::RestForOne) | (1, Strategy::OneForAll) => {
                 let child = self.children.iter_mut().find(|child| child.pid == from)?;
@@ -128,6 +312,8 @@ impl SimpleActor for SupervisorActor {
                 if child.should_restart(&reason) {
                     let pid = (child.factory)();
                     child.pid = pid;
+                } else {
+                    child.state = ChildState::Dead;
                 }
             }
             (_, Strategy::RestForOne) | (_, Strategy::OneForAll) => {
@@ -136,50 +322,65 @@ impl SimpleActor for SupervisorActor {
                     let mut affected = 0;
 
                     for (index, child) in self.children.iter_mut().enumerate() {
-                        if child.state == ChildState::Running
-                            && self.strategy.is_affected(index, failed_index)
+                        if child.state != ChildState::Running
+                            || !self.strategy.is_affected(index, failed_index)
                         {
-                            affected += 1;
+                            continue;
+                        }
 
-                            if child.should_restart(&reason) {
-                                child.state = ChildState::Restarting;
+                        // The failed child is already gone, the others are killed and restarted once all exited.
+                        if index == failed_index {
+                            child.state = if child.should_restart(&reason) {
+                                ChildState::Stopped
                             } else {
-                                child.state = ChildState::Stopping;
-                            }
+                                ChildState::Dead
+                            };
+                            continue;
+                        }
+
+                        affected += 1;
 
-                            global::send_signal(child.pid, Signal::Kill).await;
+                        if child.should_restart(&reason) {
+                            child.state = ChildState::Restarting;
+                        } else {
+                            child.state = ChildState::Stopping;
                         }
+
+                        global::kill(child.pid).await;
                     }
 
                     self.state = SupervisorState::Stopping(affected);
                 } else if let SupervisorState::Stopping(affected) = &mut self.state {
                     let child = self.children.iter_mut().find(|child| child.pid == from)?;
 
-                    if child.state == ChildState::Restarting {
-                        child.state = ChildState::Stopped;
-                    } else {
-                        child.state = ChildState::Dead;
-                    }
-
-                    *affected -= 1;
-
-                    if *affected == 0 {
-                        for child in self.children.iter_mut() {
-                            if child.state == ChildState::Stopped {
-                                if child.should_restart(&reason) {
-                                    let pid = (child.factory)();
-                                    child.pid = pid;
-                                    child.state = ChildState::Running;
-                                }
-                            }
+                    match child.state {
+                        ChildState::Restarting => {
+                            child.state = ChildState::Stopped;
+                            *affected -= 1;
                         }
-
-                        self.state = SupervisorState::Idle;
+                        ChildState::Stopping => {
+                            child.state = ChildState::Dead;
+                            *affected -= 1;
+                        }
+                        // A child that wasn't affected exited on its own, it's restarted along with the others.
+                        _ if child.should_restart(&reason) => child.state = ChildState::Stopped,
+                        _ => child.state = ChildState::Dead,
                     }
                 }
+
+                if self.state == SupervisorState::Stopping(0) {
+                    self.restart_stopped();
+                    self.state = SupervisorState::Idle;
+                }
             }
         }
 
+        // Nobody is waiting for this shutdown, the supervisor exiting is what the others see.
+        if self.state == SupervisorState::Idle && self.should_auto_shutdown() {
+            self.state = SupervisorState::ShuttingDown;
+            return self.shutdown_next().await;
+        }
+
         None
     }
 }
@@ -196,12 +397,65 @@ impl Supervisor {
     }
 
     pub fn spawn_linked(strategy: Strategy) -> Self {
-        let actor = SupervisorActor::new(strategy);
+        Self::spawn_linked_with(strategy, AutoShutdown::Never)
+    }
+
+    /// Like `spawn_linked`, the supervisor shuts itself down once its significant children are gone.
+    pub fn spawn_linked_with(strategy: Strategy, auto_shutdown: AutoShutdown) -> Self {
+        let actor = SupervisorActor::new(strategy, auto_shutdown);
         let actor_ref = global::spawn_linked(into_actor(actor));
         Self { actor: actor_ref }
     }
 
+    /// Stop all children in reverse start order, then the supervisor itself.
+    ///
+    /// Each child gets some time to exit before it is killed, children that trap exits can clean up.
+    /// Returns once all children have exited.
+    pub async fn shutdown(&self) {
+        assert!(self.actor != Pid::invalid(), "Supervisor is invalid");
+
+        let (complete, reply) = global::promise();
+        if global::sync::try_send(self.actor, Request::Shutdown(reply)).is_err() {
+            return;
+        }
+
+        // The promise breaks if the supervisor exits before it's done, it's gone either way.
+        let _ = complete.await;
+    }
+
+    /// The children in start order, with the pid of those that are running.
+    ///
+    /// Children that are being restarted or are gone have no pid.
+    pub async fn which_children(&self) -> Vec<(ChildId, Option<Pid>)> {
+        assert!(self.actor != Pid::invalid(), "Supervisor is invalid");
+
+        let (children, reply) = global::promise();
+        if global::sync::try_send(self.actor, Request::WhichChildren(reply)).is_err() {
+            return Vec::new();
+        }
+
+        // The promise breaks if the supervisor exits before it answers.
+        children.await.unwrap_or_default()
+    }
+
     pub fn supervise<F, B>(&self, policy: RestartPolicy, factory: F)
+    where
+        B: IntoAsyncActor,
+        F: Fn() -> B + Send + 'static,
+    {
+        self.supervise_child(policy, false, factory);
+    }
+
+    /// Supervise a child that counts towards the `AutoShutdown` of the supervisor.
+    pub fn supervise_significant<F, B>(&self, policy: RestartPolicy, factory: F)
+    where
+        B: IntoAsyncActor,
+        F: Fn() -> B + Send + 'static,
+    {
+        self.supervise_child(policy, true, factory);
+    }
+
+    fn supervise_child<F, B>(&self, policy: RestartPolicy, significant: bool, factory: F)
     where
         B: IntoAsyncActor,
         F: Fn() -> B + Send + 'static,
@@ -215,7 +469,10 @@ impl Supervisor {
             actor
         });
 
-        crate::global::sync::send(self.actor, Request::Supervise(factory, policy));
+        crate::global::sync::send(
+            self.actor,
+            Request::Supervise(factory, policy, significant, None),
+        );
     }
 
     pub fn supervise_named<F, B>(&self, name: &'static str, policy: RestartPolicy, factory: F)
@@ -233,6 +490,329 @@ impl Supervisor {
             actor
         });
 
-        crate::global::sync::send(self.actor, Request::Supervise(factory, policy));
+        crate::global::sync::send(
+            self.actor,
+            Request::Supervise(factory, policy, false, Some(name)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::Duration,
+    };
+
+    use crate::{Config, Exit, Pid, TrapExitMessage, global, receive};
+
+    use super::{AutoShutdown, ChildId, RestartPolicy, Strategy, Supervisor};
+
+    struct Ready;
+
+    #[test]
+    fn shutdown_stops_children_in_reverse_order() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let test = global::sync::pid();
+                let supervisor = Supervisor::spawn_linked(Strategy::OneForOne);
+
+                // Started first, so it's still around while the other child shuts down.
+                supervisor.supervise_named("shutdown_sink", RestartPolicy::Permanent, move || {
+                    async move || {
+                        global::trap_exit(true);
+                        let mut received = Vec::new();
+
+                        loop {
+                            receive! {
+                                match &'static str {
+                                    message => received.push(message),
+                                }
+                                match TrapExitMessage {
+                                    TrapExitMessage { reason, .. } => {
+                                        global::sync::send(test, received);
+                                        return reason;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                });
+
+                supervisor.supervise(RestartPolicy::Permanent, move || {
+                    async move || {
+                        global::trap_exit(true);
+                        global::sync::send(test, Ready);
+
+                        receive! {
+                            match TrapExitMessage {
+                                TrapExitMessage { reason, .. } => {
+                                    global::sync::send("shutdown_sink", "final");
+                                    reason
+                                }
+                            }
+                        }
+                    }
+                });
+
+                receive! {
+                    match Ready {
+                        Ready => {}
+                    }
+                }
+
+                supervisor.shutdown().await;
+
+                receive! {
+                    match Vec<&'static str> {
+                        received => completion.complete(received),
+                    }
+                }
+
+                Exit::Normal
+            }
+        });
+
+        let received = result.unwrap();
+
+        assert_eq!(received, ["final"]);
+    }
+
+    #[test]
+    fn significant_child_exit_shuts_the_supervisor_down() {
+        struct Done;
+
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let test = global::sync::pid();
+                global::trap_exit(true);
+
+                let supervisor = Supervisor::spawn_linked_with(
+                    Strategy::OneForOne,
+                    AutoShutdown::AnySignificant,
+                );
+
+                // Not significant, it is stopped along with the supervisor.
+                supervisor.supervise(RestartPolicy::Permanent, move || {
+                    async move || {
+                        global::trap_exit(true);
+                        global::sync::send(test, Ready);
+
+                        receive! {
+                            match TrapExitMessage {
+                                TrapExitMessage { reason, .. } => {
+                                    global::sync::send(test, reason.clone());
+                                    reason
+                                }
+                            }
+                        }
+                    }
+                });
+
+                receive! {
+                    match Ready {
+                        Ready => {}
+                    }
+                }
+
+                supervisor.supervise_significant(RestartPolicy::Transient, move || {
+                    async move || {
+                        global::sync::send(test, Done);
+                        Exit::Normal
+                    }
+                });
+
+                let mut events = Vec::new();
+                while events.len() < 3 {
+                    receive! {
+                        match Done {
+                            Done => events.push(String::from("done")),
+                        }
+                        match Exit {
+                            reason => events.push(format!("sibling {reason:?}")),
+                        }
+                        match TrapExitMessage {
+                            TrapExitMessage { reason, .. } => {
+                                events.push(format!("supervisor {reason:?}"))
+                            }
+                        }
+                    }
+                }
+
+                completion.complete(events);
+
+                Exit::Normal
+            }
+        });
+
+        let events = result.unwrap();
+
+        assert_eq!(events, ["done", "sibling Shutdown", "supervisor Normal"]);
+    }
+
+    /// Starts three children that log their start, the second one fails once after all three started.
+    fn restart_order(strategy: Strategy) -> Vec<&'static str> {
+        let result = crate::run_until(Config::default(), move |completion| {
+            async move || {
+                let test = global::sync::pid();
+                let supervisor = Supervisor::spawn_linked(strategy);
+                let failed = Arc::new(AtomicBool::new(false));
+
+                for name in ["first", "second", "third"] {
+                    let failed = failed.clone();
+
+                    supervisor.supervise(RestartPolicy::Permanent, move || {
+                        let failed = failed.clone();
+
+                        async move || {
+                            global::sync::send(test, (name, global::sync::pid()));
+
+                            if name == "second" {
+                                receive! {
+                                    match Ready {
+                                        Ready => {}
+                                    }
+                                }
+
+                                if !failed.swap(true, Ordering::AcqRel) {
+                                    return Exit::custom("failed");
+                                }
+                            }
+
+                            receive! {
+                                match () {
+                                    () => Exit::Normal,
+                                }
+                            }
+                        }
+                    });
+                }
+
+                let mut started = Vec::new();
+                let mut second = None;
+                loop {
+                    receive! {
+                        match (&'static str, Pid) {
+                            (name, pid) => {
+                                started.push(name);
+                                if name == "second" {
+                                    second = Some(pid);
+                                }
+                            }
+                        }
+                        after Duration::from_millis(200) => break,
+                    }
+
+                    if started.len() == 3
+                        && let Some(second) = second
+                    {
+                        global::sync::send(second, Ready);
+                    }
+                }
+
+                completion.complete(started);
+
+                Exit::Normal
+            }
+        });
+
+        result.unwrap()
+    }
+
+    #[test]
+    fn one_for_all_restarts_in_start_order() {
+        assert_eq!(
+            restart_order(Strategy::OneForAll),
+            ["first", "second", "third", "first", "second", "third"]
+        );
+    }
+
+    #[test]
+    fn rest_for_one_restarts_the_rest_in_start_order() {
+        assert_eq!(
+            restart_order(Strategy::RestForOne),
+            ["first", "second", "third", "second", "third"]
+        );
+    }
+
+    #[test]
+    fn child_id_survives_a_restart() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let supervisor = Supervisor::spawn_linked(Strategy::OneForOne);
+
+                let idle = || {
+                    async || {
+                        receive! {
+                            match () {
+                                () => Exit::Normal,
+                            }
+                        }
+                    }
+                };
+                supervisor.supervise_named("child_id_db", RestartPolicy::Permanent, idle);
+                supervisor.supervise(RestartPolicy::Permanent, idle);
+
+                let before = supervisor.which_children().await;
+                let (_, Some(db)) = before[0] else {
+                    panic!("The child is not running");
+                };
+
+                global::exit(db, Exit::Killed).await;
+
+                // Wait for the restart.
+                let mut after = supervisor.which_children().await;
+                while after[0].1.is_none_or(|pid| pid == db) {
+                    global::sleep(Duration::from_millis(1)).await;
+                    after = supervisor.which_children().await;
+                }
+
+                completion.complete((before, after));
+
+                Exit::Normal
+            }
+        });
+
+        let (before, after) = result.unwrap();
+
+        let ids = |children: &[(ChildId, Option<Pid>)]| {
+            children.iter().map(|&(id, _)| id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ids(&before),
+            [ChildId::Named("child_id_db"), ChildId::Index(1)]
+        );
+        assert_eq!(ids(&after), ids(&before));
+        assert_ne!(after[0].1, before[0].1);
+        assert_eq!(after[1].1, before[1].1);
+    }
+
+    #[test]
+    fn transient_restarts_abnormal_exits() {
+        let policy = RestartPolicy::Transient;
+
+        assert!(!policy.should_restart(&Exit::Normal));
+        assert!(!policy.should_restart(&Exit::Shutdown));
+        assert!(policy.should_restart(&Exit::Killed));
+        assert!(policy.should_restart(&Exit::Panic(String::from("boom"))));
+        assert!(policy.should_restart(&io::Error::from(io::ErrorKind::NotFound).into()));
+        assert!(policy.should_restart(&Exit::custom("done")));
+    }
+
+    #[test]
+    fn classifier_decides_custom_exits() {
+        struct Done;
+
+        let policy = RestartPolicy::TransientWith(|reason| reason.downcast_ref::<Done>().is_some());
+
+        assert!(!policy.should_restart(&Exit::Normal));
+        assert!(!policy.should_restart(&Exit::custom(Done)));
+        assert!(policy.should_restart(&Exit::custom("failed")));
+        assert!(policy.should_restart(&Exit::Killed));
     }
 }
@@ -1,6 +1,15 @@
 use std::fmt::Display;
 
-use crate::{Pid, utils::Timestamp};
+use crate::{
+    Pid,
+    actor::MAX_META_KV,
+    utils::{Timestamp, UnsortedSet},
+};
+
+/// Metadata that travels with a message, see `Config::propagate_metadata`.
+///
+/// Boxed so messages without propagated metadata stay small.
+pub type Propagated = Option<Box<UnsortedSet<MetaKeyValue, MAX_META_KV>>>;
 
 /// Various types that are supported as metadata.
 #[derive(Clone, Debug, PartialEq)]
@@ -35,6 +44,12 @@ impl From<&'static str> for MetaValue {
     }
 }
 
+impl From<String> for MetaValue {
+    fn from(value: String) -> Self {
+        MetaValue::OwnedString(value)
+    }
+}
+
 impl From<u64> for MetaValue {
     fn from(value: u64) -> Self {
         MetaValue::Unsigned(value)
@@ -1,3 +1,4 @@
+mod names;
 mod table;
 
 use std::{
@@ -10,16 +11,29 @@ use std::{
 };
 
 use crate::{
-    actor::{HydratedActor, HydratedActorBase, Pid},
+    actor::{ActorControlBlock, HydratedActor, HydratedActorBase, Pid},
     async_actor::IntoAsyncActor,
 };
 
+use names::{NameId, NameTable};
 use table::Table;
 
+/// Whether a pid belongs to a live actor, see [`crate::global::sync::pid_status`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PidStatus {
+    /// The actor is running.
+    Alive,
+    /// The pid was handed out, but the actor exited.
+    Dead,
+    /// The pid was never handed out in this run.
+    Never,
+}
+
 pub struct Registry {
     next_pid: AtomicU64,
     actors: Table,
-    names: RwLock<HashMap<&'static str, Pid>>,
+    names: NameTable,
+    registered: RwLock<HashMap<NameId, Pid>>,
 }
 
 impl Registry {
@@ -27,21 +41,52 @@ impl Registry {
         Self {
             next_pid: AtomicU64::new(0),
             actors: Table::new(),
-            names: RwLock::new(HashMap::new()),
+            names: NameTable::new(),
+            registered: RwLock::new(HashMap::new()),
         }
     }
 
-    pub fn register(&self, named: &'static str, actor: Pid) {
-        let mut names = self.names.write().expect("Failed to acquire lock");
+    pub fn register(&self, named: &str, actor: Pid) {
+        let id = self.names.intern(named);
+        let mut registered = self.registered.write().expect("Failed to acquire lock");
+
+        registered.insert(id, actor);
+    }
+
+    /// Register a name, unless it refers to an actor that is still alive.
+    ///
+    /// Returns the actor that owns the name on conflict. Registering the owner again succeeds.
+    pub fn try_register(&self, named: &str, actor: Pid) -> Result<(), Pid> {
+        let id = self.names.intern(named);
+        let mut registered = self.registered.write().expect("Failed to acquire lock");
 
-        let pid = actor;
+        if let Some(&owner) = registered.get(&id)
+            && owner != actor
+            && self.actors.lookup(owner).is_some()
+        {
+            return Err(owner);
+        }
 
-        names.insert(named, pid);
+        registered.insert(id, actor);
+
+        Ok(())
     }
 
-    pub fn lookup_name(&self, name: &'static str) -> Option<Pid> {
-        let names = self.names.read().expect("Failed to acquire lock");
-        names.get(name).copied()
+    pub fn unregister(&self, name: &str) {
+        let Some(id) = self.names.get(name) else {
+            return;
+        };
+
+        let mut registered = self.registered.write().expect("Failed to acquire lock");
+        registered.remove(&id);
+    }
+
+    pub fn lookup_name(&self, name: &str) -> Option<Pid> {
+        // A name that was never interned can't be registered.
+        let id = self.names.get(name)?;
+
+        let registered = self.registered.read().expect("Failed to acquire lock");
+        registered.get(&id).copied()
     }
 
     pub fn allocate_pid(&self) -> Pid {
@@ -53,10 +98,35 @@ impl Registry {
         self.actors.lookup(pid)
     }
 
+    /// Pids are never reused, so every pid below the next one was handed out at some point.
+    ///
+    /// A pid that was allocated but not added yet is reported as `Dead`.
+    pub fn pid_status(&self, pid: Pid) -> PidStatus {
+        if self.actors.lookup(pid).is_some() {
+            PidStatus::Alive
+        } else if pid.0 < self.next_pid.load(Ordering::Relaxed) {
+            PidStatus::Dead
+        } else {
+            PidStatus::Never
+        }
+    }
+
     pub fn remove(&self, pid: Pid) {
         self.actors.remove(pid);
     }
 
+    /// The number of live actors.
+    pub fn count(&self) -> usize {
+        self.actors.len()
+    }
+
+    /// The pids of all live actors matching the predicate.
+    ///
+    /// This looks at every actor, and actors can be added or removed while it runs.
+    pub fn find(&self, predicate: impl Fn(&ActorControlBlock) -> bool) -> Vec<Pid> {
+        self.actors.find(predicate)
+    }
+
     pub fn remove_all(&self) {
         self.actors.clear();
     }
@@ -69,3 +139,88 @@ impl Registry {
         self.actors.add(pid, Arc::pin(actor));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Config, Exit,
+        actor::{ActorControlBlock, HydratedActor, Pid},
+        system::System,
+    };
+
+    use super::{PidStatus, Registry};
+
+    #[test]
+    fn pid_status_tells_dead_from_never() {
+        let system = System::new(&Config::default());
+        crate::thread::give(system.clone());
+
+        let registry = &system.registry;
+        let pid = registry.allocate_pid();
+        registry.add(HydratedActor::new(
+            ActorControlBlock::new(pid, 0),
+            async || Exit::Normal,
+        ));
+
+        assert_eq!(registry.pid_status(pid), PidStatus::Alive);
+
+        registry.remove(pid);
+
+        assert_eq!(registry.pid_status(pid), PidStatus::Dead);
+        assert_eq!(registry.pid_status(Pid(pid.0 + 1)), PidStatus::Never);
+        assert_eq!(registry.pid_status(Pid::invalid()), PidStatus::Never);
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn try_register_reports_the_owner() {
+        let system = System::new(&Config::default());
+        crate::thread::give(system.clone());
+
+        let registry = &system.registry;
+        let [first, second] = [(); 2].map(|_| {
+            let pid = registry.allocate_pid();
+            registry.add(HydratedActor::new(
+                ActorControlBlock::new(pid, 0),
+                async || Exit::Normal,
+            ));
+            pid
+        });
+
+        assert_eq!(registry.try_register("service", first), Ok(()));
+        assert_eq!(registry.try_register("service", second), Err(first));
+        assert_eq!(registry.try_register("service", first), Ok(()));
+        assert_eq!(registry.lookup_name("service"), Some(first));
+
+        // A name left behind by an actor that exited can be taken over.
+        registry.remove(first);
+
+        assert_eq!(registry.try_register("service", second), Ok(()));
+        assert_eq!(registry.lookup_name("service"), Some(second));
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn runtime_names_resolve() {
+        let registry = Registry::new();
+
+        for id in 0..3 {
+            registry.register(&format!("worker-{id}"), Pid(id));
+        }
+
+        assert_eq!(registry.lookup_name("worker-1"), Some(Pid(1)));
+        assert_eq!(
+            registry.lookup_name(&String::from("worker-2")),
+            Some(Pid(2))
+        );
+        assert_eq!(registry.lookup_name("worker-3"), None);
+
+        registry.unregister("worker-1");
+        registry.register("worker-0", Pid(5));
+
+        assert_eq!(registry.lookup_name("worker-1"), None);
+        assert_eq!(registry.lookup_name("worker-0"), Some(Pid(5)));
+    }
+}
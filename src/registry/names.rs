@@ -0,0 +1,38 @@
+use std::{collections::HashMap, sync::RwLock};
+
+/// A small id for an interned name.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NameId(u32);
+
+/// Stores every name once and hands out ids for them.
+///
+/// Names are never removed, the table only grows with the number of distinct names.
+pub struct NameTable {
+    ids: RwLock<HashMap<Box<str>, NameId>>,
+}
+
+impl NameTable {
+    pub fn new() -> Self {
+        Self {
+            ids: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The id of a name, the name is stored if it wasn't seen before.
+    pub fn intern(&self, name: &str) -> NameId {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+
+        let mut ids = self.ids.write().expect("Failed to acquire lock");
+        let next = NameId(ids.len() as u32);
+
+        *ids.entry(name.into()).or_insert(next)
+    }
+
+    /// The id of a name, without storing it.
+    pub fn get(&self, name: &str) -> Option<NameId> {
+        let ids = self.ids.read().expect("Failed to acquire lock");
+        ids.get(name).copied()
+    }
+}
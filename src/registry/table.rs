@@ -5,7 +5,7 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use crate::actor::{HydratedActorBase, Pid};
+use crate::actor::{ActorControlBlock, HydratedActorBase, Pid};
 
 const NUM_SHARDS: u64 = 64;
 
@@ -21,6 +21,9 @@ impl Shard {
     }
 }
 
+/// The live actors, sharded by pid so lookups only contend within a shard.
+///
+/// Ports are helper actors, so sending to a port takes the same shard lock as sending to any other actor.
 pub struct Table {
     shards: [Shard; NUM_SHARDS as usize],
 }
@@ -63,6 +66,32 @@ impl Table {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.actors.read().expect("Failed to acquire lock").len())
+            .sum()
+    }
+
+    /// The pids of all actors matching the predicate, one shard at a time.
+    ///
+    /// The predicate runs while the shard is locked, it may lock the control block but not the registry.
+    pub fn find(&self, predicate: impl Fn(&ActorControlBlock) -> bool) -> Vec<Pid> {
+        let mut found = Vec::new();
+
+        for shard in &self.shards {
+            let actors = shard.actors.read().expect("Failed to acquire lock");
+            found.extend(
+                actors
+                    .iter()
+                    .filter(|(_, actor)| predicate(actor.control_block()))
+                    .map(|(&pid, _)| pid),
+            );
+        }
+
+        found
+    }
+
     pub fn add(&self, pid: Pid, actor: Pin<Arc<dyn HydratedActorBase>>) {
         let shard = self.shard(pid);
 
@@ -2,7 +2,7 @@ use std::{
     pin::Pin,
     sync::{
         Arc, RwLock,
-        atomic::{AtomicBool, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
 };
 
@@ -19,32 +19,58 @@ pub(crate) enum Slot {
 }
 
 pub struct Scheduler {
+    /// The number of active workers, reserved slots are not counted.
     count: AtomicUsize,
+    /// One past the highest slot ever allocated, slots below this may be empty.
+    slots: AtomicUsize,
     pub(crate) workers: [RwLock<Slot>; 128],
     pub(crate) stopped: AtomicBool,
     is_balancing: AtomicBool,
+    /// The number of times the workers were rebalanced.
+    balances: AtomicU64,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
         Self {
             count: AtomicUsize::new(0),
+            slots: AtomicUsize::new(0),
             workers: std::array::from_fn(|_| RwLock::new(Slot::Empty)),
             stopped: AtomicBool::new(false),
             is_balancing: AtomicBool::new(false),
+            balances: AtomicU64::new(0),
         }
     }
 
     pub fn count(&self) -> usize {
-        self.count.load(Ordering::Relaxed)
+        self.count.load(Ordering::Acquire)
     }
 
-    pub fn allocate_slot(&self) -> WorkerId {
-        let index = self.count.fetch_add(1, Ordering::Relaxed);
-        let slot = &mut self.workers[index].write().expect("Failed to acquire lock");
-        **slot = Slot::Reserved;
+    pub fn balances(&self) -> u64 {
+        self.balances.load(Ordering::Relaxed)
+    }
+
+    /// The number of slots that have to be checked to find every worker.
+    pub fn slots(&self) -> usize {
+        self.slots.load(Ordering::Acquire)
+    }
+
+    /// Reserve the first empty slot, returns `None` if all slots are in use.
+    ///
+    /// The slot only counts as a worker once it is activated with `replace_slot`.
+    pub fn allocate_slot(&self) -> Option<WorkerId> {
+        for (index, slot) in self.workers.iter().enumerate() {
+            let slot = &mut slot.write().expect("Failed to acquire lock");
+            if let Slot::Empty = **slot {
+                **slot = Slot::Reserved;
+
+                self.slots.fetch_max(index + 1, Ordering::Release);
+
+                return Some(index);
+            }
+        }
 
-        index
+        None
     }
 
     pub fn replace_slot(&self, id: WorkerId, worker: ActiveWorker) -> Option<ActiveWorker> {
@@ -54,7 +80,11 @@ impl Scheduler {
 
         match slot {
             Slot::Active(worker) => Some(worker),
-            _ => None,
+            _ => {
+                // Count while holding the lock, so `count` never includes a worker that isn't active yet.
+                self.count.fetch_add(1, Ordering::Release);
+                None
+            }
         }
     }
 
@@ -78,31 +108,76 @@ impl Scheduler {
     }
 
     pub fn stop_all(&self) {
-        let count = self.count.load(Ordering::Acquire);
-        for worker_id in 0..count {
+        // Mark as stopped first so workers that are being added concurrently stop themselves.
+        self.stopped.store(true, Ordering::Release);
+
+        for worker_id in 0..self.slots() {
             self.stop(worker_id);
         }
-
-        self.stopped.store(true, Ordering::Release);
     }
 
-    fn stop(&self, worker_id: WorkerId) {
-        eprintln!("Stopping worker {}", worker_id);
+    /// Stop a worker and empty its slot, returns the worker so its run queue can be drained.
+    pub fn stop(&self, worker_id: WorkerId) -> Option<Arc<Worker>> {
+        let slot = &mut self.workers[worker_id]
+            .write()
+            .expect("Failed to acquire lock");
+
+        let stopped = Self::empty_slot(worker_id, slot)?;
+        self.count.fetch_sub(1, Ordering::Release);
 
+        Some(stopped)
+    }
+
+    /// Like `stop`, but the last active worker is never stopped.
+    ///
+    /// The check and the stop are a single step, so concurrent calls can't stop every worker.
+    pub fn stop_unless_last(&self, worker_id: WorkerId) -> Option<Arc<Worker>> {
         let slot = &mut self.workers[worker_id]
             .write()
             .expect("Failed to acquire lock");
 
-        match &**slot {
+        if !matches!(**slot, Slot::Active(_)) {
+            return None;
+        }
+
+        // `count` only includes active workers, claiming the stop in it leaves at least one.
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count > 1).then(|| count - 1)
+            })
+            .ok()?;
+
+        Self::empty_slot(worker_id, slot)
+    }
+
+    /// Empty a slot, stopping the worker in it. Returns `None` if there was no active worker.
+    fn empty_slot(worker_id: WorkerId, slot: &mut Slot) -> Option<Arc<Worker>> {
+        eprintln!("Stopping worker {}", worker_id);
+
+        match std::mem::replace(slot, Slot::Empty) {
             Slot::Active(active_worker) => {
                 active_worker.worker.running.store(false, Ordering::Release);
                 active_worker.thread.unpark();
+
+                Some(active_worker.worker)
             }
-            _ => (),
+            Slot::Reserved | Slot::Empty => None,
         }
+    }
 
-        **slot = Slot::Empty;
-        self.count.fetch_sub(1, Ordering::Release);
+    /// The ids of all active workers, slots are not compacted when a worker stops.
+    pub fn active_workers(&self) -> Vec<WorkerId> {
+        (0..self.slots())
+            .filter(|&id| self.get_worker(id).is_some())
+            .collect()
+    }
+
+    /// The active worker with the shortest run queue.
+    pub fn least_loaded(&self) -> Option<WorkerId> {
+        self.active_workers().into_iter().min_by_key(|&id| {
+            self.get_worker(id)
+                .map_or(usize::MAX, |worker| worker.run_queue_length())
+        })
     }
 
     pub fn schedule_actor(&self, actor: Pin<Arc<dyn HydratedActorBase>>) {
@@ -113,14 +188,24 @@ impl Scheduler {
 
         {
             if control_block.try_schedule() {
-                let Some(worker) = self.get_worker(worker_id) else {
+                let Some(worker) = self.get_worker(worker_id).or_else(|| {
+                    // The worker was removed while this actor wasn't queued, move it to the least loaded worker left.
+                    let worker_id = self.least_loaded()?;
+                    control_block
+                        .worker_id
+                        .store(worker_id as _, Ordering::Release);
+
+                    self.get_worker(worker_id)
+                }) else {
                     eprintln!("Worker is assigned to invalid worker {}", worker_id);
+                    // Otherwise the actor would count as queued and never be scheduled again.
+                    control_block.unschedule();
                     return;
                 };
 
                 worker.run_queue.push(pid);
 
-                self.wake_worker(worker_id);
+                self.wake_worker(worker.spawn_at);
             }
         }
     }
@@ -133,6 +218,7 @@ impl Scheduler {
         {
             println!("Balancing on worker {}", worker);
             self.balance();
+            self.balances.fetch_add(1, Ordering::Relaxed);
             self.is_balancing.store(false, Ordering::Release);
 
             true
@@ -142,34 +228,43 @@ impl Scheduler {
     }
 
     fn balance(&self) {
-        let worker_count = self.count.load(Ordering::Relaxed);
+        let slots = self.slots();
 
-        let mut max_queue_lengths = Vec::with_capacity(worker_count);
-        for i in 0..worker_count {
+        // Slots can be empty after a worker was removed, so keep the worker id next to the length.
+        let mut max_queue_lengths = Vec::with_capacity(slots);
+        for i in 0..slots {
             if let Some(worker) = self.get_worker(i) {
-                max_queue_lengths.push(worker.max_queue_length.load(Ordering::Relaxed));
+                max_queue_lengths.push((i, worker.max_queue_length.load(Ordering::Relaxed)));
             }
         }
 
-        let average_queue_length = max_queue_lengths.iter().sum::<usize>() / worker_count;
+        let worker_count = max_queue_lengths.len();
+        if worker_count == 0 {
+            return;
+        }
+
+        let average_queue_length = max_queue_lengths
+            .iter()
+            .map(|&(_, length)| length)
+            .sum::<usize>()
+            / worker_count;
         let average_queue_length = average_queue_length + 4; // Add some margin
 
         // println!("Average queue length: {}", average_queue_length);
 
-        let mut max_queue_lengths = max_queue_lengths
-            .iter()
-            .copied()
-            .enumerate()
-            .collect::<Vec<_>>();
         max_queue_lengths.sort_by_key(|&(_, length)| length);
 
         // println!("{:?}", max_queue_lengths);
 
-        let mut parameters = vec![Parameters::none(); worker_count];
+        let mut parameters = vec![Parameters::none(); slots];
 
-        let mut i = 0;
+        // Both loops stop at the end of the list, e.g. when every worker is below the average.
         let mut j = worker_count - 1;
-        while max_queue_lengths[i].1 < average_queue_length {
+        for i in 0..worker_count {
+            if max_queue_lengths[i].1 >= average_queue_length {
+                break;
+            }
+
             let index = max_queue_lengths[i].0;
             let target = max_queue_lengths[j].0;
 
@@ -179,16 +274,18 @@ impl Scheduler {
                 balance: average_queue_length,
             };
 
-            i += 1;
-            j -= 1;
-            if max_queue_lengths[j].1 <= average_queue_length {
-                j = worker_count - 1;
-            }
+            j = match j.checked_sub(1) {
+                Some(next) if max_queue_lengths[next].1 > average_queue_length => next,
+                _ => worker_count - 1,
+            };
         }
 
         let mut i = 0;
-        let mut j = worker_count - 1;
-        while max_queue_lengths[j].1 > average_queue_length {
+        for j in (0..worker_count).rev() {
+            if max_queue_lengths[j].1 <= average_queue_length {
+                break;
+            }
+
             let index = max_queue_lengths[j].0;
             let target = max_queue_lengths[i].0;
             parameters[index] = Parameters {
@@ -197,16 +294,17 @@ impl Scheduler {
                 balance: average_queue_length,
             };
 
-            j -= 1;
-            i += 1;
-            if max_queue_lengths[i].1 >= average_queue_length {
-                i = 0;
-            }
+            i = match i + 1 {
+                next if next < worker_count && max_queue_lengths[next].1 < average_queue_length => {
+                    next
+                }
+                _ => 0,
+            };
         }
 
         // println!("{:?}", parameters);
 
-        for i in 0..worker_count {
+        for i in 0..slots {
             let active_worker = &self.workers[i];
             if let Slot::Active(slot) = &*active_worker.read().expect("Failed to acquire lock") {
                 slot.worker.reductions.store(2000 * 1000, Ordering::Relaxed);
@@ -218,3 +316,57 @@ impl Scheduler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use crate::worker::{ActiveWorker, Worker};
+
+    use super::Scheduler;
+
+    #[test]
+    fn count_only_includes_active_workers() {
+        let scheduler = Arc::new(Scheduler::new());
+        let adding = Arc::new(AtomicBool::new(true));
+
+        let balancer = {
+            let scheduler = scheduler.clone();
+            let adding = adding.clone();
+
+            std::thread::spawn(move || {
+                let mut rounds = 0;
+                while adding.load(Ordering::Acquire) || rounds == 0 {
+                    // Workers are only added, so every counted worker must be found afterwards.
+                    let count = scheduler.count();
+                    assert!(count <= scheduler.active_workers().len());
+
+                    scheduler.balance();
+                    rounds += 1;
+                }
+            })
+        };
+
+        for _ in 0..64 {
+            let id = scheduler.allocate_slot().unwrap();
+            std::thread::yield_now();
+
+            let _ = scheduler.replace_slot(
+                id,
+                ActiveWorker {
+                    thread: std::thread::current(),
+                    worker: Arc::new(Worker::new(id)),
+                },
+            );
+        }
+
+        adding.store(false, Ordering::Release);
+        balancer.join().unwrap();
+
+        assert_eq!(scheduler.count(), 64);
+        assert_eq!(scheduler.active_workers().len(), 64);
+    }
+}
@@ -1,35 +1,331 @@
-use std::sync::{Arc, atomic::Ordering};
+use std::{
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::JoinHandle,
+};
 
 use crate::{
-    Pid, actor::ToPid, migration::Parameters, registry::Registry, scheduler::Scheduler,
-    timer::Timer, worker::WorkerId,
+    Config, DownMessage, Exit, MailboxOverflowPolicy, MonitorRef, Pid, Priority, SpawnPlacement,
+    StealStrategy,
+    actor::{HydratedActorBase, Signal, ToPid, panic_to_string},
+    library::logger::{alert, emergency},
+    migration::Parameters,
+    registry::Registry,
+    scheduler::Scheduler,
+    timer::Timer,
+    utils::Histogram,
+    worker::{ActiveWorker, Worker, WorkerId},
 };
 
+/// The maximum number of entries `try_steal` looks at in a single run queue.
+const MAX_STEAL_SCAN: usize = 8;
+
+/// The number of bins in a run queue histogram, longer run queues go into the last bin.
+pub const QUEUE_HISTOGRAM_BINS: usize = 1024;
+
 pub struct System {
     pub registry: Registry,
     pub scheduler: Scheduler,
     pub timer: Timer,
+    pub max_budget: AtomicUsize,
+    pub poll_batch: usize,
+    pub mailbox_capacity: usize,
+    pub mailbox_ceiling: usize,
+    pub mailbox_overflow_policy: MailboxOverflowPolicy,
+    pub steal_strategy: StealStrategy,
+    pub spawn_placement: SpawnPlacement,
+    // The turn of the next spawn, see `SpawnPlacement::RoundRobin`.
+    next_spawn: AtomicUsize,
+    next_monitor_ref: AtomicU64,
+    /// Metadata keys that are sent along with messages.
+    pub propagate_metadata: Vec<&'static str>,
+    /// The actor at the root of the supervision tree, it handles an ordered shutdown.
+    pub root: OnceLock<Pid>,
+    worker_handles: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl System {
-    pub fn new() -> Arc<Self> {
+    pub fn new(config: &Config) -> Arc<Self> {
         let registry = Registry::new();
         let scheduler = Scheduler::new();
-        let timer = Timer::new();
+        let timer = Timer::new(config.clock.clone(), config.timer_slack);
 
         Arc::new(System {
             registry,
             scheduler,
             timer,
+            max_budget: AtomicUsize::new(config.max_budget.max(1)),
+            poll_batch: config.poll_batch.max(1),
+            mailbox_capacity: config.mailbox_capacity.max(1),
+            mailbox_ceiling: config.mailbox_ceiling.max(1),
+            mailbox_overflow_policy: config.mailbox_overflow_policy,
+            steal_strategy: config.steal,
+            spawn_placement: config.spawn_placement,
+            next_spawn: AtomicUsize::new(0),
+            next_monitor_ref: AtomicU64::new(0),
+            propagate_metadata: config.propagate_metadata.clone(),
+            root: OnceLock::new(),
+            worker_handles: Mutex::new(Vec::new()),
         })
     }
 
+    /// Start a worker thread in a free slot, returns `None` if all slots are in use or the system stopped.
+    pub fn start_worker(self: &Arc<Self>) -> Option<WorkerId> {
+        if self.scheduler.stopped.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let id = self.scheduler.allocate_slot()?;
+
+        let worker = Arc::new(Worker::new(id));
+
+        let handle = {
+            let worker = worker.clone();
+            let system = self.clone();
+
+            crate::thread::spawn(move || {
+                // Wait for the slot to become active, actors stolen before that can't be scheduled on this worker.
+                while worker.running.load(Ordering::Acquire)
+                    && system.scheduler.get_worker(id).is_none()
+                {
+                    std::thread::park();
+                }
+
+                if let Err(err) = catch_unwind(AssertUnwindSafe(|| worker.run())) {
+                    system.recover_worker(&worker, panic_to_string(err));
+                }
+            })
+        };
+
+        let _ = self.scheduler.replace_slot(
+            id,
+            ActiveWorker {
+                thread: handle.thread().clone(),
+                worker,
+            },
+        );
+        handle.thread().unpark();
+
+        self.worker_handles
+            .lock()
+            .expect("Failed to acquire lock")
+            .push(handle);
+
+        // The system might have stopped while this worker was starting, `stop_all` could have missed it.
+        if self.scheduler.stopped.load(Ordering::Acquire) {
+            self.scheduler.stop(id);
+        }
+
+        Some(id)
+    }
+
+    /// Wait for all worker threads to finish, including workers that are started while waiting.
+    pub fn join_workers(&self) {
+        loop {
+            let handle = self
+                .worker_handles
+                .lock()
+                .expect("Failed to acquire lock")
+                .pop();
+
+            match handle {
+                Some(handle) => handle.join().unwrap(),
+                None => break,
+            }
+        }
+    }
+
     pub fn stop_all(&self) {
         self.scheduler.stop_all();
         self.registry.remove_all();
         self.timer.stop();
     }
 
+    /// How many workers currently have each run queue length.
+    pub fn queue_histogram(&self) -> Histogram<QUEUE_HISTOGRAM_BINS> {
+        let mut histogram = Histogram::new();
+
+        for id in 0..self.scheduler.slots() {
+            if let Some(worker) = self.scheduler.get_worker(id) {
+                histogram.record(worker.run_queue_length());
+            }
+        }
+
+        histogram
+    }
+
+    /// Stop a single worker, the actors in its run queue are moved to the workers that are left.
+    ///
+    /// The last worker is never stopped, returns whether a worker was stopped.
+    pub fn stop_worker(&self, worker_id: WorkerId) -> bool {
+        let Some(stopped) = self.scheduler.stop_unless_last(worker_id) else {
+            return false;
+        };
+
+        self.redistribute(&stopped);
+
+        true
+    }
+
+    /// Clean up after a worker thread that panicked, it is replaced by a new worker.
+    ///
+    /// The actor it was running exits with `Exit::Panic`, its run queue moves to the workers that are left.
+    /// If no worker can be started and none are left, nothing would ever run again so the system is stopped.
+    fn recover_worker(self: &Arc<Self>, worker: &Worker, reason: String) {
+        emergency("Worker {worker} died: {reason}")
+            .with("worker", worker.spawn_at as u64)
+            .with("reason", reason.clone())
+            .emit();
+
+        // The actor can't be resumed, its future might be halfway through a poll.
+        if let Some(pid) = worker.take_current()
+            && let Some(actor) = self.registry.lookup_pid(pid)
+        {
+            self.exited(pid, &*actor, &Exit::Panic(reason));
+            actor
+                .control_block()
+                .is_running
+                .store(false, Ordering::Release);
+        }
+
+        // Only remove the slot if it still belongs to this worker.
+        if self
+            .scheduler
+            .get_worker(worker.spawn_at)
+            .is_some_and(|active| std::ptr::eq(&*active, worker))
+        {
+            self.scheduler.stop(worker.spawn_at);
+        }
+
+        if self.start_worker().is_none() && self.scheduler.count() == 0 {
+            self.stop_all();
+            return;
+        }
+
+        self.redistribute(worker);
+    }
+
+    /// A new reference for a monitor, see [`DownMessage`].
+    pub fn monitor_ref(&self) -> MonitorRef {
+        MonitorRef(self.next_monitor_ref.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Remove an actor that exited and let its links and monitors know why.
+    pub fn exited(&self, pid: Pid, actor: &dyn HydratedActorBase, exit: &Exit) {
+        // Copy the links so the lock is released before the registry is touched, see `ActorControlBlock`.
+        let links: Vec<Pid> = actor.links().iter().copied().collect();
+        let monitors = actor.control_block().take_monitors();
+
+        self.registry.remove(pid);
+
+        // Senders waiting for room retry, they find the actor gone, see `send_blocking`.
+        for sender in actor.control_block().take_blocked_senders() {
+            self.schedule(sender);
+        }
+
+        for linked in links {
+            if let Some(child) = self.registry.lookup_pid(linked) {
+                child.send_signal(Signal::Exit(pid, exit.clone()));
+
+                self.schedule(linked);
+            }
+        }
+
+        for (monitoring, monitor_ref) in monitors {
+            let down = DownMessage {
+                monitor_ref,
+                pid,
+                reason: exit.clone(),
+            };
+
+            self.deliver(
+                monitoring,
+                Signal::Message(pid, Box::new(down), None, Priority::Normal),
+            );
+        }
+    }
+
+    /// Move the actors queued on a stopped worker to the active workers.
+    pub fn redistribute(&self, stopped: &Worker) {
+        let survivors = self.scheduler.active_workers();
+        if survivors.is_empty() {
+            return;
+        }
+
+        let mut next = 0;
+        while let Some(pid) = stopped.run_queue.try_pop() {
+            let Some(actor) = self.registry.lookup_pid(pid) else {
+                continue;
+            };
+
+            let Some(target) = self.scheduler.get_worker(survivors[next % survivors.len()]) else {
+                continue;
+            };
+            next += 1;
+
+            // Unlike `try_push` a running actor is moved as well, there is no queue to put it back on.
+            // The new worker waits for the stopped worker to finish running it, see `Worker::run_actor`.
+            actor
+                .control_block()
+                .worker_id
+                .store(target.spawn_at as _, Ordering::Release);
+
+            target.run_queue.push(pid);
+            self.scheduler.wake_worker(target.spawn_at);
+        }
+    }
+
+    /// Push a signal into an actor's inbox and wake it.
+    ///
+    /// A message wakes the receive that is waiting for it, anything else just schedules the actor.
+    pub fn deliver(&self, pid: Pid, signal: Signal) -> bool {
+        let Some(actor) = self.registry.lookup_pid(pid) else {
+            return false;
+        };
+
+        let is_message = matches!(signal, Signal::Message(..));
+        actor.send_signal(signal);
+
+        if is_message {
+            self.check_mailbox(pid, &*actor);
+        }
+
+        if !is_message || !actor.control_block().wake_receiver() {
+            self.schedule(pid);
+        }
+
+        true
+    }
+
+    /// Alert once when a mailbox reaches the ceiling, and kill the actor if the policy says so.
+    ///
+    /// Only a single log message is sent per overflow, so a flood doesn't flood the logger as well.
+    fn check_mailbox(&self, pid: Pid, actor: &dyn HydratedActorBase) {
+        let length = actor.pending_messages();
+        let control_block = actor.control_block();
+
+        if length < self.mailbox_ceiling {
+            control_block.clear_overflowed();
+            return;
+        }
+
+        if !control_block.mark_overflowed() {
+            return;
+        }
+
+        alert("Mailbox of {pid} overflowed with {mailbox_length} messages")
+            .with("pid", pid)
+            .with("mailbox_length", length as u64)
+            .emit();
+
+        if self.mailbox_overflow_policy == MailboxOverflowPolicy::Kill {
+            control_block.request_stop(Exit::Killed);
+        }
+    }
+
     pub fn schedule(&self, pid: impl ToPid) {
         let pid = pid.to_reference(&self.registry);
 
@@ -40,43 +336,91 @@ impl System {
         self.scheduler.schedule_actor(actor);
     }
 
-    // Try and steal from a worker, starting to the next working in the ring
-    // and overflowing back around.
+    // Try and steal from a worker, starting at the worker picked by the steal strategy
+    // and overflowing back around the ring until every other worker has been tried.
     pub fn try_steal(&self, worker_id: WorkerId) -> Option<Pid> {
-        let n = self.scheduler.count();
+        // Removed workers leave empty slots behind, those are skipped below.
+        let n = self.scheduler.slots();
+        if n < 2 {
+            return None;
+        }
 
-        let mut i = (worker_id + 1) % n;
+        let start = self.steal_start(worker_id, n);
+
+        for k in 0..n - 1 {
+            let i = (worker_id + 1 + (start + k) % (n - 1)) % n;
+
+            if let Some(worker) = self.scheduler.get_worker(i)
+                && let Some(pid) = self.steal_from(&worker, worker_id)
+            {
+                return Some(pid);
+            }
+        }
 
-        while i != worker_id {
-            let Some(worker) = self.scheduler.get_worker(i) else {
-                i = (i + 1) % n;
+        None
+    }
+
+    /// The worker an actor spawned from outside an actor starts on.
+    pub fn spawn_target(&self) -> WorkerId {
+        let workers = self.scheduler.active_workers();
+        if workers.is_empty() {
+            return 0;
+        }
+
+        match self.spawn_placement {
+            SpawnPlacement::RoundRobin => {
+                workers[self.next_spawn.fetch_add(1, Ordering::Relaxed) % workers.len()]
+            }
+            SpawnPlacement::LeastLoaded => self.scheduler.least_loaded().unwrap_or(0),
+        }
+    }
+
+    // Where to start in the ring of other workers, 0 being the next worker.
+    fn steal_start(&self, worker_id: WorkerId, n: usize) -> usize {
+        match self.steal_strategy {
+            StealStrategy::Ring => 0,
+            StealStrategy::Random => self
+                .scheduler
+                .get_worker(worker_id)
+                .map_or(0, |worker| (worker.next_random() % (n as u64 - 1)) as usize),
+        }
+    }
+
+    // Look at a bounded number of entries in a run queue for an actor that isn't running.
+    // Running actors are put back once we're done, so they aren't popped again during the scan.
+    fn steal_from(&self, worker: &Worker, worker_id: WorkerId) -> Option<Pid> {
+        let mut skipped = Vec::new();
+        let mut stolen = None;
+
+        for _ in 0..worker.run_queue_length().min(MAX_STEAL_SCAN) {
+            let Some(pid) = worker.run_queue.try_pop() else {
+                break;
+            };
+
+            // The actor must have been removed from the registry
+            let Some(actor) = self.registry.lookup_pid(pid) else {
                 continue;
             };
 
-            if let Some(pid) = worker.run_queue.try_pop() {
+            let control_block = actor.control_block();
+            if !control_block.is_running.load(Ordering::Acquire) {
                 // Reassign actor to it's new worker.
-                let Some(actor) = self.registry.lookup_pid(pid) else {
-                    // actor must have been removed from the registry
-                    return None;
-                };
-
-                let control_block = actor.control_block();
-                if !control_block.is_running.load(Ordering::Acquire) {
-                    control_block
-                        .worker_id
-                        .store(worker_id as _, Ordering::Release);
-
-                    return Some(pid);
-                } else {
-                    eprintln!("Trying to steal running actor {}", pid.0);
-                    worker.run_queue.push(pid);
-                }
+                control_block
+                    .worker_id
+                    .store(worker_id as _, Ordering::Release);
+
+                stolen = Some(pid);
+                break;
             }
 
-            i = (i + 1) % n;
+            skipped.push(pid);
         }
 
-        None
+        for pid in skipped {
+            worker.run_queue.push(pid);
+        }
+
+        stolen
     }
 
     pub fn try_pull(&self, target: WorkerId, parameters: Parameters) {
@@ -159,3 +503,464 @@ impl System {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, atomic::Ordering},
+        time::Duration,
+    };
+
+    use crate::{
+        Config, Exit, SpawnPlacement, StealStrategy,
+        actor::{ActorControlBlock, HydratedActor},
+        worker::{ActiveWorker, Worker},
+    };
+
+    use super::{QUEUE_HISTOGRAM_BINS, System};
+
+    fn add_actor(system: &System, worker_id: usize) -> crate::Pid {
+        let pid = system.registry.allocate_pid();
+        let control_block = ActorControlBlock::new(pid, worker_id);
+        system
+            .registry
+            .add(HydratedActor::new(control_block, async || Exit::Normal));
+
+        pid
+    }
+
+    /// Creates a system with workers that aren't running, the caller must drop the system from the thread.
+    fn system_with_workers(config: Config, workers: usize) -> Arc<System> {
+        let system = System::new(&config);
+        crate::thread::give(system.clone());
+
+        for _ in 0..workers {
+            let id = system.scheduler.allocate_slot().unwrap();
+            let _ = system.scheduler.replace_slot(
+                id,
+                ActiveWorker {
+                    thread: std::thread::current(),
+                    worker: Arc::new(Worker::new(id)),
+                },
+            );
+        }
+
+        system
+    }
+
+    #[test]
+    fn zero_budget_is_clamped() {
+        let system = System::new(&Config {
+            max_budget: 0,
+            ..Config::default()
+        });
+
+        // With no budget at all every yield would yield again, forever.
+        assert_eq!(system.max_budget.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn stopped_worker_queue_is_redistributed() {
+        let system = system_with_workers(Config::default(), 3);
+
+        let dying = system.scheduler.get_worker(1).unwrap();
+        let pids: Vec<_> = (0..4).map(|_| add_actor(&system, 1)).collect();
+        for &pid in &pids {
+            dying.run_queue.push(pid);
+        }
+
+        system.stop_worker(1);
+
+        assert!(system.scheduler.get_worker(1).is_none());
+        assert_eq!(dying.run_queue.try_pop(), None);
+
+        let mut moved = Vec::new();
+        for worker_id in [0, 2] {
+            let worker = system.scheduler.get_worker(worker_id).unwrap();
+            let mut count = 0;
+            while let Some(pid) = worker.run_queue.try_pop() {
+                let actor = system.registry.lookup_pid(pid).unwrap();
+                assert_eq!(
+                    actor.control_block().worker_id.load(Ordering::Acquire),
+                    worker_id as _
+                );
+                moved.push(pid);
+                count += 1;
+            }
+            assert_eq!(count, 2);
+        }
+
+        moved.sort_by_key(|pid| pid.0);
+        assert_eq!(moved, pids);
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn concurrent_stops_keep_the_last_worker() {
+        const WORKERS: usize = 8;
+
+        for _ in 0..1000 {
+            let system = system_with_workers(Config::default(), WORKERS);
+
+            let barrier = std::sync::Barrier::new(WORKERS);
+            let stopped = std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..WORKERS)
+                    .map(|id| {
+                        let system = &system;
+                        let barrier = &barrier;
+                        scope.spawn(move || {
+                            barrier.wait();
+                            system.stop_worker(id)
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .filter(|&stopped| stopped)
+                    .count()
+            });
+
+            assert_eq!(stopped, WORKERS - 1);
+            assert_eq!(system.scheduler.active_workers().len(), 1);
+            assert_eq!(system.scheduler.count(), 1);
+
+            drop(unsafe { crate::thread::get() });
+        }
+    }
+
+    #[test]
+    fn actor_on_a_stopped_worker_moves_to_the_least_loaded() {
+        let system = system_with_workers(Config::default(), 3);
+
+        // Worker 0 is busy, worker 2 is idle.
+        let worker = system.scheduler.get_worker(0).unwrap();
+        for _ in 0..3 {
+            worker.run_queue.push(add_actor(&system, 0));
+        }
+
+        let pid = add_actor(&system, 1);
+        system.scheduler.stop(1);
+
+        system.schedule(pid);
+
+        let actor = system.registry.lookup_pid(pid).unwrap();
+        let idle = system.scheduler.get_worker(2).unwrap();
+        assert_eq!(idle.run_queue.try_pop(), Some(pid));
+        assert_eq!(actor.control_block().worker_id.load(Ordering::Acquire), 2);
+        assert!(actor.control_block().is_scheduled.load(Ordering::Acquire));
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn timer_message_arrives_before_the_actor_is_queued() {
+        let system = system_with_workers(Config::default(), 1);
+        let worker = system.scheduler.get_worker(0).unwrap();
+        let pid = add_actor(&system, 0);
+
+        let timer = {
+            let system = system.clone();
+            crate::thread::spawn(move || system.timer.run())
+        };
+        system
+            .timer
+            .add(pid, crate::Pid::invalid(), Duration::from_millis(1), 5u32);
+
+        // The worker doesn't run, so the actor stays queued once the timer fired.
+        while worker.run_queue_length() == 0 {
+            std::hint::spin_loop();
+        }
+
+        let actor = system.registry.lookup_pid(pid).unwrap();
+        assert!(actor.has_messages());
+        assert_eq!(worker.run_queue.try_pop(), Some(pid));
+        assert_eq!(worker.run_queue.try_pop(), None);
+
+        system.timer.stop();
+        timer.join().unwrap();
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn actor_without_a_worker_can_be_scheduled_later() {
+        let system = system_with_workers(Config::default(), 0);
+
+        let pid = add_actor(&system, 0);
+
+        // There is no worker to queue on, the actor must not be left marked as scheduled.
+        system.schedule(pid);
+        let actor = system.registry.lookup_pid(pid).unwrap();
+        assert!(!actor.control_block().is_scheduled.load(Ordering::Acquire));
+
+        let id = system.scheduler.allocate_slot().unwrap();
+        let _ = system.scheduler.replace_slot(
+            id,
+            ActiveWorker {
+                thread: std::thread::current(),
+                worker: Arc::new(Worker::new(id)),
+            },
+        );
+
+        system.schedule(pid);
+        let worker = system.scheduler.get_worker(id).unwrap();
+        assert_eq!(worker.run_queue.try_pop(), Some(pid));
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn spawns_from_outside_an_actor_are_spread_over_workers() {
+        let system = system_with_workers(Config::default(), 4);
+
+        for _ in 0..40 {
+            crate::global::sync::spawn(async || Exit::Normal);
+        }
+
+        for id in 0..4 {
+            let worker = system.scheduler.get_worker(id).unwrap();
+            assert_eq!(worker.run_queue_length(), 10);
+        }
+
+        drop(unsafe { crate::thread::get() });
+
+        let config = Config {
+            spawn_placement: SpawnPlacement::LeastLoaded,
+            ..Config::default()
+        };
+        let system = system_with_workers(config, 4);
+
+        let busy = system.scheduler.get_worker(0).unwrap();
+        for _ in 0..10 {
+            busy.run_queue.push(add_actor(&system, 0));
+        }
+
+        // The other workers catch up with the busy one before it gets another actor.
+        for _ in 0..30 {
+            crate::global::sync::spawn(async || Exit::Normal);
+        }
+
+        for id in 0..4 {
+            let worker = system.scheduler.get_worker(id).unwrap();
+            assert_eq!(worker.run_queue_length(), 10);
+        }
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn large_mailbox_absorbs_a_burst() {
+        use crate::global::{MailboxHint, SpawnOptions, sync};
+
+        // The workers don't run, so every message stays in the inbox.
+        let system = system_with_workers(Config::default(), 1);
+
+        let large = sync::spawn_opts(
+            async || Exit::Normal,
+            SpawnOptions {
+                mailbox_hint: MailboxHint::Large,
+                ..SpawnOptions::default()
+            },
+        )
+        .pid;
+        let default = sync::spawn(async || Exit::Normal);
+
+        for n in 0..2000u32 {
+            sync::send(large, n);
+            sync::send(default, n);
+        }
+
+        let large = system.registry.lookup_pid(large).unwrap();
+        let default = system.registry.lookup_pid(default).unwrap();
+
+        assert_eq!(large.pending_messages(), 2000);
+        assert_eq!(large.overflowed_signals(), 0);
+        assert_eq!(default.pending_messages(), 2000);
+        assert_eq!(default.overflowed_signals(), 2000 - 1024);
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn dead_worker_is_replaced_and_its_actors_migrate() {
+        use crate::{Pid, global, receive};
+
+        // Dropping the actor's future panics the worker, outside of the actor's own panic handling.
+        struct Bomb;
+
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("worker bomb");
+            }
+        }
+
+        let config = Config {
+            workers: 2,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let me = global::sync::pid();
+
+                let echoes = (0..4)
+                    .map(|_| {
+                        global::sync::spawn_on(1, async || {
+                            receive! {
+                                match Pid {
+                                    from => global::send(from, global::sync::pid()).await,
+                                }
+                            }
+
+                            Exit::Normal
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                let bomb = global::sync::spawn_on(1, async || {
+                    let _bomb = Bomb;
+
+                    receive! {
+                        match () {
+                            () => Exit::Normal,
+                        }
+                    }
+                });
+
+                // Kill the bomb while it waits, the worker drops its future.
+                global::sleep(Duration::from_millis(10)).await;
+                global::exit(bomb, Exit::Killed).await;
+
+                // The bomb is removed once the dead worker has been cleaned up.
+                let system = unsafe { crate::thread::borrow() };
+                let mut waited = 0;
+                while system.registry.lookup_pid(bomb).is_some() && waited < 500 {
+                    global::sleep(Duration::from_millis(10)).await;
+                    waited += 1;
+                }
+
+                let mut answered = 0;
+                for &echo in &echoes {
+                    global::send(echo, me).await;
+
+                    answered += receive! {
+                        match Pid {
+                            _ => 1,
+                        }
+                        after Duration::from_secs(1) => 0,
+                    };
+                }
+
+                let bomb_removed = system.registry.lookup_pid(bomb).is_none();
+                let workers = system.scheduler.count();
+
+                completion.complete((answered, bomb_removed, workers));
+
+                Exit::Normal
+            }
+        });
+
+        let (answered, bomb_removed, workers) = result.unwrap();
+
+        assert_eq!(answered, 4);
+        assert!(bomb_removed);
+        assert_eq!(workers, 2);
+    }
+
+    #[test]
+    fn steal_skips_running_actors() {
+        let system = system_with_workers(Config::default(), 2);
+
+        let running = add_actor(&system, 1);
+        let idle = add_actor(&system, 1);
+
+        system
+            .registry
+            .lookup_pid(running)
+            .unwrap()
+            .control_block()
+            .is_running
+            .store(true, Ordering::Release);
+
+        let source = system.scheduler.get_worker(1).unwrap();
+        source.run_queue.push(running);
+        source.run_queue.push(idle);
+
+        assert_eq!(system.try_steal(0), Some(idle));
+        assert_eq!(system.try_steal(0), None);
+
+        // The running actor stays queued on its own worker, exactly once.
+        assert_eq!(source.run_queue.try_pop(), Some(running));
+        assert_eq!(source.run_queue.try_pop(), None);
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    /// Lets worker 0 steal from three loaded workers, returns how many actors were stolen from each.
+    fn steal_victims(steal: StealStrategy) -> Vec<usize> {
+        let config = Config {
+            steal,
+            ..Config::default()
+        };
+        let system = system_with_workers(config, 4);
+
+        for worker_id in 1..4 {
+            let worker = system.scheduler.get_worker(worker_id).unwrap();
+            for _ in 0..32 {
+                worker.run_queue.push(add_actor(&system, worker_id));
+            }
+        }
+
+        for _ in 0..30 {
+            let pid = system.try_steal(0).unwrap();
+            system.registry.remove(pid);
+        }
+
+        let victims = (1..4)
+            .map(|id| 32 - system.scheduler.get_worker(id).unwrap().run_queue_length())
+            .collect();
+
+        drop(unsafe { crate::thread::get() });
+
+        victims
+    }
+
+    #[test]
+    fn queue_histogram_shows_skewed_load() {
+        let system = system_with_workers(Config::default(), 4);
+
+        let busy = system.scheduler.get_worker(2).unwrap();
+        for _ in 0..32 {
+            busy.run_queue.push(add_actor(&system, 2));
+        }
+        for _ in 0..QUEUE_HISTOGRAM_BINS + 1 {
+            system
+                .scheduler
+                .get_worker(3)
+                .unwrap()
+                .run_queue
+                .push(crate::Pid(0));
+        }
+
+        let bins = system.queue_histogram().into_bins();
+        assert_eq!(bins[0], 2);
+        assert_eq!(bins[32], 1);
+        assert_eq!(bins[QUEUE_HISTOGRAM_BINS - 1], 1);
+        assert_eq!(bins.iter().sum::<usize>(), 4);
+
+        drop(unsafe { crate::thread::get() });
+    }
+
+    #[test]
+    fn random_steal_spreads_over_workers() {
+        let ring = steal_victims(StealStrategy::Ring);
+        let random = steal_victims(StealStrategy::Random);
+
+        assert_eq!(ring, [30, 0, 0]);
+        assert!(random.iter().all(|&n| n > 0), "{random:?}");
+        assert_eq!(random.iter().sum::<usize>(), 30);
+    }
+}
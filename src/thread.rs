@@ -2,11 +2,15 @@ use std::{
     cell::Cell,
     mem::ManuallyDrop,
     panic::{AssertUnwindSafe, catch_unwind, resume_unwind},
-    sync::Arc,
+    sync::{Arc, Weak, atomic::Ordering},
     thread::{self, JoinHandle},
 };
 
-use crate::system::System;
+use crate::{
+    actor::ToPid,
+    global::{SendError, sync},
+    system::System,
+};
 
 thread_local! {
     static SYSTEM: Cell<*const System> = const { Cell::new(std::ptr::null()) };
@@ -16,6 +20,11 @@ pub(crate) fn give(system: Arc<System>) {
     SYSTEM.set(Arc::into_raw(system));
 }
 
+/// Whether the current thread has access to a system, see [`spawn`].
+pub(crate) fn has_system() -> bool {
+    !SYSTEM.get().is_null()
+}
+
 /// SAFETY: Unsafe because you need to ensure that take was called.
 pub(crate) unsafe fn get() -> Arc<System> {
     unsafe { Arc::from_raw(SYSTEM.get()) }
@@ -50,3 +59,112 @@ where
         }
     })
 }
+
+/// A handle to the system that doesn't keep it alive.
+///
+/// Threads started with [`spawn`] hold on to the system until they finish.
+/// Long lived callbacks, e.g. ones handed to another library, can hold this instead.
+/// Every use checks the system is still running, once it stopped everything is a no-op.
+#[derive(Clone)]
+pub struct WeakContext {
+    system: Weak<System>,
+}
+
+impl WeakContext {
+    /// A weak handle to the system of the current thread.
+    ///
+    /// This must be called from an actor or a thread started with [`spawn`].
+    pub fn current() -> Self {
+        let system = unsafe { borrow() };
+
+        Self {
+            system: Arc::downgrade(&system),
+        }
+    }
+
+    /// Whether the system is still running.
+    pub fn is_alive(&self) -> bool {
+        self.system
+            .upgrade()
+            .is_some_and(|system| !system.scheduler.stopped.load(Ordering::Acquire))
+    }
+
+    /// Run `f` with access to the system, like on a thread started with [`spawn`].
+    ///
+    /// Returns `None` without running `f` if the system stopped.
+    pub fn with<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        // Puts back whatever system the thread had before, also when `f` panics.
+        struct Restore(*const System);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                drop(unsafe { get() });
+                SYSTEM.set(self.0);
+            }
+        }
+
+        let system = self.system.upgrade()?;
+        if system.scheduler.stopped.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let _restore = Restore(SYSTEM.replace(Arc::into_raw(system)));
+
+        Some(f())
+    }
+
+    /// Send a message to an actor, see [`sync::try_send`].
+    ///
+    /// Returns `SendError::NoProcess` if the system stopped.
+    pub fn send<M>(&self, to: impl ToPid, message: M) -> Result<(), SendError>
+    where
+        M: Send + 'static,
+    {
+        self.with(|| sync::try_send(to, message))
+            .unwrap_or(Err(SendError::NoProcess))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Exit, Pid, global, global::SendError, receive};
+
+    use super::WeakContext;
+
+    #[test]
+    fn weak_context_is_a_no_op_after_shutdown() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let weak = WeakContext::current();
+                let pid = global::sync::pid();
+
+                // Sends from a plain thread, which has no system of its own.
+                let sent = {
+                    let weak = weak.clone();
+                    std::thread::spawn(move || weak.send(pid, 42u32))
+                        .join()
+                        .unwrap()
+                };
+
+                let received = receive! {
+                    match u32 {
+                        n => n,
+                    }
+                };
+
+                completion.complete((weak, sent, received));
+
+                Exit::Normal
+            }
+        });
+
+        let (weak, sent, received) = result.unwrap();
+
+        assert_eq!(sent, Ok(()));
+        assert_eq!(received, 42);
+
+        assert!(!weak.is_alive());
+        assert_eq!(weak.with(|| ()), None);
+        assert_eq!(weak.send(Pid(1), 42u32), Err(SendError::NoProcess));
+    }
+}
@@ -1,116 +1,654 @@
+mod heap;
+
 use std::{
-    collections::BinaryHeap,
+    cell::Cell,
     sync::{
-        Condvar, Mutex,
-        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
     time::{Duration, Instant},
 };
 
-use crate::actor::{Pid, Signal};
+use crate::{
+    actor::{Pid, Priority, Signal},
+    clock::Clock,
+};
+
+use heap::TimerHeap;
+
+thread_local! {
+    /// Set on the timer thread while it delivers a batch.
+    static DELIVERING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Identifies a single timer entry.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct TimerId(u64);
 
 pub struct Timer {
+    clock: Arc<dyn Clock>,
+    slack: Duration,
     is_running: AtomicBool,
-    entries: Mutex<BinaryHeap<Entry>>,
+    next_id: AtomicU64,
+    entries: Mutex<TimerHeap>,
+    /// Held while a batch is delivered, so `cancel` can wait for a tick that is on its way.
+    delivering: Mutex<()>,
     cond: Condvar,
 }
 
 struct Entry {
+    id: TimerId,
     pid: Pid,
     expire_at: Instant,
     message: Signal,
+    interval: Option<Interval>,
 }
 
-impl Eq for Entry {}
-
-impl PartialEq for Entry {
-    fn eq(&self, other: &Self) -> bool {
-        self.expire_at == other.expire_at && self.pid == other.pid
-    }
-}
-
-// We want a min-heap, so reverse ordering:
-impl Ord for Entry {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // Reverse to get min-heap by expire_at
-        other.expire_at.cmp(&self.expire_at)
-    }
-}
-
-impl PartialOrd for Entry {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
+/// Makes an entry fire again every period, until it is canceled.
+struct Interval {
+    period: Duration,
+    message: Box<dyn Fn() -> Signal + Send>,
 }
 
 impl Timer {
-    pub fn new() -> Self {
+    pub fn new(clock: Arc<dyn Clock>, slack: Duration) -> Self {
         Timer {
+            clock,
+            slack,
             is_running: AtomicBool::new(true),
-            entries: Mutex::new(BinaryHeap::new()),
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(TimerHeap::new()),
+            delivering: Mutex::new(()),
             cond: Condvar::new(),
         }
     }
 
+    /// The current time according to the configured clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// The number of timers that have not fired yet.
+    pub fn pending(&self) -> usize {
+        self.entries.lock().expect("Failed to acquire lock").len()
+    }
+
     pub fn stop(&self) {
         self.is_running.store(false, Ordering::SeqCst);
+
+        // Hold the lock so the timer thread is either waiting or yet to check `is_running`.
+        let _entries = self.entries.lock().expect("Failed to acquire lock");
         self.cond.notify_one();
     }
 
-    pub fn wake_up(&self, pid: Pid, duration: Duration) {
-        let expire_at = Instant::now() + duration;
-        let mut entries = self.entries.lock().expect("Failed to acquire lock");
-        entries.push(Entry {
-            pid,
-            expire_at,
-            message: Signal::TimerFired,
-        });
-        self.cond.notify_one(); // Wake timer thread if sleeping
+    /// Sends a `Signal::TimerFired` to the actor after the given duration.
+    ///
+    /// The returned id is carried by the signal, so the actor can tell which timer fired.
+    pub fn wake_up(&self, pid: Pid, duration: Duration) -> TimerId {
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.insert(id, pid, duration, Signal::TimerFired(id), None);
+
+        id
     }
 
-    pub fn add<T>(&self, pid: Pid, duration: Duration, message: T)
+    /// Sends the message to the actor after the given duration, unless the timer is canceled first.
+    pub fn add<T>(&self, pid: Pid, from: Pid, duration: Duration, message: T) -> TimerId
     where
         T: Send + 'static,
     {
-        let expire_at = Instant::now() + duration;
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.insert(
+            id,
+            pid,
+            duration,
+            Signal::Message(from, Box::new(message), None, Priority::Normal),
+            None,
+        );
+
+        id
+    }
+
+    /// Sends a copy of the message to the actor every period, until the timer is canceled or the actor is gone.
+    ///
+    /// Ticks that were missed, e.g. because the timer thread was late, are skipped rather than sent all at once.
+    pub fn add_interval<T>(&self, pid: Pid, from: Pid, period: Duration, message: T) -> TimerId
+    where
+        T: Clone + Send + 'static,
+    {
+        assert!(!period.is_zero(), "An interval needs a non-zero period");
+
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let interval = Interval {
+            period,
+            message: Box::new(move || {
+                Signal::Message(from, Box::new(message.clone()), None, Priority::Normal)
+            }),
+        };
+        self.insert(id, pid, period, (interval.message)(), Some(interval));
+
+        id
+    }
+
+    /// Remove a timer that hasn't fired yet, returns `false` if it already fired or was canceled.
+    ///
+    /// Once this returns a canceled interval doesn't tick anymore, a tick that is being delivered arrives first.
+    pub fn cancel(&self, id: TimerId) -> bool {
+        // No need to wake the timer thread, it just wakes up early if this was the next timer.
+        let removed = self
+            .entries
+            .lock()
+            .expect("Failed to acquire lock")
+            .remove(id)
+            .is_some();
+
+        // Wait for the batch in flight, it might hold a tick of this interval.
+        // Delivery itself can cancel, e.g. when a message is dropped, it checks the entries before each tick.
+        if !DELIVERING.get() {
+            drop(self.delivering.lock().expect("Failed to acquire lock"));
+        }
+
+        removed
+    }
+
+    fn insert(
+        &self,
+        id: TimerId,
+        pid: Pid,
+        duration: Duration,
+        message: Signal,
+        interval: Option<Interval>,
+    ) {
+        let expire_at = self.now() + duration;
         let mut entries = self.entries.lock().expect("Failed to acquire lock");
         entries.push(Entry {
+            id,
             pid,
             expire_at,
-            message: Signal::Message(Box::new(message)),
+            message,
+            interval,
         });
         self.cond.notify_one(); // Wake timer thread if sleeping
     }
 
+    /// Fire timers until the system stops.
+    ///
+    /// Expired timers are delivered without holding the lock on the entries, so adding a timer never waits
+    /// for delivery. Only `cancel` waits for a batch that is being delivered, see `delivering`.
     pub fn run(&self) {
         let system = unsafe { crate::thread::borrow() };
 
+        let mut fired = Vec::new();
         let mut entries = self.entries.lock().expect("Failed to acquire lock");
+        // Timers that are still pending when the system stops are dropped.
         while self.is_running.load(Ordering::Relaxed) {
-            while let Some(entry) = entries.peek() {
-                let now = Instant::now();
+            let Some(expire_at) = entries.next_expiry() else {
+                // No timers; wait indefinitely until new timers are added
+                entries = self.cond.wait(entries).expect("Failed to acquire lock");
+                continue;
+            };
+
+            let now = self.now();
 
-                if entry.expire_at <= now {
+            if expire_at <= now {
+                // Fire everything that expired, with slack this is usually a batch.
+                while entries
+                    .next_expiry()
+                    .is_some_and(|expire_at| expire_at <= now)
+                {
                     let entry = entries.pop().unwrap();
-                    system.schedule(entry.pid);
-                    if let Some(actor) = system.registry.lookup_pid(entry.pid) {
-                        let _ = actor.send_signal(entry.message);
-                        system.schedule(entry.pid);
+
+                    // Intervals keep their id, so they can be canceled with the id that was handed out.
+                    // They are re-armed before delivering, so a cancel while the batch is delivered still finds them.
+                    let rearmed = if let Some(interval) = entry.interval {
+                        entries.push(Entry {
+                            id: entry.id,
+                            pid: entry.pid,
+                            expire_at: (entry.expire_at + interval.period)
+                                .max(now + interval.period),
+                            message: (interval.message)(),
+                            interval: Some(interval),
+                        });
+                        true
+                    } else {
+                        false
+                    };
+
+                    fired.push((entry.id, entry.pid, entry.message, rearmed));
+                }
+
+                // Deliver without holding the lock, adding a timer shouldn't wait for delivery.
+                let delivering = self.delivering.lock().expect("Failed to acquire lock");
+                DELIVERING.set(true);
+                drop(entries);
+                for (id, pid, message, rearmed) in fired.drain(..) {
+                    // A cancel after this check waits for `delivering`, so no tick arrives after it returns.
+                    if rearmed
+                        && !self
+                            .entries
+                            .lock()
+                            .expect("Failed to acquire lock")
+                            .contains(id)
+                    {
+                        // Canceled while the batch was delivered.
+                        continue;
+                    }
+
+                    // Delivering schedules the actor, only once the signal is in its inbox.
+                    if !system.deliver(pid, message) && rearmed {
+                        // The actor is gone, stop the interval.
+                        self.entries
+                            .lock()
+                            .expect("Failed to acquire lock")
+                            .remove(id);
+                    }
+                }
+                DELIVERING.set(false);
+                drop(delivering);
+                entries = self.entries.lock().expect("Failed to acquire lock");
+            } else {
+                // Oversleep by the slack, so timers expiring shortly after this one share the wakeup.
+                let wait_duration = self.clock.wait_timeout(expire_at - now + self.slack);
+                entries = self
+                    .cond
+                    .wait_timeout(entries, wait_duration)
+                    .expect("Failed to acquire lock")
+                    .0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+
+    use crate::{Clock, Config, Exit, MockClock, Pid, global, receive};
+
+    /// Runs a closure when dropped.
+    ///
+    /// A message sent to an actor that is gone is dropped while it's delivered, on the timer thread.
+    struct OnDrop(Option<Box<dyn FnOnce() + Send>>);
+
+    impl OnDrop {
+        fn new(f: impl FnOnce() + Send + 'static) -> Self {
+            Self(Some(Box::new(f)))
+        }
+    }
+
+    impl Drop for OnDrop {
+        fn drop(&mut self) {
+            if let Some(f) = self.0.take() {
+                f();
+            }
+        }
+    }
+
+    #[test]
+    fn delivers_without_holding_the_lock() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = global::sync::pid();
+                let gone = global::spawn(async || Exit::Normal).await;
+                global::sleep(Duration::from_millis(5)).await;
+
+                let probe = OnDrop::new(move || {
+                    let system = unsafe { crate::thread::borrow() };
+                    let unlocked = system.timer.entries.try_lock().is_ok();
+                    global::sync::send(root, unlocked);
+                });
+                global::sync::schedule(gone, probe, Duration::from_millis(1));
+
+                let unlocked = receive! {
+                    match bool {
+                        unlocked => unlocked,
+                    }
+                };
+
+                completion.complete(unlocked);
+
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(result, Some(true));
+    }
+
+    /// Reports to an actor whether the timer lock is free whenever a copy of it is dropped.
+    #[derive(Clone)]
+    struct LockProbe(Pid);
+
+    impl Drop for LockProbe {
+        fn drop(&mut self) {
+            let system = unsafe { crate::thread::borrow() };
+            let unlocked = system.timer.entries.try_lock().is_ok();
+            global::sync::send(self.0, unlocked);
+        }
+    }
+
+    #[test]
+    fn delivers_interval_ticks_without_holding_the_lock() {
+        let unlocked = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let root = global::sync::pid();
+                let gone = global::spawn(async || Exit::Normal).await;
+                global::sleep(Duration::from_millis(5)).await;
+
+                // The first tick is dropped while it's delivered, copies still held by the timer report later.
+                global::sync::schedule_interval(gone, LockProbe(root), Duration::from_millis(1));
+
+                let unlocked = receive! {
+                    match bool {
+                        unlocked => unlocked,
+                    }
+                };
+
+                completion.complete(unlocked);
+                Exit::Normal
+            }
+        });
+
+        assert_eq!(unlocked, Some(true));
+    }
+
+    #[test]
+    fn canceled_interval_doesnt_tick_after_cancel_returns() {
+        const PERIOD: Duration = Duration::from_millis(20);
+
+        #[derive(Clone)]
+        struct Tick;
+
+        // The probe and the first tick expire within the slack, so they fire in one batch.
+        let config = Config {
+            timer_slack: Duration::from_millis(5),
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let root = global::sync::pid();
+                let gone = global::spawn(async || Exit::Normal).await;
+                global::sleep(Duration::from_millis(5)).await;
+
+                // Expires right before the first tick, and cancels the interval while the batch is delivered.
+                let interval = Arc::new(Mutex::new(None));
+                let probe = {
+                    let interval = interval.clone();
+                    OnDrop::new(move || {
+                        let id = interval.lock().unwrap().unwrap();
+                        global::sync::send(root, global::sync::cancel_timer(id));
+                    })
+                };
+                global::sync::schedule(gone, probe, PERIOD);
+                *interval.lock().unwrap() =
+                    Some(global::sync::schedule_interval(root, Tick, PERIOD));
+
+                let mut ticks = 0;
+                let canceled = loop {
+                    receive! {
+                        match bool {
+                            canceled => break canceled,
+                        }
+                        match Tick {
+                            Tick => ticks += 1,
+                        }
                     }
+                };
 
-                    continue;
-                } else {
-                    let wait_duration = entry.expire_at - now;
-                    entries = self
-                        .cond
-                        .wait_timeout(entries, wait_duration)
-                        .expect("Failed to acquire lock")
-                        .0;
+                // A tick that was already on its way when the cancel returned would show up here.
+                loop {
+                    receive! {
+                        match Tick {
+                            Tick => ticks += 1,
+                        }
+                        after PERIOD * 3 => break,
+                    }
                 }
+
+                completion.complete((canceled, ticks));
+
+                Exit::Normal
             }
+        });
+
+        let (canceled, ticks) = result.unwrap();
+
+        assert!(canceled);
+        assert_eq!(ticks, 0);
+    }
+
+    /// Counts how often the inner future is polled.
+    struct CountPolls<F> {
+        future: Pin<Box<F>>,
+        polls: usize,
+    }
 
-            // No timers; wait indefinitely until new timers are added
-            entries = self.cond.wait(entries).expect("Failed to acquire lock");
+    impl<F: Future> Future for CountPolls<F> {
+        type Output = (F::Output, usize);
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            self.polls += 1;
+            let polls = self.polls;
+
+            self.future.as_mut().poll(cx).map(|output| (output, polls))
         }
     }
+
+    #[test]
+    fn sleep_wakes_at_deadline() {
+        const DURATION: Duration = Duration::from_millis(50);
+
+        let result = crate::run_single(|completion| {
+            async move || {
+                let now = Instant::now();
+                let sleep = CountPolls {
+                    future: Box::pin(global::sleep(DURATION)),
+                    polls: 0,
+                };
+                let ((), polls) = sleep.await;
+
+                completion.complete((now.elapsed(), polls));
+
+                Exit::Normal
+            }
+        });
+
+        let (elapsed, polls) = result.unwrap();
+
+        assert!(elapsed >= DURATION, "woke early after {elapsed:?}");
+        assert!(elapsed < DURATION * 4, "woke late after {elapsed:?}");
+
+        // The initial poll and the wake up.
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn dropped_sleeps_cancel_their_timers() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                // More than the armed timers that fit in a control block.
+                for _ in 0..8 {
+                    global::race(
+                        global::sleep(Duration::from_millis(1)),
+                        global::sleep(Duration::from_secs(60)),
+                    )
+                    .await;
+                }
+
+                let system = unsafe { crate::thread::borrow() };
+                let armed = global::with_context(|context| {
+                    crate::utils::lock(&context.actor.control_block().timers).len()
+                });
+                completion.complete((system.timer.pending(), armed));
+
+                Exit::Normal
+            }
+        });
+
+        let (pending, armed) = result.unwrap();
+
+        assert_eq!(pending, 0);
+        assert_eq!(armed, 0);
+    }
+
+    #[test]
+    fn overlapping_sleeps_both_complete() {
+        let result = crate::run_until(Config::default(), |completion| {
+            async move || {
+                let now = Instant::now();
+
+                // The first sleep to fire leaves a hole in front of the id of the second.
+                global::join(
+                    global::sleep(Duration::from_millis(10)),
+                    global::sleep(Duration::from_millis(20)),
+                )
+                .await;
+
+                completion.complete(now.elapsed());
+
+                Exit::Normal
+            }
+        });
+
+        let elapsed = result.unwrap();
+
+        assert!(
+            elapsed >= Duration::from_millis(20),
+            "woke early after {elapsed:?}"
+        );
+    }
+
+    #[test]
+    fn scheduled_message_fires_at_mock_deadline() {
+        const DELAY: Duration = Duration::from_millis(100);
+
+        struct Fired;
+
+        let clock = MockClock::new();
+        let fired = Arc::new(AtomicBool::new(false));
+        let (to_driver, from_actor) = mpsc::channel();
+        let (to_actor, from_driver) = mpsc::channel();
+
+        // Drives the clock from outside the system, real time only gives the timer thread a chance to run.
+        let driver = {
+            let clock = clock.clone();
+            let fired = fired.clone();
+
+            std::thread::spawn(move || {
+                // The system waits a little before starting the entry point.
+                while from_actor.try_recv().is_err() {
+                    clock.advance(Duration::from_millis(1));
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+
+                // The clock stands still until the actor scheduled the message.
+                to_actor.send(()).unwrap();
+                from_actor.recv().unwrap();
+
+                clock.advance(DELAY - Duration::from_millis(1));
+                std::thread::sleep(Duration::from_millis(50));
+                let early = fired.load(Ordering::Acquire);
+
+                clock.advance(Duration::from_millis(1));
+
+                early
+            })
+        };
+
+        let config = Config {
+            workers: 1,
+            clock: Arc::new(clock.clone()),
+            ..Config::default()
+        };
+
+        let result = {
+            let clock = clock.clone();
+
+            crate::run_until(config, |completion| {
+                async move || {
+                    // Wait for the driver to stop advancing, so the deadline is measured from a clock that holds still.
+                    to_driver.send(()).unwrap();
+                    from_driver.recv().unwrap();
+
+                    let start = clock.now();
+                    global::schedule(global::sync::pid(), Fired, DELAY).await;
+                    to_driver.send(()).unwrap();
+
+                    receive! {
+                        match Fired {
+                            Fired => {}
+                        }
+                    }
+
+                    fired.store(true, Ordering::Release);
+                    completion.complete(clock.now() - start);
+
+                    Exit::Normal
+                }
+            })
+        };
+
+        let early = driver.join().unwrap();
+        let elapsed = result.unwrap();
+
+        assert!(!early, "fired before the deadline");
+        assert_eq!(elapsed, DELAY);
+    }
+
+    #[test]
+    fn coalesced_timers_fire_within_slack() {
+        struct Deadline(Instant);
+
+        let slack = Duration::from_millis(2);
+        let config = Config {
+            timer_slack: slack,
+            ..Config::default()
+        };
+
+        let result = crate::run_until(config, |completion| {
+            async move || {
+                let pid = global::sync::pid();
+
+                // 1000 timers spread over a single millisecond.
+                for i in 0..1000u64 {
+                    let delay = Duration::from_millis(20) + Duration::from_micros(i);
+                    global::sync::schedule(pid, Deadline(Instant::now() + delay), delay);
+                }
+
+                let mut early = 0;
+                let mut latest = Duration::ZERO;
+                for _ in 0..1000 {
+                    receive! {
+                        match Deadline {
+                            Deadline(deadline) => {
+                                let now = Instant::now();
+                                if now < deadline {
+                                    early += 1;
+                                }
+                                latest = latest.max(now.saturating_duration_since(deadline));
+                            }
+                        }
+                    }
+                }
+
+                completion.complete((early, latest));
+
+                Exit::Normal
+            }
+        });
+
+        let (early, latest) = result.unwrap();
+
+        assert_eq!(early, 0);
+        // Leave some room for the receiving actor to get scheduled.
+        assert!(latest < slack + Duration::from_millis(20), "{latest:?}");
+    }
 }
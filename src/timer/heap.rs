@@ -0,0 +1,183 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::timer::TimerId;
+
+use super::Entry;
+
+/// A min-heap of timer entries that also knows where each entry is, so any entry can be removed in O(log n).
+///
+/// Entries are ordered by deadline, entries with the same deadline fire in the order they were added.
+pub struct TimerHeap {
+    entries: Vec<Entry>,
+    positions: HashMap<TimerId, usize>,
+}
+
+impl TimerHeap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        let index = self.entries.len();
+        self.positions.insert(entry.id, index);
+        self.entries.push(entry);
+        self.sift_up(index);
+    }
+
+    /// The deadline of the entry that expires first.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.entries.first().map(|entry| entry.expire_at)
+    }
+
+    pub fn contains(&self, id: TimerId) -> bool {
+        self.positions.contains_key(&id)
+    }
+
+    pub fn pop(&mut self) -> Option<Entry> {
+        self.remove_at(0)
+    }
+
+    /// Remove an entry that hasn't fired yet, returns `None` if there is no such entry.
+    pub fn remove(&mut self, id: TimerId) -> Option<Entry> {
+        let index = *self.positions.get(&id)?;
+        self.remove_at(index)
+    }
+
+    fn remove_at(&mut self, index: usize) -> Option<Entry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+
+        let last = self.entries.len() - 1;
+        self.swap(index, last);
+
+        let entry = self.entries.pop()?;
+        self.positions.remove(&entry.id);
+
+        // The entry that was moved into the hole can belong either above or below it.
+        if index < self.entries.len() {
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+
+        Some(entry)
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if !self.is_before(index, parent) {
+                break;
+            }
+
+            self.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let left = 2 * index + 1;
+            let right = left + 1;
+            let mut first = index;
+
+            if left < self.entries.len() && self.is_before(left, first) {
+                first = left;
+            }
+            if right < self.entries.len() && self.is_before(right, first) {
+                first = right;
+            }
+            if first == index {
+                break;
+            }
+
+            self.swap(index, first);
+            index = first;
+        }
+    }
+
+    fn is_before(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (&self.entries[a], &self.entries[b]);
+        (a.expire_at, a.id.0) < (b.expire_at, b.id.0)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+
+        self.entries.swap(a, b);
+        self.positions.insert(self.entries[a].id, a);
+        self.positions.insert(self.entries[b].id, b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        Pid,
+        actor::Signal,
+        timer::{Entry, TimerId},
+    };
+
+    use super::TimerHeap;
+
+    fn entry(id: u64, expire_at: Instant) -> Entry {
+        Entry {
+            id: TimerId(id),
+            pid: Pid(0),
+            expire_at,
+            message: Signal::TimerFired(TimerId(id)),
+            interval: None,
+        }
+    }
+
+    #[test]
+    fn pops_in_deadline_order_without_canceled_entries() {
+        let start = Instant::now();
+        let mut heap = TimerHeap::new();
+
+        // A simple LCG gives a repeatable shuffled set of deadlines.
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut deadlines = Vec::new();
+        for id in 0..1000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let deadline = start + Duration::from_micros(seed >> 50);
+
+            heap.push(entry(id, deadline));
+            deadlines.push((deadline, id));
+        }
+
+        for id in (0..1000).step_by(3) {
+            assert!(heap.remove(TimerId(id)).is_some());
+        }
+        assert!(heap.remove(TimerId(0)).is_none());
+        assert!(heap.remove(TimerId(5000)).is_none());
+
+        let mut expected: Vec<_> = deadlines
+            .into_iter()
+            .filter(|(_, id)| id % 3 != 0)
+            .collect();
+        expected.sort();
+
+        assert_eq!(heap.len(), expected.len());
+        assert_eq!(heap.next_expiry(), Some(expected[0].0));
+
+        let mut popped = Vec::new();
+        while let Some(entry) = heap.pop() {
+            popped.push((entry.expire_at, entry.id.0));
+        }
+
+        assert_eq!(popped, expected);
+        assert!(heap.positions.is_empty());
+    }
+}
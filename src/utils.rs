@@ -1,9 +1,13 @@
 mod cache_padded;
+mod histogram;
+mod lock;
 mod queue;
 mod time;
 mod unsorted_set;
 
 pub use cache_padded::CachePadded;
+pub use histogram::Histogram;
+pub use lock::lock;
 pub use queue::Queue;
-pub use time::Timestamp;
+pub use time::{TimeFormat, Timestamp};
 pub use unsorted_set::UnsortedSet;
@@ -0,0 +1,35 @@
+/// Counts values in `N` bins of width one, values past the last bin are counted in the last bin.
+#[derive(Clone, Debug)]
+pub struct Histogram<const N: usize> {
+    bins: [usize; N],
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new() -> Self {
+        Self { bins: [0; N] }
+    }
+
+    pub fn record(&mut self, value: usize) {
+        self.bins[value.min(N - 1)] += 1;
+    }
+
+    pub fn into_bins(self) -> [usize; N] {
+        self.bins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+
+    #[test]
+    fn large_values_go_in_last_bin() {
+        let mut histogram = Histogram::<8>::new();
+
+        for value in [3, 1, 7, 1, 100] {
+            histogram.record(value);
+        }
+
+        assert_eq!(histogram.into_bins(), [0, 2, 0, 1, 0, 0, 0, 2]);
+    }
+}
@@ -0,0 +1,9 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Lock a mutex, recovering it if a thread panicked while holding the lock.
+///
+/// Only use this for state that stays consistent when an update is interrupted by a panic,
+/// like the collections owned by an actor. Runtime internals should keep propagating poisoning.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
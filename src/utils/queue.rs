@@ -6,6 +6,11 @@ use std::{
 
 use crate::utils::CachePadded;
 
+/// A bounded multi producer, single consumer queue.
+///
+/// `head`, `tail` and `published` count up forever and wrap around at `usize::MAX`.
+/// All arithmetic on them wraps, and `S` is a power of two so `counter % S` stays continuous across the wrap.
+/// This only holds as long as at most `S` values are in flight, which `push` guarantees.
 pub struct Queue<const S: usize, T> {
     buffer: [UnsafeCell<MaybeUninit<T>>; S],
     head: CachePadded<AtomicUsize>,
@@ -18,11 +23,18 @@ unsafe impl<const S: usize, T> Sync for Queue<S, T> where T: Send {}
 
 impl<const S: usize, T> Queue<S, T> {
     pub fn new() -> Self {
+        Self::starting_at(0)
+    }
+
+    /// Create a queue whose counters start at `start`, tests use it to cross the wrap around.
+    fn starting_at(start: usize) -> Self {
+        const { assert!(S.is_power_of_two(), "Queue size must be a power of two") };
+
         Self {
             buffer: unsafe { MaybeUninit::uninit().assume_init() },
-            head: AtomicUsize::new(0).into(),
-            tail: AtomicUsize::new(0).into(),
-            published: AtomicUsize::new(0).into(),
+            head: AtomicUsize::new(start).into(),
+            tail: AtomicUsize::new(start).into(),
+            published: AtomicUsize::new(start).into(),
         }
     }
 
@@ -36,14 +48,26 @@ impl<const S: usize, T> Queue<S, T> {
             let head = self.head.load(Ordering::Acquire);
             let tail = self.tail.load(Ordering::Acquire);
 
-            if (head - tail) >= self.capacity() {
+            let occupied = head.wrapping_sub(tail);
+
+            if occupied > self.capacity() {
+                // `head` is stale, values were pushed and popped after we loaded it.
+                continue;
+            }
+
+            if occupied == self.capacity() {
                 // Queue full
                 return Err(value);
             }
 
             if self
                 .head
-                .compare_exchange_weak(head, head + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .compare_exchange_weak(
+                    head,
+                    head.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
                 .is_ok()
             {
                 let index = head % self.capacity();
@@ -65,8 +89,10 @@ impl<const S: usize, T> Queue<S, T> {
                 loop {
                     let published = self.published.load(Ordering::Acquire);
 
+                    // Equality is wrap safe, `published` never gets ahead of `head`.
                     if published == head {
-                        self.published.store(head + 1, Ordering::Release);
+                        self.published
+                            .store(head.wrapping_add(1), Ordering::Release);
                         break;
                     }
                     std::hint::spin_loop();
@@ -81,7 +107,7 @@ impl<const S: usize, T> Queue<S, T> {
         let tail = self.tail.load(Ordering::Acquire);
         let published = self.published.load(Ordering::Acquire);
 
-        if tail >= published {
+        if tail == published {
             // Queue empty or slots not ready yet
             return None;
         }
@@ -90,7 +116,7 @@ impl<const S: usize, T> Queue<S, T> {
 
         let value = unsafe { self.buffer.get_unchecked(index).get().read().assume_init() };
 
-        self.tail.store(tail + 1, Ordering::Release);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
 
         Some(value)
     }
@@ -100,25 +126,24 @@ impl<const S: usize, T> Queue<S, T> {
         let tail = self.tail.load(Ordering::Acquire);
         let published = self.published.load(Ordering::Acquire);
 
-        tail >= published
+        tail == published
     }
 
     /// Returns true if the queue is full.
     #[allow(dead_code)]
     pub fn is_full(&self) -> bool {
         let head = self.head.load(Ordering::Acquire);
-        let tail = self.published.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
 
-        (head - tail) >= self.capacity()
+        head.wrapping_sub(tail) >= self.capacity()
     }
 
     /// Returns the number of elements in the queue.
-    #[allow(dead_code)]
     pub fn len(&self) -> usize {
         let tail = self.tail.load(Ordering::Acquire);
         let published = self.published.load(Ordering::Acquire);
 
-        published.saturating_add(tail)
+        published.wrapping_sub(tail)
     }
 }
 
@@ -129,3 +154,47 @@ impl<const S: usize, T> Drop for Queue<S, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Queue;
+
+    #[test]
+    fn len_counts_what_is_left_after_pops() {
+        let queue = Queue::<8, usize>::new();
+
+        for i in 0..5 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.len(), 5);
+
+        queue.pop();
+        queue.pop();
+        assert_eq!(queue.len(), 3);
+
+        queue.push(5).unwrap();
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn push_and_pop_across_the_wrap() {
+        let queue = Queue::<4, usize>::starting_at(usize::MAX - 2);
+
+        for round in 0..4 {
+            for i in 0..4 {
+                queue.push(round * 4 + i).unwrap();
+            }
+
+            assert!(queue.is_full());
+            assert_eq!(queue.len(), 4);
+            assert_eq!(queue.push(0), Err(0));
+
+            for i in 0..4 {
+                assert_eq!(queue.pop(), Some(round * 4 + i));
+            }
+
+            assert!(queue.is_empty());
+            assert_eq!(queue.len(), 0);
+        }
+    }
+}
@@ -1,8 +1,21 @@
 use std::{
     fmt::Display,
-    time::{SystemTime, UNIX_EPOCH},
+    ops::Add,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// How a timestamp is displayed, see [`Timestamp::format`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum TimeFormat {
+    /// ISO 8601 in UTC, e.g. `2023-11-14T22:13:20Z`.
+    #[default]
+    Utc,
+    /// ISO 8601 shifted by a fixed number of minutes east of UTC, e.g. `2023-11-15T00:13:20+02:00`.
+    Offset(i32),
+    /// Seconds since the unix epoch, e.g. `1700000000`.
+    UnixSeconds,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Timestamp {
     pub year: u32,
@@ -11,12 +24,24 @@ pub struct Timestamp {
     pub hour: u32,
     pub minute: u32,
     pub second: u32,
+    pub millisecond: u32,
 }
 
 impl Timestamp {
     pub fn now() -> Self {
-        let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Self::from_system_time(SystemTime::now())
+    }
+
+    /// Create a timestamp from a `SystemTime`.
+    ///
+    /// Times before the unix epoch are clamped to the epoch.
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let duration = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+
+        Self::from_unix(duration)
+    }
 
+    fn from_unix(duration: Duration) -> Self {
         let mut secs = duration.as_secs();
 
         let mut year = 1970;
@@ -57,14 +82,76 @@ impl Timestamp {
             hour: hour as u32,
             minute: minute as u32,
             second: second as u32,
+            millisecond: duration.subsec_millis(),
         }
     }
 
+    fn to_unix(self) -> Duration {
+        let mut days = (1970..self.year)
+            .map(|year| if Self::is_leap(year) { 366 } else { 365 })
+            .sum::<u64>();
+
+        days += Self::days_in_month(self.year)[..(self.month - 1) as usize]
+            .iter()
+            .map(|&d| d as u64)
+            .sum::<u64>();
+        days += (self.day - 1) as u64;
+
+        let secs =
+            days * 86_400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+
+        Duration::from_secs(secs) + Duration::from_millis(self.millisecond as u64)
+    }
+
     pub fn to_iso8601(&self) -> String {
-        format!(
-            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        self.to_string()
+    }
+
+    pub fn format(&self, format: TimeFormat) -> String {
+        match format {
+            TimeFormat::Utc => self.to_string(),
+            TimeFormat::Offset(minutes) => {
+                // The offset is applied to the epoch, then the local time is decomposed as if it were UTC.
+                let unix = self.to_unix();
+                let shift = Duration::from_secs(minutes.unsigned_abs() as u64 * 60);
+                let local = if minutes < 0 {
+                    unix.saturating_sub(shift)
+                } else {
+                    unix + shift
+                };
+
+                format!(
+                    "{}{}{:02}:{:02}",
+                    Self::from_unix(local).date_time(),
+                    if minutes < 0 { '-' } else { '+' },
+                    minutes.unsigned_abs() / 60,
+                    minutes.unsigned_abs() % 60
+                )
+            }
+            TimeFormat::UnixSeconds => {
+                let unix = self.to_unix();
+
+                if self.millisecond != 0 {
+                    format!("{}.{:03}", unix.as_secs(), self.millisecond)
+                } else {
+                    unix.as_secs().to_string()
+                }
+            }
+        }
+    }
+
+    /// The date and time without a timezone.
+    fn date_time(&self) -> String {
+        let mut date_time = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
             self.year, self.month, self.day, self.hour, self.minute, self.second
-        )
+        );
+
+        if self.millisecond != 0 {
+            date_time.push_str(&format!(".{:03}", self.millisecond));
+        }
+
+        date_time
     }
 
     fn is_leap(year: u32) -> bool {
@@ -80,13 +167,82 @@ impl Timestamp {
     }
 }
 
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self::from_unix(self.to_unix() + rhs)
+    }
+}
+
 impl Display for Timestamp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // Default Display uses ISO 8601 UTC format
-        write!(
-            f,
-            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
-            self.year, self.month, self.day, self.hour, self.minute, self.second
-        )
+        write!(f, "{}Z", self.date_time())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{TimeFormat, Timestamp};
+
+    #[test]
+    fn test_from_system_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let timestamp = Timestamp::from_system_time(time);
+
+        assert_eq!(
+            timestamp,
+            Timestamp {
+                year: 2023,
+                month: 11,
+                day: 14,
+                hour: 22,
+                minute: 13,
+                second: 20,
+                millisecond: 0,
+            }
+        );
+        assert_eq!(timestamp.to_iso8601(), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_millisecond_format() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_042);
+        let timestamp = Timestamp::from_system_time(time);
+
+        assert_eq!(timestamp.millisecond, 42);
+        assert_eq!(timestamp.to_string(), "2023-11-14T22:13:20.042Z");
+    }
+
+    #[test]
+    fn test_time_formats() {
+        let timestamp =
+            Timestamp::from_system_time(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        assert_eq!(timestamp.format(TimeFormat::Utc), "2023-11-14T22:13:20Z");
+        assert_eq!(
+            timestamp.format(TimeFormat::Offset(120)),
+            "2023-11-15T00:13:20+02:00"
+        );
+        assert_eq!(
+            timestamp.format(TimeFormat::Offset(-330)),
+            "2023-11-14T16:43:20-05:30"
+        );
+        assert_eq!(timestamp.format(TimeFormat::UnixSeconds), "1700000000");
+    }
+
+    #[test]
+    fn test_add_duration() {
+        // 2023-12-31T23:59:59.500Z
+        let time = UNIX_EPOCH + Duration::from_millis(1_704_067_199_500);
+        let timestamp = Timestamp::from_system_time(time) + Duration::from_millis(600);
+
+        assert_eq!(timestamp.to_string(), "2024-01-01T00:00:00.100Z");
+
+        let timestamp = timestamp + Duration::from_secs(59 * 86_400);
+        assert_eq!(timestamp.to_string(), "2024-02-29T00:00:00.100Z");
     }
 }
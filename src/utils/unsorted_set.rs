@@ -22,10 +22,23 @@ where
     /// This will override any existing items in this set with items from the other set.
     pub fn merge_with<const M: usize>(&mut self, other: UnsortedSet<T, M>) {
         for item in other.into_iter() {
-            self.insert(item);
+            self.upsert(item);
         }
     }
 
+    /// Add the item, replacing an equal item that is already in the set.
+    ///
+    /// Unlike `insert` this matters for items that are only compared by part of their value, e.g. metadata by key.
+    /// Returns `true` if the item was added, `false` if it replaced an item.
+    pub fn upsert(&mut self, value: T) -> bool {
+        if let Some(item) = self.iter_mut().find(|item| **item == value) {
+            *item = value;
+            return false;
+        }
+
+        self.insert(value)
+    }
+
     /// If the item isn't already in the set, add it.
     ///
     /// Returns `true` if the item was added, `false` if it was already in the set.
@@ -55,7 +68,8 @@ where
     ///
     /// Returns `true` if the item was removed, `false` if it was not in the set.
     pub fn remove(&mut self, value: &T) -> bool {
-        for i in 0..self.len {
+        // Items aren't packed at the front, removing leaves holes anywhere in the array.
+        for i in 0..N {
             if let Some(data) = &self.data[i] {
                 if data == value {
                     self.data[i] = None;
@@ -83,7 +97,7 @@ where
         false
     }
 
-    pub fn contains(&mut self, value: &T) -> bool {
+    pub fn contains(&self, value: &T) -> bool {
         for i in 0..N {
             if let Some(data) = &self.data[i] {
                 if data == value {
@@ -110,6 +124,48 @@ where
             .chain(self.overflow.iter())
     }
 
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.data
+            .iter_mut()
+            .filter_map(|item| item.as_mut())
+            .chain(self.overflow.iter_mut())
+    }
+
+    /// Only keep the items for which `f` returns `true`.
+    ///
+    /// Gaps left in the array are filled with items from the overflow.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        for slot in self.data.iter_mut() {
+            if slot.as_ref().is_some_and(|item| !f(item)) {
+                *slot = None;
+                self.len -= 1;
+            }
+        }
+
+        self.overflow.retain(|item| f(item));
+
+        for slot in self.data.iter_mut() {
+            if self.overflow.is_empty() {
+                break;
+            }
+
+            if slot.is_none() {
+                *slot = self.overflow.pop();
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Remove all items from the set.
+    pub fn clear(&mut self) {
+        self.data.fill_with(|| None);
+        self.len = 0;
+        self.overflow.clear();
+    }
+
     pub fn into_iter(self) -> impl Iterator<Item = T> {
         self.data
             .into_iter()
@@ -117,3 +173,164 @@ where
             .chain(self.overflow.into_iter())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::{MetaKeyValue, MetaValue};
+
+    use super::UnsortedSet;
+
+    fn sorted<const N: usize>(set: &UnsortedSet<u32, N>) -> Vec<u32> {
+        let mut items = set.iter().copied().collect::<Vec<_>>();
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn test_iter_mut() {
+        let mut set = UnsortedSet::<u32, 2>::new();
+        for i in 0..4 {
+            set.insert(i);
+        }
+
+        for item in set.iter_mut() {
+            *item *= 10;
+        }
+
+        assert_eq!(sorted(&set), vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn test_retain_across_overflow() {
+        let mut set = UnsortedSet::<u32, 4>::new();
+        for i in 0..10 {
+            set.insert(i);
+        }
+
+        // Removes from both the array (0..4) and the overflow (4..10).
+        set.retain(|&item| item % 3 == 0);
+
+        assert_eq!(set.len(), 4);
+        assert_eq!(sorted(&set), vec![0, 3, 6, 9]);
+
+        // The gaps in the array were filled, so everything fits inline again.
+        assert!(set.overflow.is_empty());
+        assert_eq!(set.len, 4);
+    }
+
+    #[test]
+    fn test_remove_behind_a_hole() {
+        let mut set = UnsortedSet::<u32, 4>::new();
+        for i in 0..3 {
+            set.insert(i);
+        }
+
+        // Leaves a hole in the first slot, the last item now sits past `len`.
+        assert!(set.remove(&0));
+        assert!(set.remove(&2));
+        assert!(!set.contains(&2));
+        assert_eq!(sorted(&set), vec![1]);
+    }
+
+    #[test]
+    fn test_remove_after_retain() {
+        let mut set = UnsortedSet::<u32, 4>::new();
+        for i in 0..4 {
+            set.insert(i);
+        }
+
+        // Leaves holes in front of the remaining items.
+        set.retain(|&item| item >= 2);
+
+        assert!(!set.contains(&0));
+        assert!(set.contains(&3));
+        assert!(set.remove(&3));
+        assert!(!set.remove(&3));
+        assert_eq!(sorted(&set), vec![2]);
+
+        // The holes are reused.
+        set.insert(10);
+        set.insert(11);
+        set.insert(12);
+        assert!(set.overflow.is_empty());
+        assert!(set.remove(&12));
+        assert_eq!(sorted(&set), vec![2, 10, 11]);
+    }
+
+    #[test]
+    fn test_retain_keeps_overflow() {
+        let mut set = UnsortedSet::<u32, 2>::new();
+        for i in 0..6 {
+            set.insert(i);
+        }
+
+        set.retain(|&item| item != 0);
+
+        assert_eq!(set.len(), 5);
+        assert_eq!(set.len, 2);
+        assert_eq!(sorted(&set), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut set = UnsortedSet::<u32, 2>::new();
+        for i in 0..4 {
+            set.insert(i);
+        }
+
+        set.clear();
+
+        assert!(set.is_empty());
+        assert_eq!(set.iter().count(), 0);
+        assert!(set.insert(1));
+    }
+
+    #[test]
+    fn test_merge_overrides_metadata() {
+        fn meta(key: &'static str, value: &'static str) -> MetaKeyValue {
+            MetaKeyValue {
+                key,
+                value: value.into(),
+            }
+        }
+
+        let mut set = UnsortedSet::<MetaKeyValue, 2>::new();
+        set.insert(meta("request_id", "parent"));
+        set.insert(meta("user", "alice"));
+        set.insert(meta("overflowed", "parent"));
+
+        // `insert` keeps the existing value, `merge_with` replaces it.
+        assert!(!set.insert(meta("user", "bob")));
+
+        let mut other = UnsortedSet::<MetaKeyValue, 4>::new();
+        other.insert(meta("request_id", "child"));
+        other.insert(meta("overflowed", "child"));
+        other.insert(meta("new", "child"));
+        set.merge_with(other);
+
+        let value = |key| {
+            set.iter()
+                .find(|meta| meta.key == key)
+                .map(|meta| meta.value.clone())
+        };
+
+        assert_eq!(set.len(), 4);
+        assert_eq!(value("request_id"), Some(MetaValue::StaticStr("child")));
+        assert_eq!(value("user"), Some(MetaValue::StaticStr("alice")));
+        assert_eq!(value("overflowed"), Some(MetaValue::StaticStr("child")));
+        assert_eq!(value("new"), Some(MetaValue::StaticStr("child")));
+    }
+
+    #[test]
+    fn test_contains_shared() {
+        let mut set = UnsortedSet::<u32, 2>::new();
+        for i in 0..4 {
+            set.insert(i);
+        }
+
+        let shared = &set;
+        assert!(shared.contains(&1));
+        assert!(shared.contains(&3));
+        assert!(!shared.contains(&4));
+    }
+}
@@ -1,21 +1,17 @@
 mod run_queue;
 
 use std::{
-    cell::UnsafeCell,
-    marker::PhantomData,
     sync::{
         Arc,
         atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
     thread::Thread,
+    time::{Duration, Instant},
 };
 
 pub use run_queue::RunQueue;
 
-use crate::{
-    actor::{Pid, Signal},
-    migration::Migration,
-};
+use crate::{actor::Pid, migration::Migration};
 
 pub type WorkerId = usize;
 
@@ -31,6 +27,42 @@ pub struct Worker {
     pub reductions: AtomicU64,
     pub max_queue_length: AtomicUsize,
     pub migration: Migration,
+    /// The xorshift state used to pick a random worker to steal from.
+    pub steal_rng: AtomicU64,
+    /// The actor that is being run, `Pid::invalid()` in between actors.
+    current: AtomicU64,
+    polls: AtomicU64,
+    actors_run: AtomicU64,
+    parks: AtomicU64,
+    spurious_wakeups: AtomicU64,
+    steal_attempts: AtomicU64,
+    steals: AtomicU64,
+    parked_nanos: AtomicU64,
+    running_nanos: AtomicU64,
+}
+
+/// The counters of a single worker, see [`crate::global::stats`].
+///
+/// Counters only go up, subtract two snapshots to get the activity in between.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WorkerSnapshot {
+    pub run_queue_length: usize,
+    /// The number of times an actor was polled, each poll handles at most one signal.
+    pub polls: u64,
+    /// The number of times an actor was taken from the run queue and run.
+    pub actors_run: u64,
+    /// The number of times the worker parked because there was nothing to do.
+    pub parks: u64,
+    /// The number of times the worker woke up from parking and still found nothing to do.
+    pub spurious_wakeups: u64,
+    /// The number of times the worker tried to steal an actor from another worker.
+    pub steal_attempts: u64,
+    /// The number of actors the worker stole, a worker that is busy spinning attempts far more than it steals.
+    pub steals: u64,
+    /// Time spent parked, waiting for work.
+    pub parked: Duration,
+    /// Time spent running actors.
+    pub running: Duration,
 }
 
 impl Worker {
@@ -42,16 +74,61 @@ impl Worker {
             reductions: AtomicU64::new(2000 * 1000),
             max_queue_length: AtomicUsize::new(0),
             migration: Migration::new(),
+            steal_rng: AtomicU64::new(0x9E37_79B9_7F4A_7C15 ^ (spawn_at as u64 + 1)),
+            current: AtomicU64::new(Pid::invalid().0),
+            polls: AtomicU64::new(0),
+            actors_run: AtomicU64::new(0),
+            parks: AtomicU64::new(0),
+            spurious_wakeups: AtomicU64::new(0),
+            steal_attempts: AtomicU64::new(0),
+            steals: AtomicU64::new(0),
+            parked_nanos: AtomicU64::new(0),
+            running_nanos: AtomicU64::new(0),
         }
     }
 
+    /// The next number from the xorshift sequence of this worker.
+    pub fn next_random(&self) -> u64 {
+        let mut x = self.steal_rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.steal_rng.store(x, Ordering::Relaxed);
+
+        x
+    }
+
+    /// The actor this worker was running when it stopped, if it stopped while running one.
+    pub fn take_current(&self) -> Option<Pid> {
+        let pid = Pid(self.current.swap(Pid::invalid().0, Ordering::AcqRel));
+
+        (pid != Pid::invalid()).then_some(pid)
+    }
+
     pub fn run_queue_length(&self) -> usize {
         self.run_queue.len()
     }
 
+    pub fn snapshot(&self) -> WorkerSnapshot {
+        WorkerSnapshot {
+            run_queue_length: self.run_queue_length(),
+            polls: self.polls.load(Ordering::Relaxed),
+            actors_run: self.actors_run.load(Ordering::Relaxed),
+            parks: self.parks.load(Ordering::Relaxed),
+            spurious_wakeups: self.spurious_wakeups.load(Ordering::Relaxed),
+            steal_attempts: self.steal_attempts.load(Ordering::Relaxed),
+            steals: self.steals.load(Ordering::Relaxed),
+            parked: Duration::from_nanos(self.parked_nanos.load(Ordering::Relaxed)),
+            running: Duration::from_nanos(self.running_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
     pub fn run(&self) {
         let system = unsafe { crate::thread::borrow() };
 
+        // Whether the previous iteration parked, to tell real wakeups from spurious ones.
+        let mut woke_up = false;
+
         while self.running.load(Ordering::Relaxed) {
             self.max_queue_length
                 .fetch_max(self.run_queue.len(), Ordering::Relaxed);
@@ -75,14 +152,37 @@ impl Worker {
             }
 
             if let Some(pid) = self.run_queue.try_pop() {
+                woke_up = false;
                 self.run_actor(pid);
-            } else if let Some(pid) = system.try_steal(self.spawn_at) {
+            } else if let Some(pid) = self.try_steal() {
+                woke_up = false;
                 eprintln!("Worker {} stealing pid {}", self.spawn_at, pid.0);
                 self.run_actor(pid);
             } else {
+                if woke_up {
+                    self.spurious_wakeups.fetch_add(1, Ordering::Relaxed);
+                }
+
+                self.parks.fetch_add(1, Ordering::Relaxed);
+                let parked_at = Instant::now();
                 std::thread::park();
+                add_elapsed(&self.parked_nanos, parked_at);
+                woke_up = true;
             }
         }
+
+        // The last actor run might have been queued again after this worker was removed.
+        system.redistribute(self);
+    }
+
+    fn try_steal(&self) -> Option<Pid> {
+        let system = unsafe { crate::thread::borrow() };
+
+        self.steal_attempts.fetch_add(1, Ordering::Relaxed);
+        let pid = system.try_steal(self.spawn_at)?;
+        self.steals.fetch_add(1, Ordering::Relaxed);
+
+        Some(pid)
     }
 
     fn run_actor(&self, pid: Pid) {
@@ -94,49 +194,64 @@ impl Worker {
         };
 
         let control_block = actor.control_block();
+        let started_at = Instant::now();
+
+        if control_block.is_running.swap(true, Ordering::AcqRel) {
+            // Another worker is still running this actor, e.g. it was moved off a stopped worker.
+            // It stays scheduled, try again later.
+            self.run_queue.push(pid);
+            return;
+        }
 
         control_block.is_scheduled.store(false, Ordering::Release);
-        control_block.is_running.store(true, Ordering::Release);
+        self.current.store(pid.0, Ordering::Release);
 
-        let global_context = UnsafeCell::new(crate::global::GlobalContext {
-            budget: 0,
-            actor: &actor,
-            _marker: PhantomData,
-        });
+        let global_context = crate::global::GlobalContext::new(&actor);
+        let context_guard = crate::global::enter_context(&global_context);
 
-        crate::global::set_context(global_context.get());
+        let mut polls = 1;
+        let mut result = actor.as_ref().poll();
+
+        // Keep handling pending signals for a while, unless the actor was scheduled again (e.g. it yielded).
+        while result.is_none()
+            && polls < system.poll_batch
+            && actor.has_messages()
+            && !control_block.is_scheduled.load(Ordering::Acquire)
+        {
+            polls += 1;
+            result = actor.as_ref().poll();
+        }
 
-        match actor.as_ref().poll() {
+        match result {
             None => {
                 if actor.has_messages() {
                     // scheduler.wake(pid);
                     if control_block.try_schedule() {
                         // Re-queue actor because it still has messages to process.
-                        // TODO Consider a bounded inner loop for more efficiency.
                         self.run_queue.push(pid);
                     }
                 }
             }
             Some(exit) => {
                 eprintln!("Actor {} exited with reason {:?}", pid.0, exit);
-                let links = actor.links();
+                // Drop the future while the context is still set, so destructors can use it.
+                actor.terminate();
 
-                // TODO: Set the inner actor to Uninitialized; so we *know* we drop the future in context.
-
-                system.registry.remove(pid);
-
-                for linked in links.iter().copied() {
-                    if let Some(child) = system.registry.lookup_pid(linked) {
-                        child.send_signal(Signal::Exit(pid, exit.clone()));
-
-                        system.schedule(linked);
-                    }
-                }
+                system.exited(pid, &*actor, &exit);
             }
         }
 
-        crate::global::reset_context();
+        drop(context_guard);
 
         control_block.is_running.store(false, Ordering::Release);
+        self.current.store(Pid::invalid().0, Ordering::Release);
+
+        self.polls.fetch_add(polls as u64, Ordering::Relaxed);
+        self.actors_run.fetch_add(1, Ordering::Relaxed);
+        add_elapsed(&self.running_nanos, started_at);
     }
 }
+
+fn add_elapsed(counter: &AtomicU64, since: Instant) {
+    counter.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
@@ -20,6 +20,7 @@ impl<T> RunQueue<T> {
         }
     }
 
+    /// The queue is unbounded, pushing only fails if the receiver is gone, which can't happen while `self` is alive.
     pub fn push(&self, item: T) {
         self.sender.send(item).expect("Failed to enqueue item");
         self.length.fetch_add(1, Ordering::Relaxed);